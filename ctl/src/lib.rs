@@ -13,13 +13,23 @@
 
 //! # Raft Engine Control
 
+use std::io::{BufRead, Write};
 use std::path::Path;
 use std::sync::Arc;
 
 use clap::{crate_authors, crate_version, Parser};
+use raft::eraftpb::Entry;
 use raft_engine::env::{DefaultFileSystem, FileSystem};
-use raft_engine::internals::LogQueue;
-use raft_engine::{Engine, Error, Result as EngineResult};
+use raft_engine::internals::debug::LogItemPosition;
+use raft_engine::internals::{Inspector, LogItem, LogItemContent, LogQueue, OpType};
+use raft_engine::{
+    Command, DeepCheckReport, DefaultPurgePolicy, Engine, Error, ExportedRegions, MessageExt,
+    ReadableSize, Result as EngineResult, Version,
+};
+
+mod output;
+
+use output::{queue_name, Format, Printer, Record};
 
 #[derive(Debug, clap::Parser)]
 #[clap(
@@ -32,6 +42,16 @@ pub struct ControlOpt {
     // sub command type
     #[clap(subcommand)]
     cmd: Option<Cmd>,
+
+    /// Output format. Binary keys and values are hex-encoded in formats other
+    /// than text.
+    #[clap(
+        long,
+        global = true,
+        default_value = "text",
+        possible_values = &["text", "json", "jsonl", "csv"]
+    )]
+    format: String,
 }
 
 #[derive(Debug, Parser)]
@@ -51,6 +71,11 @@ enum Cmd {
         /// Path of Raft Engine directory.
         #[clap(short, long)]
         path: String,
+
+        /// Also verify checksums and indexes of all log entries, and the order
+        /// of compactions.
+        #[clap(long)]
+        deep: bool,
     },
 
     /// Run Rhai script to repair data files.
@@ -77,6 +102,126 @@ enum Cmd {
         #[clap(short, long)]
         path: String,
     },
+
+    /// Export live log entries and key values of Raft Groups to a file.
+    Export {
+        /// Path of Raft Engine directory.
+        #[clap(short, long)]
+        path: String,
+
+        /// Raft Groups to export. All Raft Groups are exported if empty.
+        #[clap(short, long, use_value_delimiter = true)]
+        raft_groups: Vec<u64>,
+
+        /// Path of the exported file.
+        #[clap(short, long)]
+        out: String,
+    },
+
+    /// Import Raft Groups from an exported file.
+    Import {
+        /// Path of Raft Engine directory.
+        #[clap(short, long)]
+        path: String,
+
+        /// Path of the exported file.
+        #[clap(short, long)]
+        file: String,
+    },
+
+    /// Rewrite live data into a fresh set of log files with new format
+    /// options. The original directory is kept with suffix ".bak".
+    Rewrite {
+        /// Path of Raft Engine directory.
+        #[clap(short, long)]
+        path: String,
+
+        #[clap(long, possible_values = &["1", "2"])]
+        format_version: u64,
+
+        /// Target size of new log files, e.g. "128MB".
+        #[clap(long)]
+        target_file_size: Option<String>,
+
+        /// Minimum size of log batch to be compressed, "0" disables
+        /// compression.
+        #[clap(long)]
+        compression: Option<String>,
+
+        /// Enable log recycling in the rewritten directory.
+        #[clap(long)]
+        enable_log_recycle: bool,
+    },
+
+    /// Query data files without modifying them. Starts an interactive shell
+    /// if no query is given.
+    Inspect {
+        /// Path of Raft Engine directory.
+        #[clap(short, long)]
+        path: String,
+
+        #[clap(subcommand)]
+        query: Option<Query>,
+    },
+}
+
+#[derive(Debug, Parser)]
+enum Query {
+    /// List log files with their sizes and format versions.
+    Files,
+
+    /// List all Raft Groups.
+    Regions,
+
+    /// Show the index span, keys and referenced files of a Raft Group.
+    Region {
+        #[clap(short, long)]
+        id: u64,
+    },
+
+    /// Fetch and decode a log entry.
+    Entry {
+        #[clap(short, long)]
+        region: u64,
+
+        #[clap(short, long)]
+        index: u64,
+    },
+
+    /// Print the value of a key.
+    Get {
+        #[clap(short, long)]
+        region: u64,
+
+        #[clap(short, long)]
+        key: String,
+
+        /// Parse the key as hex string.
+        #[clap(long)]
+        hex: bool,
+    },
+
+    /// Show the watermarks of purge.
+    Watermarks,
+}
+
+/// A line of input in interactive shell.
+#[derive(Debug, Parser)]
+#[clap(no_binary_name = true)]
+struct ShellLine {
+    #[clap(subcommand)]
+    query: Query,
+}
+
+/// Log entries are decoded as Raft entries.
+struct EntryExt;
+
+impl MessageExt for EntryExt {
+    type Entry = Entry;
+
+    fn index(e: &Entry) -> u64 {
+        e.index
+    }
 }
 
 fn convert_queue(queue: &str) -> Option<LogQueue> {
@@ -88,14 +233,49 @@ fn convert_queue(queue: &str) -> Option<LogQueue> {
     }
 }
 
+fn parse_size(s: &str) -> EngineResult<ReadableSize> {
+    s.parse().map_err(Error::InvalidArgument)
+}
+
 impl ControlOpt {
     pub fn validate_and_execute(self) -> EngineResult<()> {
         self.validate_and_execute_with_file_system(Arc::new(DefaultFileSystem))
     }
 
-    pub fn validate_and_execute_with_file_system<F: FileSystem>(
+    /// Executes the command. Errors are printed in the specified format
+    /// before being returned.
+    pub fn validate_and_execute_with_file_system<F: FileSystem + 'static>(
         mut self,
         fs: Arc<F>,
+    ) -> EngineResult<()> {
+        let format = Format::from_name(&self.format).unwrap();
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+        if let Some(Cmd::Inspect { path, query: None }) = &self.cmd {
+            // Each query in the shell prints its own records.
+            let res = Engine::inspect_with_file_system(Path::new(path), fs).and_then(|inspector| {
+                run_shell(&inspector, std::io::stdin().lock(), format, &mut out)
+            });
+            if let Err(e) = &res {
+                let mut printer = Printer::new(format, &mut out);
+                printer.error(e)?;
+                printer.finish()?;
+            }
+            return res;
+        }
+        let mut printer = Printer::new(format, &mut out);
+        let res = self.execute(fs, &mut printer);
+        if let Err(e) = &res {
+            printer.error(e)?;
+        }
+        printer.finish()?;
+        res
+    }
+
+    fn execute<F: FileSystem + 'static>(
+        &mut self,
+        fs: Arc<F>,
+        printer: &mut Printer,
     ) -> EngineResult<()> {
         if self.cmd.is_none() {
             return Err(Error::InvalidArgument("subcommand is needed".to_owned()));
@@ -103,15 +283,20 @@ impl ControlOpt {
 
         match self.cmd.take().unwrap() {
             Cmd::Dump { path, raft_groups } => {
-                let it = Engine::dump_with_file_system(Path::new(&path), fs)?;
-                for item in it {
-                    if let Ok(v) = item {
-                        if raft_groups.is_empty() || raft_groups.contains(&v.raft_group_id) {
-                            println!("{:?}", v)
+                let mut it = Engine::dump_with_file_system(Path::new(&path), fs)?;
+                while let Some(item) = it.next_with_position() {
+                    match item {
+                        Ok((position, item)) => {
+                            if raft_groups.is_empty() || raft_groups.contains(&item.raft_group_id) {
+                                if printer.format() == Format::Text {
+                                    writeln!(printer.out(), "{:?}", item)?;
+                                } else {
+                                    printer.row(item_record(position, item))?;
+                                }
+                            }
                         }
-                    } else {
                         // output error message
-                        println!("{:?}", item)
+                        Err(e) => printer.error(&e)?,
                     }
                 }
             }
@@ -128,13 +313,33 @@ impl ControlOpt {
                     fs,
                 )?;
             }
-            Cmd::Check { path } => {
+            Cmd::Check { path, deep: true } => {
+                let r = Engine::deep_consistency_check_with_file_system::<EntryExt>(
+                    Path::new(&path),
+                    fs,
+                )?;
+                print_deep_check(&r, printer)?;
+            }
+            Cmd::Check { path, deep: false } => {
                 let r = Engine::consistency_check_with_file_system(Path::new(&path), fs)?;
-                if r.is_empty() {
-                    println!("All data is Ok")
+                if printer.format() != Format::Text {
+                    for (id, index) in r {
+                        printer.row(
+                            Record::new()
+                                .field("raft_group_id", id)
+                                .field("last_intact_index", index),
+                        )?;
+                    }
+                } else if r.is_empty() {
+                    writeln!(printer.out(), "All data is Ok")?;
                 } else {
-                    println!("Corrupted info are as follows:\nraft_group_id, last_intact_index\n");
-                    r.iter().for_each(|(x, y)| println!("{:?}, {:?}", x, y))
+                    writeln!(
+                        printer.out(),
+                        "Corrupted info are as follows:\nraft_group_id, last_intact_index\n"
+                    )?;
+                    for (x, y) in r {
+                        writeln!(printer.out(), "{:?}, {:?}", x, y)?;
+                    }
                 }
             }
             Cmd::TryPurge { path } => {
@@ -145,20 +350,515 @@ impl ControlOpt {
                     },
                     fs,
                 )?;
-                println!(
-                    "purge_expired_files() returns {:?}",
-                    e.purge_expired_files()?
-                );
+                let to_compact = e.purge_expired_files()?;
+                if printer.format() == Format::Text {
+                    writeln!(
+                        printer.out(),
+                        "purge_expired_files() returns {:?}",
+                        to_compact
+                    )?;
+                } else {
+                    for id in to_compact {
+                        printer.row(Record::new().field("raft_group_id", id))?;
+                    }
+                }
+            }
+            Cmd::Export {
+                path,
+                raft_groups,
+                out,
+            } => {
+                let inspector = Engine::inspect_with_file_system(Path::new(&path), fs)?;
+                let raft_groups = if raft_groups.is_empty() {
+                    inspector.raft_groups()
+                } else {
+                    raft_groups
+                };
+                let mut exported = ExportedRegions::default();
+                for id in raft_groups {
+                    exported.regions.push(inspector.export_region(id)?);
+                }
+                std::fs::write(&out, exported.encode()?)?;
+                for region in &exported.regions {
+                    printer.row(
+                        Record::new()
+                            .field("raft_group_id", region.region_id)
+                            .field("first_index", region.span().map(|s| s.0))
+                            .field("last_index", region.span().map(|s| s.1))
+                            .field("entries", region.entries.len())
+                            .field("kvs", region.kvs.len()),
+                    )?;
+                }
+            }
+            Cmd::Import { path, file } => {
+                let exported = ExportedRegions::decode(&std::fs::read(&file)?)?;
+                let engine = Engine::open_with_file_system(
+                    raft_engine::Config {
+                        dir: path,
+                        ..Default::default()
+                    },
+                    fs,
+                )?;
+                for region in engine.import_regions(&exported)? {
+                    printer.row(
+                        Record::new()
+                            .field("raft_group_id", region.region_id)
+                            .field("first_index", region.span.map(|s| s.0))
+                            .field("last_index", region.span.map(|s| s.1))
+                            .field("entries", region.entries)
+                            .field("kvs", region.kvs),
+                    )?;
+                }
+            }
+            Cmd::Rewrite {
+                path,
+                format_version,
+                target_file_size,
+                compression,
+                enable_log_recycle,
+            } => {
+                let mut cfg = raft_engine::Config {
+                    dir: path,
+                    format_version: if format_version == 1 {
+                        Version::V1
+                    } else {
+                        Version::V2
+                    },
+                    enable_log_recycle,
+                    ..Default::default()
+                };
+                if let Some(size) = target_file_size {
+                    cfg.target_file_size = parse_size(&size)?;
+                }
+                if let Some(size) = compression {
+                    cfg.batch_compression_threshold = parse_size(&size)?;
+                }
+                let report = Engine::rewrite_directory_with_file_system(cfg, fs)?;
+                if printer.format() == Format::Text {
+                    writeln!(
+                        printer.out(),
+                        "Rewrote {} Raft Groups, size {} -> {}, original files are moved to {}",
+                        report.regions.len(),
+                        report.size_before,
+                        report.size_after,
+                        report.backup_dir.display()
+                    )?;
+                } else {
+                    printer.object(
+                        Record::new()
+                            .field("raft_groups", report.regions.len())
+                            .field("size_before", report.size_before)
+                            .field("size_after", report.size_after)
+                            .field("backup_dir", report.backup_dir.display().to_string()),
+                    )?;
+                }
+            }
+            Cmd::Inspect { path, query } => {
+                let inspector = Engine::inspect_with_file_system(Path::new(&path), fs)?;
+                run_query(&inspector, query.unwrap(), printer)?;
             }
         }
         Ok(())
     }
 }
 
-pub fn run_command<F: FileSystem>(mut args: Vec<String>, fs: Arc<F>) {
+fn print_deep_check(r: &DeepCheckReport, printer: &mut Printer) -> EngineResult<()> {
+    if printer.format() != Format::Text {
+        // Files and Raft Groups share one set of columns, so that they can be
+        // printed as a single table. Columns of the other kind are left empty.
+        for f in &r.files {
+            printer.row(
+                Record::new()
+                    .field("kind", "file")
+                    .file_id(f.file_id)
+                    .field("blocks", f.blocks)
+                    .field("punched_blocks", f.punched_blocks)
+                    .field("entries", f.entries)
+                    .field("raft_group_id", None::<u64>)
+                    .field("first_index", None::<u64>)
+                    .field("last_index", None::<u64>)
+                    .field("errors", f.errors.clone()),
+            )?;
+        }
+        for region in &r.regions {
+            printer.row(
+                Record::new()
+                    .field("kind", "region")
+                    .field("queue", None::<&str>)
+                    .field("file_seq", None::<u64>)
+                    .field("blocks", None::<usize>)
+                    .field("punched_blocks", None::<usize>)
+                    .field("entries", None::<usize>)
+                    .field("raft_group_id", region.raft_group_id)
+                    .field("first_index", region.first_index)
+                    .field("last_index", region.last_index)
+                    .field("errors", region.errors.clone()),
+            )?;
+        }
+        return Ok(());
+    }
+    let out = printer.out();
+    for f in r.files.iter().filter(|f| !f.errors.is_empty()) {
+        for e in &f.errors {
+            writeln!(
+                out,
+                "{}:{}: {}",
+                queue_name(f.file_id.queue),
+                f.file_id.seq,
+                e
+            )?;
+        }
+    }
+    for region in r.regions.iter().filter(|r| !r.errors.is_empty()) {
+        for e in &region.errors {
+            writeln!(out, "raft group {}: {}", region.raft_group_id, e)?;
+        }
+    }
+    writeln!(
+        out,
+        "Checked {} files, {} blocks, {} entries, {} punched blocks, {} raft groups",
+        r.files.len(),
+        r.files.iter().map(|f| f.blocks).sum::<usize>(),
+        r.files.iter().map(|f| f.entries).sum::<usize>(),
+        r.files.iter().map(|f| f.punched_blocks).sum::<usize>(),
+        r.regions.len()
+    )?;
+    if r.is_ok() {
+        writeln!(out, "All data is Ok")?;
+    }
+    Ok(())
+}
+
+fn item_record(position: LogItemPosition, item: LogItem) -> Record {
+    let (mut first_index, mut last_index, mut compression) = (None, None, None);
+    let (mut command, mut compact_index, mut source) = (None, None, None);
+    let (mut op, mut key, mut value) = (None, None, None);
+    let item_type = match item.content {
+        LogItemContent::EntryIndexes(ents) => {
+            first_index = ents.0.first().map(|e| e.index);
+            last_index = ents.0.last().map(|e| e.index);
+            compression = ents.0.first().map(|e| format!("{:?}", e.compression_type));
+            "entries"
+        }
+        LogItemContent::Command(Command::Clean) => {
+            command = Some("clean");
+            "command"
+        }
+        LogItemContent::Command(Command::Compact { index }) => {
+            command = Some("compact");
+            compact_index = Some(index);
+            "command"
+        }
+        LogItemContent::Command(Command::Rename { from }) => {
+            command = Some("rename");
+            source = Some(from);
+            "command"
+        }
+        LogItemContent::Command(Command::Clone { from, begin, end }) => {
+            command = Some("clone");
+            source = Some(from);
+            first_index = Some(begin);
+            last_index = end.checked_sub(1);
+            "command"
+        }
+        LogItemContent::Kv(kv) => {
+            op = Some(match kv.op_type {
+                OpType::Put => "put",
+                OpType::Del => "delete",
+            });
+            key = Some(hex::encode(&kv.key));
+            value = kv.value.map(hex::encode);
+            "kv"
+        }
+    };
+    Record::new()
+        .file_id(position.file_id)
+        .field("batch_offset", position.batch_offset)
+        .field("raft_group_id", item.raft_group_id)
+        .field("type", item_type)
+        .field("first_index", first_index)
+        .field("last_index", last_index)
+        .field("compression", compression)
+        .field("command", command)
+        .field("compact_index", compact_index)
+        .field("source_raft_group_id", source)
+        .field("op", op)
+        .field("key", key)
+        .field("value", value)
+}
+
+fn run_query<F: FileSystem>(
+    inspector: &Inspector<F>,
+    query: Query,
+    printer: &mut Printer,
+) -> EngineResult<()> {
+    // Keys and values are easier to read when escaped in text.
+    let text = printer.format() == Format::Text;
+    let bytes = |b: &[u8]| {
+        if text {
+            b.escape_ascii().to_string()
+        } else {
+            hex::encode(b)
+        }
+    };
+    match query {
+        Query::Files => {
+            for f in inspector.files() {
+                printer.row(
+                    Record::new()
+                        .file_id(f.file_id)
+                        .field("size", f.size)
+                        .field("version", f.version.map(|v| v as u64))
+                        .field("alignment", f.alignment)
+                        .field("valid_size", f.valid_size)
+                        .field("corruption", f.corruption.clone()),
+                )?;
+            }
+        }
+        Query::Regions => {
+            for id in inspector.raft_groups() {
+                let info = match inspector.raft_group(id)? {
+                    Some(info) => info,
+                    None => continue,
+                };
+                printer.row(
+                    Record::new()
+                        .field("raft_group_id", id)
+                        .field("first_index", info.first_index)
+                        .field("last_index", info.last_index),
+                )?;
+            }
+        }
+        Query::Region { id } => {
+            let info = inspector
+                .raft_group(id)?
+                .ok_or_else(|| Error::InvalidArgument(format!("Raft Group {} not found", id)))?;
+            let keys: Vec<_> = info.keys.iter().map(|k| bytes(k)).collect();
+            let files: Vec<_> = info
+                .files
+                .iter()
+                .map(|f| format!("{}:{}", queue_name(f.queue), f.seq))
+                .collect();
+            printer.object(
+                Record::new()
+                    .field("raft_group_id", info.raft_group_id)
+                    .field("first_index", info.first_index)
+                    .field("last_index", info.last_index)
+                    .field("rewrite_count", info.rewrite_count)
+                    .field("keys", keys)
+                    .field("files", files),
+            )?;
+        }
+        Query::Entry { region, index } => {
+            let idx = inspector
+                .entry_index(region, index)
+                .ok_or(Error::EntryNotFound)?;
+            let data = inspector.read_entry(&idx)?;
+            let handle = idx.entries.unwrap();
+            printer.object(
+                Record::new()
+                    .field("raft_group_id", region)
+                    .field("index", idx.index)
+                    .file_id(handle.id)
+                    .field("block_offset", handle.offset)
+                    .field("block_len", handle.len)
+                    .field("compression", format!("{:?}", idx.compression_type))
+                    .field("entry_offset", idx.entry_offset)
+                    .field("entry_len", idx.entry_len)
+                    .field("data", hex::encode(&data)),
+            )?;
+        }
+        Query::Get { region, key, hex } => {
+            let key = if hex {
+                hex::decode(&key).map_err(|e| Error::InvalidArgument(e.to_string()))?
+            } else {
+                key.into_bytes()
+            };
+            let value = inspector.get(region, &key)?.ok_or_else(|| {
+                Error::InvalidArgument(format!("Key {} not found", key.escape_ascii()))
+            })?;
+            printer.object(
+                Record::new()
+                    .field("raft_group_id", region)
+                    .field("key", bytes(&key))
+                    .field("value", bytes(&value)),
+            )?;
+        }
+        Query::Watermarks => {
+            let w = inspector.purge_watermarks(&DefaultPurgePolicy);
+            printer.object(
+                Record::new()
+                    .field("append_first", w.append_span.map(|s| s.0))
+                    .field("append_active", w.append_span.map(|s| s.1))
+                    .field("rewrite_first", w.rewrite_span.map(|s| s.0))
+                    .field("rewrite_active", w.rewrite_span.map(|s| s.1))
+                    .field("rewrite_watermark", w.rewrite_watermark)
+                    .field("compact_watermark", w.compact_watermark)
+                    .field("append_min_referenced", w.append_min_referenced)
+                    .field("rewrite_min_referenced", w.rewrite_min_referenced),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Answers queries read from `input` line by line, until "exit" or EOF.
+fn run_shell<F: FileSystem>(
+    inspector: &Inspector<F>,
+    mut input: impl BufRead,
+    format: Format,
+    out: &mut dyn Write,
+) -> EngineResult<()> {
+    let mut line = String::new();
+    loop {
+        if format == Format::Text {
+            write!(out, "> ")?;
+            out.flush()?;
+        }
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            break;
+        }
+        let args: Vec<_> = line.split_whitespace().collect();
+        match args.first() {
+            None => continue,
+            Some(&"exit") | Some(&"quit") => break,
+            _ => {}
+        }
+        let mut printer = Printer::new(format, out);
+        match ShellLine::try_parse_from(args) {
+            Ok(ShellLine { query }) => {
+                if let Err(e) = run_query(inspector, query, &mut printer) {
+                    printer.error(&e)?;
+                }
+            }
+            Err(e) if format == Format::Text => writeln!(printer.out(), "{}", e)?,
+            Err(e) => printer.error(&Error::InvalidArgument(e.to_string()))?,
+        }
+        printer.finish()?;
+    }
+    Ok(())
+}
+
+pub fn run_command<F: FileSystem + 'static>(mut args: Vec<String>, fs: Arc<F>) {
     args.insert(0, "ctl".to_owned());
     let opts = ControlOpt::parse_from(args);
-    if let Err(e) = opts.validate_and_execute_with_file_system(fs) {
-        println!("{:?}", e);
+    // Errors are already printed.
+    let _ = opts.validate_and_execute_with_file_system(fs);
+}
+
+#[cfg(test)]
+mod tests {
+    use raft_engine::internals::FileId;
+    use raft_engine::{Config, FileCheckResult, LogBatch, RegionCheckResult};
+
+    use super::*;
+
+    fn shell(inspector: &Inspector<DefaultFileSystem>, input: &str) -> String {
+        let mut out = Vec::new();
+        run_shell(inspector, input.as_bytes(), Format::Jsonl, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_print_deep_check() {
+        let report = DeepCheckReport {
+            files: vec![FileCheckResult {
+                file_id: FileId::new(LogQueue::Append, 1),
+                blocks: 2,
+                punched_blocks: 1,
+                entries: 10,
+                errors: vec![],
+            }],
+            regions: vec![RegionCheckResult {
+                raft_group_id: 3,
+                first_index: Some(5),
+                last_index: None,
+                errors: vec!["bad".to_owned()],
+            }],
+        };
+        let mut out = Vec::new();
+        let mut printer = Printer::new(Format::Csv, &mut out);
+        print_deep_check(&report, &mut printer).unwrap();
+        printer.finish().unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "kind,queue,file_seq,blocks,punched_blocks,entries,raft_group_id,first_index,\
+             last_index,errors\n\
+             file,append,1,2,1,10,,,,[]\n\
+             region,,,,,,3,5,,\"[\"\"bad\"\"]\"\n"
+        );
+    }
+
+    #[test]
+    fn test_inspect_shell() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_inspect_shell")
+            .tempdir()
+            .unwrap();
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+        let engine = Engine::open(cfg).unwrap();
+        let mut batch = LogBatch::default();
+        let entries: Vec<_> = (1..=3)
+            .map(|index| Entry {
+                index,
+                data: vec![b'x'; index as usize].into(),
+                ..Default::default()
+            })
+            .collect();
+        batch.add_entries::<EntryExt>(1, &entries).unwrap();
+        batch.put(2, b"k".to_vec(), b"v".to_vec()).unwrap();
+        engine.write(&mut batch, true).unwrap();
+        drop(engine);
+
+        let inspector = Engine::inspect(dir.path()).unwrap();
+        assert_eq!(
+            shell(&inspector, "regions\n\nexit\nregions\n"),
+            "{\"raft_group_id\":1,\"first_index\":1,\"last_index\":3}\n\
+             {\"raft_group_id\":2,\"first_index\":null,\"last_index\":null}\n"
+        );
+        assert_eq!(
+            shell(
+                &inspector,
+                "region --id 2\nget -r 2 -k k\nget -r 2 -k 6b --hex\n"
+            ),
+            "{\"raft_group_id\":2,\"first_index\":null,\"last_index\":null,\
+             \"rewrite_count\":0,\"keys\":[\"6b\"],\"files\":[\"append:1\"]}\n\
+             {\"raft_group_id\":2,\"key\":\"6b\",\"value\":\"76\"}\n\
+             {\"raft_group_id\":2,\"key\":\"6b\",\"value\":\"76\"}\n"
+        );
+        let out = shell(&inspector, "entry -r 1 -i 2\n");
+        let entry: serde_json::Value = serde_json::from_str(out.trim()).unwrap();
+        assert_eq!(entry["index"], 2);
+        assert_eq!(entry["queue"], "append");
+        let data = hex::decode(entry["data"].as_str().unwrap()).unwrap();
+        assert_eq!(entry["entry_len"], data.len());
+        let out = shell(&inspector, "files\nwatermarks\n");
+        let lines: Vec<serde_json::Value> = out
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3, "{}", out);
+        assert_eq!(lines[0]["queue"], "rewrite", "{}", out);
+        assert_eq!(lines[1]["queue"], "append", "{}", out);
+        assert_eq!(lines[1]["file_seq"], 1);
+        assert_eq!(lines[1]["corruption"], serde_json::Value::Null);
+        assert_eq!(lines[2]["append_active"], 1);
+
+        // Errors don't end the shell.
+        let out = shell(
+            &inspector,
+            "region --id 3\nentry -r 1 -i 4\nget -r 2 -k x\nfoo\nregions\n",
+        );
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 6, "{}", out);
+        assert!(lines[0].contains("Raft Group 3 not found"), "{}", out);
+        assert!(lines[1].contains("EntryNotFound"), "{}", out);
+        assert!(lines[2].contains("Key x not found"), "{}", out);
+        assert!(lines[3].contains("InvalidArgument"), "{}", out);
+        assert!(lines[4].starts_with("{\"raft_group_id\":1"), "{}", out);
     }
 }
@@ -7,7 +7,8 @@ fn main() {
     env_logger::init();
     let opts: ControlOpt = ControlOpt::parse();
 
-    if let Err(e) = opts.validate_and_execute() {
-        println!("{:?}", e);
+    // Errors are already printed.
+    if opts.validate_and_execute().is_err() {
+        std::process::exit(1);
     }
 }
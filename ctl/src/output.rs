@@ -0,0 +1,283 @@
+// Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
+
+//! Formatting of command outputs.
+
+use std::io::{Result as IoResult, Write};
+
+use raft_engine::internals::{FileId, LogQueue};
+use raft_engine::Error;
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde_json::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Human readable text.
+    Text,
+    /// A JSON array of records.
+    Json,
+    /// One JSON record per line.
+    Jsonl,
+    /// Comma-separated values with a header line. Errors are printed to
+    /// stderr as JSON records.
+    Csv,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            "jsonl" => Some(Format::Jsonl),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+}
+
+/// A list of named values, serialized in order.
+#[derive(Clone, Debug, Default)]
+pub struct Record(Vec<(&'static str, Value)>);
+
+impl Record {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn field(mut self, name: &'static str, value: impl Into<Value>) -> Self {
+        self.0.push((name, value.into()));
+        self
+    }
+
+    pub fn file_id(self, file_id: FileId) -> Self {
+        self.field("queue", queue_name(file_id.queue))
+            .field("file_seq", file_id.seq)
+    }
+}
+
+impl Serialize for Record {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (name, value) in &self.0 {
+            map.serialize_entry(name, value)?;
+        }
+        map.end()
+    }
+}
+
+pub fn queue_name(queue: LogQueue) -> &'static str {
+    match queue {
+        LogQueue::Append => "append",
+        LogQueue::Rewrite => "rewrite",
+    }
+}
+
+fn error_kind(e: &Error) -> &'static str {
+    match e {
+        Error::InvalidArgument(_) => "InvalidArgument",
+        Error::Corruption(_) => "Corruption",
+        Error::Io(_) => "Io",
+        Error::Codec(_) => "Codec",
+        Error::Protobuf(_) => "Protobuf",
+        Error::EntryCompacted => "EntryCompacted",
+        Error::EntryNotFound => "EntryNotFound",
+        Error::Full => "Full",
+        Error::Cancelled => "Cancelled",
+        Error::NotReady => "NotReady",
+        Error::Other(_) => "Other",
+    }
+}
+
+fn error_record(e: &Error) -> Record {
+    let error = Record::new()
+        .field("kind", error_kind(e))
+        .field("message", e.to_string());
+    Record::new().field("error", serde_json::to_value(error).unwrap())
+}
+
+fn text_value(value: &Value) -> String {
+    match value {
+        Value::Null => "none".to_owned(),
+        Value::String(s) => s.clone(),
+        Value::Array(values) => {
+            let values: Vec<_> = values.iter().map(text_value).collect();
+            format!("[{}]", values.join(", "))
+        }
+        v => v.to_string(),
+    }
+}
+
+fn csv_value(value: &Value) -> String {
+    let s = match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    };
+    if s.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s
+    }
+}
+
+/// Prints records of a command in the specified format.
+pub struct Printer<'a> {
+    format: Format,
+    out: &'a mut dyn Write,
+    records: usize,
+    /// Column names of the last printed header.
+    header: Vec<&'static str>,
+}
+
+impl<'a> Printer<'a> {
+    pub fn new(format: Format, out: &'a mut dyn Write) -> Self {
+        Self {
+            format,
+            out,
+            records: 0,
+            header: Vec::new(),
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Returns the underlying writer, for text output that doesn't fit in
+    /// records.
+    pub fn out(&mut self) -> &mut dyn Write {
+        self.out
+    }
+
+    /// Prints a record. Text output has one line for each field.
+    pub fn object(&mut self, record: Record) -> IoResult<()> {
+        if self.format == Format::Text {
+            for (name, value) in &record.0 {
+                writeln!(self.out, "{}: {}", name, text_value(value))?;
+            }
+            Ok(())
+        } else {
+            self.record(record)
+        }
+    }
+
+    /// Prints a record. Text output is a row of table.
+    pub fn row(&mut self, record: Record) -> IoResult<()> {
+        if self.format == Format::Text {
+            self.write_header(&record)?;
+            let values: Vec<_> = record.0.iter().map(|(_, v)| text_value(v)).collect();
+            writeln!(self.out, "{}", values.join(", "))
+        } else {
+            self.record(record)
+        }
+    }
+
+    pub fn error(&mut self, e: &Error) -> IoResult<()> {
+        match self.format {
+            Format::Text => writeln!(self.out, "{:?}", e),
+            Format::Json | Format::Jsonl => self.record(error_record(e)),
+            Format::Csv => {
+                let stderr = std::io::stderr();
+                let mut stderr = stderr.lock();
+                serde_json::to_writer(&mut stderr, &error_record(e))?;
+                writeln!(stderr)
+            }
+        }
+    }
+
+    /// Completes the output.
+    pub fn finish(self) -> IoResult<()> {
+        if self.format == Format::Json {
+            write!(self.out, "{}", if self.records == 0 { "[" } else { "\n" })?;
+            writeln!(self.out, "]")?;
+        }
+        self.out.flush()
+    }
+
+    fn write_header(&mut self, record: &Record) -> IoResult<()> {
+        let names: Vec<_> = record.0.iter().map(|(name, _)| *name).collect();
+        if names != self.header {
+            let sep = if self.format == Format::Csv {
+                ","
+            } else {
+                ", "
+            };
+            writeln!(self.out, "{}", names.join(sep))?;
+            self.header = names;
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, record: Record) -> IoResult<()> {
+        match self.format {
+            Format::Text => unreachable!(),
+            Format::Json => {
+                write!(
+                    self.out,
+                    "{}",
+                    if self.records == 0 { "[\n" } else { ",\n" }
+                )?;
+                serde_json::to_writer(&mut self.out, &record)?;
+            }
+            Format::Jsonl => {
+                serde_json::to_writer(&mut self.out, &record)?;
+                writeln!(self.out)?;
+            }
+            Format::Csv => {
+                self.write_header(&record)?;
+                let values: Vec<_> = record.0.iter().map(|(_, v)| csv_value(v)).collect();
+                writeln!(self.out, "{}", values.join(","))?;
+            }
+        }
+        self.records += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn print(format: Format, records: Vec<Record>, error: Option<Error>) -> String {
+        let mut buf = Vec::new();
+        let mut printer = Printer::new(format, &mut buf);
+        for r in records {
+            printer.row(r).unwrap();
+        }
+        if let Some(e) = error {
+            printer.error(&e).unwrap();
+        }
+        printer.finish().unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn test_printer() {
+        let records = || {
+            vec![
+                Record::new().field("id", 1).field("key", "a,\"b\""),
+                Record::new()
+                    .field("id", 2)
+                    .field("key", Value::Null)
+                    .field("x", 0),
+            ]
+        };
+        assert_eq!(
+            print(Format::Text, records(), None),
+            "id, key\n1, a,\"b\"\nid, key, x\n2, none, 0\n"
+        );
+        assert_eq!(
+            print(Format::Csv, records(), None),
+            "id,key\n1,\"a,\"\"b\"\"\"\nid,key,x\n2,,0\n"
+        );
+        assert_eq!(
+            print(Format::Jsonl, records(), Some(Error::EntryNotFound)),
+            "{\"id\":1,\"key\":\"a,\\\"b\\\"\"}\n{\"id\":2,\"key\":null,\"x\":0}\n\
+             {\"error\":{\"kind\":\"EntryNotFound\",\"message\":\"Entry Not Found\"}}\n"
+        );
+        assert_eq!(print(Format::Json, vec![], None), "[]\n");
+        assert_eq!(
+            print(Format::Json, records(), None),
+            "[\n{\"id\":1,\"key\":\"a,\\\"b\\\"\"},\n{\"id\":2,\"key\":null,\"x\":0}\n]\n"
+        );
+    }
+}
@@ -1,13 +1,18 @@
 // Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
 
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
 use log::warn;
+use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 
 use crate::pipe_log::Version;
-use crate::{util::ReadableSize, Result};
+use crate::{util::ReadableSize, Error, Result};
 
 const MIN_RECOVERY_READ_BLOCK_SIZE: usize = 512;
 const MIN_RECOVERY_THREADS: usize = 1;
+const MIN_HOLE_PUNCH_BLOCK_SIZE: usize = 4096;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
@@ -20,6 +25,10 @@ pub enum RecoveryMode {
     )]
     TolerateTailCorruption,
     TolerateAnyCorruption,
+    /// Skips corrupted log batches and resumes from the next valid one. The
+    /// corrupted byte ranges are left in place, and are reported along with
+    /// the lost entries of each Raft Group.
+    Salvage,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -79,6 +88,43 @@ pub struct Config {
     /// Default: "0.6"
     pub purge_rewrite_garbage_ratio: f64,
 
+    /// Maximum bytes per second of background rewrite I/O, for each log queue.
+    /// Both reads of stale log entries and writes to rewrite queue are
+    /// throttled. Can be changed at runtime via
+    /// `Engine::set_rewrite_rate_limit`.
+    ///
+    /// Default: None (unlimited)
+    pub rewrite_rate_limit: Option<ReadableSize>,
+    /// Maximum bytes per second of background scrubbing, which reads through
+    /// sealed log files and verifies their checksums to detect corruption
+    /// early. Detected corruptions are reported via
+    /// `EventListener::on_corruption_detected`. Scrubbing is disabled if
+    /// unset. Can be changed at runtime via `Engine::update_config`.
+    ///
+    /// Default: None
+    pub scrub_rate_limit: Option<ReadableSize>,
+
+    /// Blocks of log entries are read with async I/O when there are more than
+    /// this number of them in a single read.
+    ///
+    /// Default: 5
+    pub async_read_blocks_threshold: usize,
+    /// Blocks of log entries are read with async I/O when their total size in
+    /// a single read exceeds this value. Both thresholds must be exceeded.
+    ///
+    /// Default: "1MB"
+    pub async_read_bytes_threshold: ReadableSize,
+
+    /// Values of key value pairs no smaller than this are not kept in memory.
+    /// Only their locations and checksums are kept, and they are read from log
+    /// files on demand. The threshold also applies to values replayed from
+    /// log files on startup, no matter when they were written. Values restored
+    /// from the region map of lazy recovery keep the form they had at
+    /// shutdown.
+    ///
+    /// Default: None
+    pub kv_spill_threshold: Option<ReadableSize>,
+
     /// Maximum memory bytes allowed for the in-memory index.
     /// Effective under the `swap` feature only.
     ///
@@ -99,6 +145,30 @@ pub struct Config {
     ///
     /// Default: false
     pub prefill_for_recycle: bool,
+
+    /// Whether to reclaim the disk space of obsolete log entries in append
+    /// queue by punching holes into log files. Large blocks of entries are
+    /// left in place during rewrite, and are deallocated once all of their
+    /// entries are compacted or cleaned.
+    /// Only effective on platforms that support `fallocate(2)` hole punching.
+    ///
+    /// Default: false
+    pub enable_hole_punching: bool,
+    /// Minimum size of a block of log entries to be reclaimed by hole punching.
+    /// Only effective when `enable-hole-punching` is true.
+    ///
+    /// Default: "64KB". Minimum: "4KB".
+    pub hole_punch_min_block_size: ReadableSize,
+
+    /// Whether to open the engine before log entries are indexed. If `true`,
+    /// a map of Raft Groups and their log files is persisted by
+    /// `Engine::close`. When the log files are unchanged since then, the
+    /// engine is opened with only key value pairs recovered from the map, and
+    /// entries are indexed in background. Reading or writing a Raft Group
+    /// that isn't indexed in time returns `Error::NotReady`.
+    ///
+    /// Default: false
+    pub enable_lazy_recovery: bool,
 }
 
 impl Default for Config {
@@ -116,9 +186,17 @@ impl Default for Config {
             purge_threshold: ReadableSize::gb(10),
             purge_rewrite_threshold: None,
             purge_rewrite_garbage_ratio: 0.6,
+            rewrite_rate_limit: None,
+            scrub_rate_limit: None,
+            async_read_blocks_threshold: 5,
+            async_read_bytes_threshold: ReadableSize::mb(1),
+            kv_spill_threshold: None,
             memory_limit: None,
             enable_log_recycle: false,
             prefill_for_recycle: false,
+            enable_hole_punching: false,
+            hole_punch_min_block_size: ReadableSize::kb(64),
+            enable_lazy_recovery: false,
         };
         // Test-specific configurations.
         #[cfg(test)]
@@ -136,10 +214,16 @@ impl Config {
             return Err(box_err!("purge-threshold < target-file-size"));
         }
         if self.purge_rewrite_threshold.is_none() {
-            self.purge_rewrite_threshold = Some(ReadableSize(std::cmp::max(
-                self.purge_threshold.0 / 10,
-                self.target_file_size.0,
-            )));
+            self.purge_rewrite_threshold = Some(self.derived_purge_rewrite_threshold());
+        }
+        if self.rewrite_rate_limit == Some(ReadableSize(0)) {
+            return Err(box_err!("rewrite-rate-limit must be positive"));
+        }
+        if self.scrub_rate_limit == Some(ReadableSize(0)) {
+            return Err(box_err!("scrub-rate-limit must be positive"));
+        }
+        if self.kv_spill_threshold == Some(ReadableSize(0)) {
+            return Err(box_err!("kv-spill-threshold must be positive"));
         }
         if self.bytes_per_sync.is_some() {
             warn!("bytes-per-sync has been deprecated.");
@@ -170,6 +254,14 @@ impl Config {
                 "prefill is not allowed when log recycle is disabled"
             ));
         }
+        let min_hole_punch_block_size = ReadableSize(MIN_HOLE_PUNCH_BLOCK_SIZE as u64);
+        if self.enable_hole_punching && self.hole_punch_min_block_size < min_hole_punch_block_size {
+            warn!(
+                "hole-punch-min-block-size ({}) is too small, setting it to {}",
+                self.hole_punch_min_block_size, min_hole_punch_block_size
+            );
+            self.hole_punch_min_block_size = min_hole_punch_block_size;
+        }
         #[cfg(not(feature = "swap"))]
         if self.memory_limit.is_some() {
             warn!("memory-limit will be ignored because swap feature is disabled");
@@ -177,6 +269,14 @@ impl Config {
         Ok(())
     }
 
+    // Returns the `purge-rewrite-threshold` used when it's not specified.
+    fn derived_purge_rewrite_threshold(&self) -> ReadableSize {
+        ReadableSize(std::cmp::max(
+            self.purge_threshold.0 / 10,
+            self.target_file_size.0,
+        ))
+    }
+
     /// Returns the capacity for recycling log files.
     pub(crate) fn recycle_capacity(&self) -> usize {
         // Attention please, log files with Version::V1 could not be recycled, it might
@@ -199,6 +299,188 @@ impl Config {
     }
 }
 
+/// A set of changes to be applied to a running engine's [`Config`]. Fields
+/// left as `None` are unchanged. Optional fields are reset with a zero value.
+///
+/// Only `batch-compression-threshold`, `target-file-size`, `purge-threshold`,
+/// `purge-rewrite-threshold`, `purge-rewrite-garbage-ratio`,
+/// `rewrite-rate-limit`, `scrub-rate-limit`, `async-read-blocks-threshold` and
+/// `async-read-bytes-threshold` can be changed at runtime. Other fields are rejected
+/// unless they are equal to the current value.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConfigChange {
+    pub dir: Option<String>,
+    pub recovery_mode: Option<RecoveryMode>,
+    pub recovery_read_block_size: Option<ReadableSize>,
+    pub recovery_threads: Option<usize>,
+    pub batch_compression_threshold: Option<ReadableSize>,
+    pub bytes_per_sync: Option<ReadableSize>,
+    pub format_version: Option<Version>,
+    pub target_file_size: Option<ReadableSize>,
+    pub purge_threshold: Option<ReadableSize>,
+    /// Zero makes it derived from `purge-threshold` and `target-file-size`
+    /// again. If it's not specified when the engine is opened, it's derived
+    /// again whenever they change, until a non-zero value is set.
+    pub purge_rewrite_threshold: Option<ReadableSize>,
+    pub purge_rewrite_garbage_ratio: Option<f64>,
+    /// Zero lifts the limit.
+    pub rewrite_rate_limit: Option<ReadableSize>,
+    /// Zero disables scrubbing.
+    pub scrub_rate_limit: Option<ReadableSize>,
+    pub async_read_blocks_threshold: Option<usize>,
+    pub async_read_bytes_threshold: Option<ReadableSize>,
+    pub memory_limit: Option<ReadableSize>,
+    pub enable_log_recycle: Option<bool>,
+    pub prefill_for_recycle: Option<bool>,
+    pub enable_hole_punching: Option<bool>,
+    pub hole_punch_min_block_size: Option<ReadableSize>,
+    pub enable_lazy_recovery: Option<bool>,
+}
+
+impl Config {
+    /// Returns a copy of this config with `change` applied. Fails if any
+    /// immutable field is changed, or the result doesn't pass sanitization.
+    ///
+    /// `purge_rewrite_threshold_specified` tells whether
+    /// `purge-rewrite-threshold` is specified after the change. Otherwise
+    /// it's derived from the new config.
+    pub(crate) fn apply_change(
+        &self,
+        change: &ConfigChange,
+        purge_rewrite_threshold_specified: bool,
+    ) -> Result<Config> {
+        macro_rules! check_immutable {
+            ($($field:ident),+) => {
+                $(
+                    if let Some(v) = &change.$field {
+                        if *v != self.$field {
+                            return Err(Error::InvalidArgument(format!(
+                                "{} can't be changed at runtime",
+                                stringify!($field).replace('_', "-")
+                            )));
+                        }
+                    }
+                )+
+            };
+        }
+        check_immutable!(
+            dir,
+            recovery_mode,
+            recovery_read_block_size,
+            recovery_threads,
+            format_version,
+            enable_log_recycle,
+            prefill_for_recycle,
+            enable_hole_punching,
+            hole_punch_min_block_size,
+            enable_lazy_recovery
+        );
+        if change.bytes_per_sync.is_some() && change.bytes_per_sync != self.bytes_per_sync {
+            return Err(Error::InvalidArgument(
+                "bytes-per-sync can't be changed at runtime".to_owned(),
+            ));
+        }
+        if change.memory_limit.is_some() && change.memory_limit != self.memory_limit {
+            return Err(Error::InvalidArgument(
+                "memory-limit can't be changed at runtime".to_owned(),
+            ));
+        }
+
+        let mut cfg = self.clone();
+        if let Some(v) = change.batch_compression_threshold {
+            cfg.batch_compression_threshold = v;
+        }
+        if let Some(v) = change.target_file_size {
+            cfg.target_file_size = v;
+        }
+        if let Some(v) = change.purge_threshold {
+            cfg.purge_threshold = v;
+        }
+        if !purge_rewrite_threshold_specified {
+            // Derived again by sanitization.
+            cfg.purge_rewrite_threshold = None;
+        } else if let Some(v) = change.purge_rewrite_threshold {
+            cfg.purge_rewrite_threshold = Some(v);
+        }
+        if let Some(v) = change.purge_rewrite_garbage_ratio {
+            cfg.purge_rewrite_garbage_ratio = v;
+        }
+        if let Some(v) = change.rewrite_rate_limit {
+            cfg.rewrite_rate_limit = Some(v).filter(|v| v.0 > 0);
+        }
+        if let Some(v) = change.scrub_rate_limit {
+            cfg.scrub_rate_limit = Some(v).filter(|v| v.0 > 0);
+        }
+        if let Some(v) = change.async_read_blocks_threshold {
+            cfg.async_read_blocks_threshold = v;
+        }
+        if let Some(v) = change.async_read_bytes_threshold {
+            cfg.async_read_bytes_threshold = v;
+        }
+        cfg.sanitize()?;
+        Ok(cfg)
+    }
+}
+
+/// A [`Config`] shared by engine components, which can be updated at runtime.
+/// Each read returns a consistent snapshot.
+pub struct SharedConfig {
+    // Immutable, kept out of the lock to be borrowed directly.
+    dir: String,
+    cfg: RwLock<Arc<Config>>,
+    // Whether `purge-rewrite-threshold` is specified by user, instead of
+    // derived from other fields. Only updated with `cfg` locked for write.
+    purge_rewrite_threshold_specified: AtomicBool,
+}
+
+impl SharedConfig {
+    /// Creates a shared config from a sanitized `cfg`.
+    /// `purge_rewrite_threshold_specified` tells whether
+    /// `purge-rewrite-threshold` was specified before sanitization.
+    pub fn new(cfg: Config, purge_rewrite_threshold_specified: bool) -> Self {
+        Self {
+            dir: cfg.dir.clone(),
+            cfg: RwLock::new(Arc::new(cfg)),
+            purge_rewrite_threshold_specified: AtomicBool::new(purge_rewrite_threshold_specified),
+        }
+    }
+
+    /// Returns a snapshot of the current config.
+    pub fn get(&self) -> Arc<Config> {
+        self.cfg.read().clone()
+    }
+
+    pub fn dir(&self) -> &str {
+        &self.dir
+    }
+
+    /// Applies `change` to the config. The new config is passed to `apply`
+    /// before it's visible to readers, and is discarded if `apply` fails.
+    /// Readers are blocked until the update is done.
+    pub(crate) fn update(
+        &self,
+        change: &ConfigChange,
+        apply: impl FnOnce(&Config) -> Result<()>,
+    ) -> Result<()> {
+        let mut cfg = self.cfg.write();
+        let specified = change.purge_rewrite_threshold.map_or_else(
+            || {
+                self.purge_rewrite_threshold_specified
+                    .load(Ordering::Relaxed)
+            },
+            |v| v.0 > 0,
+        );
+        let new_cfg = cfg.apply_change(change, specified)?;
+        apply(&new_cfg)?;
+        *cfg = Arc::new(new_cfg);
+        self.purge_rewrite_threshold_specified
+            .store(specified, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -222,6 +504,7 @@ mod tests {
             format-version = 1
             enable-log-recycle = false
             prefill-for-recycle = false
+            rewrite-rate-limit = "10MB"
         "#;
         let mut load: Config = toml::from_str(custom).unwrap();
         assert_eq!(load.dir, "custom_dir");
@@ -230,6 +513,7 @@ mod tests {
         assert_eq!(load.target_file_size, ReadableSize::mb(1));
         assert_eq!(load.purge_threshold, ReadableSize::mb(3));
         assert_eq!(load.format_version, Version::V1);
+        assert_eq!(load.rewrite_rate_limit, Some(ReadableSize::mb(10)));
         load.sanitize().unwrap();
     }
 
@@ -242,6 +526,22 @@ mod tests {
         let mut hard_load: Config = toml::from_str(hard_error).unwrap();
         assert!(hard_load.sanitize().is_err());
 
+        let rate_limit_error = r#"
+            rewrite-rate-limit = "0KB"
+        "#;
+        let mut cfg_load: Config = toml::from_str(rate_limit_error).unwrap();
+        assert!(cfg_load.sanitize().is_err());
+        let scrub_rate_limit_error = r#"
+            scrub-rate-limit = "0KB"
+        "#;
+        let mut cfg_load: Config = toml::from_str(scrub_rate_limit_error).unwrap();
+        assert!(cfg_load.sanitize().is_err());
+        let kv_spill_threshold_error = r#"
+            kv-spill-threshold = "0KB"
+        "#;
+        let mut cfg_load: Config = toml::from_str(kv_spill_threshold_error).unwrap();
+        assert!(cfg_load.sanitize().is_err());
+
         let soft_error = r#"
             recovery-read-block-size = "1KB"
             recovery-threads = 0
@@ -249,6 +549,8 @@ mod tests {
             format-version = 2
             enable-log-recycle = true
             prefill-for-recycle = true
+            enable-hole-punching = true
+            hole-punch-min-block-size = "1KB"
         "#;
         let soft_load: Config = toml::from_str(soft_error).unwrap();
         let mut soft_sanitized = soft_load;
@@ -261,6 +563,10 @@ mod tests {
         );
         assert_eq!(soft_sanitized.format_version, Version::V2);
         assert!(soft_sanitized.enable_log_recycle);
+        assert_eq!(
+            soft_sanitized.hole_punch_min_block_size.0,
+            MIN_HOLE_PUNCH_BLOCK_SIZE as u64
+        );
 
         let recycle_error = r#"
             enable-log-recycle = true
@@ -278,6 +584,128 @@ mod tests {
         assert!(cfg_load.sanitize().is_err());
     }
 
+    #[test]
+    fn test_apply_change() {
+        let mut cfg = Config::default();
+        cfg.sanitize().unwrap();
+        let shared = SharedConfig::new(cfg.clone(), false);
+        let update = |change: &ConfigChange| shared.update(change, |_| Ok(()));
+        update(&ConfigChange {
+            target_file_size: Some(ReadableSize::mb(1)),
+            purge_threshold: Some(ReadableSize::mb(10)),
+            purge_rewrite_garbage_ratio: Some(0.3),
+            async_read_blocks_threshold: Some(10),
+            dir: Some(cfg.dir.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+        let new_cfg = shared.get();
+        assert_eq!(new_cfg.target_file_size, ReadableSize::mb(1));
+        assert_eq!(new_cfg.purge_threshold, ReadableSize::mb(10));
+        assert_eq!(new_cfg.purge_rewrite_garbage_ratio, 0.3);
+        assert_eq!(new_cfg.async_read_blocks_threshold, 10);
+        assert_eq!(new_cfg.dir, cfg.dir);
+        // Derived from the new values.
+        assert_eq!(new_cfg.purge_rewrite_threshold, Some(ReadableSize::mb(1)));
+
+        let change: ConfigChange = toml::from_str(
+            r#"
+            purge-rewrite-threshold = "5MB"
+            rewrite-rate-limit = "10MB"
+            scrub-rate-limit = "1MB"
+        "#,
+        )
+        .unwrap();
+        update(&change).unwrap();
+        let new_cfg = shared.get();
+        assert_eq!(new_cfg.purge_rewrite_threshold, Some(ReadableSize::mb(5)));
+        assert_eq!(new_cfg.rewrite_rate_limit, Some(ReadableSize::mb(10)));
+        assert_eq!(new_cfg.scrub_rate_limit, Some(ReadableSize::mb(1)));
+        // Specified values are kept.
+        update(&ConfigChange {
+            purge_threshold: Some(ReadableSize::mb(20)),
+            ..Default::default()
+        })
+        .unwrap();
+        let new_cfg = shared.get();
+        assert_eq!(new_cfg.purge_rewrite_threshold, Some(ReadableSize::mb(5)));
+        assert_eq!(new_cfg.rewrite_rate_limit, Some(ReadableSize::mb(10)));
+        // Zero values reset them.
+        let change: ConfigChange = toml::from_str(
+            r#"
+            purge-rewrite-threshold = 0
+            rewrite-rate-limit = 0
+            scrub-rate-limit = 0
+        "#,
+        )
+        .unwrap();
+        update(&change).unwrap();
+        let new_cfg = shared.get();
+        assert_eq!(new_cfg.purge_rewrite_threshold, Some(ReadableSize::mb(2)));
+        assert_eq!(new_cfg.rewrite_rate_limit, None);
+        assert_eq!(new_cfg.scrub_rate_limit, None);
+        update(&ConfigChange {
+            purge_threshold: Some(ReadableSize::mb(30)),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            shared.get().purge_rewrite_threshold,
+            Some(ReadableSize::mb(3))
+        );
+
+        // A specified value is kept even if it equals the derived one.
+        let mut specified_cfg = Config {
+            purge_rewrite_threshold: Some(ReadableSize::gb(1)),
+            ..Default::default()
+        };
+        specified_cfg.sanitize().unwrap();
+        assert_eq!(
+            specified_cfg.purge_rewrite_threshold,
+            Some(specified_cfg.derived_purge_rewrite_threshold())
+        );
+        let specified = SharedConfig::new(specified_cfg, true);
+        specified
+            .update(
+                &ConfigChange {
+                    purge_threshold: Some(ReadableSize::gb(20)),
+                    ..Default::default()
+                },
+                |_| Ok(()),
+            )
+            .unwrap();
+        assert_eq!(
+            specified.get().purge_rewrite_threshold,
+            Some(ReadableSize::gb(1))
+        );
+
+        let immutable = ConfigChange {
+            format_version: Some(Version::V1),
+            ..Default::default()
+        };
+        let e = update(&immutable).unwrap_err();
+        assert!(e.to_string().contains("format-version"), "{}", e);
+        let immutable: ConfigChange = toml::from_str(r#"dir = "other_dir""#).unwrap();
+        assert!(update(&immutable).is_err());
+
+        let invalid = ConfigChange {
+            target_file_size: Some(ReadableSize::gb(20)),
+            ..Default::default()
+        };
+        assert!(update(&invalid).is_err());
+        // Nothing is changed if it fails to be applied.
+        assert!(shared
+            .update(
+                &ConfigChange {
+                    target_file_size: Some(ReadableSize::mb(2)),
+                    ..Default::default()
+                },
+                |_| Err(box_err!("failed to apply")),
+            )
+            .is_err());
+        assert_eq!(shared.get().target_file_size, ReadableSize::mb(1));
+    }
+
     #[test]
     fn test_backward_compactibility() {
         // Upgrade from older version.
@@ -1,11 +1,21 @@
 // Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
 
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
 use hashbrown::HashMap;
+use protobuf::parse_from_bytes;
 
+use crate::env::FileSystem;
+use crate::file_pipe_log::debug::{build_file_reader, replay_file};
 use crate::file_pipe_log::ReplayMachine;
-use crate::log_batch::{LogItemBatch, LogItemContent};
-use crate::pipe_log::{FileId, LogQueue};
-use crate::Result;
+use crate::inspector::list_log_files;
+use crate::log_batch::{
+    Command, KeyValue, LogBatch, LogItemBatch, LogItemContent, MessageExt, OpType,
+};
+use crate::memtable::EntryIndex;
+use crate::pipe_log::{FileBlockHandle, FileId, LogQueue};
+use crate::{Error, Result};
 
 /// A `ConsistencyChecker` scans for log entry holes in a log queue. It will
 /// return a list of corrupted raft groups along with their last valid log
@@ -69,3 +79,366 @@ impl ReplayMachine for ConsistencyChecker {
         Ok(())
     }
 }
+
+/// Result of deep consistency check on a log file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileCheckResult {
+    pub file_id: FileId,
+    /// Number of verified blocks of log entries.
+    pub blocks: usize,
+    /// Number of blocks reclaimed by hole punching. They are not verified.
+    pub punched_blocks: usize,
+    /// Number of verified log entries.
+    pub entries: usize,
+    pub errors: Vec<String>,
+}
+
+/// Result of deep consistency check on a Raft Group.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RegionCheckResult {
+    pub raft_group_id: u64,
+    /// Index of the first live entry.
+    pub first_index: Option<u64>,
+    /// Index of the last live entry.
+    pub last_index: Option<u64>,
+    pub errors: Vec<String>,
+}
+
+/// Result of [`Engine::deep_consistency_check`].
+///
+/// [`Engine::deep_consistency_check`]: crate::Engine::deep_consistency_check
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeepCheckReport {
+    /// Results of all log files, ordered by file ID.
+    pub files: Vec<FileCheckResult>,
+    /// Results of Raft Groups that have live entries or errors, ordered by
+    /// Raft Group ID.
+    pub regions: Vec<RegionCheckResult>,
+}
+
+impl DeepCheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.files.iter().all(|f| f.errors.is_empty())
+            && self.regions.iter().all(|r| r.errors.is_empty())
+    }
+}
+
+#[derive(Default)]
+struct RegionOrder {
+    // Span of entries that are not compacted or cleaned.
+    span: Option<(u64, u64)>,
+    // Whether `span` is left by rewrite queue. Stale append files may contain
+    // entries that are already rewritten and compacted.
+    from_rewrite: bool,
+    // Locations of live entries.
+    live: BTreeMap<u64, (FileId, u64)>,
+    errors: Vec<String>,
+}
+
+/// A `ReplayMachine` that validates the order of log items of each Raft
+/// Group, and collects the blocks of log entries to be verified.
+struct OrderChecker {
+    queue: LogQueue,
+    regions: BTreeMap<u64, RegionOrder>,
+    // Blocks in file order, along with the entries they contain.
+    blocks: Vec<(FileBlockHandle, Vec<(u64, EntryIndex)>)>,
+}
+
+impl OrderChecker {
+    fn new(queue: LogQueue) -> Self {
+        Self {
+            queue,
+            regions: BTreeMap::new(),
+            blocks: Vec::new(),
+        }
+    }
+
+    fn replay_entries(&mut self, raft_group_id: u64, ents: &[EntryIndex]) {
+        let (first, last) = (ents[0].index, ents[ents.len() - 1].index);
+        let region = self.regions.entry(raft_group_id).or_default();
+        if ents
+            .iter()
+            .enumerate()
+            .any(|(i, ei)| ei.index != first + i as u64)
+        {
+            region.errors.push(format!(
+                "entry indexes [{}, {}] are not continuous",
+                first, last
+            ));
+        }
+        region.span = match (self.queue, region.span) {
+            (_, None) => Some((first, last)),
+            // Rewrite queue may contain holes left by compaction of append queue.
+            (LogQueue::Rewrite, Some((f, l))) => Some((f.min(first), l.max(last))),
+            (LogQueue::Append, Some((f, l))) => {
+                if first > l + 1 {
+                    region.errors.push(format!(
+                        "entries [{}, {}] leave a hole after {}{}",
+                        first,
+                        last,
+                        l,
+                        if region.from_rewrite {
+                            " in rewrite queue"
+                        } else {
+                            ""
+                        }
+                    ));
+                    region.live.clear();
+                } else if first < f && !region.from_rewrite {
+                    region.errors.push(format!(
+                        "entries [{}, {}] overwrite entries compacted before {}",
+                        first, last, f
+                    ));
+                }
+                region.live.split_off(&first);
+                Some((first.min(f), last))
+            }
+        };
+        region.from_rewrite = false;
+        for ei in ents {
+            let handle = ei.entries.unwrap();
+            region.live.insert(ei.index, (handle.id, handle.offset));
+        }
+    }
+
+    fn replay_command(&mut self, raft_group_id: u64, cmd: &Command) {
+        if cmd.source().is_some() {
+            self.replay_migration(raft_group_id, cmd);
+            return;
+        }
+        let region = self.regions.entry(raft_group_id).or_default();
+        match cmd {
+            Command::Clean => {
+                region.span = None;
+                region.live.clear();
+            }
+            Command::Compact { index } => {
+                if let Some((f, l)) = region.span {
+                    if *index > l {
+                        region.span = None;
+                    } else if *index > f {
+                        region.span = Some((*index, l));
+                    }
+                }
+                region.live = region.live.split_off(index);
+            }
+            Command::Rename { .. } | Command::Clone { .. } => unreachable!(),
+        }
+        region.from_rewrite = false;
+    }
+
+    // Moves or copies live entries from the source Raft Group, the same way
+    // as memtables do.
+    fn replay_migration(&mut self, raft_group_id: u64, cmd: &Command) {
+        let src = match self.regions.get_mut(&cmd.source().unwrap()) {
+            Some(src) => src,
+            None => return,
+        };
+        let (span, live) = match *cmd {
+            Command::Rename { .. } => {
+                src.from_rewrite = false;
+                (src.span.take(), std::mem::take(&mut src.live))
+            }
+            Command::Clone { begin, end, .. } => {
+                let span = src.span.and_then(|(f, l)| {
+                    let (f, l) = (f.max(begin), l.min(end.saturating_sub(1)));
+                    if f <= l {
+                        Some((f, l))
+                    } else {
+                        None
+                    }
+                });
+                (
+                    span,
+                    src.live.range(begin..end).map(|(k, v)| (*k, *v)).collect(),
+                )
+            }
+            _ => unreachable!(),
+        };
+        let region = self.regions.entry(raft_group_id).or_default();
+        if matches!(cmd, Command::Rename { .. }) || region.span.is_none() {
+            region.span = span;
+            region.live = live;
+        }
+        region.from_rewrite = false;
+    }
+}
+
+impl ReplayMachine for OrderChecker {
+    fn replay(&mut self, item_batch: LogItemBatch, _file_id: FileId) -> Result<()> {
+        for item in item_batch.iter() {
+            match &item.content {
+                LogItemContent::EntryIndexes(ents) if !ents.0.is_empty() => {
+                    for ei in &ents.0 {
+                        let handle = ei.entries.unwrap();
+                        match self.blocks.last_mut() {
+                            Some((h, entries)) if *h == handle => {
+                                entries.push((item.raft_group_id, *ei))
+                            }
+                            _ => self.blocks.push((handle, vec![(item.raft_group_id, *ei)])),
+                        }
+                    }
+                    self.replay_entries(item.raft_group_id, &ents.0);
+                }
+                LogItemContent::EntryIndexes(_) => {}
+                LogItemContent::Command(cmd) => self.replay_command(item.raft_group_id, cmd),
+                LogItemContent::Kv(KeyValue { op_type, key, .. }) => {
+                    // Rewrite doesn't produce tombstones.
+                    if self.queue == LogQueue::Rewrite
+                        && *op_type == OpType::Del
+                        && !crate::is_internal_key(key, None)
+                    {
+                        self.regions
+                            .entry(item.raft_group_id)
+                            .or_default()
+                            .errors
+                            .push(format!(
+                                "tombstone of key {} in rewrite queue",
+                                key.escape_ascii()
+                            ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn merge(&mut self, _rhs: Self, _queue: LogQueue) -> Result<()> {
+        unreachable!("log files are checked sequentially")
+    }
+}
+
+// Verifies the checksum of a block and the indexes of entries in it. Returns
+// `Ok(false)` if the block is punched.
+fn check_block<M: MessageExt>(
+    buf: &[u8],
+    handle: FileBlockHandle,
+    entries: &[(u64, EntryIndex)],
+) -> Result<bool> {
+    if buf.iter().all(|b| *b == 0) {
+        return Ok(false);
+    }
+    let block = LogBatch::decode_entries_block(buf, handle, entries[0].1.compression_type)?;
+    for (raft_group_id, ei) in entries {
+        let (offset, len) = (ei.entry_offset as usize, ei.entry_len as usize);
+        if offset + len > block.len() {
+            return Err(Error::Corruption(format!(
+                "entry {} of Raft Group {} is out of block",
+                ei.index, raft_group_id
+            )));
+        }
+        let entry: M::Entry = parse_from_bytes(&block[offset..offset + len])?;
+        if M::index(&entry) != ei.index {
+            return Err(Error::Corruption(format!(
+                "entry {} of Raft Group {} has index {}",
+                ei.index,
+                raft_group_id,
+                M::index(&entry)
+            )));
+        }
+    }
+    Ok(true)
+}
+
+pub(crate) fn deep_check<M: MessageExt, F: FileSystem>(
+    file_system: &F,
+    dir: &Path,
+) -> Result<DeepCheckReport> {
+    // Pass 1: replay log items in order.
+    let mut files = Vec::new();
+    let mut blocks = Vec::new();
+    let mut checker = OrderChecker::new(LogQueue::Rewrite);
+    for (file_id, path) in list_log_files(file_system, dir)? {
+        if file_id.queue != checker.queue {
+            // Rewrite queue is followed by append queue.
+            let mut append = OrderChecker::new(LogQueue::Append);
+            append.regions = std::mem::take(&mut checker.regions);
+            for region in append.regions.values_mut() {
+                region.from_rewrite = true;
+            }
+            checker = append;
+        }
+        let mut result = FileCheckResult {
+            file_id,
+            blocks: 0,
+            punched_blocks: 0,
+            entries: 0,
+            errors: Vec::new(),
+        };
+        match replay_file(file_system, &path, file_id, &mut checker) {
+            Ok(replayed) => {
+                if let Some(e) = replayed.corruption {
+                    result
+                        .errors
+                        .push(format!("{} at offset {}", e, replayed.valid_offset));
+                }
+            }
+            Err(e) => result.errors.push(e.to_string()),
+        }
+        blocks.push(std::mem::take(&mut checker.blocks));
+        files.push((result, path));
+    }
+
+    let mut regions = BTreeMap::new();
+    let mut live_blocks = BTreeSet::new();
+    for (id, order) in checker.regions {
+        for (file_id, offset) in order.live.values() {
+            live_blocks.insert((*file_id, *offset, id));
+        }
+        if !order.live.is_empty() || !order.errors.is_empty() {
+            regions.insert(
+                id,
+                RegionCheckResult {
+                    raft_group_id: id,
+                    first_index: order.live.keys().next().copied(),
+                    last_index: order.live.keys().next_back().copied(),
+                    errors: order.errors,
+                },
+            );
+        }
+    }
+
+    // Pass 2: verify blocks of entries.
+    let mut report = DeepCheckReport::default();
+    for ((mut result, path), blocks) in files.into_iter().zip(blocks) {
+        if !blocks.is_empty() {
+            let mut reader = build_file_reader(file_system, &path)?;
+            for (handle, entries) in blocks {
+                let mut live_regions: Vec<_> = entries
+                    .iter()
+                    .map(|(id, _)| *id)
+                    .filter(|id| live_blocks.contains(&(handle.id, handle.offset, *id)))
+                    .collect();
+                live_regions.dedup();
+                let res = reader
+                    .read(handle)
+                    .and_then(|buf| check_block::<M>(&buf, handle, &entries));
+                let error = match res {
+                    Ok(true) => {
+                        result.blocks += 1;
+                        result.entries += entries.len();
+                        continue;
+                    }
+                    Ok(false) if live_regions.is_empty() => {
+                        result.punched_blocks += 1;
+                        continue;
+                    }
+                    Ok(false) => "live entries are punched".to_owned(),
+                    Err(e) => e.to_string(),
+                };
+                result
+                    .errors
+                    .push(format!("block at offset {}: {}", handle.offset, error));
+                for id in live_regions {
+                    regions.get_mut(&id).unwrap().errors.push(format!(
+                        "block at offset {} of {:?} file {}: {}",
+                        handle.offset, handle.id.queue, handle.id.seq, error
+                    ));
+                }
+            }
+        }
+        report.files.push(result);
+    }
+    report.regions = regions.into_values().collect();
+    Ok(report)
+}
@@ -1,6 +1,7 @@
 // Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
 
 use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::marker::PhantomData;
 use std::path::Path;
 use std::sync::{mpsc, Arc, Mutex};
@@ -8,30 +9,46 @@ use std::thread::{Builder as ThreadBuilder, JoinHandle};
 use std::time::{Duration, Instant};
 
 use log::{error, info};
+use parking_lot::RwLock;
 use protobuf::{parse_from_bytes, Message};
 
-use crate::config::{Config, RecoveryMode};
-use crate::consistency::ConsistencyChecker;
+use crate::config::{Config, ConfigChange, RecoveryMode, SharedConfig};
+use crate::consistency::{ConsistencyChecker, DeepCheckReport};
+use crate::entry_iter::EntryIter;
 use crate::env::{DefaultFileSystem, FileSystem};
 use crate::event_listener::EventListener;
+use crate::export::{DirectoryRewriteReport, ExportedRegions, ImportedRegion};
 use crate::file_pipe_log::debug::LogItemReader;
 use crate::file_pipe_log::{DefaultMachineFactory, FilePipeLog, FilePipeLogBuilder};
-use crate::log_batch::{Command, LogBatch, MessageExt};
-use crate::memtable::{EntryIndex, MemTableRecoverContextFactory, MemTables};
+use crate::inspector::Inspector;
+use crate::lazy_recovery::{
+    LazyRecovery, RegionFileTracker, RegionMap, RegionRecord, INDEX_WAIT_TIMEOUT,
+};
+use crate::log_batch::{AtomicGroupBuilder, Command, LogBatch, LogItem, MessageExt};
+use crate::memtable::{
+    EntryIndex, KvValue, MemTable, MemTableRecoverContextFactory, MemTables, SelectedAllocator,
+};
 use crate::metrics::*;
 use crate::pipe_log::{FileBlockHandle, FileId, LogQueue, PipeLog};
 use crate::purge::{PurgeHook, PurgeManager};
+use crate::purge_policy::{DefaultPurgePolicy, PurgePolicy};
+use crate::recovery::{CancellationToken, RecoveryProgressSink, RecoveryReport};
+use crate::scrubber::Scrubber;
+use crate::space::{collect_space_stats, SpaceStats};
+use crate::util::{Factory, ReadableSize};
 use crate::write_barrier::{WriteBarrier, Writer};
 use crate::{perf_context, Error, GlobalStats, Result};
 
 const METRICS_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+/// Max size of log entries in a log batch written by `import_regions`.
+const MAX_IMPORT_BATCH_BYTES: usize = 128 * 1024;
 
 pub struct Engine<F = DefaultFileSystem, P = FilePipeLog<F>>
 where
     F: FileSystem,
     P: PipeLog,
 {
-    cfg: Arc<Config>,
+    cfg: Arc<SharedConfig>,
     listeners: Vec<Arc<dyn EventListener>>,
 
     #[allow(dead_code)]
@@ -41,10 +58,19 @@ where
     purge_manager: PurgeManager<P>,
 
     write_barrier: WriteBarrier<LogBatch, Result<FileBlockHandle>>,
+    // Writes hold the read lock until they are applied to memtables. Snapshots
+    // are taken under the write lock, after all write groups in progress are
+    // applied.
+    apply_lock: RwLock<()>,
 
     tx: Mutex<mpsc::Sender<()>>,
     metrics_flusher: Option<JoinHandle<()>>,
 
+    // `None` if lazy recovery is disabled.
+    lazy_recovery: Option<Arc<LazyRecovery<F>>>,
+    // Started when background scrubbing is enabled.
+    scrubber: Arc<Scrubber>,
+
     _phantom: PhantomData<F>,
 }
 
@@ -57,7 +83,10 @@ impl Engine<DefaultFileSystem, FilePipeLog<DefaultFileSystem>> {
         cfg: Config,
         listeners: Vec<Arc<dyn EventListener>>,
     ) -> Result<Engine<DefaultFileSystem, FilePipeLog<DefaultFileSystem>>> {
-        Self::open_with(cfg, Arc::new(DefaultFileSystem), listeners)
+        EngineBuilder::new(cfg)
+            .listeners(listeners)
+            .open_lazily()
+            .map(|(engine, _)| engine)
     }
 }
 
@@ -73,31 +102,323 @@ where
     }
 
     pub fn open_with(
-        mut cfg: Config,
+        cfg: Config,
         file_system: Arc<F>,
-        mut listeners: Vec<Arc<dyn EventListener>>,
+        listeners: Vec<Arc<dyn EventListener>>,
     ) -> Result<Engine<F, FilePipeLog<F>>> {
+        EngineBuilder::new(cfg)
+            .file_system(file_system)
+            .listeners(listeners)
+            .open()
+    }
+
+    /// Opens the engine, and returns a summary of decisions made during
+    /// recovery along with it.
+    pub fn open_with_report(
+        cfg: Config,
+        file_system: Arc<F>,
+        listeners: Vec<Arc<dyn EventListener>>,
+    ) -> Result<(Engine<F, FilePipeLog<F>>, RecoveryReport)> {
+        EngineBuilder::new(cfg)
+            .file_system(file_system)
+            .listeners(listeners)
+            .open_with_report()
+    }
+
+    /// Applies `change` to the configuration of a running engine. Changes of
+    /// all fields take effect together, or none of them does if the change is
+    /// invalid.
+    pub fn update_config(&self, change: &ConfigChange) -> Result<()>
+    where
+        F: 'static,
+    {
+        self.cfg.update(change, |new_cfg| {
+            if new_cfg.scrub_rate_limit.is_some() {
+                self.scrubber.start(
+                    self.pipe_log.clone(),
+                    self.memtables.clone(),
+                    self.listeners.clone(),
+                )?;
+            }
+            self.pipe_log.update_config(new_cfg);
+            self.purge_manager
+                .set_rewrite_rate_limit(new_cfg.rewrite_rate_limit);
+            self.scrubber
+                .set_rate_limit(new_cfg.scrub_rate_limit.map(|r| r.0));
+            Ok(())
+        })?;
+        info!("Raft engine config updated: {:?}", change);
+        Ok(())
+    }
+}
+
+/// A builder for opening an [`Engine`] with customized components.
+pub struct EngineBuilder<F = DefaultFileSystem>
+where
+    F: FileSystem,
+{
+    cfg: Config,
+    file_system: Arc<F>,
+    listeners: Vec<Arc<dyn EventListener>>,
+    purge_policy: Arc<dyn PurgePolicy>,
+    progress_sink: Option<Arc<dyn RecoveryProgressSink>>,
+    cancellation_token: Option<CancellationToken>,
+}
+
+impl EngineBuilder<DefaultFileSystem> {
+    pub fn new(cfg: Config) -> Self {
+        Self {
+            cfg,
+            file_system: Arc::new(DefaultFileSystem),
+            listeners: Vec::new(),
+            purge_policy: Arc::new(DefaultPurgePolicy),
+            progress_sink: None,
+            cancellation_token: None,
+        }
+    }
+}
+
+impl<F> EngineBuilder<F>
+where
+    F: FileSystem,
+{
+    /// Sets the file system used to access log files.
+    pub fn file_system<G: FileSystem>(self, file_system: Arc<G>) -> EngineBuilder<G> {
+        EngineBuilder {
+            cfg: self.cfg,
+            file_system,
+            listeners: self.listeners,
+            purge_policy: self.purge_policy,
+            progress_sink: self.progress_sink,
+            cancellation_token: self.cancellation_token,
+        }
+    }
+
+    /// Sets the listeners of engine events, replacing existing ones.
+    pub fn listeners(mut self, listeners: Vec<Arc<dyn EventListener>>) -> Self {
+        self.listeners = listeners;
+        self
+    }
+
+    pub fn add_listener(mut self, listener: Arc<dyn EventListener>) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    /// Sets the policy of purging obsolete log files. [`DefaultPurgePolicy`]
+    /// is used if not specified.
+    pub fn purge_policy(mut self, purge_policy: Arc<dyn PurgePolicy>) -> Self {
+        self.purge_policy = purge_policy;
+        self
+    }
+
+    /// Sets the receiver of recovery progress.
+    pub fn recovery_progress_sink(mut self, sink: Arc<dyn RecoveryProgressSink>) -> Self {
+        self.progress_sink = Some(sink);
+        self
+    }
+
+    /// Sets the token to cancel recovery. Cancelled recovery fails with
+    /// [`Error::Cancelled`] without modifying any log file.
+    pub fn cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancellation_token = Some(token);
+        self
+    }
+
+    pub fn open(self) -> Result<Engine<F, FilePipeLog<F>>> {
+        self.open_with_report().map(|(engine, _)| engine)
+    }
+
+    /// Opens the engine, and returns a summary of decisions made during
+    /// recovery along with it.
+    ///
+    /// With lazy recovery, Raft Groups restored from the region map are
+    /// indexed before this returns. Use [`EngineBuilder::open_lazily`] to
+    /// index them in background, or to start background scrubbing.
+    pub fn open_with_report(self) -> Result<(Engine<F, FilePipeLog<F>>, RecoveryReport)> {
+        if self.cfg.scrub_rate_limit.is_some() {
+            return Err(Error::InvalidArgument(
+                "scrub-rate-limit requires the engine to be opened by `open_lazily`".to_owned(),
+            ));
+        }
+        let (engine, report, factory) = self.recover()?;
+        if let Some(lazy_recovery) = &engine.lazy_recovery {
+            lazy_recovery.index_all(
+                engine.pipe_log.clone(),
+                engine.memtables.clone(),
+                engine.stats.clone(),
+                factory,
+                engine.cfg.get().recovery_read_block_size.0 as usize,
+            )?;
+        }
+        for listener in &engine.listeners {
+            listener.post_recovery(&report);
+        }
+        Ok((engine, report))
+    }
+
+    /// Same as [`EngineBuilder::open_with_report`], except that Raft Groups
+    /// restored from the region map of lazy recovery are indexed by a
+    /// background thread after this returns. Background scrubbing is started
+    /// as well if `scrub-rate-limit` is set.
+    pub fn open_lazily(self) -> Result<(Engine<F, FilePipeLog<F>>, RecoveryReport)>
+    where
+        F: 'static,
+    {
+        let (engine, report, factory) = self.recover()?;
+        let cfg = engine.cfg.get();
+        if let Some(lazy_recovery) = &engine.lazy_recovery {
+            lazy_recovery.start(
+                engine.pipe_log.clone(),
+                engine.memtables.clone(),
+                engine.stats.clone(),
+                factory,
+                cfg.recovery_read_block_size.0 as usize,
+            )?;
+        }
+        if let Some(rate_limit) = cfg.scrub_rate_limit {
+            engine.scrubber.set_rate_limit(Some(rate_limit.0));
+            engine.scrubber.start(
+                engine.pipe_log.clone(),
+                engine.memtables.clone(),
+                engine.listeners.clone(),
+            )?;
+        }
+        for listener in &engine.listeners {
+            listener.post_recovery(&report);
+        }
+        Ok((engine, report))
+    }
+
+    // Recovers the engine without starting any background thread. Returns the
+    // factory to index the entries of lazily recovered Raft Groups.
+    fn recover(
+        self,
+    ) -> Result<(
+        Engine<F, FilePipeLog<F>>,
+        RecoveryReport,
+        MemTableRecoverContextFactory,
+    )> {
+        let EngineBuilder {
+            mut cfg,
+            file_system,
+            mut listeners,
+            purge_policy,
+            progress_sink,
+            cancellation_token,
+        } = self;
+        let purge_rewrite_threshold_specified = cfg.purge_rewrite_threshold.is_some();
         cfg.sanitize()?;
+        purge_policy.validate()?;
         listeners.push(Arc::new(PurgeHook::default()) as Arc<dyn EventListener>);
 
         let start = Instant::now();
-        let mut builder = FilePipeLogBuilder::new(cfg.clone(), file_system, listeners.clone());
+        let dir = Path::new(&cfg.dir).to_path_buf();
+        let mut builder =
+            FilePipeLogBuilder::new(cfg.clone(), file_system.clone(), listeners.clone());
+        if let Some(sink) = progress_sink {
+            builder.set_progress_sink(sink);
+        }
+        if let Some(token) = cancellation_token {
+            builder.set_cancellation_token(token);
+        }
         builder.scan()?;
+        // The region map is only valid for log files as they are now, it must
+        // be removed before any file is modified.
+        let region_map = if cfg.enable_lazy_recovery {
+            RegionMap::load(file_system.as_ref(), &dir)?
+        } else {
+            None
+        };
+        builder.check_cancelled()?;
+        RegionMap::remove(file_system.as_ref(), &dir)?;
+        let region_map = match region_map {
+            Some(map)
+                if Some(map.spans[LogQueue::Append as usize])
+                    == builder.file_span(LogQueue::Append)
+                    && Some(map.spans[LogQueue::Rewrite as usize])
+                        == builder.file_span(LogQueue::Rewrite)
+                    && builder.skip_recovery()? =>
+            {
+                Some(map)
+            }
+            Some(_) => {
+                info!("Region map is outdated, recover from log files");
+                None
+            }
+            None => None,
+        };
+        let scan_duration = start.elapsed();
         let factory = MemTableRecoverContextFactory::new(&cfg);
-        let (append, rewrite) = builder.recover(&factory)?;
-        let pipe_log = Arc::new(builder.finish()?);
-        rewrite.merge_append_context(append);
-        let (memtables, stats) = rewrite.finish();
+        let mut region_files = Vec::new();
+        let mut pending_regions = HashSet::new();
+        let (pipe_log, memtables, stats, tracked_blocks, mut report) = if let Some(map) = region_map
+        {
+            let mut report = builder.take_report();
+            let (memtables, stats) = factory.new_target().finish();
+            let tracker = RegionFileTracker::default();
+            for region in map.regions {
+                let memtable = memtables.get_or_insert(region.region_id);
+                let mut memtable = memtable.write();
+                for (key, value, file_id) in region.kvs {
+                    memtable.put(key, value, file_id);
+                }
+                tracker.insert(region.region_id, region.files);
+                pending_regions.insert(region.region_id);
+            }
+            region_files.push(tracker);
+            report.regions_deferred = pending_regions.len();
+            let pipe_log = Arc::new(builder.finish()?);
+            (pipe_log, memtables, stats, Vec::new(), report)
+        } else {
+            let (mut append, mut rewrite) = builder.recover(&factory)?;
+            let mut report = builder.take_report();
+            report.lost_entries = append.take_lost_entries();
+            for ranges in report.lost_entries.values_mut() {
+                ranges.sort_by_key(|r| r.start);
+            }
+            report.discarded_atomic_groups = rewrite.take_discarded_atomic_groups();
+            report
+                .discarded_atomic_groups
+                .extend(append.take_discarded_atomic_groups());
+            region_files.extend(append.take_region_files());
+            region_files.extend(rewrite.take_region_files());
+            let pipe_log = Arc::new(builder.finish()?);
+            let tracked_blocks = append.take_tracked_blocks();
+            rewrite.merge_append_context(append);
+            let (memtables, stats) = rewrite.finish();
+            (pipe_log, memtables, stats, tracked_blocks, report)
+        };
+        let replay_duration = start.elapsed() - scan_duration;
+        report.regions_recovered = memtables.fold(0, |count, _| count + 1);
+        report.scan_duration = scan_duration;
+        report.replay_duration = replay_duration;
+        report.build_duration = start.elapsed() - scan_duration - replay_duration;
         info!("Recovering raft logs takes {:?}", start.elapsed());
 
-        let cfg = Arc::new(cfg);
+        let cfg = Arc::new(SharedConfig::new(cfg, purge_rewrite_threshold_specified));
         let purge_manager = PurgeManager::new(
             cfg.clone(),
             memtables.clone(),
             pipe_log.clone(),
             stats.clone(),
             listeners.clone(),
+            purge_policy,
         );
+        if let Some(tracker) = purge_manager.hole_punch_tracker() {
+            tracker.extend(tracked_blocks);
+        }
+        let lazy_recovery = purge_manager.region_file_tracker().map(|tracker| {
+            for files in region_files {
+                tracker.merge(files);
+            }
+            Arc::new(LazyRecovery::new(
+                file_system,
+                dir,
+                tracker.clone(),
+                pending_regions,
+            ))
+        });
 
         let (tx, rx) = mpsc::channel();
         let stats_clone = stats.clone();
@@ -112,7 +433,7 @@ where
                 }
             })?;
 
-        Ok(Self {
+        let engine = Engine {
             cfg,
             listeners,
             stats,
@@ -120,10 +441,14 @@ where
             pipe_log,
             purge_manager,
             write_barrier: Default::default(),
+            apply_lock: RwLock::new(()),
             tx: Mutex::new(tx),
             metrics_flusher: Some(metrics_flusher),
+            lazy_recovery,
+            scrubber: Default::default(),
             _phantom: PhantomData,
-        })
+        };
+        Ok((engine, report, factory))
     }
 }
 
@@ -135,13 +460,41 @@ where
     /// Writes the content of `log_batch` into the engine and returns written
     /// bytes. If `sync` is true, the write will be followed by a call to
     /// `fdatasync` on the log file.
-    pub fn write(&self, log_batch: &mut LogBatch, mut sync: bool) -> Result<usize> {
+    ///
+    /// If the engine is lazily recovered, returns `Error::NotReady` if the
+    /// entries of the written Raft Groups are not indexed in time.
+    pub fn write(&self, log_batch: &mut LogBatch, sync: bool) -> Result<usize> {
         if log_batch.is_empty() {
             return Ok(0);
         }
+        self.write_impl(log_batch, sync, true /* apply */)
+            .map(|(len, _)| len)
+    }
+
+    // Writes a non-empty `log_batch` to append queue. Returns written bytes and
+    // the file written to. Items of the batch are applied to memtables if
+    // `apply` is true, otherwise they are left in `log_batch`.
+    fn write_impl(
+        &self,
+        log_batch: &mut LogBatch,
+        mut sync: bool,
+        apply: bool,
+    ) -> Result<(usize, FileId)> {
+        if let Some(lazy_recovery) = &self.lazy_recovery {
+            if !lazy_recovery.is_finished() {
+                let region_ids: Vec<u64> = log_batch
+                    .item_batch()
+                    .iter()
+                    .map(|item| item.raft_group_id)
+                    .collect();
+                lazy_recovery.wait(&region_ids, Some(INDEX_WAIT_TIMEOUT))?;
+            }
+        }
         let start = Instant::now();
-        let len = log_batch.finish_populate(self.cfg.batch_compression_threshold.0 as usize)?;
+        let compression_threshold = self.cfg.get().batch_compression_threshold.0 as usize;
+        let len = log_batch.finish_populate(compression_threshold)?;
         debug_assert!(len > 0);
+        let apply_guard = self.apply_lock.read();
         let block_handle = {
             let mut writer = Writer::new(log_batch, sync);
             // Snapshot and clear the current perf context temporarily, so the write group
@@ -182,9 +535,20 @@ where
         };
         let mut now = Instant::now();
         log_batch.finish_write(block_handle);
-        self.memtables.apply_append_writes(log_batch.drain());
-        for listener in &self.listeners {
-            listener.post_apply_memtables(block_handle.id);
+        // The file is protected from purge until the write is applied, so it's
+        // safe to track the block before memtables are updated.
+        if let Some(tracker) = self.purge_manager.hole_punch_tracker() {
+            tracker.track(log_batch.item_batch());
+        }
+        if let Some(tracker) = self.purge_manager.region_file_tracker() {
+            tracker.track(log_batch.item_batch(), block_handle.id);
+        }
+        if apply {
+            self.memtables.apply_append_writes(log_batch.drain());
+            drop(apply_guard);
+            for listener in &self.listeners {
+                listener.post_apply_memtables(block_handle.id);
+            }
         }
         let end = Instant::now();
         let apply_duration = end.saturating_duration_since(now);
@@ -193,7 +557,106 @@ where
         now = end;
         ENGINE_WRITE_DURATION_HISTOGRAM.observe(now.saturating_duration_since(start).as_secs_f64());
         ENGINE_WRITE_SIZE_HISTOGRAM.observe(len as f64);
-        Ok(len)
+        Ok((len, block_handle.id))
+    }
+
+    /// Returns a [`Snapshot`] of all Raft Groups, which reflects all writes
+    /// applied before it and none after it. Writes in progress are waited for
+    /// until their write group is applied. Returns `Error::NotReady` if the
+    /// engine is lazily recovered and entries are still being indexed.
+    pub fn snapshot(&self) -> Result<Snapshot<'_, F, P>> {
+        if let Some(lazy_recovery) = &self.lazy_recovery {
+            if !lazy_recovery.is_finished() {
+                return Err(Error::NotReady);
+            }
+        }
+        Ok(self.snapshot_with(None))
+    }
+
+    /// Returns a [`Snapshot`] of the specified Raft Groups, same as
+    /// [`Engine::snapshot`] otherwise. Other Raft Groups appear empty in it.
+    /// Cheaper than a snapshot of all Raft Groups, since only the specified
+    /// ones are copied. If the engine is lazily
+    /// recovered, waits for their entries to be indexed, and returns
+    /// `Error::NotReady` if they are not indexed in time.
+    pub fn snapshot_regions(&self, region_ids: &[u64]) -> Result<Snapshot<'_, F, P>> {
+        if let Some(lazy_recovery) = &self.lazy_recovery {
+            lazy_recovery.wait(region_ids, Some(INDEX_WAIT_TIMEOUT))?;
+        }
+        Ok(self.snapshot_with(Some(region_ids)))
+    }
+
+    fn snapshot_with(&self, region_ids: Option<&[u64]>) -> Snapshot<'_, F, P> {
+        // Files referenced by the copied memtables must be registered before
+        // purge sees the newer memtables. Instead of pausing purge, all
+        // existing files are registered, and the barrier is narrowed down to
+        // the files actually referenced afterwards.
+        let id = self.pin_files();
+        // Writes are blocked only while the snapshot is started. Memtables
+        // are copied afterwards, or by writes before they are changed.
+        let pending = {
+            let _apply_guard = self.apply_lock.write();
+            self.memtables.start_snapshot(region_ids)
+        };
+        let memtables = self.memtables.finish_snapshot(pending);
+        let mut first_files = [None; 2];
+        for queue in [LogQueue::Append, LogQueue::Rewrite] {
+            first_files[queue as usize] = memtables
+                .values()
+                .filter_map(|m| m.min_file_seq(queue))
+                .min();
+        }
+        self.purge_manager.snapshot_files().update(id, first_files);
+        Snapshot {
+            engine: self,
+            id,
+            memtables,
+        }
+    }
+
+    // Registers all existing log files in `SnapshotFiles`, so that they can't
+    // be purged until the returned ID is removed.
+    fn pin_files(&self) -> u64 {
+        let barrier = [LogQueue::Append, LogQueue::Rewrite]
+            .map(|queue| Some(self.pipe_log.file_span(queue).0));
+        self.purge_manager.snapshot_files().insert(barrier)
+    }
+
+    // Copies key value pairs out of the memtable of `region_id` with `copy`,
+    // which also returns whether any of them is spilled. If so, they are
+    // copied again with the log files pinned, so that the spilled values can
+    // be read after the memtable lock is released.
+    fn copy_kvs<T>(
+        &self,
+        region_id: u64,
+        copy: impl Fn(&MemTable<SelectedAllocator>) -> Result<(T, bool)>,
+    ) -> Result<Option<(T, Option<PinnedFiles<'_, P>>)>> {
+        let memtable = match self.memtables.get(region_id) {
+            Some(memtable) => memtable,
+            None => return Ok(None),
+        };
+        let (kvs, spilled) = copy(&memtable.read())?;
+        if !spilled {
+            return Ok(Some((kvs, None)));
+        }
+        let pinned = PinnedFiles {
+            purge_manager: &self.purge_manager,
+            id: self.pin_files(),
+        };
+        let (kvs, _) = copy(&memtable.read())?;
+        Ok(Some((kvs, Some(pinned))))
+    }
+
+    /// Starts an [`AtomicGroup`] of writes that are recovered as a whole after
+    /// restart.
+    pub fn begin_atomic_group(&self) -> AtomicGroup<'_, F, P> {
+        AtomicGroup {
+            engine: self,
+            builder: AtomicGroupBuilder::default(),
+            items: Vec::new(),
+            files: Vec::new(),
+            aborted: false,
+        }
     }
 
     /// Synchronizes the Raft engine.
@@ -204,20 +667,31 @@ where
 
     pub fn get_message<S: Message>(&self, region_id: u64, key: &[u8]) -> Result<Option<S>> {
         let _t = StopWatch::new(&*ENGINE_READ_MESSAGE_DURATION_HISTOGRAM);
-        if let Some(memtable) = self.memtables.get(region_id) {
-            if let Some(value) = memtable.read().get(key) {
-                return Ok(Some(parse_from_bytes(&value)?));
-            }
+        if let Some((Some(value), _pinned)) = self.copy_kvs(region_id, |m| copy_value(m, key))? {
+            return Ok(Some(parse_from_bytes(
+                &value.read(self.pipe_log.as_ref())?,
+            )?));
         }
         Ok(None)
     }
 
-    pub fn get(&self, region_id: u64, key: &[u8]) -> Option<Vec<u8>> {
+    /// Returns the value of a key. Values spilled to log files are read from
+    /// disk, which is the only case that can fail.
+    pub fn get(&self, region_id: u64, key: &[u8]) -> Result<Option<Vec<u8>>> {
         let _t = StopWatch::new(&*ENGINE_READ_MESSAGE_DURATION_HISTOGRAM);
-        if let Some(memtable) = self.memtables.get(region_id) {
-            return memtable.read().get(key);
+        let memtable = match self.memtables.get(region_id) {
+            Some(memtable) => memtable,
+            None => return Ok(None),
+        };
+        match memtable.read().get(key) {
+            None => return Ok(None),
+            Some(KvValue::Inline(value)) => return Ok(Some(value.clone())),
+            Some(KvValue::Spilled(..)) => {}
         }
-        None
+        if let Some((Some(value), _pinned)) = self.copy_kvs(region_id, |m| copy_value(m, key))? {
+            return Ok(Some(value.read(self.pipe_log.as_ref())?.into_owned()));
+        }
+        Ok(None)
     }
 
     /// Iterates over [start_key, end_key) range of Raft Group key-values and
@@ -251,16 +725,28 @@ where
         start_key: Option<&[u8]>,
         end_key: Option<&[u8]>,
         reverse: bool,
-        callback: C,
+        mut callback: C,
     ) -> Result<()>
     where
         C: FnMut(&[u8], &[u8]) -> bool,
     {
         let _t = StopWatch::new(&*ENGINE_READ_MESSAGE_DURATION_HISTOGRAM);
-        if let Some(memtable) = self.memtables.get(region_id) {
-            memtable
-                .read()
-                .scan(start_key, end_key, reverse, callback)?;
+        let copied = self.copy_kvs(region_id, |m| {
+            let mut kvs = Vec::new();
+            let mut spilled = false;
+            m.scan(start_key, end_key, reverse, |key, value| {
+                spilled |= matches!(value, KvValue::Spilled(..));
+                kvs.push((key.to_vec(), value.clone()));
+                true
+            })?;
+            Ok((kvs, spilled))
+        })?;
+        if let Some((kvs, _pinned)) = copied {
+            for (key, value) in kvs {
+                if !callback(&key, &value.read(self.pipe_log.as_ref())?) {
+                    break;
+                }
+            }
         }
         Ok(())
     }
@@ -271,6 +757,7 @@ where
         log_idx: u64,
     ) -> Result<Option<M::Entry>> {
         let _t = StopWatch::new(&*ENGINE_READ_ENTRY_DURATION_HISTOGRAM);
+        self.wait_for_entries(region_id)?;
         if let Some(memtable) = self.memtables.get(region_id) {
             if let Some(idx) = memtable.read().get_entry(log_idx) {
                 ENGINE_READ_ENTRY_COUNT_HISTOGRAM.observe(1.0);
@@ -284,8 +771,14 @@ where
     }
 
     /// Purges expired logs files and returns a set of Raft group ids that need
-    /// to be compacted.
+    /// to be compacted. Nothing is purged before all entries are indexed if the
+    /// engine is lazily recovered.
     pub fn purge_expired_files(&self) -> Result<Vec<u64>> {
+        if let Some(lazy_recovery) = &self.lazy_recovery {
+            if !lazy_recovery.is_finished() {
+                return Ok(vec![]);
+            }
+        }
         self.purge_manager.purge_expired_files()
     }
 
@@ -299,56 +792,177 @@ where
         vec: &mut Vec<M::Entry>,
     ) -> Result<usize> {
         let _t = StopWatch::new(&*ENGINE_READ_ENTRY_DURATION_HISTOGRAM);
+        self.wait_for_entries(region_id)?;
         if let Some(memtable) = self.memtables.get(region_id) {
             let mut ents_idx: Vec<EntryIndex> = Vec::with_capacity((end - begin) as usize);
             memtable
                 .read()
                 .fetch_entries_to(begin, end, max_size, &mut ents_idx)?;
+            self.read_entries::<M>(&mut ents_idx, vec)?;
 
-            let mut blocks: Vec<FileBlockHandle> = Vec::new();
-            let mut total_bytes = 0;
-            for (t, i) in ents_idx.iter().enumerate() {
-                if t == 0 || (i.entries.unwrap() != ents_idx[t - 1].entries.unwrap()) {
-                    blocks.push(i.entries.unwrap());
-                    total_bytes += i.entries.unwrap().len;
-                }
+            ENGINE_READ_ENTRY_COUNT_HISTOGRAM.observe(ents_idx.len() as f64);
+
+            return Ok(ents_idx.len());
+        }
+        Ok(0)
+    }
+
+    fn read_entries<M: MessageExt>(
+        &self,
+        ents_idx: &mut [EntryIndex],
+        vec: &mut Vec<M::Entry>,
+    ) -> Result<()> {
+        let mut blocks: Vec<FileBlockHandle> = Vec::new();
+        for (t, i) in ents_idx.iter().enumerate() {
+            if t == 0 || (i.entries.unwrap() != ents_idx[t - 1].entries.unwrap()) {
+                blocks.push(i.entries.unwrap());
             }
+        }
 
-            if blocks.len() > 5 && total_bytes > 1024 * 1024 {
-                //Async IO
-                let bytes = self.pipe_log.async_read_bytes(blocks)?;
-                parse_entries_from_bytes::<M>(bytes, &mut ents_idx, vec)?;
-            } else {
-                //Sync IO
-                for i in ents_idx.iter() {
-                    vec.push(read_entry_from_file::<M, _>(self.pipe_log.as_ref(), i)?);
-                }
+        if use_async_read(&self.cfg.get(), &blocks) {
+            //Async IO
+            let bytes = self.pipe_log.async_read_bytes(blocks)?;
+            parse_entries_from_bytes::<M>(bytes, ents_idx, vec)?;
+        } else {
+            //Sync IO
+            for i in ents_idx.iter() {
+                vec.push(read_entry_from_file::<M, _>(self.pipe_log.as_ref(), i)?);
             }
+        }
+        Ok(())
+    }
 
-            ENGINE_READ_ENTRY_COUNT_HISTOGRAM.observe(ents_idx.len() as f64);
+    /// Returns an iterator over log entries of a Raft Group, from `start_index`
+    /// to the last entry at the time of calling. Unlike repeated calls of
+    /// `fetch_entries_to`, blocks of entries are read ahead with async I/O
+    /// while the caller consumes the previous ones.
+    pub fn entry_iter<M: MessageExt>(
+        &self,
+        region_id: u64,
+        start_index: u64,
+    ) -> Result<EntryIter<'_, M, P>> {
+        self.wait_for_entries(region_id)?;
+        EntryIter::new(
+            &self.memtables,
+            self.pipe_log.as_ref(),
+            region_id,
+            start_index,
+        )
+    }
 
-            return Ok(ents_idx.len());
+    /// Fetches the entries of multiple Raft Groups. Each request is
+    /// `(region_id, begin, end, max_size)`, same as the arguments of
+    /// [`Engine::fetch_entries_to`]. Blocks shared by the requests are read and
+    /// decoded only once. All blocks are read in one batch of async I/O,
+    /// regardless of `async-read-blocks-threshold` and
+    /// `async-read-bytes-threshold`.
+    ///
+    /// Returns the entries of each request in order. A request fails alone if
+    /// its entries are compacted or missing, while all of them fail if any
+    /// block can't be read.
+    pub fn multi_fetch<M: MessageExt>(
+        &self,
+        requests: &[(u64, u64, u64, Option<usize>)],
+    ) -> Result<Vec<Result<Vec<M::Entry>>>> {
+        let _t = StopWatch::new(&*ENGINE_READ_ENTRY_DURATION_HISTOGRAM);
+        let mut ents_idxes = Vec::with_capacity(requests.len());
+        let mut blocks = Vec::new();
+        for &(region_id, begin, end, max_size) in requests {
+            let ents_idx = self.wait_for_entries(region_id).and_then(|_| {
+                let mut ents_idx = Vec::new();
+                if let Some(memtable) = self.memtables.get(region_id) {
+                    memtable
+                        .read()
+                        .fetch_entries_to(begin, end, max_size, &mut ents_idx)?;
+                }
+                Ok(ents_idx)
+            });
+            if let Ok(ents_idx) = &ents_idx {
+                blocks.extend(
+                    ents_idx
+                        .iter()
+                        .map(|i| (i.entries.unwrap(), i.compression_type)),
+                );
+            }
+            ents_idxes.push(ents_idx);
         }
-        Ok(0)
+        blocks.sort_unstable_by_key(|(b, _)| (b.id, b.offset));
+        blocks.dedup_by_key(|(b, _)| *b);
+
+        let handles: Vec<FileBlockHandle> = blocks.iter().map(|(b, _)| *b).collect();
+        let bytes = if handles.is_empty() {
+            Vec::new()
+        } else {
+            self.pipe_log.async_read_bytes(handles)?
+        };
+        let mut decoded = HashMap::with_capacity(blocks.len());
+        for ((handle, compression_type), bytes) in blocks.into_iter().zip(bytes) {
+            decoded.insert(
+                handle,
+                LogBatch::decode_entries_block(&bytes, handle, compression_type)?,
+            );
+        }
+        Ok(ents_idxes
+            .into_iter()
+            .map(|ents_idx| {
+                let ents_idx = ents_idx?;
+                let mut entries = Vec::with_capacity(ents_idx.len());
+                for i in &ents_idx {
+                    let block = &decoded[&i.entries.unwrap()];
+                    let e: M::Entry = parse_from_bytes(
+                        &block[i.entry_offset as usize..(i.entry_offset + i.entry_len) as usize],
+                    )?;
+                    assert_eq!(M::index(&e), i.index);
+                    entries.push(e);
+                }
+                ENGINE_READ_ENTRY_COUNT_HISTOGRAM.observe(entries.len() as f64);
+                Ok(entries)
+            })
+            .collect())
     }
 
+    /// Returns the index of the first entry. Returns `None` if the entries
+    /// are not indexed in time by lazy recovery, use
+    /// [`Engine::try_first_index`] to tell it apart.
     pub fn first_index(&self, region_id: u64) -> Option<u64> {
-        if let Some(memtable) = self.memtables.get(region_id) {
-            return memtable.read().first_index();
-        }
-        None
+        self.try_first_index(region_id).ok().flatten()
     }
 
+    /// Returns the index of the last entry. Returns `None` if the entries are
+    /// not indexed in time by lazy recovery, use [`Engine::try_last_index`]
+    /// to tell it apart.
     pub fn last_index(&self, region_id: u64) -> Option<u64> {
-        if let Some(memtable) = self.memtables.get(region_id) {
-            return memtable.read().last_index();
-        }
-        None
+        self.try_last_index(region_id).ok().flatten()
+    }
+
+    /// Same as [`Engine::first_index`], but returns `Error::NotReady` if the
+    /// entries are not indexed in time by lazy recovery.
+    pub fn try_first_index(&self, region_id: u64) -> Result<Option<u64>> {
+        self.wait_for_entries(region_id)?;
+        Ok(self
+            .memtables
+            .get(region_id)
+            .and_then(|t| t.read().first_index()))
+    }
+
+    /// Same as [`Engine::last_index`], but returns `Error::NotReady` if the
+    /// entries are not indexed in time by lazy recovery.
+    pub fn try_last_index(&self, region_id: u64) -> Result<Option<u64>> {
+        self.wait_for_entries(region_id)?;
+        Ok(self
+            .memtables
+            .get(region_id)
+            .and_then(|t| t.read().last_index()))
     }
 
     /// Deletes log entries before `index` in the specified Raft group. Returns
     /// the number of deleted entries.
     pub fn compact_to(&self, region_id: u64, index: u64) -> u64 {
+        // Like writes, fails if the entries are not indexed in time.
+        if let Err(e) = self.wait_for_entries(region_id) {
+            error!("Failed to compact raft group {}: {}", region_id, e);
+            return 0;
+        }
         let first_index = match self.first_index(region_id) {
             Some(index) => index,
             None => return 0,
@@ -363,6 +977,72 @@ where
         self.first_index(region_id).unwrap_or(index) - first_index
     }
 
+    /// Moves all entries and key value pairs of Raft Group `from` to `to`
+    /// without copying the log entries. `to` must not have any entry or key
+    /// value pair.
+    ///
+    /// Blocks until the ongoing purge is finished.
+    pub fn rename_region(&self, from: u64, to: u64) -> Result<()> {
+        self.migrate_region(to, Command::Rename { from })
+    }
+
+    /// Shares the entries of Raft Group `from` within `[begin, end)` with Raft
+    /// Group `to` without copying them. `to` must not have any entry, its key
+    /// value pairs are kept. Returns `Error::EntryCompacted` or
+    /// `Error::EntryNotFound` if any of the entries is missing.
+    ///
+    /// Blocks until the ongoing purge is finished.
+    pub fn clone_region(&self, from: u64, to: u64, begin: u64, end: u64) -> Result<()> {
+        self.migrate_region(to, Command::Clone { from, begin, end })
+    }
+
+    fn migrate_region(&self, to: u64, cmd: Command) -> Result<()> {
+        let from = cmd.source().unwrap();
+        if from == to {
+            return Err(Error::InvalidArgument(format!(
+                "Raft Group {} is migrated to itself",
+                from
+            )));
+        }
+        self.wait_for_entries(from)?;
+        // Entries of both Raft Groups must not be rewritten in between, or the
+        // command might be rewritten after them and replayed out of order.
+        let _guard = self.purge_manager.pause_purge();
+        let src = self
+            .memtables
+            .get(from)
+            .ok_or_else(|| Error::InvalidArgument(format!("Raft Group {} doesn't exist", from)))?;
+        if let Some(dst) = self.memtables.get(to) {
+            let dst = dst.read();
+            if dst.entries_count() > 0
+                || matches!(cmd, Command::Rename { .. }) && dst.kvs().next().is_some()
+            {
+                return Err(Error::InvalidArgument(format!(
+                    "Raft Group {} is not empty",
+                    to
+                )));
+            }
+        }
+        if let Command::Clone { begin, end, .. } = cmd {
+            if begin >= end {
+                return Err(Error::InvalidArgument(format!(
+                    "Empty range [{}, {})",
+                    begin, end
+                )));
+            }
+            let src = src.read();
+            match src.first_index().zip(src.last_index()) {
+                Some((first, _)) if begin < first => return Err(Error::EntryCompacted),
+                Some((_, last)) if end <= last + 1 => {}
+                _ => return Err(Error::EntryNotFound),
+            }
+        }
+        let mut log_batch = LogBatch::default();
+        log_batch.add_command(to, cmd);
+        self.write(&mut log_batch, true)?;
+        Ok(())
+    }
+
     pub fn raft_groups(&self) -> Vec<u64> {
         self.memtables.fold(vec![], |mut v, m| {
             v.push(m.region_id());
@@ -370,6 +1050,153 @@ where
         })
     }
 
+    /// Writes exported Raft Groups into this engine. Entries of a Raft Group
+    /// must directly follow its existing entries, otherwise nothing is
+    /// written and `Error::InvalidArgument` is returned. Existing keys are
+    /// overwritten.
+    ///
+    /// Each Raft Group is written in one or more log batches, so a failed
+    /// import can leave some Raft Groups partially imported.
+    pub fn import_regions(&self, exported: &ExportedRegions) -> Result<Vec<ImportedRegion>> {
+        let mut region_ids = HashSet::new();
+        for region in &exported.regions {
+            if !region_ids.insert(region.region_id) {
+                return Err(Error::InvalidArgument(format!(
+                    "Raft Group {} is exported more than once",
+                    region.region_id
+                )));
+            }
+            if let (Some((first, last)), Some(span)) = (
+                region.span(),
+                self.try_first_index(region.region_id)?
+                    .zip(self.try_last_index(region.region_id)?),
+            ) {
+                if first != span.1 + 1 {
+                    return Err(Error::InvalidArgument(format!(
+                        "Entries [{}, {}] of Raft Group {} collide with existing entries [{}, {}]",
+                        first, last, region.region_id, span.0, span.1
+                    )));
+                }
+            }
+        }
+
+        let mut imported = Vec::with_capacity(exported.regions.len());
+        for region in &exported.regions {
+            let mut log_batch = LogBatch::default();
+            let (mut entry_indexes, mut entries, mut size) = (Vec::new(), Vec::new(), 0);
+            for (i, entry) in region.entries.iter().enumerate() {
+                entry_indexes.push(EntryIndex {
+                    index: region.first_index + i as u64,
+                    ..Default::default()
+                });
+                entries.push(entry.clone());
+                size += entry.len();
+                if size >= MAX_IMPORT_BATCH_BYTES {
+                    log_batch.add_raw_entries(
+                        region.region_id,
+                        std::mem::take(&mut entry_indexes),
+                        std::mem::take(&mut entries),
+                    )?;
+                    self.write(&mut log_batch, false)?;
+                    size = 0;
+                }
+            }
+            log_batch.add_raw_entries(region.region_id, entry_indexes, entries)?;
+            for (key, value) in &region.kvs {
+                log_batch.put(region.region_id, key.clone(), value.clone())?;
+            }
+            self.write(&mut log_batch, false)?;
+            imported.push(ImportedRegion {
+                region_id: region.region_id,
+                span: region.span(),
+                entries: region.entries.len(),
+                kvs: region.kvs.len(),
+            });
+        }
+        self.sync()?;
+        Ok(imported)
+    }
+
+    /// Indexes the entries of the specified Raft Groups before others. Only
+    /// effective when the engine is lazily recovered and still indexing.
+    pub fn prioritize_regions(&self, region_ids: &[u64]) {
+        if let Some(lazy_recovery) = &self.lazy_recovery {
+            lazy_recovery.prioritize(region_ids);
+        }
+    }
+
+    /// Returns whether the entries of the specified Raft Group are indexed and
+    /// ready to be read.
+    pub fn is_region_ready(&self, region_id: u64) -> bool {
+        self.lazy_recovery
+            .as_ref()
+            .map_or(true, |l| l.is_ready(region_id))
+    }
+
+    // Waits until the entries of `region_id` are indexed. Returns
+    // `Error::NotReady` if they are not indexed in time.
+    fn wait_for_entries(&self, region_id: u64) -> Result<()> {
+        if let Some(lazy_recovery) = &self.lazy_recovery {
+            lazy_recovery.wait(&[region_id], Some(INDEX_WAIT_TIMEOUT))?;
+        }
+        Ok(())
+    }
+
+    /// Shuts down the engine. If lazy recovery is enabled, a map of Raft
+    /// Groups is persisted for the next startup, which rotates the active log
+    /// files first. Otherwise it's the same as dropping the engine.
+    ///
+    /// The next startup recovers from log files fully if the engine is
+    /// dropped without this.
+    pub fn close(self) -> Result<()> {
+        self.scrubber.stop();
+        if let Some(lazy_recovery) = &self.lazy_recovery {
+            lazy_recovery.stop();
+            self.persist_region_map(lazy_recovery)?;
+        }
+        Ok(())
+    }
+
+    // Persists the map of Raft Groups for lazy recovery. Active files are
+    // rotated first, so that any later write invalidates the map.
+    fn persist_region_map(&self, lazy_recovery: &LazyRecovery<F>) -> Result<()> {
+        if lazy_recovery.tracker().has_migrations() {
+            // Renamed or cloned entries are only found in the files of their
+            // source Raft Groups, fully recover next time.
+            info!("Skip persisting region map because of renamed or cloned regions");
+            return Ok(());
+        }
+        let mut map = RegionMap::default();
+        for queue in [LogQueue::Append, LogQueue::Rewrite] {
+            self.pipe_log.rotate(queue)?;
+            map.spans[queue as usize] = self.pipe_log.file_span(queue);
+        }
+        let spans = map.spans;
+        let tracker = lazy_recovery.tracker();
+        map.regions = self.memtables.fold(Vec::new(), |mut regions, t| {
+            regions.push(RegionRecord {
+                region_id: t.region_id(),
+                files: tracker
+                    .files(t.region_id())
+                    .into_iter()
+                    .filter(|f| f.seq >= spans[f.queue as usize].0)
+                    .collect(),
+                kvs: t
+                    .kvs()
+                    .map(|(key, value, file_id)| (key.to_vec(), value.clone(), file_id))
+                    .collect(),
+            });
+            regions
+        });
+        lazy_recovery.persist(&map)
+    }
+
+    /// Changes the maximum rate of background rewrite I/O for each log queue.
+    /// `None` means unlimited.
+    pub fn set_rewrite_rate_limit(&self, rate_limit: Option<ReadableSize>) {
+        self.purge_manager.set_rewrite_rate_limit(rate_limit);
+    }
+
     /// Returns `true` if the engine contains no Raft Group. Empty Raft Group
     /// that isn't cleaned is counted as well.
     pub fn is_empty(&self) -> bool {
@@ -383,12 +1210,22 @@ where
         self.pipe_log.file_span(queue)
     }
 
+    /// Returns the total size of log files in bytes.
     pub fn get_used_size(&self) -> usize {
         self.pipe_log.total_size(LogQueue::Append) + self.pipe_log.total_size(LogQueue::Rewrite)
     }
 
+    /// Returns the space usage of log files, including how much of it is
+    /// still referenced, and the bytes written and purged since the engine
+    /// was opened.
+    ///
+    /// This scans all memtables and is meant for diagnosis only.
+    pub fn space_stats(&self) -> Result<SpaceStats> {
+        collect_space_stats(self.pipe_log.as_ref(), &self.memtables)
+    }
+
     pub fn path(&self) -> &str {
-        self.cfg.dir.as_str()
+        self.cfg.dir()
     }
 
     #[cfg(feature = "internals")]
@@ -397,32 +1234,316 @@ where
     }
 }
 
-impl<F, P> Drop for Engine<F, P>
+/// A group of writes to the append queue that becomes visible as a whole. It
+/// can carry more data than a single [`LogBatch`], and can be written
+/// incrementally.
+///
+/// Log batches written via [`write`] are persisted but not applied to
+/// memtables, until the group is ended by [`commit`]. After restart, the
+/// group is recovered only if the commit had been written. Otherwise all of it
+/// is discarded, and its ID is included in
+/// [`RecoveryReport::discarded_atomic_groups`]. A group dropped without commit
+/// or after a failed write is discarded likewise.
+///
+/// Raft Groups written in an atomic group must not be written elsewhere before
+/// the atomic group is committed or dropped. Otherwise their recovered state is
+/// undefined. Log files holding uncommitted atomic groups are not purged.
+///
+/// [`write`]: AtomicGroup::write
+/// [`commit`]: AtomicGroup::commit
+/// [`RecoveryReport::discarded_atomic_groups`]: crate::RecoveryReport::discarded_atomic_groups
+pub struct AtomicGroup<'a, F, P>
 where
     F: FileSystem,
     P: PipeLog,
 {
-    fn drop(&mut self) {
-        self.tx.lock().unwrap().send(()).unwrap();
-        if let Some(t) = self.metrics_flusher.take() {
-            t.join().unwrap();
-        }
-    }
+    engine: &'a Engine<F, P>,
+    builder: AtomicGroupBuilder,
+    // Written items waiting to be applied.
+    items: Vec<LogItem>,
+    files: Vec<FileId>,
+    aborted: bool,
 }
 
-impl Engine<DefaultFileSystem, FilePipeLog<DefaultFileSystem>> {
-    pub fn consistency_check(path: &Path) -> Result<Vec<(u64, u64)>> {
-        Self::consistency_check_with_file_system(path, Arc::new(DefaultFileSystem))
+impl<'a, F, P> AtomicGroup<'a, F, P>
+where
+    F: FileSystem,
+    P: PipeLog,
+{
+    /// Returns the ID of this group, which is used to report the group when
+    /// it's discarded during recovery.
+    pub fn id(&self) -> u64 {
+        self.builder.id()
     }
 
-    #[cfg(feature = "scripting")]
-    pub fn unsafe_repair(path: &Path, queue: Option<LogQueue>, script: String) -> Result<()> {
-        Self::unsafe_repair_with_file_system(path, queue, script, Arc::new(DefaultFileSystem))
-    }
+    /// Writes a part of the group and returns written bytes. It's invisible
+    /// until the group is committed.
+    pub fn write(&mut self, log_batch: &mut LogBatch) -> Result<usize> {
+        self.check_aborted()?;
+        if self.files.is_empty() {
+            let (_, active) = self.engine.pipe_log.file_span(LogQueue::Append);
+            self.engine
+                .purge_manager
+                .open_atomic_groups()
+                .insert(self.id(), active);
+            self.builder.begin(log_batch);
+        } else {
+            self.builder.add(log_batch);
+        }
+        self.write_part(log_batch, false)
+    }
+
+    /// Writes the last part of the group and makes all of it visible. The
+    /// write is always synced. Returns written bytes.
+    pub fn commit(mut self, log_batch: &mut LogBatch) -> Result<usize> {
+        self.check_aborted()?;
+        if self.files.is_empty() {
+            // A single log batch is atomic by itself.
+            return self.engine.write(log_batch, true);
+        }
+        self.builder.end(log_batch);
+        let len = self.write_part(log_batch, true)?;
+        {
+            let _guard = self.engine.apply_lock.read();
+            self.engine
+                .memtables
+                .apply_append_writes(self.items.drain(..));
+        }
+        for listener in &self.engine.listeners {
+            for file_id in &self.files {
+                listener.post_apply_memtables(*file_id);
+            }
+        }
+        Ok(len)
+    }
+
+    fn write_part(&mut self, log_batch: &mut LogBatch, sync: bool) -> Result<usize> {
+        match self.engine.write_impl(log_batch, sync, false /* apply */) {
+            Ok((len, file_id)) => {
+                self.items.extend(log_batch.drain());
+                if self.files.last() != Some(&file_id) {
+                    self.files.push(file_id);
+                }
+                Ok(len)
+            }
+            Err(e) => {
+                self.aborted = true;
+                Err(e)
+            }
+        }
+    }
+
+    fn check_aborted(&self) -> Result<()> {
+        if self.aborted {
+            return Err(Error::InvalidArgument(
+                "atomic group is aborted by a failed write".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<'a, F, P> Drop for AtomicGroup<'a, F, P>
+where
+    F: FileSystem,
+    P: PipeLog,
+{
+    fn drop(&mut self) {
+        if !self.files.is_empty() {
+            self.engine
+                .purge_manager
+                .open_atomic_groups()
+                .remove(self.id());
+        }
+    }
+}
+
+/// A consistent read view of all Raft Groups, returned by
+/// [`Engine::snapshot`].
+///
+/// Reads through a snapshot reflect exactly the writes applied before it was
+/// taken. Log files referenced by it are not purged until it's dropped, so it
+/// shouldn't be held for long.
+pub struct Snapshot<'a, F, P>
+where
+    F: FileSystem,
+    P: PipeLog,
+{
+    engine: &'a Engine<F, P>,
+    id: u64,
+    memtables: hashbrown::HashMap<u64, MemTable<SelectedAllocator>>,
+}
+
+impl<'a, F, P> Snapshot<'a, F, P>
+where
+    F: FileSystem,
+    P: PipeLog,
+{
+    pub fn get_message<S: Message>(&self, region_id: u64, key: &[u8]) -> Result<Option<S>> {
+        let _t = StopWatch::new(&*ENGINE_READ_MESSAGE_DURATION_HISTOGRAM);
+        if let Some(memtable) = self.memtables.get(&region_id) {
+            if let Some(value) = memtable.get(key) {
+                return Ok(Some(S::parse_from_bytes(
+                    &value.read(self.engine.pipe_log.as_ref())?,
+                )?));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn get(&self, region_id: u64, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let _t = StopWatch::new(&*ENGINE_READ_MESSAGE_DURATION_HISTOGRAM);
+        if let Some(memtable) = self.memtables.get(&region_id) {
+            if let Some(value) = memtable.get(key) {
+                return Ok(Some(
+                    value.read(self.engine.pipe_log.as_ref())?.into_owned(),
+                ));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Same as [`Engine::scan_messages`].
+    pub fn scan_messages<S, C>(
+        &self,
+        region_id: u64,
+        start_key: Option<&[u8]>,
+        end_key: Option<&[u8]>,
+        reverse: bool,
+        mut callback: C,
+    ) -> Result<()>
+    where
+        S: Message,
+        C: FnMut(&[u8], S) -> bool,
+    {
+        self.scan_raw_messages(region_id, start_key, end_key, reverse, move |k, raw_v| {
+            if let Ok(v) = S::parse_from_bytes(raw_v) {
+                callback(k, v)
+            } else {
+                true
+            }
+        })
+    }
+
+    /// Same as [`Engine::scan_raw_messages`].
+    pub fn scan_raw_messages<C>(
+        &self,
+        region_id: u64,
+        start_key: Option<&[u8]>,
+        end_key: Option<&[u8]>,
+        reverse: bool,
+        callback: C,
+    ) -> Result<()>
+    where
+        C: FnMut(&[u8], &[u8]) -> bool,
+    {
+        let _t = StopWatch::new(&*ENGINE_READ_MESSAGE_DURATION_HISTOGRAM);
+        if let Some(memtable) = self.memtables.get(&region_id) {
+            scan_kvs(
+                memtable,
+                self.engine.pipe_log.as_ref(),
+                start_key,
+                end_key,
+                reverse,
+                callback,
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn first_index(&self, region_id: u64) -> Option<u64> {
+        self.memtables.get(&region_id)?.first_index()
+    }
+
+    pub fn last_index(&self, region_id: u64) -> Option<u64> {
+        self.memtables.get(&region_id)?.last_index()
+    }
+
+    /// Same as [`Engine::fetch_entries_to`]. Returns count of fetched entries.
+    pub fn fetch_entries_to<M: MessageExt>(
+        &self,
+        region_id: u64,
+        begin: u64,
+        end: u64,
+        max_size: Option<usize>,
+        vec: &mut Vec<M::Entry>,
+    ) -> Result<usize> {
+        let _t = StopWatch::new(&*ENGINE_READ_ENTRY_DURATION_HISTOGRAM);
+        if let Some(memtable) = self.memtables.get(&region_id) {
+            let mut ents_idx: Vec<EntryIndex> = Vec::with_capacity((end - begin) as usize);
+            memtable.fetch_entries_to(begin, end, max_size, &mut ents_idx)?;
+            self.engine.read_entries::<M>(&mut ents_idx, vec)?;
+            ENGINE_READ_ENTRY_COUNT_HISTOGRAM.observe(ents_idx.len() as f64);
+            return Ok(ents_idx.len());
+        }
+        Ok(0)
+    }
+}
+
+impl<'a, F, P> Drop for Snapshot<'a, F, P>
+where
+    F: FileSystem,
+    P: PipeLog,
+{
+    fn drop(&mut self) {
+        self.engine.purge_manager.snapshot_files().remove(self.id);
+    }
+}
+
+/// Log files that can't be purged until this is dropped.
+struct PinnedFiles<'a, P: PipeLog> {
+    purge_manager: &'a PurgeManager<P>,
+    id: u64,
+}
+
+impl<'a, P: PipeLog> Drop for PinnedFiles<'a, P> {
+    fn drop(&mut self) {
+        self.purge_manager.snapshot_files().remove(self.id);
+    }
+}
+
+impl<F, P> Drop for Engine<F, P>
+where
+    F: FileSystem,
+    P: PipeLog,
+{
+    fn drop(&mut self) {
+        self.scrubber.stop();
+        if let Some(lazy_recovery) = &self.lazy_recovery {
+            lazy_recovery.stop();
+        }
+        self.tx.lock().unwrap().send(()).unwrap();
+        if let Some(t) = self.metrics_flusher.take() {
+            t.join().unwrap();
+        }
+    }
+}
+
+impl Engine<DefaultFileSystem, FilePipeLog<DefaultFileSystem>> {
+    pub fn consistency_check(path: &Path) -> Result<Vec<(u64, u64)>> {
+        Self::consistency_check_with_file_system(path, Arc::new(DefaultFileSystem))
+    }
+
+    pub fn deep_consistency_check<M: MessageExt>(path: &Path) -> Result<DeepCheckReport> {
+        Self::deep_consistency_check_with_file_system::<M>(path, Arc::new(DefaultFileSystem))
+    }
+
+    #[cfg(feature = "scripting")]
+    pub fn unsafe_repair(path: &Path, queue: Option<LogQueue>, script: String) -> Result<()> {
+        Self::unsafe_repair_with_file_system(path, queue, script, Arc::new(DefaultFileSystem))
+    }
 
     pub fn dump(path: &Path) -> Result<LogItemReader<DefaultFileSystem>> {
         Self::dump_with_file_system(path, Arc::new(DefaultFileSystem))
     }
+
+    pub fn rewrite_directory(cfg: Config) -> Result<DirectoryRewriteReport> {
+        Self::rewrite_directory_with_file_system(cfg, Arc::new(DefaultFileSystem))
+    }
+
+    pub fn inspect(path: &Path) -> Result<Inspector<DefaultFileSystem>> {
+        Self::inspect_with_file_system(path, Arc::new(DefaultFileSystem))
+    }
 }
 
 impl<F> Engine<F, FilePipeLog<F>>
@@ -460,6 +1581,20 @@ where
         Ok(list)
     }
 
+    /// Reads and verifies all log files without modifying them. Besides log
+    /// entry holes, it verifies the checksum of every block of log entries,
+    /// decodes log entries to check their indexes, and validates the order of
+    /// compactions and cleanups of each Raft Group.
+    ///
+    /// Blocks reclaimed by hole punching are skipped unless they contain live
+    /// entries.
+    pub fn deep_consistency_check_with_file_system<M: MessageExt>(
+        path: &Path,
+        file_system: Arc<F>,
+    ) -> Result<DeepCheckReport> {
+        crate::consistency::deep_check::<M, F>(file_system.as_ref(), path)
+    }
+
     #[cfg(feature = "scripting")]
     pub fn unsafe_repair_with_file_system(
         path: &Path,
@@ -535,6 +1670,26 @@ where
             LogItemReader::new_file_reader(file_system, path)
         }
     }
+
+    /// Rewrites all live data under `cfg.dir` into a fresh set of log files
+    /// created with `cfg`, e.g. to upgrade the format version or to squash
+    /// obsolete data.
+    ///
+    /// New files are written into a sibling directory with suffix ".rewrite"
+    /// and verified before being swapped in. The original directory is kept
+    /// with suffix ".bak".
+    pub fn rewrite_directory_with_file_system(
+        cfg: Config,
+        file_system: Arc<F>,
+    ) -> Result<DirectoryRewriteReport> {
+        crate::export::rewrite_directory(cfg, file_system)
+    }
+
+    /// Indexes log files under the directory without modifying them, and
+    /// returns a read-only view of them.
+    pub fn inspect_with_file_system(path: &Path, file_system: Arc<F>) -> Result<Inspector<F>> {
+        Inspector::open(file_system, path)
+    }
 }
 
 struct BlockCache {
@@ -579,23 +1734,80 @@ pub(crate) fn parse_entries_from_bytes<M: MessageExt>(
                         &bytes[seq],
                         idx.entries.unwrap(),
                         idx.compression_type,
-                    )
-                    .unwrap(),
+                    )?,
                 );
                 seq += 1;
             }
             let e = parse_from_bytes(
                 &cache.block.borrow()
                     [idx.entry_offset as usize..(idx.entry_offset + idx.entry_len) as usize],
-            )
-            .unwrap();
+            )?;
             assert_eq!(M::index(&e), idx.index);
             vec.push(e);
-        });
+            Ok::<_, Error>(())
+        })?;
     }
     Ok(())
 }
 
+/// Returns whether a read of `blocks` exceeds the thresholds for async I/O.
+fn use_async_read(cfg: &Config, blocks: &[FileBlockHandle]) -> bool {
+    blocks.len() > cfg.async_read_blocks_threshold
+        && blocks.iter().map(|b| b.len).sum::<usize>() > cfg.async_read_bytes_threshold.0 as usize
+}
+
+/// Reads blocks of log entries from either queue, with async I/O if there are
+/// many of them.
+pub(crate) fn read_blocks<P: PipeLog>(
+    pipe_log: &P,
+    cfg: &Config,
+    blocks: &[FileBlockHandle],
+) -> Result<Vec<Vec<u8>>> {
+    if use_async_read(cfg, blocks) {
+        pipe_log.async_read_bytes(blocks.to_vec())
+    } else {
+        blocks.iter().map(|b| pipe_log.read_bytes(*b)).collect()
+    }
+}
+
+// Copies the value of `key` out of `memtable`, along with whether it's spilled.
+fn copy_value(
+    memtable: &MemTable<SelectedAllocator>,
+    key: &[u8],
+) -> Result<(Option<KvValue>, bool)> {
+    let value = memtable.get(key).cloned();
+    let spilled = matches!(value, Some(KvValue::Spilled(..)));
+    Ok((value, spilled))
+}
+
+/// Iterates over [start_key, end_key) range of key value pairs in `memtable`.
+/// Spilled values are read from `pipe_log`, which must not be purged until
+/// this returns.
+fn scan_kvs<P, C>(
+    memtable: &MemTable<SelectedAllocator>,
+    pipe_log: &P,
+    start_key: Option<&[u8]>,
+    end_key: Option<&[u8]>,
+    reverse: bool,
+    mut callback: C,
+) -> Result<()>
+where
+    P: PipeLog,
+    C: FnMut(&[u8], &[u8]) -> bool,
+{
+    let mut res = Ok(());
+    memtable.scan(start_key, end_key, reverse, |key, value| {
+        match value.read(pipe_log) {
+            Ok(value) => callback(key, &value),
+            Err(e) => {
+                res = Err(e);
+                false
+            }
+        }
+    })?;
+    res
+}
+
 pub(crate) fn read_entry_from_file<M, P>(pipe_log: &P, idx: &EntryIndex) -> Result<M::Entry>
 where
     M: MessageExt,
@@ -621,44 +1833,27 @@ where
     })
 }
 
-pub(crate) fn read_entry_bytes_from_file<P>(pipe_log: &P, idx: &EntryIndex) -> Result<Vec<u8>>
-where
-    P: PipeLog,
-{
-    BLOCK_CACHE.with(|cache| {
-        if cache.key.get() != idx.entries.unwrap() {
-            cache.insert(
-                idx.entries.unwrap(),
-                LogBatch::decode_entries_block(
-                    &pipe_log.read_bytes(idx.entries.unwrap())?,
-                    idx.entries.unwrap(),
-                    idx.compression_type,
-                )?,
-            );
-        }
-        Ok(cache.block.borrow()
-            [idx.entry_offset as usize..(idx.entry_offset + idx.entry_len) as usize]
-            .to_owned())
-    })
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::env::ObfuscatedFileSystem;
+    use crate::env::{MemFileSystem, ObfuscatedFileSystem};
     use crate::file_pipe_log::{parse_recycled_file_name, FileNameExt};
     use crate::log_batch::AtomicGroupBuilder;
+    use crate::log_batch::LOG_BATCH_HEADER_LEN;
     use crate::pipe_log::Version;
+    use crate::purge_policy::SizeTieredPurgePolicy;
+    use crate::rate_limiter::ManualClock;
+    use crate::recovery::RecoveryProgress;
     use crate::test_util::{generate_entries, PanicGuard};
-    use crate::util::ReadableSize;
     use kvproto::raft_serverpb::RaftLocalState;
     use raft::eraftpb::Entry;
     use std::collections::{BTreeSet, HashSet};
     use std::fs::OpenOptions;
+    use std::io::{Read, Seek, SeekFrom, Write};
     use std::path::PathBuf;
 
     type RaftLogEngine<F = DefaultFileSystem> = Engine<F>;
-    impl<F: FileSystem> RaftLogEngine<F> {
+    impl<F: FileSystem + 'static> RaftLogEngine<F> {
         fn append(&self, rid: u64, start_index: u64, end_index: u64, data: Option<&[u8]>) {
             let entries = generate_entries(start_index, end_index, data);
             if !entries.is_empty() {
@@ -691,12 +1886,18 @@ mod tests {
         }
 
         fn reopen(self) -> Self {
-            let cfg: Config = self.cfg.as_ref().clone();
+            let cfg: Config = self.cfg.get().as_ref().clone();
             let file_system = self.pipe_log.file_system();
             let mut listeners = self.listeners.clone();
             listeners.pop();
+            let purge_policy = self.purge_manager.purge_policy().clone();
             drop(self);
-            RaftLogEngine::open_with(cfg, file_system, listeners).unwrap()
+            EngineBuilder::new(cfg)
+                .file_system(file_system)
+                .listeners(listeners)
+                .purge_policy(purge_policy)
+                .open()
+                .unwrap()
         }
 
         fn scan_entries<FR: Fn(u64, LogQueue, &[u8])>(
@@ -762,44 +1963,508 @@ mod tests {
     }
 
     #[test]
-    fn test_get_entry() {
-        let normal_batch_size = 10;
-        let compressed_batch_size = 5120;
-        for &entry_size in &[normal_batch_size, compressed_batch_size] {
-            let dir = tempfile::Builder::new()
-                .prefix("test_get_entry")
-                .tempdir()
-                .unwrap();
-            let cfg = Config {
-                dir: dir.path().to_str().unwrap().to_owned(),
-                target_file_size: ReadableSize(1),
-                ..Default::default()
-            };
-
-            let engine = RaftLogEngine::open_with_file_system(
-                cfg.clone(),
-                Arc::new(ObfuscatedFileSystem::default()),
-            )
+    fn test_multi_fetch() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_multi_fetch")
+            .tempdir()
             .unwrap();
-            assert_eq!(engine.path(), dir.path().to_str().unwrap());
-            let data = vec![b'x'; entry_size];
-            for i in 10..20 {
-                let rid = i;
-                let index = i;
-                engine.append(rid, index, index + 2, Some(&data));
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            target_file_size: ReadableSize::kb(256),
+            batch_compression_threshold: ReadableSize::mb(1),
+            ..Default::default()
+        };
+        let engine = RaftLogEngine::open(cfg).unwrap();
+        let data = vec![b'x'; 64 * 1024];
+        // Entries of all Raft Groups are written in the same blocks.
+        for index in 1..=10 {
+            let mut log_batch = LogBatch::default();
+            for rid in 1..=3 {
+                log_batch
+                    .add_entries::<Entry>(rid, &generate_entries(index, index + 1, Some(&data)))
+                    .unwrap();
             }
-            for i in 10..20 {
-                let rid = i;
-                let index = i;
-                engine.scan_entries(rid, index, index + 2, |_, q, d| {
-                    assert_eq!(q, LogQueue::Append);
-                    assert_eq!(d, &data);
-                });
+            engine.write(&mut log_batch, false).unwrap();
+            if index == 5 {
+                engine.purge_manager.must_rewrite_append_queue(None, None);
             }
+        }
+        engine.compact_to(3, 3);
+
+        let requests = [
+            (1, 1, 11, None),
+            (2, 4, 8, None),
+            (3, 1, 5, None),
+            (4, 1, 5, None),
+            (2, 1, 11, Some(1)),
+        ];
+        let results = engine.multi_fetch::<Entry>(&requests).unwrap();
+        assert_eq!(results.len(), requests.len());
+        assert!(matches!(results[2], Err(Error::EntryCompacted)));
+        assert!(results[3].as_ref().unwrap().is_empty());
+        for (i, &(rid, begin, end, max_size)) in requests.iter().enumerate() {
+            if let Ok(entries) = &results[i] {
+                let mut expected = Vec::new();
+                engine
+                    .fetch_entries_to::<Entry>(rid, begin, end, max_size, &mut expected)
+                    .unwrap();
+                assert_eq!(entries, &expected);
+            }
+        }
+        assert_eq!(results[0].as_ref().unwrap().len(), 10);
+        assert_eq!(results[4].as_ref().unwrap().len(), 1);
+    }
 
-            // Recover the engine.
-            let engine = engine.reopen();
-            for i in 10..20 {
+    #[test]
+    fn test_async_read() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_async_read")
+            .tempdir()
+            .unwrap();
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            target_file_size: ReadableSize::kb(16),
+            // Any read of more than one block is done with async I/O.
+            async_read_blocks_threshold: 1,
+            async_read_bytes_threshold: ReadableSize(0),
+            ..Default::default()
+        };
+        let engine = RaftLogEngine::open(cfg).unwrap();
+        let data = vec![b'x'; 1024];
+        for index in 1..=20 {
+            for rid in 1..=3 {
+                engine.append(rid, index, index + 1, Some(&data));
+            }
+        }
+        engine.purge_manager.must_rewrite_append_queue(None, None);
+        for index in 21..=30 {
+            for rid in 1..=3 {
+                engine.append(rid, index, index + 1, Some(&data));
+            }
+        }
+        let queue_of = |engine: &RaftLogEngine, rid: u64, index: u64| {
+            let memtable = engine.memtables.get(rid).unwrap();
+            let ei = memtable.read().get_entry(index).unwrap();
+            ei.entries.unwrap().id.queue
+        };
+        // Entries are read from both queues at once.
+        assert_eq!(queue_of(&engine, 1, 20), LogQueue::Rewrite);
+        assert_eq!(queue_of(&engine, 1, 21), LogQueue::Append);
+        let check = |engine: &RaftLogEngine| {
+            for rid in 1..=3 {
+                let mut entries = Vec::new();
+                engine
+                    .fetch_entries_to::<Entry>(rid, 1, 31, None, &mut entries)
+                    .unwrap();
+                assert_eq!(entries.len(), 30);
+                for (i, e) in entries.iter().enumerate() {
+                    assert_eq!(e.index, i as u64 + 1);
+                    assert_eq!(e.data, &data[..]);
+                }
+            }
+        };
+        check(&engine);
+
+        // Rewrite reads blocks of both queues in batches.
+        engine.purge_manager.must_rewrite_append_queue(None, None);
+        assert_eq!(queue_of(&engine, 1, 30), LogQueue::Rewrite);
+        check(&engine);
+        engine.purge_manager.must_rewrite_rewrite_queue();
+        check(&engine);
+        let engine = engine.reopen();
+        check(&engine);
+    }
+
+    #[test]
+    fn test_entry_iter() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_entry_iter")
+            .tempdir()
+            .unwrap();
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            target_file_size: ReadableSize::kb(16),
+            ..Default::default()
+        };
+        let engine = RaftLogEngine::open(cfg).unwrap();
+        let data = vec![b'x'; 1024];
+        for index in 1..=100 {
+            for rid in 1..=3 {
+                engine.append(rid, index, index + 1, Some(&data));
+            }
+        }
+        let collect = |iter: EntryIter<'_, Entry, _>| -> Result<Vec<Entry>> { iter.collect() };
+
+        let mut expected = Vec::new();
+        engine
+            .fetch_entries_to::<Entry>(1, 10, 101, None, &mut expected)
+            .unwrap();
+        let iter = engine.entry_iter::<Entry>(1, 10).unwrap();
+        assert_eq!(
+            collect(iter.max_prefetch_bytes(8 * 1024)).unwrap(),
+            expected
+        );
+        // Entries written afterwards are not returned.
+        let iter = engine.entry_iter::<Entry>(1, 10).unwrap();
+        engine.append(1, 101, 102, Some(&data));
+        assert_eq!(collect(iter).unwrap(), expected);
+        assert!(collect(engine.entry_iter(1, 102).unwrap())
+            .unwrap()
+            .is_empty());
+        assert!(collect(engine.entry_iter(4, 1).unwrap())
+            .unwrap()
+            .is_empty());
+
+        // Entries are compacted.
+        let mut iter = engine
+            .entry_iter::<Entry>(3, 1)
+            .unwrap()
+            .max_prefetch_bytes(8 * 1024);
+        assert_eq!(iter.next().unwrap().unwrap().index, 1);
+        engine.compact_to(3, 50);
+        let mut last = 1;
+        loop {
+            match iter.next().unwrap() {
+                Ok(e) => last = e.index,
+                Err(e) => {
+                    assert!(matches!(e, Error::EntryCompacted), "{}", e);
+                    break;
+                }
+            }
+        }
+        assert!(last < 50);
+        assert!(iter.next().is_none());
+        assert!(matches!(
+            engine.entry_iter::<Entry>(3, 1),
+            Err(Error::EntryCompacted)
+        ));
+        assert_eq!(
+            collect(engine.entry_iter(3, 50).unwrap()).unwrap().len(),
+            51
+        );
+
+        // Entries are moved by rewrite, and their old files purged.
+        let mut iter = engine
+            .entry_iter::<Entry>(2, 1)
+            .unwrap()
+            .max_prefetch_bytes(8 * 1024);
+        for index in 1..=10 {
+            assert_eq!(iter.next().unwrap().unwrap().index, index);
+        }
+        engine.purge_manager.must_rewrite_append_queue(None, None);
+        assert_eq!(
+            engine.file_span(LogQueue::Append).0,
+            engine.file_span(LogQueue::Append).1
+        );
+        let rest = collect(iter).unwrap();
+        assert_eq!(rest.len(), 90);
+        for (i, e) in rest.iter().enumerate() {
+            assert_eq!(e.index, i as u64 + 11);
+            assert_eq!(e.data, &data[..]);
+        }
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_snapshot")
+            .tempdir()
+            .unwrap();
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            target_file_size: ReadableSize::kb(4),
+            ..Default::default()
+        };
+        let engine = Arc::new(RaftLogEngine::open(cfg).unwrap());
+        let data = vec![b'x'; 1024];
+        for rid in 1..=3 {
+            engine.append(rid, 1, 11, Some(&data));
+        }
+        let snapshot = engine.snapshot().unwrap();
+        for rid in 1..=3 {
+            engine.append(rid, 11, 21, Some(&data));
+        }
+        engine.compact_to(1, 15);
+        engine.clean(2);
+        let check_snapshot = |snapshot: &Snapshot<'_, _, _>| {
+            for rid in 1..=3 {
+                assert_eq!(snapshot.first_index(rid), Some(1));
+                assert_eq!(snapshot.last_index(rid), Some(10));
+                let state: RaftLocalState =
+                    snapshot.get_message(rid, b"last_index").unwrap().unwrap();
+                assert_eq!(state.last_index, 10);
+                let mut count = 0;
+                snapshot
+                    .scan_messages::<RaftLocalState, _>(rid, None, None, false, |_, _| {
+                        count += 1;
+                        true
+                    })
+                    .unwrap();
+                assert_eq!(count, 1);
+                let mut entries = Vec::new();
+                snapshot
+                    .fetch_entries_to::<Entry>(rid, 1, 11, None, &mut entries)
+                    .unwrap();
+                assert_eq!(entries.len(), 10);
+                assert!(entries.iter().all(|e| e.data == data));
+            }
+            assert!(snapshot.get(4, b"last_index").unwrap().is_none());
+        };
+        check_snapshot(&snapshot);
+        assert_eq!(engine.first_index(1), Some(15));
+        assert_eq!(engine.last_index(2), None);
+        assert_eq!(engine.decode_last_index(3), Some(20));
+
+        // Files referenced by the snapshot are not purged.
+        let first_file = engine.file_span(LogQueue::Append).0;
+        engine.purge_manager.must_rewrite_append_queue(None, None);
+        engine.purge_manager.must_rewrite_rewrite_queue();
+        assert_eq!(engine.file_span(LogQueue::Append).0, first_file);
+        check_snapshot(&snapshot);
+        drop(snapshot);
+        engine.purge_manager.must_rewrite_append_queue(None, None);
+        assert!(engine.file_span(LogQueue::Append).0 > first_file);
+
+        // Only the specified Raft Groups are copied, and a purge in progress
+        // doesn't block taking snapshots.
+        {
+            let _purge_guard = engine.purge_manager.pause_purge();
+            let snapshot = engine.snapshot_regions(&[1, 7]).unwrap();
+            assert_eq!(snapshot.first_index(1), Some(15));
+            assert_eq!(snapshot.last_index(1), Some(20));
+            assert_eq!(snapshot.last_index(3), None);
+            assert_eq!(snapshot.last_index(7), None);
+            assert_eq!(
+                engine
+                    .purge_manager
+                    .snapshot_files()
+                    .first_file(LogQueue::Rewrite),
+                engine
+                    .memtables
+                    .get(1)
+                    .unwrap()
+                    .read()
+                    .min_file_seq(LogQueue::Rewrite)
+            );
+        }
+
+        // Writes touching multiple Raft Groups are not torn.
+        let writer = {
+            let engine = engine.clone();
+            std::thread::spawn(move || {
+                for index in 21..=200 {
+                    let mut log_batch = LogBatch::default();
+                    for rid in 4..=6 {
+                        log_batch
+                            .add_entries::<Entry>(rid, &generate_entries(index, index + 1, None))
+                            .unwrap();
+                        log_batch
+                            .put(rid, b"index".to_vec(), index.to_le_bytes().to_vec())
+                            .unwrap();
+                    }
+                    engine.write(&mut log_batch, false).unwrap();
+                }
+            })
+        };
+        for _ in 0..100 {
+            let snapshot = engine.snapshot().unwrap();
+            let states: Vec<_> = (4..=6)
+                .map(|rid| {
+                    (
+                        snapshot.get(rid, b"index").unwrap(),
+                        snapshot.last_index(rid),
+                    )
+                })
+                .collect();
+            assert!(states.iter().all(|s| *s == states[0]), "{:?}", states);
+        }
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn test_kv_spill() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_kv_spill")
+            .tempdir()
+            .unwrap();
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            target_file_size: ReadableSize::kb(4),
+            kv_spill_threshold: Some(ReadableSize::kb(1)),
+            enable_lazy_recovery: true,
+            ..Default::default()
+        };
+        let entry_data = vec![b'x'; 128];
+        let large = |rid: u64, version: u8| vec![rid as u8 + version; 2048];
+        let spilled = |engine: &RaftLogEngine, rid, key: &[u8]| match engine
+            .memtables
+            .get(rid)
+            .unwrap()
+            .read()
+            .get(key)
+        {
+            Some(KvValue::Spilled(handle, _)) => Some(handle.id.queue),
+            _ => None,
+        };
+        let check = |engine: &RaftLogEngine, versions: &[Option<u8>]| {
+            for rid in 1..=versions.len() as u64 {
+                let expected = versions[rid as usize - 1].map(|v| large(rid, v));
+                assert_eq!(engine.get(rid, b"large").unwrap(), expected);
+                assert_eq!(engine.get(rid, b"small").unwrap().unwrap(), b"small");
+                assert_eq!(engine.decode_last_index(rid), Some(10));
+                let mut kvs = Vec::new();
+                engine
+                    .scan_raw_messages(rid, None, None, false, |k, v| {
+                        kvs.push((k.to_vec(), v.to_vec()));
+                        true
+                    })
+                    .unwrap();
+                assert_eq!(kvs.len(), 2 + expected.is_some() as usize);
+                assert_eq!(kvs[0].1, expected.unwrap_or_else(|| kvs[0].1.clone()));
+                assert!(spilled(engine, rid, b"small").is_none());
+            }
+        };
+
+        let engine = RaftLogEngine::open(cfg.clone()).unwrap();
+        for rid in 1..=5 {
+            engine.append(rid, 1, 11, Some(&entry_data));
+            let mut log_batch = LogBatch::default();
+            log_batch
+                .put(rid, b"large".to_vec(), large(rid, 0))
+                .unwrap();
+            log_batch
+                .put(rid, b"small".to_vec(), b"small".to_vec())
+                .unwrap();
+            engine.write(&mut log_batch, false).unwrap();
+        }
+        let mut versions = vec![Some(0); 5];
+        check(&engine, &versions);
+        assert_eq!(spilled(&engine, 1, b"large"), Some(LogQueue::Append));
+        // Files pinned by reads are released.
+        assert!(engine
+            .purge_manager
+            .snapshot_files()
+            .first_file(LogQueue::Append)
+            .is_none());
+        // Spilled values are verified by checksum.
+        {
+            let memtable = engine.memtables.get(1).unwrap();
+            let mut memtable = memtable.write();
+            let (value, file_id) = memtable
+                .kvs()
+                .find(|(k, ..)| *k == b"large")
+                .map(|(_, v, f)| (v.clone(), f))
+                .unwrap();
+            if let KvValue::Spilled(handle, checksum) = value {
+                memtable.put(
+                    b"corrupted".to_vec(),
+                    KvValue::Spilled(handle, checksum ^ 1),
+                    file_id,
+                );
+            }
+        }
+        assert!(matches!(
+            engine.get(1, b"corrupted"),
+            Err(Error::Corruption(_))
+        ));
+        engine
+            .memtables
+            .get(1)
+            .unwrap()
+            .write()
+            .delete(b"corrupted");
+        let snapshot = engine.snapshot().unwrap();
+        assert_eq!(snapshot.get(1, b"large").unwrap(), Some(large(1, 0)));
+        drop(snapshot);
+
+        // Spilled values are moved by rewrite.
+        let first_file = engine.file_span(LogQueue::Append).0;
+        engine.purge_manager.must_rewrite_append_queue(None, None);
+        assert!(engine.file_span(LogQueue::Append).0 > first_file);
+        assert_eq!(spilled(&engine, 1, b"large"), Some(LogQueue::Rewrite));
+        check(&engine, &versions);
+        engine.purge_manager.must_rewrite_rewrite_queue();
+        assert_eq!(spilled(&engine, 1, b"large"), Some(LogQueue::Rewrite));
+        check(&engine, &versions);
+
+        let mut log_batch = LogBatch::default();
+        log_batch.put(1, b"large".to_vec(), large(1, 1)).unwrap();
+        log_batch.delete(2, b"large".to_vec());
+        engine.write(&mut log_batch, false).unwrap();
+        versions[0] = Some(1);
+        versions[1] = None;
+        assert_eq!(spilled(&engine, 1, b"large"), Some(LogQueue::Append));
+        check(&engine, &versions);
+        engine.close().unwrap();
+
+        // Recovered from region map.
+        let (engine, report) = EngineBuilder::new(cfg.clone()).open_with_report().unwrap();
+        assert_eq!(report.regions_deferred, 5);
+        assert_eq!(spilled(&engine, 3, b"large"), Some(LogQueue::Rewrite));
+        check(&engine, &versions);
+        drop(engine);
+
+        // Recovered from log files.
+        let engine = RaftLogEngine::open(Config {
+            enable_lazy_recovery: false,
+            ..cfg.clone()
+        })
+        .unwrap();
+        assert_eq!(spilled(&engine, 1, b"large"), Some(LogQueue::Append));
+        assert_eq!(spilled(&engine, 3, b"large"), Some(LogQueue::Rewrite));
+        check(&engine, &versions);
+        drop(engine);
+
+        // Values are kept in memory if spilling is disabled.
+        let engine = RaftLogEngine::open(Config {
+            kv_spill_threshold: None,
+            enable_lazy_recovery: false,
+            ..cfg
+        })
+        .unwrap();
+        assert!(spilled(&engine, 1, b"large").is_none());
+        check(&engine, &versions);
+    }
+
+    #[test]
+    fn test_get_entry() {
+        let normal_batch_size = 10;
+        let compressed_batch_size = 5120;
+        for &entry_size in &[normal_batch_size, compressed_batch_size] {
+            let dir = tempfile::Builder::new()
+                .prefix("test_get_entry")
+                .tempdir()
+                .unwrap();
+            let cfg = Config {
+                dir: dir.path().to_str().unwrap().to_owned(),
+                target_file_size: ReadableSize(1),
+                ..Default::default()
+            };
+
+            let engine = RaftLogEngine::open_with_file_system(
+                cfg.clone(),
+                Arc::new(ObfuscatedFileSystem::default()),
+            )
+            .unwrap();
+            assert_eq!(engine.path(), dir.path().to_str().unwrap());
+            let data = vec![b'x'; entry_size];
+            for i in 10..20 {
+                let rid = i;
+                let index = i;
+                engine.append(rid, index, index + 2, Some(&data));
+            }
+            for i in 10..20 {
+                let rid = i;
+                let index = i;
+                engine.scan_entries(rid, index, index + 2, |_, q, d| {
+                    assert_eq!(q, LogQueue::Append);
+                    assert_eq!(d, &data);
+                });
+            }
+
+            // Recover the engine.
+            let engine = engine.reopen();
+            for i in 10..20 {
                 let rid = i;
                 let index = i;
                 engine.scan_entries(rid, index, index + 2, |_, q, d| {
@@ -1051,7 +2716,7 @@ mod tests {
             engine.get_message::<RaftLocalState>(rid, &key).unwrap(),
             None
         );
-        assert_eq!(engine.get(rid, &key), None);
+        assert_eq!(engine.get(rid, &key).unwrap(), None);
 
         // put | delete
         //     ^ rewrite
@@ -1060,7 +2725,7 @@ mod tests {
         engine.purge_manager.must_rewrite_append_queue(None, None);
         engine.write(&mut delete_batch.clone(), true).unwrap();
         let engine = engine.reopen();
-        assert_eq!(engine.get(rid, &key), None);
+        assert_eq!(engine.get(rid, &key).unwrap(), None);
         assert_eq!(
             engine.get_message::<RaftLocalState>(rid, &key).unwrap(),
             None
@@ -1073,7 +2738,7 @@ mod tests {
             .must_rewrite_append_queue(None, Some(2));
         engine.write(&mut delete_batch.clone(), true).unwrap();
         let engine = engine.reopen();
-        assert_eq!(engine.get(rid, &key), None);
+        assert_eq!(engine.get(rid, &key).unwrap(), None);
 
         // TODO: Preserve kv tombstone during rewrite and activate this test case.
         // put | delete |
@@ -1093,7 +2758,7 @@ mod tests {
         engine.write(&mut delete_batch.clone(), true).unwrap();
         engine.write(&mut batch_2.clone(), true).unwrap();
         let engine = engine.reopen();
-        assert_eq!(engine.get(rid, &key).unwrap(), v2);
+        assert_eq!(engine.get(rid, &key).unwrap().unwrap(), v2);
         // Incomplete purge.
         engine.write(&mut batch_1.clone(), true).unwrap();
         engine
@@ -1102,7 +2767,7 @@ mod tests {
         engine.write(&mut delete_batch.clone(), true).unwrap();
         engine.write(&mut batch_2.clone(), true).unwrap();
         let engine = engine.reopen();
-        assert_eq!(engine.get(rid, &key).unwrap(), v2);
+        assert_eq!(engine.get(rid, &key).unwrap().unwrap(), v2);
 
         // put | delete | put
         //              ^ rewrite
@@ -1112,7 +2777,7 @@ mod tests {
         engine.purge_manager.must_rewrite_append_queue(None, None);
         engine.write(&mut batch_2.clone(), true).unwrap();
         let engine = engine.reopen();
-        assert_eq!(engine.get(rid, &key).unwrap(), v2);
+        assert_eq!(engine.get(rid, &key).unwrap().unwrap(), v2);
         // Incomplete purge.
         engine.write(&mut batch_1.clone(), true).unwrap();
         engine.write(&mut delete_batch.clone(), true).unwrap();
@@ -1121,7 +2786,7 @@ mod tests {
             .must_rewrite_append_queue(None, Some(2));
         engine.write(&mut batch_2.clone(), true).unwrap();
         let engine = engine.reopen();
-        assert_eq!(engine.get(rid, &key).unwrap(), v2);
+        assert_eq!(engine.get(rid, &key).unwrap().unwrap(), v2);
 
         // put | delete | put |
         //                    ^ rewrite
@@ -1131,7 +2796,7 @@ mod tests {
         engine.write(&mut batch_2.clone(), true).unwrap();
         engine.purge_manager.must_rewrite_append_queue(None, None);
         let engine = engine.reopen();
-        assert_eq!(engine.get(rid, &key).unwrap(), v2);
+        assert_eq!(engine.get(rid, &key).unwrap().unwrap(), v2);
         // Incomplete purge.
         let engine = engine.reopen();
         engine.write(&mut batch_1.clone(), true).unwrap();
@@ -1141,7 +2806,7 @@ mod tests {
             .purge_manager
             .must_rewrite_append_queue(None, Some(2));
         let engine = engine.reopen();
-        assert_eq!(engine.get(rid, &key).unwrap(), v2);
+        assert_eq!(engine.get(rid, &key).unwrap().unwrap(), v2);
     }
 
     #[test]
@@ -1373,84 +3038,239 @@ mod tests {
     }
 
     #[test]
-    fn test_rewrite_and_recover() {
+    fn test_purge_with_size_tiered_policy() {
         let dir = tempfile::Builder::new()
-            .prefix("test_rewrite_and_recover")
+            .prefix("test_purge_with_size_tiered_policy")
             .tempdir()
             .unwrap();
         let cfg = Config {
             dir: dir.path().to_str().unwrap().to_owned(),
-            target_file_size: ReadableSize::kb(5),
-            purge_threshold: ReadableSize::kb(80),
+            target_file_size: ReadableSize::kb(1),
+            purge_threshold: ReadableSize::kb(10),
             ..Default::default()
         };
-        let engine =
-            RaftLogEngine::open_with_file_system(cfg, Arc::new(ObfuscatedFileSystem::default()))
-                .unwrap();
+        let policy = SizeTieredPurgePolicy {
+            small_tier_entries: 5,
+            large_tier_entries: 40,
+            max_compact_requests: 2,
+            ..Default::default()
+        };
+        let engine = EngineBuilder::new(cfg)
+            .file_system(Arc::new(ObfuscatedFileSystem::default()))
+            .purge_policy(Arc::new(policy))
+            .open()
+            .unwrap();
         let data = vec![b'x'; 1024];
-
-        // Put 100 entries into 10 regions.
-        for index in 1..=10 {
-            for rid in 1..=10 {
-                engine.append(rid, index, index + 1, Some(&data));
-            }
+        // Region 1 is large, region 2 is medium.
+        for index in 0..50 {
+            engine.append(1, index, index + 1, Some(&data[..10]));
         }
-
-        // The engine needs purge, and all old entries should be rewritten.
-        assert!(engine
-            .purge_manager
-            .needs_rewrite_log_files(LogQueue::Append));
-        assert!(engine.purge_expired_files().unwrap().is_empty());
-        assert!(engine.file_span(LogQueue::Append).0 > 1);
-
-        let rewrite_file_size = engine.pipe_log.total_size(LogQueue::Rewrite);
-        assert!(rewrite_file_size > 59); // The rewrite queue isn't empty.
-
-        // All entries should be available.
-        for rid in 1..=10 {
-            engine.scan_entries(rid, 1, 11, |_, _, d| {
-                assert_eq!(d, &data);
-            });
+        for index in 0..20 {
+            engine.append(2, index, index + 1, Some(&data[..10]));
         }
-
-        // Recover with rewrite queue and append queue.
-        let cleaned_region_ids = engine.memtables.cleaned_region_ids();
-
-        let engine = engine.reopen();
-        assert_eq!(engine.memtables.cleaned_region_ids(), cleaned_region_ids);
-
-        for rid in 1..=10 {
-            engine.scan_entries(rid, 1, 11, |_, _, d| {
-                assert_eq!(d, &data);
-            });
+        // Small regions.
+        for rid in 3..=50 {
+            engine.append(rid, 1, 2, Some(&data));
         }
 
-        // Rewrite again to check the rewrite queue is healthy.
-        for index in 11..=20 {
-            for rid in 1..=10 {
-                engine.append(rid, index, index + 1, Some(&data));
-            }
+        for _ in 0..2 {
+            assert_eq!(engine.purge_expired_files().unwrap(), vec![1]);
+            assert_eq!(engine.memtables.get(1).unwrap().read().rewrite_count(), 0);
         }
+        // Medium region lagging behind is rewritten without being asked to compact.
+        assert_eq!(engine.memtables.get(2).unwrap().read().rewrite_count(), 20);
+        // Large region is rewritten after ignoring compact requests.
+        assert_eq!(engine.purge_expired_files().unwrap(), vec![1]);
+        assert_eq!(engine.memtables.get(1).unwrap().read().rewrite_count(), 50);
 
-        assert!(engine
-            .purge_manager
-            .needs_rewrite_log_files(LogQueue::Append));
-        assert!(engine.purge_expired_files().unwrap().is_empty());
+        let engine = engine.reopen();
+        engine.scan_entries(1, 0, 50, |_, q, d| {
+            assert_eq!(q, LogQueue::Rewrite);
+            assert_eq!(d, &data[..10]);
+        });
     }
 
     #[test]
-    fn test_empty_protobuf_message() {
+    fn test_rewrite_rate_limit() {
         let dir = tempfile::Builder::new()
-            .prefix("test_empty_protobuf_message")
+            .prefix("test_rewrite_rate_limit")
             .tempdir()
             .unwrap();
         let cfg = Config {
             dir: dir.path().to_str().unwrap().to_owned(),
+            batch_compression_threshold: ReadableSize(0),
+            target_file_size: ReadableSize::kb(1),
+            purge_threshold: ReadableSize::kb(10),
+            rewrite_rate_limit: Some(ReadableSize::mb(1)),
             ..Default::default()
         };
-        let engine =
-            RaftLogEngine::open_with_file_system(cfg, Arc::new(ObfuscatedFileSystem::default()))
-                .unwrap();
+        let mut engine = RaftLogEngine::open(cfg).unwrap();
+        let clock = Arc::new(ManualClock::new());
+        engine.purge_manager.set_rate_limiter_clock(clock.clone());
+        let data = vec![b'x'; 16 * 1024];
+        for rid in 1..=40 {
+            engine.append(rid, 1, 2, Some(&data));
+        }
+        // Around 640KB is read from append queue, and then written to rewrite
+        // queue. It takes more than 500ms at 1MB/s, excluding the burst.
+        engine.purge_manager.must_rewrite_append_queue(None, None);
+        let slept = clock.slept();
+        assert!(slept >= Duration::from_millis(500), "{:?}", slept);
+        assert_eq!(engine.file_count(Some(LogQueue::Append)), 1);
+
+        engine.set_rewrite_rate_limit(None);
+        for rid in 1..=40 {
+            engine.append(rid, 2, 3, Some(&data));
+        }
+        engine.purge_manager.must_rewrite_append_queue(None, None);
+        assert_eq!(clock.slept(), slept);
+        for rid in 1..=40 {
+            engine.scan_entries(rid, 1, 3, |_, q, d| {
+                assert_eq!(q, LogQueue::Rewrite);
+                assert_eq!(d, &data);
+            });
+        }
+    }
+
+    #[test]
+    fn test_update_config() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_update_config")
+            .tempdir()
+            .unwrap();
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            target_file_size: ReadableSize::kb(64),
+            purge_threshold: ReadableSize::mb(1),
+            ..Default::default()
+        };
+        let engine = RaftLogEngine::open(cfg).unwrap();
+        let data = vec![b'x'; 1024];
+        for rid in 1..=10 {
+            engine.append(rid, 1, 2, Some(&data));
+        }
+        assert_eq!(engine.file_count(Some(LogQueue::Append)), 1);
+        assert!(engine.purge_expired_files().unwrap().is_empty());
+
+        // Invalid changes are rejected as a whole.
+        for change in [
+            ConfigChange {
+                target_file_size: Some(ReadableSize::kb(1)),
+                format_version: Some(Version::V1),
+                ..Default::default()
+            },
+            ConfigChange {
+                dir: Some("other_dir".to_owned()),
+                ..Default::default()
+            },
+            ConfigChange {
+                target_file_size: Some(ReadableSize::mb(2)),
+                ..Default::default()
+            },
+        ] {
+            assert!(engine.update_config(&change).is_err());
+        }
+        assert_eq!(engine.cfg.get().target_file_size, ReadableSize::kb(64));
+
+        engine
+            .update_config(&ConfigChange {
+                target_file_size: Some(ReadableSize::kb(1)),
+                purge_threshold: Some(ReadableSize::kb(4)),
+                ..Default::default()
+            })
+            .unwrap();
+        for rid in 1..=10 {
+            engine.append(rid, 2, 3, Some(&data));
+        }
+        assert!(engine.file_count(Some(LogQueue::Append)) > 5);
+        engine.purge_expired_files().unwrap();
+        assert!(engine.file_count(Some(LogQueue::Append)) < 5);
+
+        let engine = engine.reopen();
+        for rid in 1..=10 {
+            engine.scan_entries(rid, 1, 3, |_, _, d| assert_eq!(d, &data));
+        }
+    }
+
+    #[test]
+    fn test_rewrite_and_recover() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_rewrite_and_recover")
+            .tempdir()
+            .unwrap();
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            target_file_size: ReadableSize::kb(5),
+            purge_threshold: ReadableSize::kb(80),
+            ..Default::default()
+        };
+        let engine =
+            RaftLogEngine::open_with_file_system(cfg, Arc::new(ObfuscatedFileSystem::default()))
+                .unwrap();
+        let data = vec![b'x'; 1024];
+
+        // Put 100 entries into 10 regions.
+        for index in 1..=10 {
+            for rid in 1..=10 {
+                engine.append(rid, index, index + 1, Some(&data));
+            }
+        }
+
+        // The engine needs purge, and all old entries should be rewritten.
+        assert!(engine
+            .purge_manager
+            .needs_rewrite_log_files(LogQueue::Append));
+        assert!(engine.purge_expired_files().unwrap().is_empty());
+        assert!(engine.file_span(LogQueue::Append).0 > 1);
+
+        let rewrite_file_size = engine.pipe_log.total_size(LogQueue::Rewrite);
+        assert!(rewrite_file_size > 59); // The rewrite queue isn't empty.
+
+        // All entries should be available.
+        for rid in 1..=10 {
+            engine.scan_entries(rid, 1, 11, |_, _, d| {
+                assert_eq!(d, &data);
+            });
+        }
+
+        // Recover with rewrite queue and append queue.
+        let cleaned_region_ids = engine.memtables.cleaned_region_ids();
+
+        let engine = engine.reopen();
+        assert_eq!(engine.memtables.cleaned_region_ids(), cleaned_region_ids);
+
+        for rid in 1..=10 {
+            engine.scan_entries(rid, 1, 11, |_, _, d| {
+                assert_eq!(d, &data);
+            });
+        }
+
+        // Rewrite again to check the rewrite queue is healthy.
+        for index in 11..=20 {
+            for rid in 1..=10 {
+                engine.append(rid, index, index + 1, Some(&data));
+            }
+        }
+
+        assert!(engine
+            .purge_manager
+            .needs_rewrite_log_files(LogQueue::Append));
+        assert!(engine.purge_expired_files().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_empty_protobuf_message() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_empty_protobuf_message")
+            .tempdir()
+            .unwrap();
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+        let engine =
+            RaftLogEngine::open_with_file_system(cfg, Arc::new(ObfuscatedFileSystem::default()))
+                .unwrap();
 
         let mut log_batch = LogBatch::default();
         let empty_entry = Entry::new();
@@ -2467,10 +4287,10 @@ mod tests {
             RaftLogEngine::open_with_file_system(cfg_v2.clone(), file_system.clone()).unwrap();
         assert_eq!(engine.file_span(LogQueue::Append), (start, end));
         assert!(recycled_count > file_system.inner.file_count() - engine.file_count(None));
-        // Recycled files have filled the LogQueue::Append, purge_expired_files won't
-        // truely remove files from it.
+        // Stale files are purged as the queue exceeds `purge_threshold`, while the
+        // active file is kept.
         engine.purge_expired_files().unwrap();
-        assert_eq!(engine.file_span(LogQueue::Append), (start, end));
+        assert_eq!(engine.file_span(LogQueue::Append).1, end);
         for rid in 1..=10 {
             engine.append(rid, 20, 31, Some(&entry_data));
         }
@@ -2633,11 +4453,180 @@ mod tests {
         let engine = engine.reopen();
         for rid in engine.raft_groups() {
             assert!(data.remove(&rid), "{}", rid);
-            assert_eq!(engine.get(rid, &key).unwrap(), value);
+            assert_eq!(engine.get(rid, &key).unwrap().unwrap(), value);
         }
         assert!(data.is_empty());
     }
 
+    #[test]
+    fn test_append_atomic_group() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_append_atomic_group")
+            .tempdir()
+            .unwrap();
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            target_file_size: ReadableSize(1),
+            purge_threshold: ReadableSize(1),
+            ..Default::default()
+        };
+        let entry_data = vec![b'x'; 128];
+        let add_entries = |log_batch: &mut LogBatch, rid: u64| {
+            log_batch
+                .add_entries::<Entry>(rid, &generate_entries(1, 11, Some(&entry_data)))
+                .unwrap();
+        };
+        let engine = RaftLogEngine::open(cfg.clone()).unwrap();
+
+        // A committed group spanning several files.
+        let mut group = engine.begin_atomic_group();
+        let mut log_batch = LogBatch::default();
+        add_entries(&mut log_batch, 1);
+        group.write(&mut log_batch).unwrap();
+        add_entries(&mut log_batch, 2);
+        group.write(&mut log_batch).unwrap();
+        // Files of an open group are not purged.
+        let (first, _) = engine.file_span(LogQueue::Append);
+        engine.append(9, 1, 11, Some(&entry_data));
+        engine.clean(9);
+        engine.purge_expired_files().unwrap();
+        assert_eq!(engine.file_span(LogQueue::Append).0, first);
+        assert!(engine.first_index(1).is_none());
+        log_batch
+            .put(3, b"key".to_vec(), b"value".to_vec())
+            .unwrap();
+        group.commit(&mut log_batch).unwrap();
+        for rid in 1..=2 {
+            assert_eq!(engine.first_index(rid), Some(1));
+            assert_eq!(engine.last_index(rid), Some(10));
+        }
+        assert_eq!(engine.get(3, b"key").unwrap().unwrap(), b"value");
+
+        // An uncommitted group.
+        let mut group = engine.begin_atomic_group();
+        let discarded_id = group.id();
+        add_entries(&mut log_batch, 4);
+        group.write(&mut log_batch).unwrap();
+        add_entries(&mut log_batch, 5);
+        group.write(&mut log_batch).unwrap();
+        drop(group);
+        assert!(engine.first_index(4).is_none());
+        engine.sync().unwrap();
+        drop(engine);
+
+        let (engine, report) =
+            RaftLogEngine::open_with_report(cfg.clone(), Arc::new(DefaultFileSystem), vec![])
+                .unwrap();
+        assert_eq!(report.discarded_atomic_groups, vec![discarded_id]);
+        for rid in 1..=2 {
+            assert_eq!(engine.first_index(rid), Some(1));
+            assert_eq!(engine.last_index(rid), Some(10));
+        }
+        assert_eq!(engine.get(3, b"key").unwrap().unwrap(), b"value");
+        assert!(engine.first_index(4).is_none());
+        assert!(engine.first_index(5).is_none());
+
+        // The beginning of a committed group is purged.
+        let mut group = engine.begin_atomic_group();
+        add_entries(&mut log_batch, 6);
+        group.write(&mut log_batch).unwrap();
+        let (_, begin_file) = engine.file_span(LogQueue::Append);
+        add_entries(&mut log_batch, 7);
+        group.commit(&mut log_batch).unwrap();
+        engine
+            .purge_manager
+            .must_rewrite_append_queue(Some(begin_file), None);
+        assert!(engine.file_span(LogQueue::Append).0 > begin_file);
+        let engine = engine.reopen();
+        for rid in [1, 2, 6, 7] {
+            assert_eq!(engine.first_index(rid), Some(1));
+            assert_eq!(engine.last_index(rid), Some(10));
+            let mut entries = Vec::new();
+            engine
+                .fetch_entries_to::<Entry>(rid, 1, 11, None, &mut entries)
+                .unwrap();
+            assert_eq!(entries.len(), 10);
+        }
+    }
+
+    #[test]
+    fn test_migrate_region() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_migrate_region")
+            .tempdir()
+            .unwrap();
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            target_file_size: ReadableSize(1),
+            purge_threshold: ReadableSize(1),
+            ..Default::default()
+        };
+        let data = vec![b'x'; 128];
+        let check = |engine: &RaftLogEngine, rid: u64, begin: u64, end: u64| {
+            assert_eq!(engine.first_index(rid), Some(begin));
+            assert_eq!(engine.last_index(rid), Some(end - 1));
+            let mut entries = Vec::new();
+            engine
+                .fetch_entries_to::<Entry>(rid, begin, end, None, &mut entries)
+                .unwrap();
+            assert_eq!(entries, generate_entries(begin, end, Some(&data)));
+        };
+        let engine = RaftLogEngine::open(cfg).unwrap();
+        engine.append(1, 1, 6, Some(&data));
+        engine.purge_manager.must_rewrite_append_queue(None, None);
+        engine.append(1, 6, 11, Some(&data));
+        let appended = engine.space_stats().unwrap().user_bytes();
+        engine.rename_region(1, 2).unwrap();
+        engine.clone_region(2, 3, 3, 9).unwrap();
+        // Entries are not copied.
+        assert!(engine.space_stats().unwrap().user_bytes() - appended < 128);
+
+        assert!(matches!(
+            engine.rename_region(2, 3),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            engine.rename_region(1, 4),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            engine.clone_region(2, 2, 1, 5),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            engine.clone_region(2, 4, 0, 5),
+            Err(Error::EntryCompacted)
+        ));
+        assert!(matches!(
+            engine.clone_region(2, 4, 5, 12),
+            Err(Error::EntryNotFound)
+        ));
+
+        let check_all = |engine: &RaftLogEngine| {
+            assert!(engine.first_index(1).is_none());
+            assert_eq!(engine.decode_last_index(2), Some(10));
+            check(engine, 2, 1, 11);
+            check(engine, 3, 3, 9);
+        };
+        check_all(&engine);
+        let engine = engine.reopen();
+        check_all(&engine);
+        // Commands are rewritten before the renamed entries.
+        engine.purge_manager.must_rewrite_append_queue(None, None);
+        check_all(&engine);
+        let engine = engine.reopen();
+        check_all(&engine);
+
+        // Cloned entries outlive the source.
+        engine.clean(2);
+        engine.append(3, 9, 12, Some(&data));
+        engine.purge_manager.must_rewrite_append_queue(None, None);
+        engine.purge_manager.must_rewrite_rewrite_queue();
+        let engine = engine.reopen();
+        assert!(engine.first_index(2).is_none());
+        check(&engine, 3, 3, 12);
+    }
+
     #[test]
     fn test_internal_key_filter() {
         let dir = tempfile::Builder::new()
@@ -2680,4 +4669,982 @@ mod tests {
         // Replay of rewrite filtered.
         assert!(engine.raft_groups().is_empty());
     }
+
+    #[test]
+    fn test_hole_punching() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempfile::Builder::new()
+            .prefix("test_hole_punching")
+            .tempdir()
+            .unwrap();
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            batch_compression_threshold: ReadableSize(0),
+            target_file_size: ReadableSize::kb(128),
+            purge_threshold: ReadableSize::kb(128),
+            enable_hole_punching: true,
+            hole_punch_min_block_size: ReadableSize::kb(4),
+            ..Default::default()
+        };
+        let allocated_bytes = |seq| {
+            let path = FileId::new(LogQueue::Append, seq).build_file_path(dir.path());
+            std::fs::metadata(path).unwrap().blocks() * 512
+        };
+        let data = vec![b'x'; 8192];
+        let engine = RaftLogEngine::open(cfg).unwrap();
+        // Region 2 is kept alive in the first file.
+        engine.append(2, 1, 11, Some(&data));
+        for i in 0..6 {
+            engine.append(1, i * 10 + 1, i * 10 + 11, Some(&data));
+        }
+        assert!(engine.file_count(Some(LogQueue::Append)) > 3);
+        let tracker = engine.purge_manager.hole_punch_tracker().unwrap();
+        assert_eq!(tracker.len(), 7);
+        let allocated = allocated_bytes(1);
+
+        engine.compact_to(1, 41);
+        engine.purge_expired_files().unwrap();
+        // Entries of region 1 are reclaimed in place, while live entries of
+        // region 2 are not rewritten.
+        assert_eq!(engine.file_span(LogQueue::Append).0, 1);
+        assert!(allocated_bytes(1) + 64 * 1024 < allocated);
+        assert!(tracker.len() <= 3);
+        engine.scan_entries(2, 1, 11, |_, q, d| {
+            assert_eq!(q, LogQueue::Append);
+            assert_eq!(d, &data);
+        });
+
+        let engine = engine.reopen();
+        engine.scan_entries(1, 41, 61, |_, _, d| assert_eq!(d, &data));
+        engine.scan_entries(2, 1, 11, |_, q, d| {
+            assert_eq!(q, LogQueue::Append);
+            assert_eq!(d, &data);
+        });
+        // Punched blocks are tracked again after restart. Punching them twice is
+        // harmless.
+        engine.purge_expired_files().unwrap();
+        engine.scan_entries(2, 1, 11, |_, _, d| assert_eq!(d, &data));
+
+        // Files are purged once their live entries are gone.
+        engine.clean(2);
+        engine.compact_to(1, 61);
+        engine.append(1, 61, 71, Some(&data));
+        engine.purge_expired_files().unwrap();
+        assert!(engine.file_span(LogQueue::Append).0 > 1);
+        let engine = engine.reopen();
+        engine.scan_entries(1, 61, 71, |_, _, d| assert_eq!(d, &data));
+        assert!(engine.raft_groups().iter().all(|&rid| rid == 1));
+    }
+
+    #[test]
+    fn test_crash_with_mem_file_system() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_crash_with_mem_file_system")
+            .tempdir()
+            .unwrap();
+        let entry_data = vec![b'x'; 128];
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            target_file_size: ReadableSize::kb(2),
+            ..Default::default()
+        };
+        let fs = Arc::new(MemFileSystem::new());
+        let append_unsynced = |engine: &RaftLogEngine<MemFileSystem>, rid: u64| {
+            let mut batch = LogBatch::default();
+            batch
+                .add_entries::<Entry>(rid, &generate_entries(1, 11, Some(&entry_data)))
+                .unwrap();
+            engine.write(&mut batch, false).unwrap();
+        };
+
+        let engine = RaftLogEngine::open_with_file_system(cfg.clone(), fs.clone()).unwrap();
+        for rid in 1..=10 {
+            engine.append(rid, 1, 11, Some(&entry_data));
+        }
+        append_unsynced(&engine, 11);
+        assert!(engine.file_count(Some(LogQueue::Append)) > 1);
+        fs.crash();
+        // Dropping the engine won't persist anything after the crash.
+        drop(engine);
+        let engine = RaftLogEngine::open_with_file_system(cfg.clone(), fs.clone()).unwrap();
+        for rid in 1..=10 {
+            engine.scan_entries(rid, 1, 11, |_, _, d| assert_eq!(d, &entry_data));
+        }
+        assert!(engine.first_index(11).is_none());
+
+        // Tear the last write.
+        append_unsynced(&engine, 11);
+        fs.crash_with_torn_write(entry_data.len());
+        drop(engine);
+        let engine = RaftLogEngine::open_with_file_system(cfg, fs).unwrap();
+        for rid in 1..=10 {
+            engine.scan_entries(rid, 1, 11, |_, _, d| assert_eq!(d, &entry_data));
+        }
+        assert!(engine.first_index(11).is_none());
+    }
+
+    #[test]
+    fn test_salvage_recovery() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_salvage_recovery")
+            .tempdir()
+            .unwrap();
+        let entry_data = vec![b'x'; 16];
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            // One big file.
+            target_file_size: ReadableSize::gb(10),
+            recovery_mode: RecoveryMode::Salvage,
+            ..Default::default()
+        };
+        let engine = RaftLogEngine::open(cfg.clone()).unwrap();
+        for i in 0..10 {
+            for rid in 1..=3 {
+                engine.append(rid, i * 5 + 1, i * 5 + 6, Some(&entry_data));
+            }
+        }
+        let handle = engine
+            .memtables
+            .get(2)
+            .unwrap()
+            .read()
+            .get_entry(26)
+            .unwrap()
+            .entries
+            .unwrap();
+        let handle1 = engine
+            .memtables
+            .get(1)
+            .unwrap()
+            .read()
+            .get_entry(36)
+            .unwrap()
+            .entries
+            .unwrap();
+        drop(engine);
+
+        let mut f = OpenOptions::new()
+            .write(true)
+            .open(handle.id.build_file_path(dir.path()))
+            .unwrap();
+        // Corrupt the header of a log batch in the middle.
+        let batch_offset = handle.offset - LOG_BATCH_HEADER_LEN as u64;
+        f.seek(SeekFrom::Start(batch_offset)).unwrap();
+        f.write_all(&[0xff; LOG_BATCH_HEADER_LEN]).unwrap();
+        // Corrupt the footer of another log batch, whose header is intact.
+        let batch_offset1 = handle1.offset - LOG_BATCH_HEADER_LEN as u64;
+        f.seek(SeekFrom::Start(handle1.offset + handle1.len as u64))
+            .unwrap();
+        f.write_all(&[0xff]).unwrap();
+        drop(f);
+
+        for i in 0..2 {
+            let (engine, report) = EngineBuilder::new(cfg.clone()).open_with_report().unwrap();
+            assert!(!report.is_clean());
+            assert_eq!(report.corrupted_ranges.len(), 2);
+            let range = &report.corrupted_ranges[0];
+            assert_eq!(range.file_id, handle.id);
+            assert_eq!(range.offset, batch_offset);
+            assert!(!range.truncated);
+            // Only the corrupted log batch is skipped.
+            let range = &report.corrupted_ranges[1];
+            assert_eq!(range.offset, batch_offset1);
+            assert!(range.len > handle1.len as u64 + LOG_BATCH_HEADER_LEN as u64);
+            assert!(range.len < 2 * (handle1.len as u64 + LOG_BATCH_HEADER_LEN as u64));
+            assert_eq!(report.lost_entries.len(), 2);
+            assert_eq!(report.lost_entries[&1], vec![1..41]);
+            assert_eq!(report.lost_entries[&2], vec![1..31]);
+            engine.scan_entries(1, 41, 51, |_, _, d| assert_eq!(d, &entry_data));
+            engine.scan_entries(2, 31, 51 + i, |_, _, d| assert_eq!(d, &entry_data));
+            engine.scan_entries(3, 1, 51, |_, _, d| assert_eq!(d, &entry_data));
+            // The corrupted range is left in place, new data is appended after it.
+            engine.append(2, 51 + i, 52 + i, Some(&entry_data));
+        }
+
+        // Corrupt the tail, which is truncated.
+        let f = OpenOptions::new()
+            .write(true)
+            .open(handle.id.build_file_path(dir.path()))
+            .unwrap();
+        let len = f.metadata().unwrap().len();
+        f.set_len(len - 1).unwrap();
+        let (engine, report) = EngineBuilder::new(cfg).open_with_report().unwrap();
+        assert_eq!(report.corrupted_ranges.len(), 3);
+        let range = &report.corrupted_ranges[2];
+        assert!(range.truncated);
+        assert_eq!(range.offset + range.len, len - 1);
+        assert_eq!(engine.last_index(2), Some(51));
+    }
+
+    #[test]
+    fn test_recovery_report() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_recovery_report")
+            .tempdir()
+            .unwrap();
+        let entry_data = vec![b'x'; 128];
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            target_file_size: ReadableSize::kb(2),
+            ..Default::default()
+        };
+
+        #[derive(Default)]
+        struct ReportListener(Mutex<Option<RecoveryReport>>);
+        impl EventListener for ReportListener {
+            fn post_recovery(&self, report: &RecoveryReport) {
+                *self.0.lock().unwrap() = Some(report.clone());
+            }
+        }
+        let listener = Arc::new(ReportListener::default());
+        let open = || {
+            let (engine, report) = RaftLogEngine::open_with_report(
+                cfg.clone(),
+                Arc::new(DefaultFileSystem),
+                vec![listener.clone()],
+            )
+            .unwrap();
+            let notified = listener.0.lock().unwrap().take().unwrap();
+            assert_eq!(notified.files_scanned, report.files_scanned);
+            assert_eq!(notified.bytes_replayed, report.bytes_replayed);
+            (engine, report)
+        };
+
+        let (engine, report) = open();
+        assert!(report.is_clean());
+        assert_eq!(report.files_scanned, 0);
+        assert_eq!(report.regions_recovered, 0);
+        for rid in 1..=10 {
+            engine.append(rid, 1, 11, Some(&entry_data));
+        }
+        // An incomplete atomic group.
+        let mut log_batch = LogBatch::default();
+        AtomicGroupBuilder::with_id(7).begin(&mut log_batch);
+        log_batch
+            .put(11, b"key".to_vec(), b"value".to_vec())
+            .unwrap();
+        log_batch.finish_populate(0).unwrap();
+        engine
+            .pipe_log
+            .append(LogQueue::Rewrite, &mut log_batch)
+            .unwrap();
+        engine.pipe_log.sync(LogQueue::Rewrite).unwrap();
+        let (first, last) = engine.file_span(LogQueue::Append);
+        assert!(last - first >= 2);
+        drop(engine);
+
+        let (engine, report) = open();
+        assert!(!report.is_clean());
+        assert_eq!(report.files_scanned, (last - first + 2) as usize);
+        assert!(report.bytes_replayed > 10 * 10 * entry_data.len() as u64);
+        assert_eq!(report.regions_recovered, 10);
+        assert_eq!(report.discarded_atomic_groups, vec![7]);
+        assert!(report.truncations.is_empty());
+        assert!(report.discarded_files.is_empty());
+        assert!(report.total_duration() > Duration::ZERO);
+        assert!(engine.get(11, b"key").unwrap().is_none());
+        drop(engine);
+
+        // Corrupt the tail of the last file.
+        let last_file = FileId {
+            queue: LogQueue::Append,
+            seq: last,
+        };
+        let f = OpenOptions::new()
+            .write(true)
+            .open(last_file.build_file_path(dir.path()))
+            .unwrap();
+        let len = f.metadata().unwrap().len();
+        f.set_len(len - 1).unwrap();
+        // Leave a hole in file sequence.
+        let hole = FileId {
+            queue: LogQueue::Append,
+            seq: first + 1,
+        };
+        std::fs::remove_file(hole.build_file_path(dir.path())).unwrap();
+        let (_, report) = open();
+        assert_eq!(
+            report.discarded_files,
+            vec![FileId {
+                queue: LogQueue::Append,
+                seq: first
+            }]
+        );
+        assert_eq!(report.truncations.len(), 1);
+        assert_eq!(report.truncations[0].file_id, last_file);
+        assert!(report.truncations[0].offset < len);
+    }
+
+    #[test]
+    fn test_recovery_progress_and_cancellation() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_recovery_progress_and_cancellation")
+            .tempdir()
+            .unwrap();
+        let entry_data = vec![b'x'; 128];
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            target_file_size: ReadableSize::kb(2),
+            ..Default::default()
+        };
+
+        #[derive(Default)]
+        struct ProgressRecorder {
+            progress: Mutex<Vec<RecoveryProgress>>,
+            cancel_on_progress: Option<CancellationToken>,
+        }
+        impl RecoveryProgressSink for ProgressRecorder {
+            fn on_progress(&self, progress: RecoveryProgress) {
+                self.progress.lock().unwrap().push(progress);
+                if let Some(token) = &self.cancel_on_progress {
+                    token.cancel();
+                }
+            }
+        }
+
+        let engine = RaftLogEngine::open(cfg.clone()).unwrap();
+        for rid in 1..=10 {
+            engine.append(rid, 1, 11, Some(&entry_data));
+        }
+        let (first, last) = engine.file_span(LogQueue::Append);
+        drop(engine);
+        // Corrupt the tail of the last file, which will be truncated by a
+        // successful recovery.
+        let last_file = FileId {
+            queue: LogQueue::Append,
+            seq: last,
+        };
+        let f = OpenOptions::new()
+            .write(true)
+            .open(last_file.build_file_path(dir.path()))
+            .unwrap();
+        let len = f.metadata().unwrap().len() - 1;
+        f.set_len(len).unwrap();
+
+        let token = CancellationToken::new();
+        let recorder = Arc::new(ProgressRecorder {
+            cancel_on_progress: Some(token.clone()),
+            ..Default::default()
+        });
+        let r = EngineBuilder::new(cfg.clone())
+            .recovery_progress_sink(recorder.clone())
+            .cancellation_token(token.clone())
+            .open();
+        assert!(matches!(r, Err(Error::Cancelled)));
+        assert!(!recorder.progress.lock().unwrap().is_empty());
+        assert_eq!(f.metadata().unwrap().len(), len);
+        // Cancelled token takes effect immediately.
+        assert!(matches!(
+            EngineBuilder::new(cfg.clone())
+                .cancellation_token(token.clone())
+                .open(),
+            Err(Error::Cancelled)
+        ));
+        // The directory isn't created either.
+        let new_dir = dir.path().join("new");
+        assert!(matches!(
+            EngineBuilder::new(Config {
+                dir: new_dir.to_str().unwrap().to_owned(),
+                ..cfg.clone()
+            })
+            .cancellation_token(token)
+            .open(),
+            Err(Error::Cancelled)
+        ));
+        assert!(!new_dir.exists());
+
+        let recorder = Arc::new(ProgressRecorder::default());
+        let engine = EngineBuilder::new(cfg)
+            .recovery_progress_sink(recorder.clone())
+            .cancellation_token(CancellationToken::new())
+            .open()
+            .unwrap();
+        assert!(f.metadata().unwrap().len() < len);
+        let progress = recorder.progress.lock().unwrap();
+        let files_total = (last - first + 1) as usize;
+        let append_progress: Vec<_> = progress
+            .iter()
+            .filter(|p| p.queue == LogQueue::Append)
+            .collect();
+        assert_eq!(append_progress.len(), files_total);
+        let final_progress = append_progress
+            .iter()
+            .max_by_key(|p| p.files_processed)
+            .unwrap();
+        assert_eq!(final_progress.files_processed, files_total);
+        assert_eq!(final_progress.files_total, files_total);
+        assert_eq!(final_progress.bytes_processed, final_progress.bytes_total);
+        for rid in 1..=9 {
+            assert_eq!(engine.first_index(rid), Some(1));
+        }
+    }
+
+    #[test]
+    fn test_lazy_recovery() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_lazy_recovery")
+            .tempdir()
+            .unwrap();
+        let entry_data = vec![b'x'; 128];
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            target_file_size: ReadableSize::kb(2),
+            enable_lazy_recovery: true,
+            ..Default::default()
+        };
+        fn snapshot(engine: &RaftLogEngine) -> Vec<(u64, Vec<Entry>, Option<u64>)> {
+            let mut regions = engine.raft_groups();
+            regions.sort_unstable();
+            regions
+                .into_iter()
+                .map(|rid| {
+                    let mut entries = Vec::new();
+                    if let (Some(first), Some(last)) =
+                        (engine.first_index(rid), engine.last_index(rid))
+                    {
+                        engine
+                            .fetch_entries_to::<Entry>(rid, first, last + 1, None, &mut entries)
+                            .unwrap();
+                    }
+                    (rid, entries, engine.decode_last_index(rid))
+                })
+                .collect()
+        }
+
+        let engine = RaftLogEngine::open(cfg.clone()).unwrap();
+        for rid in 1..=10 {
+            engine.append(rid, 1, 21, Some(&entry_data));
+        }
+        engine.compact_to(2, 5);
+        engine.clean(3);
+        engine.purge_manager.must_rewrite_append_queue(None, None);
+        engine.append(1, 21, 31, Some(&entry_data));
+        engine.compact_to(4, 10);
+        let expected = snapshot(&engine);
+        engine.close().unwrap();
+
+        let (engine, report) = EngineBuilder::new(cfg.clone()).open_lazily().unwrap();
+        assert_eq!(report.regions_deferred, 10);
+        assert_eq!(report.regions_recovered, 10);
+        // Key values are available before entries are indexed.
+        for rid in [1, 2, 4, 10] {
+            assert!(engine.decode_last_index(rid).is_some());
+        }
+        engine.prioritize_regions(&[7]);
+        // Writes wait for the entries to be indexed for a limited time.
+        let mut batch = LogBatch::default();
+        batch
+            .add_entries::<Entry>(7, &generate_entries(21, 22, Some(&entry_data)))
+            .unwrap();
+        loop {
+            match engine.write(&mut batch, false) {
+                Ok(_) => break,
+                Err(Error::NotReady) => {}
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        assert!(engine.is_region_ready(7));
+        while (1..=10).any(|rid| !engine.is_region_ready(rid)) {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let mut actual = snapshot(&engine);
+        assert_eq!(actual[6].0, 7);
+        assert_eq!(actual[6].1.pop().unwrap().index, 21);
+        actual[6].2 = Some(20);
+        assert_eq!(actual, expected);
+        engine.purge_expired_files().unwrap();
+        engine.append(5, 21, 31, Some(&entry_data));
+        let expected = snapshot(&engine);
+        engine.close().unwrap();
+
+        // Entries are indexed before returning without the background thread.
+        let (engine, report) = EngineBuilder::new(cfg.clone()).open_with_report().unwrap();
+        assert_eq!(report.regions_deferred, 10);
+        assert!((1..=10).all(|rid| engine.is_region_ready(rid)));
+        assert_eq!(snapshot(&engine), expected);
+        engine.close().unwrap();
+
+        // The map is discarded once log files are modified.
+        let engine = RaftLogEngine::open(Config {
+            enable_lazy_recovery: false,
+            ..cfg.clone()
+        })
+        .unwrap();
+        engine.append(6, 21, 31, Some(&entry_data));
+        let expected = snapshot(&engine);
+        drop(engine);
+        let (engine, report) = EngineBuilder::new(cfg.clone()).open_with_report().unwrap();
+        assert_eq!(report.regions_deferred, 0);
+        assert_eq!(snapshot(&engine), expected);
+
+        // The map is only persisted by `close`.
+        drop(engine);
+        let (engine, report) = EngineBuilder::new(cfg).open_with_report().unwrap();
+        assert_eq!(report.regions_deferred, 0);
+        assert_eq!(snapshot(&engine), expected);
+    }
+
+    #[test]
+    fn test_export_import_regions() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_export_import_regions")
+            .tempdir()
+            .unwrap();
+        let target_dir = tempfile::Builder::new()
+            .prefix("test_export_import_regions_target")
+            .tempdir()
+            .unwrap();
+        let entry_data = vec![b'x'; 1024];
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            target_file_size: ReadableSize::kb(4),
+            ..Default::default()
+        };
+        let engine = RaftLogEngine::open(cfg.clone()).unwrap();
+        engine.append(1, 1, 301, Some(&entry_data));
+        engine.compact_to(1, 100);
+        engine.purge_manager.must_rewrite_append_queue(None, None);
+        engine.append(1, 301, 311, Some(&entry_data));
+        engine.append(2, 1, 11, Some(&entry_data));
+        engine.append(3, 1, 11, Some(&entry_data));
+        drop(engine);
+
+        let inspector = Engine::inspect(dir.path()).unwrap();
+        let exported = ExportedRegions {
+            regions: vec![
+                inspector.export_region(1).unwrap(),
+                inspector.export_region(2).unwrap(),
+            ],
+        };
+        assert!(inspector.export_region(4).is_err());
+        assert_eq!(exported.regions[0].span(), Some((100, 310)));
+        let exported = ExportedRegions::decode(&exported.encode().unwrap()).unwrap();
+
+        let target = RaftLogEngine::open(Config {
+            dir: target_dir.path().to_str().unwrap().to_owned(),
+            ..cfg.clone()
+        })
+        .unwrap();
+        target.append(2, 1, 5, Some(&entry_data));
+        // Entries of Raft Group 2 collide, nothing is imported.
+        assert!(target.import_regions(&exported).is_err());
+        assert!(target.first_index(1).is_none());
+        target.compact_to(2, 5);
+        target.clean(2);
+        let imported = target.import_regions(&exported).unwrap();
+        assert_eq!(imported.len(), 2);
+        assert_eq!(imported[0].span, Some((100, 310)));
+        assert_eq!(imported[0].entries, 211);
+        assert_eq!(imported[1].kvs, 1);
+
+        let target = target.reopen();
+        let source = RaftLogEngine::open(cfg).unwrap();
+        for rid in [1, 2] {
+            let (first, last) = (
+                source.first_index(rid).unwrap(),
+                source.last_index(rid).unwrap(),
+            );
+            assert_eq!(target.first_index(rid), Some(first));
+            assert_eq!(target.last_index(rid), Some(last));
+            for i in first..=last {
+                assert_eq!(
+                    target.get_entry::<Entry>(rid, i).unwrap(),
+                    source.get_entry::<Entry>(rid, i).unwrap()
+                );
+            }
+            assert_eq!(target.decode_last_index(rid), source.decode_last_index(rid));
+        }
+        assert!(target.first_index(3).is_none());
+    }
+
+    #[test]
+    fn test_rewrite_directory() {
+        let root = tempfile::Builder::new()
+            .prefix("test_rewrite_directory")
+            .tempdir()
+            .unwrap();
+        let dir = root.path().join("raft");
+        let entry_data = vec![b'x'; 1024];
+        let cfg = Config {
+            dir: dir.to_str().unwrap().to_owned(),
+            target_file_size: ReadableSize::kb(4),
+            format_version: Version::V1,
+            enable_log_recycle: false,
+            ..Default::default()
+        };
+        let engine = RaftLogEngine::open(cfg.clone()).unwrap();
+        for rid in 1..=5 {
+            engine.append(rid, 1, 51, Some(&entry_data));
+        }
+        engine.purge_manager.must_rewrite_append_queue(None, None);
+        engine.compact_to(1, 40);
+        engine.clean(2);
+        engine.append(3, 51, 61, Some(&entry_data));
+        // The directory is in use.
+        assert!(Engine::rewrite_directory(cfg.clone()).is_err());
+        drop(engine);
+
+        let new_cfg = Config {
+            format_version: Version::V2,
+            target_file_size: ReadableSize::kb(64),
+            enable_log_recycle: true,
+            ..cfg.clone()
+        };
+        let report = Engine::rewrite_directory(new_cfg.clone()).unwrap();
+        assert_eq!(
+            report
+                .regions
+                .iter()
+                .map(|r| r.region_id)
+                .collect::<Vec<_>>(),
+            vec![1, 3, 4, 5]
+        );
+        assert!(report.size_after < report.size_before);
+        assert_eq!(report.backup_dir, root.path().join("raft.bak"));
+        assert!(!root.path().join("raft.rewrite").exists());
+        let inspector = Engine::inspect(&dir).unwrap();
+        assert!(inspector
+            .files()
+            .iter()
+            .all(|f| f.version == Some(Version::V2)));
+
+        let source = RaftLogEngine::open(Config {
+            dir: report.backup_dir.to_str().unwrap().to_owned(),
+            ..cfg
+        })
+        .unwrap();
+        let target = RaftLogEngine::open(new_cfg.clone()).unwrap();
+        let mut regions = target.raft_groups();
+        regions.sort_unstable();
+        assert_eq!(regions, vec![1, 3, 4, 5]);
+        for rid in regions {
+            let (first, last) = (
+                source.first_index(rid).unwrap(),
+                source.last_index(rid).unwrap(),
+            );
+            assert_eq!(target.first_index(rid), Some(first));
+            assert_eq!(target.last_index(rid), Some(last));
+            for i in first..=last {
+                assert_eq!(
+                    target.get_entry::<Entry>(rid, i).unwrap(),
+                    source.get_entry::<Entry>(rid, i).unwrap()
+                );
+            }
+            assert_eq!(target.decode_last_index(rid), source.decode_last_index(rid));
+        }
+        drop(target);
+
+        // Interrupted after the original directory is moved away.
+        std::fs::rename(&dir, root.path().join("raft.rewrite")).unwrap();
+        let target = RaftLogEngine::open(new_cfg.clone()).unwrap();
+        assert!(!root.path().join("raft.rewrite").exists());
+        assert_eq!(target.raft_groups().len(), 4);
+        assert_eq!(
+            target.get_entry::<Entry>(3, 60).unwrap(),
+            source.get_entry::<Entry>(3, 60).unwrap()
+        );
+        drop(target);
+        // The backup directory is never overwritten.
+        assert!(Engine::rewrite_directory(new_cfg).is_err());
+    }
+
+    #[test]
+    fn test_deep_consistency_check() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_deep_consistency_check")
+            .tempdir()
+            .unwrap();
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            target_file_size: ReadableSize::kb(4),
+            ..Default::default()
+        };
+        let entry_data = vec![b'x'; 1024];
+        let engine = RaftLogEngine::open(cfg.clone()).unwrap();
+        for rid in 1..=3 {
+            engine.append(rid, 1, 11, Some(&entry_data));
+        }
+        engine.purge_manager.must_rewrite_append_queue(None, None);
+        engine.append(1, 11, 21, Some(&entry_data));
+        engine.compact_to(2, 5);
+        engine.append(3, 11, 16, Some(&entry_data));
+        let dead = engine
+            .memtables
+            .get(3)
+            .unwrap()
+            .read()
+            .get_entry(11)
+            .unwrap()
+            .entries
+            .unwrap();
+        engine.compact_to(3, 16);
+        drop(engine);
+
+        let report = RaftLogEngine::deep_consistency_check::<Entry>(dir.path()).unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+        let spans: Vec<_> = report
+            .regions
+            .iter()
+            .map(|r| (r.raft_group_id, r.first_index, r.last_index))
+            .collect();
+        assert_eq!(spans, vec![(1, Some(1), Some(20)), (2, Some(5), Some(10))]);
+        assert_eq!(
+            report.files.iter().map(|f| f.entries).sum::<usize>(),
+            3 * 10 + 10 + 5
+        );
+
+        // Punch an obsolete block of Raft Group 3, and a live block of Raft Group 2.
+        let punch = |handle: FileBlockHandle| {
+            let path = handle.id.build_file_path(dir.path());
+            let mut f = OpenOptions::new().write(true).open(path).unwrap();
+            f.seek(SeekFrom::Start(handle.offset)).unwrap();
+            f.write_all(&vec![0; handle.len]).unwrap();
+        };
+        punch(dead);
+        let live = Engine::inspect(dir.path())
+            .unwrap()
+            .entry_index(2, 5)
+            .unwrap()
+            .entries
+            .unwrap();
+        punch(live);
+        // Directly write to pipe log.
+        let engine = RaftLogEngine::open(cfg.clone()).unwrap();
+        let write = |queue, lb: &mut LogBatch| {
+            lb.finish_populate(0).unwrap();
+            engine.pipe_log.append(queue, lb).unwrap();
+            lb.drain();
+        };
+        let mut lb = LogBatch::default();
+        lb.delete(4, b"key".to_vec());
+        write(LogQueue::Rewrite, &mut lb);
+        let mut e = Entry::new();
+        e.set_index(1);
+        lb.add_raw_entries(
+            5,
+            vec![EntryIndex {
+                index: 2,
+                ..Default::default()
+            }],
+            vec![e.write_to_bytes().unwrap()],
+        )
+        .unwrap();
+        write(LogQueue::Append, &mut lb);
+        lb.add_entries::<Entry>(6, &generate_entries(5, 8, None))
+            .unwrap();
+        write(LogQueue::Append, &mut lb);
+        lb.add_entries::<Entry>(1, &generate_entries(15, 16, None))
+            .unwrap();
+        write(LogQueue::Append, &mut lb);
+        lb.add_command(1, Command::Compact { index: 12 });
+        write(LogQueue::Append, &mut lb);
+        lb.add_entries::<Entry>(1, &generate_entries(11, 13, None))
+            .unwrap();
+        write(LogQueue::Append, &mut lb);
+        lb.add_entries::<Entry>(6, &generate_entries(10, 11, None))
+            .unwrap();
+        write(LogQueue::Append, &mut lb);
+        engine.pipe_log.sync(LogQueue::Append).unwrap();
+        drop(engine);
+
+        let report = RaftLogEngine::deep_consistency_check::<Entry>(dir.path()).unwrap();
+        assert!(!report.is_ok());
+        let file = report.files.iter().find(|f| f.file_id == dead.id).unwrap();
+        assert_eq!(file.punched_blocks, 1);
+        assert!(file.errors.is_empty());
+        let errors = |rid| {
+            report
+                .regions
+                .iter()
+                .find(|r| r.raft_group_id == rid)
+                .unwrap()
+                .errors
+                .clone()
+        };
+        assert_eq!(errors(1).len(), 1);
+        assert!(errors(1)[0].contains("overwrite"));
+        assert!(errors(2)[0].contains("punched"));
+        assert!(errors(4)[0].contains("tombstone"));
+        assert!(errors(5)[0].contains("has index 1"));
+        assert!(errors(6)[0].contains("hole"));
+        let file = report.files.iter().find(|f| f.file_id == live.id).unwrap();
+        assert!(file.errors[0].contains(&format!("offset {}", live.offset)));
+    }
+
+    #[test]
+    fn test_background_scrub() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_background_scrub")
+            .tempdir()
+            .unwrap();
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            target_file_size: ReadableSize::kb(4),
+            ..Default::default()
+        };
+        let entry_data = vec![b'x'; 1024];
+        let engine = RaftLogEngine::open(cfg.clone()).unwrap();
+        for rid in 1..=3 {
+            engine.append(rid, 1, 11, Some(&entry_data));
+        }
+        let handle = engine
+            .memtables
+            .get(2)
+            .unwrap()
+            .read()
+            .get_entry(5)
+            .unwrap()
+            .entries
+            .unwrap();
+        engine.pipe_log.rotate(LogQueue::Append).unwrap();
+        drop(engine);
+
+        // Flip a byte of the entries block, which is not verified by recovery.
+        let path = handle.id.build_file_path(dir.path());
+        let mut f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        let mut byte = [0];
+        f.seek(SeekFrom::Start(handle.offset + 1)).unwrap();
+        f.read_exact(&mut byte).unwrap();
+        byte[0] ^= 1;
+        f.seek(SeekFrom::Start(handle.offset + 1)).unwrap();
+        f.write_all(&byte).unwrap();
+        drop(f);
+
+        #[derive(Default)]
+        struct CorruptionListener(Mutex<Vec<(FileId, u64, Vec<u64>)>>);
+        impl EventListener for CorruptionListener {
+            fn on_corruption_detected(&self, file_id: FileId, offset: u64, regions: &[u64]) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push((file_id, offset, regions.to_vec()));
+            }
+        }
+        let listener = Arc::new(CorruptionListener::default());
+        let scrub_cfg = Config {
+            scrub_rate_limit: Some(ReadableSize::mb(10)),
+            ..cfg.clone()
+        };
+        // Scrubbing runs in background, which isn't allowed without
+        // `open_lazily`.
+        assert!(
+            RaftLogEngine::open_with(scrub_cfg.clone(), Arc::new(DefaultFileSystem), vec![])
+                .is_err()
+        );
+        let (engine, _) = EngineBuilder::new(scrub_cfg)
+            .add_listener(listener.clone())
+            .open_lazily()
+            .unwrap();
+        let start = Instant::now();
+        while listener.0.lock().unwrap().is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        drop(engine);
+        assert_eq!(
+            *listener.0.lock().unwrap(),
+            vec![(handle.id, handle.offset, vec![2])]
+        );
+
+        // Enabled at runtime.
+        let listener = Arc::new(CorruptionListener::default());
+        let engine = RaftLogEngine::open_with(
+            cfg.clone(),
+            Arc::new(DefaultFileSystem),
+            vec![listener.clone()],
+        )
+        .unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(listener.0.lock().unwrap().is_empty());
+        engine
+            .update_config(&ConfigChange {
+                scrub_rate_limit: Some(ReadableSize::mb(10)),
+                ..Default::default()
+            })
+            .unwrap();
+        let start = Instant::now();
+        while listener.0.lock().unwrap().is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        engine
+            .update_config(&ConfigChange {
+                scrub_rate_limit: Some(ReadableSize(0)),
+                ..Default::default()
+            })
+            .unwrap();
+        drop(engine);
+
+        // Stopping isn't blocked by a low rate limit.
+        let engine = RaftLogEngine::open(Config {
+            scrub_rate_limit: Some(ReadableSize(1)),
+            ..cfg
+        })
+        .unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        let start = Instant::now();
+        drop(engine);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_space_stats() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_space_stats")
+            .tempdir()
+            .unwrap();
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            target_file_size: ReadableSize::kb(4),
+            batch_compression_threshold: ReadableSize(0),
+            ..Default::default()
+        };
+        let entry_data = vec![b'x'; 1024];
+        let engine = RaftLogEngine::open(cfg).unwrap();
+        for rid in 1..=3 {
+            engine.append(rid, 1, 11, Some(&entry_data));
+        }
+        let stats = engine.space_stats().unwrap();
+        assert_eq!(stats.size(), engine.get_used_size());
+        let (first, active) = engine.file_span(LogQueue::Append);
+        assert_eq!(stats.append.files.len() as u64, active - first + 1);
+        for f in &stats.append.files[..stats.append.files.len() - 1] {
+            let path = FileId::new(LogQueue::Append, f.seq).build_file_path(dir.path());
+            assert_eq!(std::fs::metadata(path).unwrap().len() as usize, f.size);
+        }
+        assert!(stats.append.live_bytes() >= 3 * 10 * entry_data.len());
+        assert!(stats.append.live_bytes() <= stats.user_bytes() as usize);
+        assert!(stats.user_bytes() < stats.append.size() as u64);
+        assert_eq!(stats.rewrite_bytes(), 0);
+        assert_eq!(stats.purged_bytes(), 0);
+        assert_eq!(stats.write_amplification(), 1.0);
+
+        engine.clean(1);
+        let stats2 = engine.space_stats().unwrap();
+        assert!(stats2.append.live_bytes() + 10 * entry_data.len() <= stats.append.live_bytes());
+        assert!(stats2.append.dead_bytes() > stats.append.dead_bytes());
+
+        engine.purge_manager.must_rewrite_append_queue(None, None);
+        let stats3 = engine.space_stats().unwrap();
+        assert_eq!(stats3.size(), engine.get_used_size());
+        assert_eq!(stats3.append.live_bytes(), 0);
+        assert!(stats3.rewrite.live_bytes() >= 2 * 10 * entry_data.len());
+        assert!(stats3.rewrite_bytes() >= stats3.rewrite.live_bytes() as u64);
+        assert!(stats3.append.purged_bytes >= stats2.append.size() as u64 - 4096);
+        assert!(stats3.write_amplification() > 1.0);
+
+        // Counters start over after restart.
+        let size = engine.get_used_size();
+        let engine = engine.reopen();
+        let stats4 = engine.space_stats().unwrap();
+        assert_eq!(stats4.size(), size);
+        assert_eq!(stats4.user_bytes(), 0);
+        assert_eq!(stats4.rewrite_bytes(), 0);
+        assert_eq!(stats4.rewrite.live_bytes(), stats3.rewrite.live_bytes());
+    }
 }
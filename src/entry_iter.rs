@@ -0,0 +1,223 @@
+// Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
+
+//! Streaming reads of log entries.
+
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+
+use protobuf::Message;
+
+use crate::log_batch::{CompressionType, LogBatch, MessageExt};
+use crate::memtable::{EntryIndex, MemTables};
+use crate::pipe_log::{FileBlockHandle, PipeLog};
+use crate::{Error, Result};
+
+const DEFAULT_MAX_PREFETCH_BYTES: usize = 4 * 1024 * 1024;
+
+type Batch = Vec<(FileBlockHandle, CompressionType)>;
+
+/// An iterator over log entries of a Raft Group, returned by
+/// [`Engine::entry_iter`](crate::Engine::entry_iter).
+///
+/// Entry indexes are snapshotted when the iterator is created, entries written
+/// afterwards are not returned. Blocks of entries are read ahead in batches
+/// with async I/O, the next batch being read while the current one is
+/// consumed. Entries moved by rewrite are followed. If the remaining entries
+/// are compacted, `Error::EntryCompacted` is returned before the next batch,
+/// and the iteration ends.
+pub struct EntryIter<'a, M: MessageExt, P: PipeLog> {
+    memtables: &'a MemTables,
+    pipe_log: &'a P,
+    region_id: u64,
+    // Exclusive end of the snapshotted entries.
+    end: u64,
+    max_prefetch_bytes: usize,
+
+    // Entries not yet returned.
+    entries: VecDeque<EntryIndex>,
+    // Blocks of `entries` not yet submitted for reading.
+    blocks: VecDeque<(FileBlockHandle, CompressionType)>,
+    // Decoded blocks to be consumed.
+    ready: VecDeque<(FileBlockHandle, Vec<u8>)>,
+    // The batch of blocks being read.
+    inflight: Option<(Batch, P::PendingRead)>,
+    done: bool,
+
+    _phantom: PhantomData<M>,
+}
+
+impl<'a, M: MessageExt, P: PipeLog> EntryIter<'a, M, P> {
+    pub(crate) fn new(
+        memtables: &'a MemTables,
+        pipe_log: &'a P,
+        region_id: u64,
+        start_index: u64,
+    ) -> Result<Self> {
+        let mut iter = Self {
+            memtables,
+            pipe_log,
+            region_id,
+            end: start_index,
+            max_prefetch_bytes: DEFAULT_MAX_PREFETCH_BYTES,
+            entries: VecDeque::new(),
+            blocks: VecDeque::new(),
+            ready: VecDeque::new(),
+            inflight: None,
+            done: false,
+            _phantom: PhantomData,
+        };
+        if let Some(memtable) = memtables.get(region_id) {
+            let memtable = memtable.read();
+            if let Some(last) = memtable.last_index() {
+                iter.end = std::cmp::max(last + 1, start_index);
+            }
+            let mut entries = Vec::new();
+            memtable.fetch_entries_to(start_index, iter.end, None, &mut entries)?;
+            iter.reset(entries);
+        }
+        Ok(iter)
+    }
+
+    /// Sets the maximum bytes of entry blocks that are read ahead and kept in
+    /// memory, measured by their size in log files. Half of it is used by the
+    /// batch being consumed, the other half by the next batch being read. A
+    /// block larger than that is still read alone.
+    ///
+    /// Default: 4MB.
+    pub fn max_prefetch_bytes(mut self, bytes: usize) -> Self {
+        self.max_prefetch_bytes = bytes;
+        self
+    }
+
+    fn reset(&mut self, entries: Vec<EntryIndex>) {
+        self.blocks.clear();
+        for ei in &entries {
+            let block = ei.entries.unwrap();
+            if self.blocks.back().map(|(b, _)| *b) != Some(block) {
+                self.blocks.push_back((block, ei.compression_type));
+            }
+        }
+        self.entries = entries.into();
+        self.ready.clear();
+        self.inflight = None;
+    }
+
+    // Submits the next batch of blocks for reading, if there is any.
+    fn submit_next_batch(&mut self) -> Result<()> {
+        debug_assert!(self.inflight.is_none());
+        let limit = self.max_prefetch_bytes / 2;
+        let mut len = 0;
+        let mut size = 0;
+        for (block, _) in &self.blocks {
+            if len > 0 && size + block.len > limit {
+                break;
+            }
+            len += 1;
+            size += block.len;
+        }
+        if len > 0 {
+            let handles = self.blocks.iter().take(len).map(|(b, _)| *b).collect();
+            let pending = self.pipe_log.submit_read(handles)?;
+            self.inflight = Some((self.blocks.drain(..len).collect(), pending));
+        }
+        Ok(())
+    }
+
+    // Waits for the batch being read, and submits the next one so that it's
+    // read while this one is consumed.
+    fn load_next_batch(&mut self) -> Result<()> {
+        if self.inflight.is_none() {
+            self.submit_next_batch()?;
+        }
+        let (batch, pending) = self.inflight.take().unwrap();
+        let bytes = self.pipe_log.finish_read(pending)?;
+        for ((block, compression_type), bytes) in batch.into_iter().zip(bytes) {
+            let decoded = LogBatch::decode_entries_block(&bytes, block, compression_type)?;
+            self.ready.push_back((block, decoded));
+        }
+        // Failures are left to be reported when the batch is needed.
+        let _ = self.submit_next_batch();
+        Ok(())
+    }
+
+    fn check_compacted(&self, index: u64) -> Result<()> {
+        let first = self
+            .memtables
+            .get(self.region_id)
+            .and_then(|m| m.read().first_index());
+        match first {
+            Some(first) if first <= index => Ok(()),
+            _ => Err(Error::EntryCompacted),
+        }
+    }
+
+    // Snapshots the remaining entries again after a failed read. Entries might
+    // have been moved to other log files by rewrite before their old files are
+    // purged.
+    fn reload(&mut self, err: Error) -> Result<()> {
+        let index = self.entries.front().unwrap().index;
+        let mut entries = Vec::new();
+        if let Some(memtable) = self.memtables.get(self.region_id) {
+            let memtable = memtable.read();
+            if let Some(last) = memtable.last_index() {
+                let end = std::cmp::min(self.end, last + 1);
+                memtable.fetch_entries_to(index, end, None, &mut entries)?;
+            }
+        }
+        if entries.first().map(|e| e.entries) == self.entries.front().map(|e| e.entries) {
+            // The block is still in use, so the error isn't caused by purge.
+            return Err(err);
+        }
+        self.reset(entries);
+        Ok(())
+    }
+
+    fn next_entry(&mut self) -> Result<Option<M::Entry>> {
+        let index = match self.entries.front() {
+            Some(ei) => ei.index,
+            None => return Ok(None),
+        };
+        while self.ready.is_empty() {
+            self.check_compacted(index)?;
+            if let Err(e) = self.load_next_batch() {
+                self.reload(e)?;
+                if self.entries.is_empty() {
+                    return Ok(None);
+                }
+            }
+        }
+        let ei = self.entries.pop_front().unwrap();
+        let (block, bytes) = self.ready.front().unwrap();
+        debug_assert_eq!(Some(*block), ei.entries);
+        let entry = M::Entry::parse_from_bytes(
+            &bytes[ei.entry_offset as usize..(ei.entry_offset + ei.entry_len) as usize],
+        )?;
+        assert_eq!(M::index(&entry), ei.index);
+        if self.entries.front().map(|e| e.entries) != Some(ei.entries) {
+            self.ready.pop_front();
+        }
+        Ok(Some(entry))
+    }
+}
+
+impl<'a, M: MessageExt, P: PipeLog> Iterator for EntryIter<'a, M, P> {
+    type Item = Result<M::Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                self.reset(Vec::new());
+                Some(Err(e))
+            }
+        }
+    }
+}
@@ -189,6 +189,29 @@ impl Handle for LogFd {
             nix::unistd::fsync(self.0).map_err(|e| from_nix_error(e, "fsync"))
         }
     }
+
+    #[inline]
+    fn punch_hole(&self, offset: usize, size: usize) -> IoResult<()> {
+        fail_point!("log_fd::punch_hole::err", |_| {
+            Err(from_nix_error(nix::Error::EINVAL, "fp"))
+        });
+        #[cfg(target_os = "linux")]
+        {
+            fcntl::fallocate(
+                self.0,
+                fcntl::FallocateFlags::FALLOC_FL_PUNCH_HOLE
+                    | fcntl::FallocateFlags::FALLOC_FL_KEEP_SIZE,
+                offset as i64,
+                size as i64,
+            )
+            .map_err(|e| from_nix_error(e, "fallocate"))
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (offset, size);
+            Err(from_nix_error(nix::Error::EOPNOTSUPP, "punch_hole"))
+        }
+    }
 }
 
 impl Drop for LogFd {
@@ -265,6 +288,31 @@ impl WriteExt for LogFile {
 pub struct AioContext {
     aio_vec: Vec<Pin<Box<AioRead<'static>>>>,
     buf_vec: Vec<Vec<u8>>,
+    // Files being read are kept open until the reads are finished, so that
+    // their descriptors aren't closed or reused by others in the meantime.
+    fd_vec: Vec<Arc<LogFd>>,
+}
+
+impl AioContext {
+    // Waits for a submitted read, returns the number of bytes read.
+    fn wait(aior: &mut Pin<Box<AioRead<'static>>>) -> nix::Result<usize> {
+        while aior.as_mut().error() == Err(Errno::EINPROGRESS) {
+            // Only fails when interrupted, retry.
+            let _ = aio_suspend(&[&**aior], None);
+        }
+        aior.as_mut().aio_return()
+    }
+}
+
+impl Drop for AioContext {
+    fn drop(&mut self) {
+        // Reads in flight must be finished before their buffers are freed.
+        for aior in self.aio_vec.iter_mut() {
+            if aior.in_progress() {
+                let _ = Self::wait(aior);
+            }
+        }
+    }
 }
 
 pub struct DefaultFileSystem;
@@ -281,32 +329,34 @@ impl FileSystem for DefaultFileSystem {
         handle: Arc<Self::Handle>,
         block: &FileBlockHandle,
     ) -> IoResult<()> {
-        let buf = vec![0_u8; block.len];
-        ctx.buf_vec.push(buf);
-
+        let mut buf = vec![0_u8; block.len];
+        // The heap buffer stays in place after being moved into the context.
         let mut aior = Box::pin(AioRead::new(
             handle.0,
             block.offset as i64,
-            unsafe {
-                slice::from_raw_parts_mut(ctx.buf_vec.last_mut().unwrap().as_mut_ptr(), block.len)
-            },
+            unsafe { slice::from_raw_parts_mut(buf.as_mut_ptr(), block.len) },
             0,
             SigevNotify::SigevNone,
         ));
         aior.as_mut().submit()?;
+        ctx.buf_vec.push(buf);
         ctx.aio_vec.push(aior);
+        ctx.fd_vec.push(handle);
 
         Ok(())
     }
 
     fn async_finish(&self, mut ctx: Self::MultiReadContext) -> IoResult<Vec<Vec<u8>>> {
-        for seq in 0..ctx.aio_vec.len() {
-            let buf_len = ctx.buf_vec[seq].len();
-            aio_suspend(&[&*ctx.aio_vec[seq]], None)?;
-            assert_eq!(ctx.aio_vec[seq].as_mut().aio_return()?, buf_len);
+        // Reads left unfinished on error are waited for when `ctx` is dropped.
+        for (aior, buf) in ctx.aio_vec.iter_mut().zip(ctx.buf_vec.iter()) {
+            if AioContext::wait(aior)? != buf.len() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "async read",
+                ));
+            }
         }
-        let res = ctx.buf_vec.to_owned();
-        Ok(res)
+        Ok(std::mem::take(&mut ctx.buf_vec))
     }
 
     fn create<P: AsRef<Path>>(&self, path: P) -> IoResult<Self::Handle> {
@@ -0,0 +1,531 @@
+// Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Result as IoResult, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::env::{FileSystem, Handle, WriteExt};
+use crate::pipe_log::FileBlockHandle;
+
+#[derive(Default)]
+struct Inode {
+    data: Vec<u8>,
+    // Content that survives a crash.
+    synced: Vec<u8>,
+    // Range of `data` that has been modified since last sync. The range can be
+    // empty when only the file size is changed.
+    dirty: Option<(usize, usize)>,
+}
+
+impl Inode {
+    fn from_synced(synced: Vec<u8>) -> Self {
+        Self {
+            data: synced.clone(),
+            synced,
+            dirty: None,
+        }
+    }
+
+    fn mark_dirty(&mut self, begin: usize, end: usize) {
+        self.dirty = Some(match self.dirty {
+            Some((b, e)) => (std::cmp::min(b, begin), std::cmp::max(e, end)),
+            None => (begin, end),
+        });
+    }
+
+    fn write(&mut self, offset: usize, buf: &[u8]) {
+        let end = offset + buf.len();
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        self.data[offset..end].copy_from_slice(buf);
+        self.mark_dirty(offset, end);
+    }
+
+    fn truncate(&mut self, size: usize) {
+        self.data.truncate(size);
+        self.mark_dirty(size, size);
+    }
+
+    fn allocate(&mut self, offset: usize, size: usize) {
+        let end = offset + size;
+        if self.data.len() < end {
+            let len = self.data.len();
+            self.data.resize(end, 0);
+            self.mark_dirty(len, end);
+        }
+    }
+
+    fn punch_hole(&mut self, offset: usize, size: usize) {
+        let end = std::cmp::min(offset + size, self.data.len());
+        if offset < end {
+            self.data[offset..end].fill(0);
+            self.mark_dirty(offset, end);
+        }
+    }
+
+    fn sync(&mut self) {
+        if let Some((begin, end)) = self.dirty.take() {
+            self.synced.resize(self.data.len(), 0);
+            let end = std::cmp::min(end, self.data.len());
+            if begin < end {
+                self.synced[begin..end].copy_from_slice(&self.data[begin..end]);
+            }
+        }
+    }
+
+    fn unsynced_bytes(&self) -> usize {
+        match self.dirty {
+            Some((begin, end)) => std::cmp::max(
+                end.saturating_sub(begin),
+                self.data.len().abs_diff(self.synced.len()),
+            ),
+            None => 0,
+        }
+    }
+}
+
+struct LastWrite {
+    inode: Arc<Mutex<Inode>>,
+    offset: usize,
+    len: usize,
+}
+
+#[derive(Default)]
+struct State {
+    // Current directory entries.
+    entries: HashMap<PathBuf, Arc<Mutex<Inode>>>,
+    // Directory entries that survive a crash.
+    durable_entries: HashMap<PathBuf, Arc<Mutex<Inode>>>,
+    // The latest write that hasn't been synced.
+    last_write: Option<LastWrite>,
+}
+
+/// An in-memory file system that simulates power loss.
+///
+/// Both file content and directory entries are tracked in two versions, the
+/// current one and the synced one. File content is synced by
+/// [`Handle::sync`], and directory entries (creations, deletions and renames)
+/// are synced by [`FileSystem::sync_dir`]. [`MemFileSystem::crash`] discards
+/// everything that hasn't been synced.
+///
+/// Only log files are kept in memory. The engine directory itself must still
+/// exist on the local file system, because it is used for locking.
+#[derive(Clone, Default)]
+pub struct MemFileSystem {
+    state: Arc<Mutex<State>>,
+}
+
+impl MemFileSystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Simulates a power loss. All unsynced file content and directory
+    /// entries are discarded.
+    ///
+    /// Handles opened before the crash are detached from the file system, any
+    /// operation on them no longer has visible effect.
+    pub fn crash(&self) {
+        self.crash_imp(None)
+    }
+
+    /// Simulates a power loss during the last unsynced write. Only the first
+    /// `kept_bytes` of that write are persisted, all other unsynced file
+    /// content and directory entries are discarded.
+    pub fn crash_with_torn_write(&self, kept_bytes: usize) {
+        self.crash_imp(Some(kept_bytes))
+    }
+
+    fn crash_imp(&self, torn_write: Option<usize>) {
+        let mut state = self.state.lock();
+        if let (Some(kept_bytes), Some(w)) = (torn_write, state.last_write.take()) {
+            let mut inode = w.inode.lock();
+            let begin = w.offset;
+            let end = std::cmp::min(begin + std::cmp::min(kept_bytes, w.len), inode.data.len());
+            if begin < end {
+                if inode.synced.len() < end {
+                    inode.synced.resize(end, 0);
+                }
+                let data = inode.data[begin..end].to_vec();
+                inode.synced[begin..end].copy_from_slice(&data);
+            }
+        }
+        state.last_write = None;
+        let entries: HashMap<_, _> = state
+            .durable_entries
+            .iter()
+            .map(|(path, inode)| {
+                let synced = inode.lock().synced.clone();
+                (
+                    path.clone(),
+                    Arc::new(Mutex::new(Inode::from_synced(synced))),
+                )
+            })
+            .collect();
+        state.durable_entries = entries.clone();
+        state.entries = entries;
+    }
+
+    /// Returns the size of file at `path`, or `None` if it doesn't exist.
+    pub fn file_size<P: AsRef<Path>>(&self, path: P) -> Option<usize> {
+        let state = self.state.lock();
+        state
+            .entries
+            .get(path.as_ref())
+            .map(|inode| inode.lock().data.len())
+    }
+
+    /// Returns the number of bytes of file at `path` that would be lost in a
+    /// crash, or `None` if it doesn't exist.
+    pub fn unsynced_bytes<P: AsRef<Path>>(&self, path: P) -> Option<usize> {
+        let state = self.state.lock();
+        state
+            .entries
+            .get(path.as_ref())
+            .map(|inode| inode.lock().unsynced_bytes())
+    }
+
+    /// Returns the paths whose directory entries haven't been synced, i.e.
+    /// files that would disappear or reappear in a crash.
+    pub fn unsynced_entries(&self) -> Vec<PathBuf> {
+        let state = self.state.lock();
+        let mut paths: Vec<_> = state
+            .entries
+            .iter()
+            .filter(|(path, inode)| {
+                !matches!(state.durable_entries.get(*path), Some(d) if Arc::ptr_eq(d, inode))
+            })
+            .map(|(path, _)| path.clone())
+            .chain(
+                state
+                    .durable_entries
+                    .keys()
+                    .filter(|path| !state.entries.contains_key(*path))
+                    .cloned(),
+            )
+            .collect();
+        paths.sort();
+        paths
+    }
+}
+
+/// A file of [`MemFileSystem`].
+pub struct MemFile {
+    inode: Arc<Mutex<Inode>>,
+    state: Arc<Mutex<State>>,
+}
+
+impl MemFile {
+    fn read(&self, offset: usize, buf: &mut [u8]) -> IoResult<usize> {
+        let inode = self.inode.lock();
+        if offset >= inode.data.len() {
+            return Ok(0);
+        }
+        let len = std::cmp::min(buf.len(), inode.data.len() - offset);
+        buf[..len].copy_from_slice(&inode.data[offset..offset + len]);
+        Ok(len)
+    }
+
+    fn write(&self, offset: usize, buf: &[u8]) -> IoResult<usize> {
+        let mut state = self.state.lock();
+        self.inode.lock().write(offset, buf);
+        state.last_write = Some(LastWrite {
+            inode: self.inode.clone(),
+            offset,
+            len: buf.len(),
+        });
+        Ok(buf.len())
+    }
+}
+
+impl Handle for MemFile {
+    fn truncate(&self, offset: usize) -> IoResult<()> {
+        self.inode.lock().truncate(offset);
+        Ok(())
+    }
+
+    fn file_size(&self) -> IoResult<usize> {
+        Ok(self.inode.lock().data.len())
+    }
+
+    fn sync(&self) -> IoResult<()> {
+        let mut state = self.state.lock();
+        if matches!(&state.last_write, Some(w) if Arc::ptr_eq(&w.inode, &self.inode)) {
+            state.last_write = None;
+        }
+        self.inode.lock().sync();
+        Ok(())
+    }
+
+    fn punch_hole(&self, offset: usize, size: usize) -> IoResult<()> {
+        self.inode.lock().punch_hole(offset, size);
+        Ok(())
+    }
+}
+
+/// A [`MemFile`] adapted for standard interfaces including [`Seek`],
+/// [`Write`] and [`Read`].
+pub struct MemFileCursor {
+    inner: Arc<MemFile>,
+    offset: usize,
+}
+
+impl Write for MemFileCursor {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let len = self.inner.write(self.offset, buf)?;
+        self.offset += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl Read for MemFileCursor {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let len = self.inner.read(self.offset, buf)?;
+        self.offset += len;
+        Ok(len)
+    }
+}
+
+impl Seek for MemFileCursor {
+    fn seek(&mut self, pos: SeekFrom) -> IoResult<u64> {
+        match pos {
+            SeekFrom::Start(offset) => self.offset = offset as usize,
+            SeekFrom::Current(i) => self.offset = (self.offset as i64 + i) as usize,
+            SeekFrom::End(i) => self.offset = (self.inner.file_size()? as i64 + i) as usize,
+        }
+        Ok(self.offset as u64)
+    }
+}
+
+impl WriteExt for MemFileCursor {
+    fn truncate(&mut self, offset: usize) -> IoResult<()> {
+        self.inner.truncate(offset)?;
+        self.offset = offset;
+        Ok(())
+    }
+
+    fn allocate(&mut self, offset: usize, size: usize) -> IoResult<()> {
+        self.inner.inode.lock().allocate(offset, size);
+        Ok(())
+    }
+}
+
+fn not_found(path: &Path) -> Error {
+    Error::new(
+        ErrorKind::NotFound,
+        format!("{} doesn't exist", path.display()),
+    )
+}
+
+fn in_dir(path: &Path, dir: &Path) -> bool {
+    path.parent() == Some(dir)
+}
+
+impl FileSystem for MemFileSystem {
+    type Handle = MemFile;
+    type Reader = MemFileCursor;
+    type Writer = MemFileCursor;
+    type MultiReadContext = Vec<Vec<u8>>;
+
+    fn multi_read(
+        &self,
+        ctx: &mut Self::MultiReadContext,
+        handle: Arc<Self::Handle>,
+        block: &FileBlockHandle,
+    ) -> IoResult<()> {
+        let mut buf = vec![0; block.len];
+        if handle.read(block.offset as usize, &mut buf)? != block.len {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "multi_read"));
+        }
+        ctx.push(buf);
+        Ok(())
+    }
+
+    fn async_finish(&self, ctx: Self::MultiReadContext) -> IoResult<Vec<Vec<u8>>> {
+        Ok(ctx)
+    }
+
+    fn create<P: AsRef<Path>>(&self, path: P) -> IoResult<Self::Handle> {
+        let mut state = self.state.lock();
+        let inode = state
+            .entries
+            .entry(path.as_ref().to_path_buf())
+            .or_default()
+            .clone();
+        Ok(MemFile {
+            inode,
+            state: self.state.clone(),
+        })
+    }
+
+    fn open<P: AsRef<Path>>(&self, path: P) -> IoResult<Self::Handle> {
+        let state = self.state.lock();
+        let inode = state
+            .entries
+            .get(path.as_ref())
+            .ok_or_else(|| not_found(path.as_ref()))?
+            .clone();
+        Ok(MemFile {
+            inode,
+            state: self.state.clone(),
+        })
+    }
+
+    fn delete<P: AsRef<Path>>(&self, path: P) -> IoResult<()> {
+        let mut state = self.state.lock();
+        state
+            .entries
+            .remove(path.as_ref())
+            .ok_or_else(|| not_found(path.as_ref()))?;
+        Ok(())
+    }
+
+    fn rename<P: AsRef<Path>>(&self, src_path: P, dst_path: P) -> IoResult<()> {
+        let mut state = self.state.lock();
+        let inode = state
+            .entries
+            .remove(src_path.as_ref())
+            .ok_or_else(|| not_found(src_path.as_ref()))?;
+        state.entries.insert(dst_path.as_ref().to_path_buf(), inode);
+        Ok(())
+    }
+
+    fn exists<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.state.lock().entries.contains_key(path.as_ref())
+    }
+
+    fn list_files<P: AsRef<Path>>(&self, path: P) -> IoResult<Vec<PathBuf>> {
+        let state = self.state.lock();
+        Ok(state
+            .entries
+            .keys()
+            .filter(|p| in_dir(p, path.as_ref()))
+            .cloned()
+            .collect())
+    }
+
+    fn sync_dir<P: AsRef<Path>>(&self, path: P) -> IoResult<()> {
+        let mut state = self.state.lock();
+        let dir = path.as_ref();
+        state.durable_entries.retain(|p, _| !in_dir(p, dir));
+        let entries: Vec<_> = state
+            .entries
+            .iter()
+            .filter(|(p, _)| in_dir(p, dir))
+            .map(|(p, inode)| (p.clone(), inode.clone()))
+            .collect();
+        state.durable_entries.extend(entries);
+        Ok(())
+    }
+
+    fn new_reader(&self, handle: Arc<Self::Handle>) -> IoResult<Self::Reader> {
+        Ok(MemFileCursor {
+            inner: handle,
+            offset: 0,
+        })
+    }
+
+    fn new_writer(&self, handle: Arc<Self::Handle>) -> IoResult<Self::Writer> {
+        Ok(MemFileCursor {
+            inner: handle,
+            offset: 0,
+        })
+    }
+
+    fn new_async_io_context(&self) -> IoResult<Self::MultiReadContext> {
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(fs: &MemFileSystem, path: &Path) -> Vec<u8> {
+        let handle = Arc::new(fs.open(path).unwrap());
+        let mut reader = fs.new_reader(handle).unwrap();
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_mem_file_system_crash() {
+        let fs = MemFileSystem::new();
+        let dir = Path::new("dir");
+        let (a, b) = (dir.join("a"), dir.join("b"));
+
+        let handle = Arc::new(fs.create(&a).unwrap());
+        let mut writer = fs.new_writer(handle.clone()).unwrap();
+        writer.write_all(b"hello").unwrap();
+        handle.sync().unwrap();
+        writer.write_all(b" world").unwrap();
+        assert_eq!(fs.unsynced_bytes(&a), Some(6));
+        assert_eq!(fs.unsynced_entries(), vec![a.clone()]);
+        // File content is not reachable without syncing the directory.
+        fs.crash();
+        assert!(!fs.exists(&a));
+        assert!(fs.list_files(dir).unwrap().is_empty());
+
+        let handle = Arc::new(fs.create(&a).unwrap());
+        let mut writer = fs.new_writer(handle.clone()).unwrap();
+        writer.write_all(b"hello").unwrap();
+        handle.sync().unwrap();
+        fs.sync_dir(dir).unwrap();
+        writer.write_all(b" world").unwrap();
+        fs.rename(&a, &b).unwrap();
+        assert_eq!(fs.unsynced_entries(), vec![a.clone(), b.clone()]);
+        fs.crash();
+        assert_eq!(fs.list_files(dir).unwrap(), vec![a.clone()]);
+        assert_eq!(read_all(&fs, &a), b"hello");
+        assert_eq!(fs.unsynced_bytes(&a), Some(0));
+
+        // Stale handles are detached.
+        writer.write_all(b"!").unwrap();
+        handle.sync().unwrap();
+        assert_eq!(read_all(&fs, &a), b"hello");
+
+        let handle = Arc::new(fs.open(&a).unwrap());
+        let mut writer = fs.new_writer(handle.clone()).unwrap();
+        writer.seek(SeekFrom::End(0)).unwrap();
+        writer.allocate(5, 10).unwrap();
+        assert_eq!(handle.file_size().unwrap(), 15);
+        writer.write_all(b" world").unwrap();
+        writer.truncate(11).unwrap();
+        handle.sync().unwrap();
+        handle.punch_hole(0, 5).unwrap();
+        fs.crash();
+        assert_eq!(read_all(&fs, &a), b"hello world");
+    }
+
+    #[test]
+    fn test_mem_file_system_torn_write() {
+        let fs = MemFileSystem::new();
+        let dir = Path::new("dir");
+        let path = dir.join("a");
+        let handle = Arc::new(fs.create(&path).unwrap());
+        fs.sync_dir(dir).unwrap();
+        let mut writer = fs.new_writer(handle.clone()).unwrap();
+        writer.write_all(b"hello").unwrap();
+        handle.sync().unwrap();
+        writer.write_all(b" world").unwrap();
+        writer.write_all(b" again").unwrap();
+        fs.crash_with_torn_write(3);
+        assert_eq!(read_all(&fs, &path), b"hello\0\0\0\0\0\0 ag");
+
+        // A synced write can't be torn.
+        let handle = Arc::new(fs.open(&path).unwrap());
+        handle.truncate(5).unwrap();
+        handle.sync().unwrap();
+        fs.crash_with_torn_write(1);
+        assert_eq!(read_all(&fs, &path), b"hello");
+    }
+}
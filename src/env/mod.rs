@@ -1,16 +1,22 @@
 // Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
 
 use std::io::{Read, Result, Seek, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 mod default;
+mod memory;
 mod obfuscated;
 
 pub use default::DefaultFileSystem;
+pub use memory::MemFileSystem;
 pub use obfuscated::ObfuscatedFileSystem;
 
 use crate::pipe_log::FileBlockHandle;
+
+/// A lock on a directory, which is released when dropped.
+pub type DirLock = Box<dyn Send + Sync>;
+
 /// FileSystem
 pub trait FileSystem: Send + Sync {
     type Handle: Send + Sync + Handle;
@@ -18,6 +24,8 @@ pub trait FileSystem: Send + Sync {
     type Writer: Seek + Write + Send + WriteExt;
     type MultiReadContext;
 
+    /// Submits a read of `block` to `ctx`. `handle` must be kept alive by
+    /// `ctx` until the read is finished by `async_finish` or `ctx` is dropped.
     fn multi_read(
         &self,
         ctx: &mut Self::MultiReadContext,
@@ -62,6 +70,61 @@ pub trait FileSystem: Send + Sync {
         false
     }
 
+    /// Returns whether a file exists at `path`. The default implementation
+    /// queries the local file system.
+    fn exists<P: AsRef<Path>>(&self, path: P) -> bool {
+        path.as_ref().exists()
+    }
+
+    /// Returns whether `path` is an existing directory. The default
+    /// implementation queries the local file system.
+    fn is_dir<P: AsRef<Path>>(&self, path: P) -> bool {
+        path.as_ref().is_dir()
+    }
+
+    /// Returns the paths of all files directly under directory `path`. The
+    /// default implementation queries the local file system.
+    fn list_files<P: AsRef<Path>>(&self, path: P) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for e in std::fs::read_dir(path)?.flatten() {
+            let p = e.path();
+            if p.is_file() {
+                files.push(p);
+            }
+        }
+        Ok(files)
+    }
+
+    /// Creates directory `path`. The default implementation creates it on the
+    /// local file system.
+    fn create_dir<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        std::fs::create_dir(path)
+    }
+
+    /// Exclusively locks directory `path` until the returned lock is dropped,
+    /// so that it's not used by another instance. The default implementation
+    /// locks a file under it on the local file system.
+    fn lock_dir<P: AsRef<Path>>(&self, path: P) -> Result<DirLock> {
+        Ok(Box::new(crate::file_pipe_log::lock_dir(path)?))
+    }
+
+    /// Deletes directory `path` along with all files under it. Files are
+    /// deleted via [`FileSystem::delete`], and the default implementation
+    /// removes the emptied directory from the local file system.
+    fn delete_dir<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        for p in self.list_files(path.as_ref())? {
+            self.delete(p)?;
+        }
+        std::fs::remove_dir(path)
+    }
+
+    /// Persists the entries of directory `path`, i.e. creations, deletions and
+    /// renames of files under it. The default implementation syncs the
+    /// directory of the local file system.
+    fn sync_dir<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        std::fs::File::open(path).and_then(|d| d.sync_all())
+    }
+
     fn new_reader(&self, handle: Arc<Self::Handle>) -> Result<Self::Reader>;
 
     fn new_writer(&self, handle: Arc<Self::Handle>) -> Result<Self::Writer>;
@@ -76,6 +139,17 @@ pub trait Handle {
     fn file_size(&self) -> Result<usize>;
 
     fn sync(&self) -> Result<()>;
+
+    /// Deallocates the space of `size` bytes starting at `offset`. Subsequent
+    /// reads of this range return zeros, and the file size is left unchanged.
+    ///
+    /// The default implementation reports the operation as unsupported.
+    fn punch_hole(&self, _offset: usize, _size: usize) -> Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "punch_hole",
+        ))
+    }
 }
 
 /// WriteExt is writer extension api
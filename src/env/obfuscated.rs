@@ -102,7 +102,7 @@ impl FileSystem for ObfuscatedFileSystem {
     }
 
     fn async_finish(&self, ctx: Self::MultiReadContext) -> IoResult<Vec<Vec<u8>>> {
-        let mut base = self.inner.async_finish(ctx).unwrap();
+        let mut base = self.inner.async_finish(ctx)?;
 
         for v in base.iter_mut() {
             for c in v.iter_mut() {
@@ -25,6 +25,10 @@ pub enum Error {
     EntryNotFound,
     #[error("Full")]
     Full,
+    #[error("Cancelled")]
+    Cancelled,
+    #[error("Not Ready")]
+    NotReady,
     #[error("Other Error: {0}")]
     Other(#[from] Box<dyn error::Error + Send + Sync>),
 }
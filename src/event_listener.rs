@@ -1,6 +1,7 @@
 // Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
 
 use crate::pipe_log::{FileBlockHandle, FileId, FileSeq, LogQueue};
+use crate::recovery::RecoveryReport;
 
 /// `EventListener` contains a set of callback functions that will be notified
 /// on specific events inside Raft Engine.
@@ -34,4 +35,13 @@ pub trait EventListener: Sync + Send {
 
     /// Called *after* a log file is purged.
     fn post_purge(&self, _file_id: FileId) {}
+
+    /// Called *after* the engine is recovered from existing log files, before
+    /// it's returned to the user.
+    fn post_recovery(&self, _report: &RecoveryReport) {}
+
+    /// Called when background scrubbing finds corrupted data at `offset` of a
+    /// log file. `regions` are the Raft Groups that are known to have data in
+    /// the corrupted part, which may need to be restored from other replicas.
+    fn on_corruption_detected(&self, _file_id: FileId, _offset: u64, _regions: &[u64]) {}
 }
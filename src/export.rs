@@ -0,0 +1,337 @@
+// Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
+
+//! Moving Raft Groups across Raft Engine directories.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::info;
+
+use crate::codec::{self, NumberEncoder};
+use crate::env::FileSystem;
+use crate::inspector::Inspector;
+use crate::util::crc32;
+use crate::{Config, Engine, Error, Result};
+
+const EXPORT_MAGIC: u64 = 0x5241_4654_4558_5054;
+const EXPORT_VERSION: u64 = 1;
+
+/// Live log entries and key values of a Raft Group.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExportedRegion {
+    pub region_id: u64,
+    /// Index of the first entry in `entries`. Meaningless if there is no
+    /// entry.
+    pub first_index: u64,
+    /// Encoded log entries with continuous indexes.
+    pub entries: Vec<Vec<u8>>,
+    pub kvs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl ExportedRegion {
+    /// Returns the index span of exported entries.
+    pub fn span(&self) -> Option<(u64, u64)> {
+        if self.entries.is_empty() {
+            None
+        } else {
+            Some((
+                self.first_index,
+                self.first_index + self.entries.len() as u64 - 1,
+            ))
+        }
+    }
+}
+
+/// A set of exported Raft Groups.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExportedRegions {
+    pub regions: Vec<ExportedRegion>,
+}
+
+/// Summary of a Raft Group imported by [`Engine::import_regions`].
+///
+/// [`Engine::import_regions`]: crate::Engine::import_regions
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImportedRegion {
+    pub region_id: u64,
+    /// Index span of imported entries.
+    pub span: Option<(u64, u64)>,
+    pub entries: usize,
+    pub kvs: usize,
+}
+
+fn encode_bytes(buf: &mut Vec<u8>, bytes: &[u8]) -> Result<()> {
+    buf.encode_var_u64(bytes.len() as u64)?;
+    buf.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn decode_bytes(buf: &mut &[u8]) -> Result<Vec<u8>> {
+    let len = codec::decode_var_u64(buf)? as usize;
+    if buf.len() < len {
+        return Err(Error::Corruption("exported file is truncated".to_owned()));
+    }
+    let (bytes, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(bytes.to_vec())
+}
+
+impl ExportedRegions {
+    // { magic | version | region count | [regions] | crc32 }
+    //
+    // region: { id | first index | entry count | [entries] | kv count | [kvs] }
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        buf.encode_u64(EXPORT_MAGIC)?;
+        buf.encode_var_u64(EXPORT_VERSION)?;
+        buf.encode_var_u64(self.regions.len() as u64)?;
+        for region in &self.regions {
+            buf.encode_var_u64(region.region_id)?;
+            buf.encode_var_u64(region.first_index)?;
+            buf.encode_var_u64(region.entries.len() as u64)?;
+            for entry in &region.entries {
+                encode_bytes(&mut buf, entry)?;
+            }
+            buf.encode_var_u64(region.kvs.len() as u64)?;
+            for (key, value) in &region.kvs {
+                encode_bytes(&mut buf, key)?;
+                encode_bytes(&mut buf, value)?;
+            }
+        }
+        let checksum = crc32(&buf);
+        buf.encode_u32_le(checksum)?;
+        Ok(buf)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 4 {
+            return Err(Error::Corruption("exported file is truncated".to_owned()));
+        }
+        let (mut buf, mut footer) = buf.split_at(buf.len() - 4);
+        if codec::decode_u32_le(&mut footer)? != crc32(buf) {
+            return Err(Error::Corruption(
+                "exported file checksum mismatch".to_owned(),
+            ));
+        }
+        if codec::decode_u64(&mut buf)? != EXPORT_MAGIC {
+            return Err(Error::Corruption("exported file magic mismatch".to_owned()));
+        }
+        let version = codec::decode_var_u64(&mut buf)?;
+        if version != EXPORT_VERSION {
+            return Err(Error::Corruption(format!(
+                "unsupported exported file version {version}"
+            )));
+        }
+        let mut exported = ExportedRegions::default();
+        let count = codec::decode_var_u64(&mut buf)?;
+        for _ in 0..count {
+            let mut region = ExportedRegion {
+                region_id: codec::decode_var_u64(&mut buf)?,
+                first_index: codec::decode_var_u64(&mut buf)?,
+                ..Default::default()
+            };
+            let entries = codec::decode_var_u64(&mut buf)?;
+            for _ in 0..entries {
+                region.entries.push(decode_bytes(&mut buf)?);
+            }
+            let kvs = codec::decode_var_u64(&mut buf)?;
+            for _ in 0..kvs {
+                let key = decode_bytes(&mut buf)?;
+                region.kvs.push((key, decode_bytes(&mut buf)?));
+            }
+            exported.regions.push(region);
+        }
+        Ok(exported)
+    }
+}
+
+/// Summary of [`Engine::rewrite_directory`].
+///
+/// [`Engine::rewrite_directory`]: crate::Engine::rewrite_directory
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DirectoryRewriteReport {
+    pub regions: Vec<ImportedRegion>,
+    /// Total size of log files before rewrite.
+    pub size_before: usize,
+    /// Total size of log files after rewrite.
+    pub size_after: usize,
+    /// Where the original log files are moved to.
+    pub backup_dir: PathBuf,
+}
+
+fn sibling_dir(dir: &Path, suffix: &str) -> PathBuf {
+    let mut name = dir.file_name().unwrap_or_default().to_owned();
+    name.push(suffix);
+    dir.with_file_name(name)
+}
+
+fn total_size<F: FileSystem>(inspector: &Inspector<F>) -> usize {
+    inspector.files().iter().map(|f| f.size).sum()
+}
+
+fn sync_parent_dir<F: FileSystem>(file_system: &F, dir: &Path) -> Result<()> {
+    if let Some(parent) = dir.parent() {
+        file_system.sync_dir(parent)?;
+    }
+    Ok(())
+}
+
+/// Finishes a [`rewrite_directory`] that is interrupted after the original
+/// directory is moved away but before the rewritten one is moved into place.
+/// Called before opening `dir`.
+pub(crate) fn recover_directory_swap<F: FileSystem>(file_system: &F, dir: &Path) -> Result<()> {
+    if file_system.exists(dir) {
+        return Ok(());
+    }
+    let tmp_dir = sibling_dir(dir, ".rewrite");
+    let backup_dir = sibling_dir(dir, ".bak");
+    // The original directory is only moved away after the rewritten one is
+    // verified and persisted.
+    if file_system.is_dir(&backup_dir) && file_system.is_dir(&tmp_dir) {
+        info!(
+            "Moving rewritten directory {} to {}, original files are in {}",
+            tmp_dir.display(),
+            dir.display(),
+            backup_dir.display()
+        );
+        file_system.rename(tmp_dir.as_path(), dir)?;
+        sync_parent_dir(file_system, dir)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn rewrite_directory<F: FileSystem>(
+    cfg: Config,
+    file_system: Arc<F>,
+) -> Result<DirectoryRewriteReport> {
+    let dir = PathBuf::from(&cfg.dir);
+    if !file_system.is_dir(&dir) {
+        return Err(Error::InvalidArgument(format!(
+            "raft-engine directory '{}' does not exist.",
+            dir.display()
+        )));
+    }
+    let tmp_dir = sibling_dir(&dir, ".rewrite");
+    let backup_dir = sibling_dir(&dir, ".bak");
+    for d in [&tmp_dir, &backup_dir] {
+        if file_system.exists(d) {
+            return Err(Error::InvalidArgument(format!(
+                "'{}' already exists",
+                d.display()
+            )));
+        }
+    }
+    // Held until the directory is swapped, so that no engine can open it.
+    let _lock = file_system.lock_dir(&dir)?;
+    let source = Inspector::open(file_system.clone(), &dir)?;
+    if let Some(f) = source.files().iter().find(|f| f.corruption.is_some()) {
+        return Err(Error::Corruption(format!(
+            "log file {:?} is corrupted: {}",
+            f.file_id,
+            f.corruption.as_ref().unwrap()
+        )));
+    }
+
+    let res = (|| {
+        let engine = Engine::open_with_file_system(
+            Config {
+                dir: tmp_dir.to_str().unwrap().to_owned(),
+                ..cfg
+            },
+            file_system.clone(),
+        )?;
+        let mut regions = Vec::new();
+        for id in source.raft_groups() {
+            let region = source.export_region(id)?;
+            if region.entries.is_empty() && region.kvs.is_empty() {
+                continue;
+            }
+            regions.extend(engine.import_regions(&ExportedRegions {
+                regions: vec![region],
+            })?);
+        }
+        drop(engine);
+
+        let corrupted = Engine::consistency_check_with_file_system(&tmp_dir, file_system.clone())?;
+        if let Some((id, index)) = corrupted.first() {
+            return Err(Error::Corruption(format!(
+                "rewritten Raft Group {} is only intact until {}",
+                id, index
+            )));
+        }
+        let target = Inspector::open(file_system.clone(), &tmp_dir)?;
+        for region in &regions {
+            let info = target.raft_group(region.region_id)?;
+            let span = info
+                .as_ref()
+                .and_then(|info| info.first_index.zip(info.last_index));
+            let keys = info.as_ref().map_or(0, |info| info.keys.len());
+            if span != region.span || keys != region.kvs {
+                return Err(Error::Corruption(format!(
+                    "rewritten Raft Group {} mismatches: {:?} and {} keys, expect {:?} and {} keys",
+                    region.region_id, span, keys, region.span, region.kvs
+                )));
+            }
+        }
+        Ok(DirectoryRewriteReport {
+            regions,
+            size_before: total_size(&source),
+            size_after: total_size(&target),
+            backup_dir: backup_dir.clone(),
+        })
+    })();
+    let report = match res {
+        Ok(report) => report,
+        Err(e) => {
+            let _ = file_system.delete_dir(&tmp_dir);
+            return Err(e);
+        }
+    };
+
+    // If interrupted after the first rename, the swap is finished by
+    // `recover_directory_swap` when the directory is opened.
+    file_system.rename(&dir, &backup_dir)?;
+    sync_parent_dir(file_system.as_ref(), &dir)?;
+    if let Err(e) = file_system.rename(&tmp_dir, &dir) {
+        file_system.rename(&backup_dir, &dir)?;
+        return Err(e.into());
+    }
+    sync_parent_dir(file_system.as_ref(), &dir)?;
+    info!(
+        "Rewrote {} Raft Groups under {}, original files are moved to {}",
+        report.regions.len(),
+        dir.display(),
+        backup_dir.display()
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exported_regions_codec() {
+        let exported = ExportedRegions {
+            regions: vec![
+                ExportedRegion {
+                    region_id: 1,
+                    first_index: 5,
+                    entries: vec![b"e5".to_vec(), vec![], b"e7".to_vec()],
+                    kvs: vec![(b"k".to_vec(), b"v".to_vec())],
+                },
+                ExportedRegion {
+                    region_id: 2,
+                    ..Default::default()
+                },
+            ],
+        };
+        assert_eq!(exported.regions[0].span(), Some((5, 7)));
+        assert_eq!(exported.regions[1].span(), None);
+        let mut buf = exported.encode().unwrap();
+        assert_eq!(ExportedRegions::decode(&buf).unwrap(), exported);
+        buf[10] ^= 1;
+        assert!(ExportedRegions::decode(&buf).is_err());
+        assert!(ExportedRegions::decode(&buf[..3]).is_err());
+    }
+}
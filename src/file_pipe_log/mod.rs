@@ -11,7 +11,8 @@ mod pipe_builder;
 mod reader;
 
 pub use format::{parse_recycled_file_name, FileNameExt};
-pub use pipe::DualPipes as FilePipeLog;
+pub use pipe::{DualPipes as FilePipeLog, PendingRead};
+pub(crate) use pipe_builder::lock_dir;
 pub use pipe_builder::{
     DefaultMachineFactory, DualPipesBuilder as FilePipeLogBuilder, RecoveryConfig, ReplayMachine,
 };
@@ -24,12 +25,13 @@ pub mod debug {
     use std::sync::Arc;
 
     use crate::env::FileSystem;
-    use crate::log_batch::LogItem;
-    use crate::pipe_log::FileId;
+    use crate::log_batch::{LogItem, LogItemBatch};
+    use crate::pipe_log::{FileId, Version};
     use crate::{Error, Result};
 
     use super::format::{FileNameExt, LogFileFormat};
     use super::log_file::{LogFileReader, LogFileWriter};
+    use super::pipe_builder::ReplayMachine;
     use super::reader::LogItemBatchFileReader;
 
     /// Opens a log file for write. When `create` is true, the specified file
@@ -59,19 +61,71 @@ pub mod debug {
         super::log_file::build_file_reader(file_system, fd)
     }
 
+    /// Summary of a log file read by [`replay_file`].
+    pub struct ReplayedFile {
+        pub version: Version,
+        pub alignment: u64,
+        /// File offset of the end of the last intact log batch.
+        pub valid_offset: usize,
+        /// The error that stops reading the file, if any.
+        pub corruption: Option<Error>,
+    }
+
+    /// Replays all intact log batches of a log file to `machine`. Reading stops
+    /// at the first corrupted log batch. Unlike recovery, the file is never
+    /// modified.
+    pub fn replay_file<F: FileSystem, M: ReplayMachine>(
+        file_system: &F,
+        path: &Path,
+        file_id: FileId,
+        machine: &mut M,
+    ) -> Result<ReplayedFile> {
+        let mut reader = build_file_reader(file_system, path)?;
+        let format = reader.parse_format()?;
+        let mut batch_reader = LogItemBatchFileReader::new(0);
+        batch_reader.open(file_id, format, reader)?;
+        let mut corruption = None;
+        loop {
+            match batch_reader.next() {
+                Ok(Some(item_batch)) => machine.replay(item_batch, file_id)?,
+                Ok(None) => break,
+                Err(e) => {
+                    corruption = Some(e);
+                    break;
+                }
+            }
+        }
+        Ok(ReplayedFile {
+            version: format.version,
+            alignment: format.alignment,
+            valid_offset: batch_reader.valid_offset(),
+            corruption,
+        })
+    }
+
+    /// Location of a log item in log files.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct LogItemPosition {
+        pub file_id: FileId,
+        /// File offset of the log batch that contains the item.
+        pub batch_offset: usize,
+    }
+
     /// An iterator over the log items in log files.
     pub struct LogItemReader<F: FileSystem> {
         system: Arc<F>,
         files: VecDeque<(FileId, PathBuf)>,
+        file_id: Option<FileId>,
         batch_reader: LogItemBatchFileReader<F>,
-        items: VecDeque<LogItem>,
+        items: VecDeque<(LogItemPosition, LogItem)>,
     }
 
     impl<F: FileSystem> Iterator for LogItemReader<F> {
         type Item = Result<LogItem>;
 
         fn next(&mut self) -> Option<Self::Item> {
-            self.next()
+            self.next_with_position()
+                .map(|item| item.map(|(_, item)| item))
         }
     }
 
@@ -95,6 +149,7 @@ pub mod debug {
             Ok(Self {
                 system,
                 files: vec![(file_id.unwrap(), file.into())].into(),
+                file_id: None,
                 batch_reader: LogItemBatchFileReader::new(0),
                 items: VecDeque::new(),
             })
@@ -128,17 +183,20 @@ pub mod debug {
             Ok(Self {
                 system,
                 files: files.into(),
+                file_id: None,
                 batch_reader: LogItemBatchFileReader::new(0),
                 items: VecDeque::new(),
             })
         }
 
-        fn next(&mut self) -> Option<Result<LogItem>> {
+        /// Returns the next log item along with its location.
+        pub fn next_with_position(&mut self) -> Option<Result<(LogItemPosition, LogItem)>> {
             if self.items.is_empty() {
+                let batch_offset = self.batch_reader.valid_offset();
                 let next_batch = self.batch_reader.next();
                 match next_batch {
                     Ok(Some(b)) => {
-                        self.items.extend(b.into_items());
+                        self.push_items(b, batch_offset);
                     }
                     Ok(None) => {
                         if let Err(e) = self.find_next_readable_file() {
@@ -155,13 +213,24 @@ pub mod debug {
             self.items.pop_front().map(Ok)
         }
 
+        fn push_items(&mut self, batch: LogItemBatch, batch_offset: usize) {
+            let position = LogItemPosition {
+                file_id: self.file_id.unwrap(),
+                batch_offset,
+            };
+            self.items
+                .extend(batch.into_items().into_iter().map(|item| (position, item)));
+        }
+
         fn find_next_readable_file(&mut self) -> Result<()> {
             while let Some((file_id, path)) = self.files.pop_front() {
                 let mut reader = build_file_reader(self.system.as_ref(), &path)?;
                 let format = reader.parse_format()?;
                 self.batch_reader.open(file_id, format, reader)?;
+                self.file_id = Some(file_id);
+                let batch_offset = self.batch_reader.valid_offset();
                 if let Some(b) = self.batch_reader.next()? {
-                    self.items.extend(b.into_items());
+                    self.push_items(b, batch_offset);
                     break;
                 }
             }
@@ -218,8 +287,10 @@ pub mod debug {
                 )
                 .unwrap();
                 let log_file_format = LogFileContext::new(file_id, Version::default());
+                let mut offsets = Vec::new();
                 for batch in bs.iter_mut() {
                     let offset = writer.offset() as u64;
+                    offsets.push(offset as usize);
                     let len = batch
                         .finish_populate(1 /* compression_threshold */)
                         .unwrap();
@@ -237,9 +308,17 @@ pub mod debug {
                 // Read and verify.
                 let mut reader =
                     LogItemReader::new_file_reader(file_system.clone(), &file_path).unwrap();
-                for batch in bs {
+                for (batch, batch_offset) in bs.iter().zip(offsets) {
                     for item in batch.clone().drain() {
-                        assert_eq!(item, reader.next().unwrap().unwrap());
+                        let (position, read) = reader.next_with_position().unwrap().unwrap();
+                        assert_eq!(item, read);
+                        assert_eq!(
+                            position,
+                            LogItemPosition {
+                                file_id,
+                                batch_offset
+                            }
+                        );
                     }
                 }
                 assert!(reader.next().is_none());
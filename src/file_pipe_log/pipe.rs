@@ -1,8 +1,8 @@
 // Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
 
 use std::collections::VecDeque;
-use std::fs::File as StdFile;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use crossbeam::utils::CachePadded;
@@ -11,18 +11,24 @@ use log::error;
 use parking_lot::{Mutex, MutexGuard, RwLock};
 
 use crate::config::Config;
-use crate::env::FileSystem;
+use crate::env::{DirLock, FileSystem, Handle};
 use crate::event_listener::EventListener;
+use crate::log_batch::{CompressionType, LogBatch, LogItemBatch, LogItemContent};
 use crate::memtable::EntryIndex;
 use crate::metrics::*;
 use crate::pipe_log::{
-    FileBlockHandle, FileId, FileSeq, LogFileContext, LogQueue, PipeLog, ReactiveBytes,
+    FileBlockHandle, FileId, FileSeq, LogFileContext, LogQueue, PipeLog, QueueIoStats,
+    ReactiveBytes,
 };
 use crate::{perf_context, Error, Result};
 
 use super::format::{build_recycled_file_name, FileNameExt, LogFileFormat};
 use super::log_file::build_file_reader;
 use super::log_file::{build_file_writer, LogFileWriter};
+use super::reader::LogItemBatchFileReader;
+
+/// Size of reads issued when scrubbing log batches of a file.
+const SCRUB_READ_BLOCK_SIZE: usize = 64 * 1024;
 
 pub const DEFAULT_PATH_ID: PathId = 0;
 /// FileSeq of logs must start from `1` by default to keep backward
@@ -53,9 +59,13 @@ pub(super) struct SinglePipe<F: FileSystem> {
     file_system: Arc<F>,
     listeners: Vec<Arc<dyn EventListener>>,
     default_format: LogFileFormat,
-    target_file_size: usize,
+    target_file_size: AtomicUsize,
 
-    capacity: usize,
+    capacity: AtomicUsize,
+    /// Total bytes of active files.
+    size: AtomicUsize,
+    appended_bytes: AtomicU64,
+    purged_bytes: AtomicU64,
     active_files: CachePadded<RwLock<VecDeque<File<F>>>>,
     recycled_files: CachePadded<RwLock<VecDeque<File<F>>>>,
 
@@ -120,10 +130,14 @@ impl<F: FileSystem> SinglePipe<F> {
         };
 
         let len = active_files.len();
+        let mut size = writable_file.writer.offset();
         for f in active_files.iter() {
             for listener in &listeners {
                 listener.post_new_log_file(FileId { queue, seq: f.seq });
             }
+            if f.seq != writable_file.seq {
+                size += f.handle.file_size()?;
+            }
         }
 
         let pipe = Self {
@@ -132,26 +146,49 @@ impl<F: FileSystem> SinglePipe<F> {
             file_system,
             listeners,
             default_format,
-            target_file_size: cfg.target_file_size.0 as usize,
-            capacity: if queue == LogQueue::Append {
-                cfg.recycle_capacity()
-            } else {
-                0
-            },
+            target_file_size: AtomicUsize::new(cfg.target_file_size.0 as usize),
+            capacity: AtomicUsize::new(Self::capacity(cfg, queue)),
+            size: AtomicUsize::new(size),
+            appended_bytes: AtomicU64::new(0),
+            purged_bytes: AtomicU64::new(0),
             active_files: RwLock::new(active_files.into()).into(),
             recycled_files: RwLock::new(recycled_files.into()).into(),
             writable_file: Mutex::new(writable_file).into(),
         };
+        if no_active_files {
+            // Persist the entry of newly created file.
+            pipe.sync_dir()?;
+        }
         pipe.flush_metrics(len);
         Ok(pipe)
     }
 
+    fn capacity(cfg: &Config, queue: LogQueue) -> usize {
+        if queue == LogQueue::Append {
+            cfg.recycle_capacity()
+        } else {
+            0
+        }
+    }
+
+    /// Applies changes of `target_file_size` and recycle capacity. Takes
+    /// effect on subsequent writes and purges.
+    fn update_config(&self, cfg: &Config) {
+        self.target_file_size
+            .store(cfg.target_file_size.0 as usize, Ordering::Relaxed);
+        self.capacity
+            .store(Self::capacity(cfg, self.queue), Ordering::Relaxed);
+    }
+
+    fn target_file_size(&self) -> usize {
+        self.target_file_size.load(Ordering::Relaxed)
+    }
+
     /// Synchronizes all metadatas associated with the working directory to the
     /// filesystem.
     fn sync_dir(&self) -> Result<()> {
         debug_assert!(!self.paths.is_empty());
-        let path = PathBuf::from(&self.paths[0]);
-        std::fs::File::open(path).and_then(|d| d.sync_all())?;
+        self.file_system.sync_dir(&self.paths[0])?;
         Ok(())
     }
 
@@ -221,6 +258,8 @@ impl<F: FileSystem> SinglePipe<F> {
         new_file.writer.sync()?;
         self.sync_dir()?;
 
+        self.size
+            .fetch_add(new_file.writer.offset(), Ordering::Relaxed);
         **writable_file = new_file;
         let len = {
             let mut files = self.active_files.write();
@@ -239,9 +278,16 @@ impl<F: FileSystem> SinglePipe<F> {
 
     /// Synchronizes current states to related metrics.
     fn flush_metrics(&self, len: usize) {
+        let size = self.size.load(Ordering::Relaxed) as i64;
         match self.queue {
-            LogQueue::Append => LOG_FILE_COUNT.append.set(len as i64),
-            LogQueue::Rewrite => LOG_FILE_COUNT.rewrite.set(len as i64),
+            LogQueue::Append => {
+                LOG_FILE_COUNT.append.set(len as i64);
+                LOG_FILE_BYTES.append.set(size);
+            }
+            LogQueue::Rewrite => {
+                LOG_FILE_COUNT.rewrite.set(len as i64);
+                LOG_FILE_BYTES.rewrite.set(size);
+            }
         }
     }
 }
@@ -255,19 +301,17 @@ impl<F: FileSystem> SinglePipe<F> {
         reader.read(handle)
     }
 
-    fn async_read(&self, ctx: &mut F::MultiReadContext, blocks: Vec<FileBlockHandle>) {
-        for block in blocks.iter() {
-            let fd = self.get_fd(block.id.seq).unwrap();
-            self.file_system
-                .multi_read(ctx, fd, block)
-                .expect("Async read failed.");
-        }
+    fn submit_read(&self, ctx: &mut F::MultiReadContext, block: &FileBlockHandle) -> Result<()> {
+        let fd = self.get_fd(block.id.seq)?;
+        self.file_system.multi_read(ctx, fd, block)?;
+        Ok(())
     }
 
     fn append<T: ReactiveBytes + ?Sized>(&self, bytes: &mut T) -> Result<FileBlockHandle> {
         fail_point!("file_pipe_log::append");
         let mut writable_file = self.writable_file.lock();
-        if writable_file.writer.offset() >= self.target_file_size {
+        let target_file_size = self.target_file_size();
+        if writable_file.writer.offset() >= target_file_size {
             if let Err(e) = self.rotate_imp(&mut writable_file) {
                 panic!(
                     "error when rotate [{:?}:{}]: {}",
@@ -283,6 +327,7 @@ impl<F: FileSystem> SinglePipe<F> {
             version: format.version,
         };
         let writer = &mut writable_file.writer;
+        let prev_offset = writer.offset();
 
         #[cfg(feature = "failpoints")]
         {
@@ -300,12 +345,12 @@ impl<F: FileSystem> SinglePipe<F> {
                     if corrupted_padding() {
                         zeros[len - 1] = 8_u8;
                     }
-                    writer.write(&zeros, self.target_file_size)?;
+                    writer.write(&zeros, target_file_size)?;
                 }
             }
         }
         let start_offset = writer.offset();
-        if let Err(e) = writer.write(bytes.as_bytes(&ctx), self.target_file_size) {
+        if let Err(e) = writer.write(bytes.as_bytes(&ctx), target_file_size) {
             if let Err(te) = writer.truncate() {
                 panic!(
                     "error when truncate {} after error: {}, get: {}",
@@ -322,6 +367,20 @@ impl<F: FileSystem> SinglePipe<F> {
             offset: start_offset as u64,
             len: writer.offset() - start_offset,
         };
+        let written = writer.offset() - prev_offset;
+        let size = self.size.fetch_add(written, Ordering::Relaxed) + written;
+        self.appended_bytes
+            .fetch_add(written as u64, Ordering::Relaxed);
+        match self.queue {
+            LogQueue::Append => {
+                LOG_FILE_BYTES.append.set(size as i64);
+                LOG_APPENDED_BYTES.append.inc_by(written as u64);
+            }
+            LogQueue::Rewrite => {
+                LOG_FILE_BYTES.rewrite.set(size as i64);
+                LOG_APPENDED_BYTES.rewrite.inc_by(written as u64);
+            }
+        }
         for listener in &self.listeners {
             listener.on_append_log_file(handle);
         }
@@ -348,8 +407,29 @@ impl<F: FileSystem> SinglePipe<F> {
     }
 
     fn total_size(&self) -> usize {
-        let (first_seq, last_seq) = self.file_span();
-        (last_seq - first_seq + 1) as usize * self.target_file_size
+        self.size.load(Ordering::Relaxed)
+    }
+
+    fn file_sizes(&self) -> Result<Vec<(FileSeq, usize)>> {
+        let writable_file = self.writable_file.lock();
+        let files = self.active_files.read();
+        let mut sizes = Vec::with_capacity(files.len());
+        for f in files.iter() {
+            let size = if f.seq == writable_file.seq {
+                writable_file.writer.offset()
+            } else {
+                f.handle.file_size()?
+            };
+            sizes.push((f.seq, size));
+        }
+        Ok(sizes)
+    }
+
+    fn io_stats(&self) -> QueueIoStats {
+        QueueIoStats {
+            appended_bytes: self.appended_bytes.load(Ordering::Relaxed),
+            purged_bytes: self.purged_bytes.load(Ordering::Relaxed),
+        }
     }
 
     fn rotate(&self) -> Result<()> {
@@ -363,13 +443,20 @@ impl<F: FileSystem> SinglePipe<F> {
                 return Err(box_err!("FileSeq out of range, cannot be purged"));
             }
             let off = (file_seq - files[0].seq) as usize;
+            // Collect sizes before detaching the files, so that an error doesn't
+            // leave purged files untracked.
+            let sizes = files
+                .iter()
+                .take(off)
+                .map(|f| f.handle.file_size())
+                .collect::<std::io::Result<Vec<_>>>()?;
             let mut tail = files.split_off(off);
             std::mem::swap(&mut tail, &mut files);
-            (files.len(), tail)
+            (files.len(), tail.into_iter().zip(sizes))
         };
         let purged_len = purged_files.len();
         if purged_len > 0 {
-            let remains_capacity = self.capacity.saturating_sub(len);
+            let remains_capacity = self.capacity.load(Ordering::Relaxed).saturating_sub(len);
             let (recycled_start, mut recycled_len) = {
                 let files = self.recycled_files.read();
                 files
@@ -380,11 +467,17 @@ impl<F: FileSystem> SinglePipe<F> {
             // The newly purged files from `self.active_files` should be renamed
             // to recycled files with LOG_APPEND_RESERVED_SUFFIX suffix, to reduce the
             // unnecessary recovery timecost when restarting.
-            for f in purged_files {
+            for (f, size) in purged_files {
                 let file_id = FileId {
                     seq: f.seq,
                     queue: self.queue,
                 };
+                self.size.fetch_sub(size, Ordering::Relaxed);
+                self.purged_bytes.fetch_add(size as u64, Ordering::Relaxed);
+                match self.queue {
+                    LogQueue::Append => LOG_PURGED_BYTES.append.inc_by(size as u64),
+                    LogQueue::Rewrite => LOG_PURGED_BYTES.rewrite.inc_by(size as u64),
+                }
                 let path = file_id.build_file_path(&self.paths[f.path_id]);
                 // Recycle purged files whose version meets the requirement.
                 if f.format.version.has_log_signing() && recycled_len < remains_capacity {
@@ -409,26 +502,40 @@ impl<F: FileSystem> SinglePipe<F> {
                 // marked not recycled.
                 self.file_system.delete(&path)?;
             }
-            debug_assert!(recycled_len <= remains_capacity);
+            // Capacity might have been reduced at runtime.
+            debug_assert!(recycled_len <= remains_capacity || new_recycled.is_empty());
             self.recycled_files.write().append(&mut new_recycled);
         }
         self.flush_metrics(len);
         Ok(purged_len)
     }
+
+    fn punch_hole(&self, handle: FileBlockHandle) -> Result<()> {
+        let fd = {
+            let files = self.active_files.read();
+            let (first, last) = (files[0].seq, files[files.len() - 1].seq);
+            if !(first..last).contains(&handle.id.seq) {
+                return Err(box_err!("FileSeq out of range, cannot be punched"));
+            }
+            files[(handle.id.seq - first) as usize].handle.clone()
+        };
+        fd.punch_hole(handle.offset as usize, handle.len)?;
+        Ok(())
+    }
 }
 
 /// A [`PipeLog`] implementation that stores data in filesystem.
 pub struct DualPipes<F: FileSystem> {
     pipes: [SinglePipe<F>; 2],
 
-    _dir_lock: StdFile,
+    _dir_lock: DirLock,
 }
 
 impl<F: FileSystem> DualPipes<F> {
     /// Open a new [`DualPipes`]. Assumes the two [`SinglePipe`]s share the
     /// same directory, and that directory is locked by `dir_lock`.
     pub(super) fn open(
-        dir_lock: StdFile,
+        dir_lock: DirLock,
         appender: SinglePipe<F>,
         rewriter: SinglePipe<F>,
     ) -> Result<Self> {
@@ -446,23 +553,171 @@ impl<F: FileSystem> DualPipes<F> {
     pub fn file_system(&self) -> Arc<F> {
         self.pipes[0].file_system.clone()
     }
+
+    /// Reads through log items in the specified log files, and passes them to
+    /// `replay` in order.
+    pub fn replay_files(
+        &self,
+        files: &[FileId],
+        read_block_size: usize,
+        replay: &mut dyn FnMut(LogItemBatch, FileId) -> Result<()>,
+    ) -> Result<()> {
+        let mut reader = LogItemBatchFileReader::new(read_block_size);
+        for file_id in files {
+            let pipe = &self.pipes[file_id.queue as usize];
+            let mut file_reader =
+                build_file_reader(pipe.file_system.as_ref(), pipe.get_fd(file_id.seq)?)?;
+            let format = file_reader.parse_format()?;
+            reader.open(*file_id, format, file_reader)?;
+            while let Some(item_batch) = reader.next()? {
+                replay(item_batch, *file_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads through the specified log file, and verifies the checksums of
+    /// its log batches and blocks of log entries. `throttle` is called with
+    /// the size of each log batch, including its entries, scanning is aborted
+    /// if it fails. Corrupted parts are passed to `on_corruption` along with
+    /// their offsets and the Raft Groups whose entries are in them. Scanning stops at the first broken log batch, in
+    /// which case no Raft Group is known.
+    pub fn scrub_file(
+        &self,
+        file_id: FileId,
+        throttle: &mut dyn FnMut(usize) -> Result<()>,
+        on_corruption: &mut dyn FnMut(u64, Vec<u64>, Error),
+    ) -> Result<()> {
+        let pipe = &self.pipes[file_id.queue as usize];
+        let fd = pipe.get_fd(file_id.seq)?;
+        let mut file_reader = build_file_reader(pipe.file_system.as_ref(), fd.clone())?;
+        let format = file_reader.parse_format()?;
+        let mut reader = LogItemBatchFileReader::new(SCRUB_READ_BLOCK_SIZE);
+        reader.open(file_id, format, file_reader)?;
+        let mut block_reader = build_file_reader(pipe.file_system.as_ref(), fd)?;
+        let mut offset = 0;
+        loop {
+            let item_batch = match reader.next() {
+                Ok(Some(item_batch)) => item_batch,
+                Ok(None) => break,
+                Err(e) => {
+                    on_corruption(reader.valid_offset() as u64, Vec::new(), e);
+                    break;
+                }
+            };
+            throttle(reader.valid_offset() - offset)?;
+            offset = reader.valid_offset();
+
+            // Entries of a log batch are usually stored in one block.
+            let mut blocks: Vec<(FileBlockHandle, CompressionType, Vec<u64>, usize)> = Vec::new();
+            for item in item_batch.iter() {
+                if let LogItemContent::EntryIndexes(ents) = &item.content {
+                    for ei in &ents.0 {
+                        let handle = ei.entries.unwrap();
+                        let end = (ei.entry_offset + ei.entry_len) as usize;
+                        match blocks.iter_mut().find(|b| b.0 == handle) {
+                            Some((_, _, regions, max_end)) => {
+                                if !regions.contains(&item.raft_group_id) {
+                                    regions.push(item.raft_group_id);
+                                }
+                                *max_end = std::cmp::max(*max_end, end);
+                            }
+                            None => blocks.push((
+                                handle,
+                                ei.compression_type,
+                                vec![item.raft_group_id],
+                                end,
+                            )),
+                        }
+                    }
+                }
+            }
+            // Blocks are part of the log batch, which has been throttled.
+            for (handle, compression, regions, max_end) in blocks {
+                let buf = block_reader.read(handle)?;
+                let res = if buf.len() < handle.len {
+                    Err(Error::Corruption("entries block is truncated".to_owned()))
+                } else if buf.iter().all(|b| *b == 0) {
+                    // Reclaimed by hole punching.
+                    continue;
+                } else {
+                    LogBatch::decode_entries_block(&buf, handle, compression).and_then(|block| {
+                        if max_end > block.len() {
+                            Err(Error::Corruption("entry is out of block".to_owned()))
+                        } else {
+                            Ok(())
+                        }
+                    })
+                };
+                if let Err(e) = res {
+                    on_corruption(handle.offset, regions, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies runtime changes of `cfg` to both queues.
+    pub fn update_config(&self, cfg: &Config) {
+        for pipe in &self.pipes {
+            pipe.update_config(cfg);
+        }
+    }
+}
+
+/// Reads submitted by [`DualPipes::submit_read`], with a context for each
+/// queue.
+pub struct PendingRead<F: FileSystem> {
+    blocks: Vec<FileBlockHandle>,
+    append: F::MultiReadContext,
+    rewrite: F::MultiReadContext,
 }
 
 impl<F: FileSystem> PipeLog for DualPipes<F> {
+    type PendingRead = PendingRead<F>;
+
     #[inline]
     fn read_bytes(&self, handle: FileBlockHandle) -> Result<Vec<u8>> {
         self.pipes[handle.id.queue as usize].read_bytes(handle)
     }
 
-    #[inline]
-    fn async_read_bytes(&self, blocks: Vec<FileBlockHandle>) -> Result<Vec<Vec<u8>>> {
-        let fs = &self.pipes[LogQueue::Append as usize].file_system;
-        let mut ctx = fs.new_async_io_context()?;
-
-        self.pipes[LogQueue::Append as usize].async_read(&mut ctx, blocks);
-        let res = fs.async_finish(ctx)?;
+    fn submit_read(&self, blocks: Vec<FileBlockHandle>) -> Result<PendingRead<F>> {
+        let mut pending = PendingRead {
+            append: self.pipes[LogQueue::Append as usize]
+                .file_system
+                .new_async_io_context()?,
+            rewrite: self.pipes[LogQueue::Rewrite as usize]
+                .file_system
+                .new_async_io_context()?,
+            blocks,
+        };
+        for block in &pending.blocks {
+            let ctx = match block.id.queue {
+                LogQueue::Append => &mut pending.append,
+                LogQueue::Rewrite => &mut pending.rewrite,
+            };
+            self.pipes[block.id.queue as usize].submit_read(ctx, block)?;
+        }
+        Ok(pending)
+    }
 
-        Ok(res)
+    fn finish_read(&self, pending: PendingRead<F>) -> Result<Vec<Vec<u8>>> {
+        let mut append = self.pipes[LogQueue::Append as usize]
+            .file_system
+            .async_finish(pending.append)?
+            .into_iter();
+        let mut rewrite = self.pipes[LogQueue::Rewrite as usize]
+            .file_system
+            .async_finish(pending.rewrite)?
+            .into_iter();
+        Ok(pending
+            .blocks
+            .iter()
+            .map(|b| match b.id.queue {
+                LogQueue::Append => append.next().unwrap(),
+                LogQueue::Rewrite => rewrite.next().unwrap(),
+            })
+            .collect())
     }
 
     #[inline]
@@ -489,6 +744,16 @@ impl<F: FileSystem> PipeLog for DualPipes<F> {
         self.pipes[queue as usize].total_size()
     }
 
+    #[inline]
+    fn file_sizes(&self, queue: LogQueue) -> Result<Vec<(FileSeq, usize)>> {
+        self.pipes[queue as usize].file_sizes()
+    }
+
+    #[inline]
+    fn io_stats(&self, queue: LogQueue) -> QueueIoStats {
+        self.pipes[queue as usize].io_stats()
+    }
+
     #[inline]
     fn rotate(&self, queue: LogQueue) -> Result<()> {
         self.pipes[queue as usize].rotate()
@@ -498,6 +763,11 @@ impl<F: FileSystem> PipeLog for DualPipes<F> {
     fn purge_to(&self, file_id: FileId) -> Result<usize> {
         self.pipes[file_id.queue as usize].purge_to(file_id.seq)
     }
+
+    #[inline]
+    fn punch_hole(&self, handle: FileBlockHandle) -> Result<()> {
+        self.pipes[handle.id.queue as usize].punch_hole(handle)
+    }
 }
 
 #[cfg(test)]
@@ -505,7 +775,6 @@ mod tests {
     use tempfile::Builder;
 
     use super::super::format::LogFileFormat;
-    use super::super::pipe_builder::lock_dir;
     use super::*;
     use crate::env::{DefaultFileSystem, ObfuscatedFileSystem};
     use crate::pipe_log::Version;
@@ -521,7 +790,7 @@ mod tests {
 
     fn new_test_pipes(cfg: &Config) -> Result<DualPipes<DefaultFileSystem>> {
         DualPipes::open(
-            lock_dir(&cfg.dir)?,
+            DefaultFileSystem.lock_dir(&cfg.dir)?,
             new_test_pipe(cfg, LogQueue::Append, Arc::new(DefaultFileSystem))?,
             new_test_pipe(cfg, LogQueue::Rewrite, Arc::new(DefaultFileSystem))?,
         )
@@ -572,6 +841,7 @@ mod tests {
         assert_eq!(file_handle.id.seq, 2);
         assert_eq!(file_handle.offset, header_size);
         assert_eq!(pipe_log.file_span(queue).1, 2);
+        let purged_handle = file_handle;
 
         pipe_log.rotate(queue).unwrap();
 
@@ -611,9 +881,41 @@ mod tests {
         });
         assert!(abnormal_content_readed.is_err());
 
+        // async read from both queues
+        let r_content = b"rewrite content".to_vec();
+        let r_handle = pipe_log.append(LogQueue::Rewrite, &mut &r_content).unwrap();
+        let a_handle = FileBlockHandle {
+            id: FileId { queue, seq: 3 },
+            offset: header_size,
+            len: s_content.len(),
+        };
+        let bytes = pipe_log
+            .async_read_bytes(vec![r_handle, a_handle, r_handle])
+            .unwrap();
+        assert_eq!(bytes, vec![r_content.clone(), s_content.clone(), r_content]);
+        let abnormal_handle = FileBlockHandle {
+            id: FileId { queue, seq: 12 },
+            ..a_handle
+        };
+        assert!(pipe_log
+            .async_read_bytes(vec![a_handle, abnormal_handle])
+            .is_err());
+        // read beyond the end of file
+        let abnormal_handle = FileBlockHandle {
+            offset: 1 << 20,
+            ..a_handle
+        };
+        assert!(pipe_log
+            .async_read_bytes(vec![abnormal_handle, a_handle])
+            .is_err());
+
+        // reads in flight are not affected by purge
+        let pending = pipe_log.submit_read(vec![purged_handle]).unwrap();
+
         // leave only 1 file to truncate
         pipe_log.purge_to(FileId { queue, seq: 3 }).unwrap();
         assert_eq!(pipe_log.file_span(queue), (3, 3));
+        assert_eq!(pipe_log.finish_read(pending).unwrap(), vec![content]);
     }
 
     #[test]
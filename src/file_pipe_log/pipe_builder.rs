@@ -3,23 +3,28 @@
 //! Helper types to recover in-memory states from log files.
 
 use std::cmp;
-use std::fs::{self, File as StdFile};
+use std::fs::File as StdFile;
 use std::io::Write;
 use std::marker::PhantomData;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
 
 use fs2::FileExt;
 use log::{error, info, warn};
+use parking_lot::Mutex;
 use rayon::prelude::*;
 
 use crate::config::{Config, RecoveryMode};
-use crate::env::FileSystem;
-use crate::env::Handle;
+use crate::env::{DirLock, FileSystem, Handle};
 use crate::event_listener::EventListener;
 use crate::log_batch::LogItemBatch;
-use crate::pipe_log::{FileId, LogQueue};
+use crate::pipe_log::{FileId, FileSeq, LogQueue};
+use crate::recovery::{
+    CancellationToken, CorruptedRange, RecoveryProgress, RecoveryProgressSink, RecoveryReport,
+    Truncation,
+};
 use crate::util::{Factory, ReadableSize};
 use crate::{Error, Result};
 
@@ -70,6 +75,81 @@ pub struct RecoveryConfig {
     pub read_block_size: u64,
 }
 
+/// Progress of recovering one queue, shared by concurrent recovery tasks.
+struct QueueProgress {
+    queue: LogQueue,
+    files_total: usize,
+    bytes_total: u64,
+    files_processed: AtomicUsize,
+    bytes_processed: AtomicU64,
+}
+
+/// States shared by concurrent recovery tasks.
+struct RecoveryContext<'a, F: FileSystem> {
+    report: Mutex<RecoveryReport>,
+    // Truncations are deferred until all files are recovered, so that a
+    // cancelled recovery won't modify any file.
+    pending_truncations: Mutex<Vec<(Arc<F::Handle>, usize)>>,
+    progress_sink: Option<&'a dyn RecoveryProgressSink>,
+    cancellation_token: Option<&'a CancellationToken>,
+}
+
+impl<'a, F: FileSystem> RecoveryContext<'a, F> {
+    fn new(
+        report: RecoveryReport,
+        progress_sink: Option<&'a dyn RecoveryProgressSink>,
+        cancellation_token: Option<&'a CancellationToken>,
+    ) -> Self {
+        Self {
+            report: Mutex::new(report),
+            pending_truncations: Mutex::new(Vec::new()),
+            progress_sink,
+            cancellation_token,
+        }
+    }
+
+    #[inline]
+    fn check_cancelled(&self) -> Result<()> {
+        if self.cancellation_token.map_or(false, |t| t.is_cancelled()) {
+            return Err(Error::Cancelled);
+        }
+        Ok(())
+    }
+
+    fn truncate(&self, truncation: Truncation, handle: Arc<F::Handle>) {
+        self.pending_truncations
+            .lock()
+            .push((handle, truncation.offset as usize));
+        self.report.lock().truncations.push(truncation);
+    }
+
+    fn file_processed(&self, progress: &QueueProgress, file_size: usize) {
+        let files_processed = progress.files_processed.fetch_add(1, Ordering::Relaxed) + 1;
+        let bytes_processed = progress
+            .bytes_processed
+            .fetch_add(file_size as u64, Ordering::Relaxed)
+            + file_size as u64;
+        if let Some(sink) = self.progress_sink {
+            sink.on_progress(RecoveryProgress {
+                queue: progress.queue,
+                files_processed,
+                files_total: progress.files_total,
+                bytes_processed,
+                bytes_total: progress.bytes_total,
+            });
+        }
+    }
+
+    /// Applies pending truncations if the recovery isn't cancelled.
+    fn finish(self) -> Result<RecoveryReport> {
+        self.check_cancelled()?;
+        for (handle, offset) in self.pending_truncations.into_inner() {
+            handle.truncate(offset)?;
+        }
+        Ok(self.report.into_inner())
+    }
+}
+
 /// [`DualPipes`] factory that can also recover other customized memory states.
 pub struct DualPipesBuilder<F: FileSystem> {
     cfg: Config,
@@ -77,10 +157,15 @@ pub struct DualPipesBuilder<F: FileSystem> {
     listeners: Vec<Arc<dyn EventListener>>,
 
     /// Only filled after a successful call of `DualPipesBuilder::scan`.
-    dir_lock: Option<StdFile>,
+    dir_lock: Option<DirLock>,
     append_files: Vec<File<F>>,
     rewrite_files: Vec<File<F>>,
     recycled_files: Vec<File<F>>,
+    /// Filled during `DualPipesBuilder::scan` and recovery.
+    report: RecoveryReport,
+
+    progress_sink: Option<Arc<dyn RecoveryProgressSink>>,
+    cancellation_token: Option<CancellationToken>,
 }
 
 impl<F: FileSystem> DualPipesBuilder<F> {
@@ -94,58 +179,81 @@ impl<F: FileSystem> DualPipesBuilder<F> {
             append_files: Vec::new(),
             rewrite_files: Vec::new(),
             recycled_files: Vec::new(),
+            report: RecoveryReport::default(),
+            progress_sink: None,
+            cancellation_token: None,
+        }
+    }
+
+    /// Sets the receiver of recovery progress.
+    pub fn set_progress_sink(&mut self, sink: Arc<dyn RecoveryProgressSink>) {
+        self.progress_sink = Some(sink);
+    }
+
+    /// Sets the token to cancel recovery. It's checked between log batches.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancellation_token = Some(token);
+    }
+
+    /// Returns [`Error::Cancelled`] if the recovery is cancelled.
+    pub fn check_cancelled(&self) -> Result<()> {
+        if self
+            .cancellation_token
+            .as_ref()
+            .map_or(false, |t| t.is_cancelled())
+        {
+            return Err(Error::Cancelled);
         }
+        Ok(())
     }
 
     /// Scans for all log files under the working directory. The directory will
     /// be created if not exists.
     pub fn scan(&mut self) -> Result<()> {
+        // Nothing is modified by a cancelled recovery, including the stale
+        // metadata deleted below.
+        self.check_cancelled()?;
         let root_path = Path::new(&self.cfg.dir);
+        crate::export::recover_directory_swap(self.file_system.as_ref(), root_path)?;
         if !root_path.exists() {
             info!("Create raft log directory: {}", root_path.display());
-            fs::create_dir(root_path)?;
-            self.dir_lock = Some(lock_dir(root_path)?);
+            self.file_system.create_dir(root_path)?;
+            self.dir_lock = Some(self.file_system.lock_dir(root_path)?);
             return Ok(());
         }
         if !root_path.is_dir() {
             return Err(box_err!("Not directory: {}", root_path.display()));
         }
-        self.dir_lock = Some(lock_dir(root_path)?);
+        self.dir_lock = Some(self.file_system.lock_dir(root_path)?);
 
         let (mut min_append_id, mut max_append_id) = (u64::MAX, 0);
         let (mut min_rewrite_id, mut max_rewrite_id) = (u64::MAX, 0);
         let (mut min_recycled_id, mut max_recycled_id) = (u64::MAX, 0);
-        fs::read_dir(root_path)?.for_each(|e| {
-            if let Ok(e) = e {
-                let p = e.path();
-                if !p.is_file() {
-                    return;
+        for p in self.file_system.list_files(root_path)? {
+            let name = p.file_name().unwrap().to_str().unwrap();
+            match FileId::parse_file_name(name) {
+                Some(FileId {
+                    queue: LogQueue::Append,
+                    seq,
+                }) => {
+                    min_append_id = cmp::min(min_append_id, seq);
+                    max_append_id = cmp::max(max_append_id, seq);
                 }
-                let name = p.file_name().unwrap().to_str().unwrap();
-                match FileId::parse_file_name(name) {
-                    Some(FileId {
-                        queue: LogQueue::Append,
-                        seq,
-                    }) => {
-                        min_append_id = cmp::min(min_append_id, seq);
-                        max_append_id = cmp::max(max_append_id, seq);
-                    }
-                    Some(FileId {
-                        queue: LogQueue::Rewrite,
-                        seq,
-                    }) => {
-                        min_rewrite_id = cmp::min(min_rewrite_id, seq);
-                        max_rewrite_id = cmp::max(max_rewrite_id, seq);
-                    }
-                    _ => {
-                        if let Some(seq) = parse_recycled_file_name(name) {
-                            min_recycled_id = cmp::min(min_recycled_id, seq);
-                            max_recycled_id = cmp::max(max_recycled_id, seq);
-                        }
+                Some(FileId {
+                    queue: LogQueue::Rewrite,
+                    seq,
+                }) => {
+                    min_rewrite_id = cmp::min(min_rewrite_id, seq);
+                    max_rewrite_id = cmp::max(max_rewrite_id, seq);
+                }
+                _ => {
+                    if let Some(seq) = parse_recycled_file_name(name) {
+                        min_recycled_id = cmp::min(min_recycled_id, seq);
+                        max_recycled_id = cmp::max(max_recycled_id, seq);
                     }
                 }
             }
-        });
+        }
 
         for (queue, min_id, max_id, files, is_recycled_file) in [
             (
@@ -208,6 +316,7 @@ impl<F: FileSystem> DualPipesBuilder<F> {
                         "deleted {} stale files of {:?} in range [{}, {}).",
                         success, queue, start, min_id,
                     );
+                    self.report.stale_metadata_deleted += success;
                 }
                 for seq in min_id..=max_id {
                     let file_id = FileId { queue, seq };
@@ -216,11 +325,16 @@ impl<F: FileSystem> DualPipesBuilder<F> {
                     } else {
                         file_id.build_file_path(root_path)
                     };
-                    if !path.exists() {
+                    if !self.file_system.exists(&path) {
                         warn!(
                             "Detected a hole when scanning directory, discarding files before {:?}.",
                             file_id,
                         );
+                        if !is_recycled_file {
+                            self.report
+                                .discarded_files
+                                .extend(files.iter().map(|f| FileId { queue, seq: f.seq }));
+                        }
                         files.clear();
                     } else {
                         let handle = Arc::new(self.file_system.open(&path)?);
@@ -234,6 +348,7 @@ impl<F: FileSystem> DualPipesBuilder<F> {
                 }
             }
         }
+        self.report.files_scanned = self.append_files.len() + self.rewrite_files.len();
         Ok(())
     }
 
@@ -279,6 +394,11 @@ impl<F: FileSystem> DualPipesBuilder<F> {
         let append_files = &mut self.append_files;
         let rewrite_files = &mut self.rewrite_files;
         let file_system = self.file_system.clone();
+        let ctx = RecoveryContext::new(
+            std::mem::take(&mut self.report),
+            self.progress_sink.as_deref(),
+            self.cancellation_token.as_ref(),
+        );
         // As the `recover_queue` would update the `LogFileFormat` of each log file
         // in `apend_files` and `rewrite_files`, we re-design the implementation on
         // `recover_queue` to make it compatiable to concurrent processing
@@ -290,6 +410,7 @@ impl<F: FileSystem> DualPipesBuilder<F> {
                     append_recovery_cfg,
                     append_files,
                     machine_factory,
+                    &ctx,
                 )
             },
             || {
@@ -298,10 +419,13 @@ impl<F: FileSystem> DualPipesBuilder<F> {
                     rewrite_recovery_cfg,
                     rewrite_files,
                     machine_factory,
+                    &ctx,
                 )
             },
         );
-        Ok((append?, rewrite?))
+        let (append, rewrite) = (append?, rewrite?);
+        self.report = ctx.finish()?;
+        Ok((append, rewrite))
     }
 
     /// Manually reads through log items in all available log files of the
@@ -312,11 +436,23 @@ impl<F: FileSystem> DualPipesBuilder<F> {
         recovery_cfg: RecoveryConfig,
         files: &mut Vec<File<F>>,
         machine_factory: &FA,
+        ctx: &RecoveryContext<F>,
     ) -> Result<M> {
         if recovery_cfg.concurrency == 0 || files.is_empty() {
             return Ok(machine_factory.new_target());
         }
         let queue = recovery_cfg.queue;
+        let mut bytes_total = 0;
+        for f in files.iter() {
+            bytes_total += f.handle.file_size()? as u64;
+        }
+        let progress = QueueProgress {
+            queue,
+            files_total: files.len(),
+            bytes_total,
+            files_processed: AtomicUsize::new(0),
+            bytes_processed: AtomicU64::new(0),
+        };
         let concurrency = recovery_cfg.concurrency;
         let recovery_mode = recovery_cfg.mode;
         let recovery_read_block_size = recovery_cfg.read_block_size as usize;
@@ -333,20 +469,51 @@ impl<F: FileSystem> DualPipesBuilder<F> {
                 let mut machine = machine_factory.new_target();
                 let file_count = chunk.len();
                 for (i, f) in chunk.iter_mut().enumerate() {
+                    ctx.check_cancelled()?;
                     let is_last_file = index == chunk_count - 1 && i == file_count - 1;
+                    let file_id = FileId { queue, seq: f.seq };
+                    let file_size = f.handle.file_size()?;
                     let mut file_reader = build_file_reader(file_system.as_ref(), f.handle.clone())?;
                     match file_reader.parse_format() {
                         Err(e) => {
                             // TODO: More reliable tail detection.
-                            if recovery_mode == RecoveryMode::TolerateAnyCorruption
+                            if recovery_mode == RecoveryMode::Salvage && !is_last_file {
+                                warn!(
+                                    "File header is corrupted, skip the whole file: {:?}:{}, {}",
+                                    queue, f.seq, e
+                                );
+                                ctx.report.lock().corrupted_ranges.push(CorruptedRange {
+                                    file_id,
+                                    offset: 0,
+                                    len: file_size as u64,
+                                    truncated: false,
+                                });
+                                f.format = LogFileFormat::default();
+                                ctx.file_processed(&progress, file_size);
+                                continue;
+                            } else if recovery_mode == RecoveryMode::TolerateAnyCorruption
+                              || recovery_mode == RecoveryMode::Salvage
                               || recovery_mode == RecoveryMode::TolerateTailCorruption
                                 && is_last_file {
                                 warn!(
                                     "File header is corrupted but ignored: {:?}:{}, {}",
                                     queue, f.seq, e
                                 );
-                                f.handle.truncate(0)?;
+                                if recovery_mode == RecoveryMode::Salvage {
+                                    ctx.report.lock().corrupted_ranges.push(CorruptedRange {
+                                        file_id,
+                                        offset: 0,
+                                        len: file_size as u64,
+                                        truncated: true,
+                                    });
+                                }
+                                ctx.truncate(Truncation {
+                                    file_id,
+                                    offset: 0,
+                                    reason: e.to_string(),
+                                }, f.handle.clone());
                                 f.format = LogFileFormat::default();
+                                ctx.file_processed(&progress, file_size);
                                 continue;
                             } else {
                                 error!(
@@ -358,14 +525,17 @@ impl<F: FileSystem> DualPipesBuilder<F> {
                         },
                         Ok(format) => {
                             f.format = format;
-                            reader.open(FileId { queue, seq: f.seq }, format, file_reader)?;
+                            reader.open(file_id, format, file_reader)?;
                         }
                     }
+                    let mut bytes_replayed = 0;
                     loop {
+                        ctx.check_cancelled()?;
+                        let offset = reader.valid_offset();
                         match reader.next() {
                             Ok(Some(item_batch)) => {
-                                machine
-                                    .replay(item_batch, FileId { queue, seq: f.seq })?;
+                                bytes_replayed += reader.valid_offset() - offset;
+                                machine.replay(item_batch, file_id)?;
                             }
                             Ok(None) => break,
                             Err(e)
@@ -376,26 +546,71 @@ impl<F: FileSystem> DualPipesBuilder<F> {
                                     "The last log file is corrupted but ignored: {:?}:{}, {}",
                                     queue, f.seq, e
                                 );
-                                f.handle.truncate(reader.valid_offset())?;
+                                ctx.truncate(Truncation {
+                                    file_id,
+                                    offset: offset as u64,
+                                    reason: e.to_string(),
+                                }, f.handle.clone());
                                 break;
                             }
+                            Err(e) if recovery_mode == RecoveryMode::Salvage => {
+                                if let Some(next_offset) = reader.resync()? {
+                                    warn!(
+                                        "Log batches are corrupted and skipped: {:?}:{} offset={} len={}, {}",
+                                        queue, f.seq, offset, next_offset - offset, e
+                                    );
+                                    ctx.report.lock().corrupted_ranges.push(CorruptedRange {
+                                        file_id,
+                                        offset: offset as u64,
+                                        len: (next_offset - offset) as u64,
+                                        truncated: false,
+                                    });
+                                } else {
+                                    warn!(
+                                        "File tail is corrupted and skipped: {:?}:{} offset={} len={}, {}",
+                                        queue, f.seq, offset, file_size - offset, e
+                                    );
+                                    ctx.report.lock().corrupted_ranges.push(CorruptedRange {
+                                        file_id,
+                                        offset: offset as u64,
+                                        len: (file_size - offset) as u64,
+                                        truncated: is_last_file,
+                                    });
+                                    // The last file will be written again, so
+                                    // its tail is truncated.
+                                    if is_last_file {
+                                        ctx.truncate(Truncation {
+                                            file_id,
+                                            offset: offset as u64,
+                                            reason: e.to_string(),
+                                        }, f.handle.clone());
+                                    }
+                                    break;
+                                }
+                            }
                             Err(e) if recovery_mode == RecoveryMode::TolerateAnyCorruption => {
                                 warn!(
                                     "File is corrupted but ignored: {:?}:{}, {}",
                                     queue, f.seq, e
                                 );
-                                f.handle.truncate(reader.valid_offset())?;
+                                ctx.truncate(Truncation {
+                                    file_id,
+                                    offset: offset as u64,
+                                    reason: e.to_string(),
+                                }, f.handle.clone());
                                 break;
                             }
                             Err(e) => {
                                 error!(
                                     "Failed to open log file due to broken entry: {:?}:{} offset={}",
-                                    queue, f.seq, reader.valid_offset()
+                                    queue, f.seq, offset
                                 );
                                 return Err(e);
                             }
                         }
                     }
+                    ctx.report.lock().bytes_replayed += bytes_replayed as u64;
+                    ctx.file_processed(&progress, file_size);
                 }
                 Ok(machine)
             })
@@ -425,12 +640,62 @@ impl<F: FileSystem> DualPipesBuilder<F> {
         } else {
             &mut self.rewrite_files
         };
-        DualPipesBuilder::recover_queue_imp(
+        let ctx = RecoveryContext::new(
+            std::mem::take(&mut self.report),
+            self.progress_sink.as_deref(),
+            self.cancellation_token.as_ref(),
+        );
+        let machine = DualPipesBuilder::recover_queue_imp(
             file_system,
             recovery_cfg,
             files,
             replay_machine_factory,
-        )
+            &ctx,
+        )?;
+        self.report = ctx.finish()?;
+        Ok(machine)
+    }
+
+    /// Returns the smallest and largest file sequence number of the scanned
+    /// log files of the specified queue.
+    pub fn file_span(&self, queue: LogQueue) -> Option<(FileSeq, FileSeq)> {
+        let files = match queue {
+            LogQueue::Append => &self.append_files,
+            LogQueue::Rewrite => &self.rewrite_files,
+        };
+        Some((files.first()?.seq, files.last()?.seq))
+    }
+
+    /// Prepares the scanned log files to be opened without replaying them.
+    /// Only file headers are parsed. Returns `false` if any header is broken,
+    /// or the active file of any queue is not empty, in which case the files
+    /// must be recovered via [`recover`].
+    ///
+    /// [`recover`]: DualPipesBuilder::recover
+    pub fn skip_recovery(&mut self) -> Result<bool> {
+        for files in [&mut self.append_files, &mut self.rewrite_files] {
+            let len = files.len();
+            for (i, f) in files.iter_mut().enumerate() {
+                let mut file_reader =
+                    build_file_reader(self.file_system.as_ref(), f.handle.clone())?;
+                match file_reader.parse_format() {
+                    Ok(format) => f.format = format,
+                    Err(_) => return Ok(false),
+                }
+                if i == len - 1
+                    && file_reader.file_size()? > LogFileFormat::encoded_len(f.format.version)
+                {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Takes out the decisions made during scanning and recovery. Only
+    /// fields about log files are filled.
+    pub fn take_report(&mut self) -> RecoveryReport {
+        std::mem::take(&mut self.report)
     }
 
     fn initialize_files(&mut self) -> Result<()> {
@@ -514,13 +779,16 @@ impl<F: FileSystem> DualPipesBuilder<F> {
 }
 
 /// Creates and exclusively locks a lock file under the given directory.
-pub(super) fn lock_dir<P: AsRef<Path>>(dir: P) -> Result<StdFile> {
+pub(crate) fn lock_dir<P: AsRef<Path>>(dir: P) -> std::io::Result<StdFile> {
     let lock_file = StdFile::create(lock_file_path(dir))?;
     lock_file.try_lock_exclusive().map_err(|e| {
-        Error::Other(box_err!(
-            "Failed to lock file: {}, maybe another instance is using this directory.",
-            e
-        ))
+        std::io::Error::new(
+            e.kind(),
+            format!(
+                "Failed to lock file: {}, maybe another instance is using this directory.",
+                e
+            ),
+        )
     })?;
     Ok(lock_file)
 }
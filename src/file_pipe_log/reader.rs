@@ -1,7 +1,7 @@
 // Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
 
 use crate::env::FileSystem;
-use crate::log_batch::{LogBatch, LogItemBatch, LOG_BATCH_HEADER_LEN};
+use crate::log_batch::{CompressionType, LogBatch, LogItemBatch, LOG_BATCH_HEADER_LEN};
 use crate::pipe_log::{FileBlockHandle, FileId, LogFileContext};
 use crate::util::round_up;
 use crate::{Error, Result};
@@ -108,35 +108,139 @@ impl<F: FileSystem> LogItemBatchFileReader<F> {
                 // it means that the header is broken or the padding is filled
                 // with non-zero bytes, and the err will be returned.
             }
-            let (footer_offset, compression_type, len) = r?;
-            if self.valid_offset + len > self.size {
-                return Err(Error::Corruption("log batch header broken".to_owned()));
-            }
-            let handle = FileBlockHandle {
-                id: self.file_id.unwrap(),
-                offset: (self.valid_offset + LOG_BATCH_HEADER_LEN) as u64,
-                len: footer_offset - LOG_BATCH_HEADER_LEN,
-            };
-            let context = LogFileContext {
-                id: self.file_id.unwrap(),
-                version: format.version,
-            };
-            let item_batch = LogItemBatch::decode(
-                &mut self.peek(
-                    self.valid_offset + footer_offset,
-                    len - footer_offset,
-                    LOG_BATCH_HEADER_LEN,
-                )?,
-                handle,
-                compression_type,
-                &context,
-            )?;
+            let (item_batch, len) = self.decode_at(self.valid_offset, r)?;
             self.valid_offset += len;
             return Ok(Some(item_batch));
         }
         Ok(None)
     }
 
+    /// Skips the log batch at current offset, which failed to be decoded, and
+    /// searches forward for the next log batch with a valid checksum.
+    ///
+    /// Returns the file offset of the found log batch, which will be returned
+    /// by the next call of [`next`](Self::next). Returns `None` if the end of
+    /// file is reached.
+    pub fn resync(&mut self) -> Result<Option<usize>> {
+        let start = self.valid_offset;
+        let alignment = self.format.unwrap().alignment as usize;
+        // If only the body of the log batch is corrupted, the next one follows
+        // right after it, or after the paddings to the next aligned offset.
+        if start + LOG_BATCH_HEADER_LEN <= self.size {
+            if let Ok((_, _, len)) =
+                LogBatch::decode_header(&mut self.peek(start, LOG_BATCH_HEADER_LEN, 0)?)
+            {
+                if len > 0 {
+                    if self.try_resync_at(start + len)? {
+                        return Ok(Some(start + len));
+                    }
+                    if alignment > 0 {
+                        let aligned_offset = round_up(start + len, alignment);
+                        if self.try_resync_at(aligned_offset)? {
+                            return Ok(Some(aligned_offset));
+                        }
+                    }
+                }
+            }
+        }
+        // In an aligned file, log batches are likely to start at aligned
+        // offsets, so these offsets are probed first.
+        if alignment > 0 {
+            let mut offset = round_up(start + 1, alignment);
+            while offset + LOG_BATCH_HEADER_LEN <= self.size {
+                if self.try_resync_at(offset)? {
+                    return Ok(Some(offset));
+                }
+                offset += alignment;
+            }
+        }
+        // Otherwise, search for a decodable header in chunks, and only verify
+        // the log batches behind them.
+        let mut chunk = Vec::new();
+        let mut chunk_offset = start + 1;
+        while chunk_offset + LOG_BATCH_HEADER_LEN <= self.size {
+            chunk.resize(
+                std::cmp::min(
+                    std::cmp::max(self.read_block_size, LOG_BATCH_HEADER_LEN),
+                    self.size - chunk_offset,
+                ),
+                0,
+            );
+            let read = self
+                .reader
+                .as_mut()
+                .unwrap()
+                .read_to(chunk_offset as u64, &mut chunk)?;
+            if read < LOG_BATCH_HEADER_LEN {
+                break;
+            }
+            for i in 0..=read - LOG_BATCH_HEADER_LEN {
+                let offset = chunk_offset + i;
+                if let Ok((_, _, len)) =
+                    LogBatch::decode_header(&mut &chunk[i..i + LOG_BATCH_HEADER_LEN])
+                {
+                    if offset + len <= self.size && self.try_resync_at(offset)? {
+                        return Ok(Some(offset));
+                    }
+                }
+            }
+            // Headers across the chunk boundary are searched in the next chunk.
+            chunk_offset += read - LOG_BATCH_HEADER_LEN + 1;
+        }
+        Ok(None)
+    }
+
+    // Moves to the log batch at `offset` if it can be decoded.
+    fn try_resync_at(&mut self, offset: usize) -> Result<bool> {
+        if offset + LOG_BATCH_HEADER_LEN > self.size {
+            return Ok(false);
+        }
+        if offset < self.buffer_offset {
+            // The buffer has been moved past it by a previous attempt.
+            self.buffer.clear();
+            self.buffer_offset = offset;
+        }
+        let r = LogBatch::decode_header(&mut self.peek(offset, LOG_BATCH_HEADER_LEN, 0)?);
+        if r.is_ok() && self.decode_at(offset, r).is_ok() {
+            self.valid_offset = offset;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Decodes the log batch at `offset` with its decoded header. Returns the
+    /// log items and the total length of this log batch.
+    fn decode_at(
+        &mut self,
+        offset: usize,
+        header: Result<(usize, CompressionType, usize)>,
+    ) -> Result<(LogItemBatch, usize)> {
+        let (footer_offset, compression_type, len) = header?;
+        if offset + len > self.size {
+            return Err(Error::Corruption("log batch header broken".to_owned()));
+        }
+        let handle = FileBlockHandle {
+            id: self.file_id.unwrap(),
+            offset: (offset + LOG_BATCH_HEADER_LEN) as u64,
+            len: footer_offset - LOG_BATCH_HEADER_LEN,
+        };
+        let context = LogFileContext {
+            id: self.file_id.unwrap(),
+            version: self.format.unwrap().version,
+        };
+        let item_batch = LogItemBatch::decode(
+            &mut self.peek(
+                offset + footer_offset,
+                len - footer_offset,
+                LOG_BATCH_HEADER_LEN,
+            )?,
+            handle,
+            compression_type,
+            &context,
+        )?;
+        Ok((item_batch, len))
+    }
+
     /// Reads some bytes starting at `offset`. Pulls bytes from the file into
     /// its internal buffer if necessary, and attempts to prefetch in that
     /// process.
@@ -124,6 +124,29 @@ impl RaftGroupState {
         }
         Ok(())
     }
+
+    /// Returns the state of entries within `[begin, end)`.
+    pub fn slice(&self, begin: u64, end: u64) -> Self {
+        let first = std::cmp::max(begin, self.first_index);
+        let last = std::cmp::min(end, self.first_index + self.count as u64);
+        if self.count == 0 || first >= last {
+            return RaftGroupState {
+                first_index: 0,
+                count: 0,
+                rewrite_count: 0,
+            };
+        }
+        let count = (last - first) as usize;
+        RaftGroupState {
+            first_index: first,
+            count,
+            rewrite_count: std::cmp::min(
+                self.rewrite_count
+                    .saturating_sub((first - self.first_index) as usize),
+                count,
+            ),
+        }
+    }
 }
 
 /// `RhaiFilter` is a stateless machine that filters incoming log items. Its
@@ -371,6 +394,27 @@ impl RhaiFilterMachine {
             ));
         }
         state.apply(file_id.queue, &item.content)?;
+        if let LogItemContent::Command(cmd) = &item.content {
+            // Entries of the source Raft Group are taken over.
+            match *cmd {
+                Command::Rename { from } if from != item.raft_group_id => {
+                    let src = self.states.remove(&from);
+                    let state = self.states.get_mut(&item.raft_group_id).unwrap();
+                    if let Some(src) = src {
+                        *state = src;
+                    }
+                }
+                Command::Clone { from, begin, end } if from != item.raft_group_id => {
+                    if let Some(src) = self.states.get(&from).map(|s| s.slice(begin, end)) {
+                        let state = self.states.get_mut(&item.raft_group_id).unwrap();
+                        if state.count == 0 {
+                            *state = src;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
         current.items.push(item.clone());
         Ok(())
     }
@@ -0,0 +1,423 @@
+// Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
+
+//! Read-only inspection of a Raft Engine directory.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::warn;
+
+use crate::env::{FileSystem, Handle};
+use crate::export::ExportedRegion;
+use crate::file_pipe_log::debug::{build_file_reader, replay_file};
+use crate::file_pipe_log::FileNameExt;
+use crate::log_batch::LogBatch;
+use crate::memtable::{
+    EntryIndex, KvValue, MemTableAccessor, MemTableRecoverContext, VacantAllocator,
+};
+use crate::pipe_log::{FileBlockHandle, FileId, FileSeq, LogQueue, Version};
+use crate::purge_policy::PurgePolicy;
+use crate::{Error, Result};
+
+/// Information of a log file.
+#[derive(Clone, Debug)]
+pub struct LogFileInfo {
+    pub file_id: FileId,
+    pub path: PathBuf,
+    pub size: usize,
+    /// `None` if the file header can't be parsed.
+    pub version: Option<Version>,
+    pub alignment: u64,
+    /// Size of the intact part of the file, including the file header.
+    pub valid_size: usize,
+    /// Description of the corruption that stops reading the file.
+    pub corruption: Option<String>,
+}
+
+/// Information of a Raft Group.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RaftGroupInfo {
+    pub raft_group_id: u64,
+    pub first_index: Option<u64>,
+    pub last_index: Option<u64>,
+    /// Number of entries stored in rewrite queue.
+    pub rewrite_count: usize,
+    pub keys: Vec<Vec<u8>>,
+    /// Log files that contain live entries or key values of this Raft Group.
+    pub files: Vec<FileId>,
+}
+
+/// Watermarks that decide which log files are rewritten or purged.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PurgeWatermarks {
+    /// The first and active file of append queue.
+    pub append_span: Option<(FileSeq, FileSeq)>,
+    /// The first and active file of rewrite queue.
+    pub rewrite_span: Option<(FileSeq, FileSeq)>,
+    /// Logs in append files no newer than it are candidates for rewrite.
+    pub rewrite_watermark: Option<FileSeq>,
+    /// Raft Groups with logs in append files older than it are asked to
+    /// compact.
+    pub compact_watermark: Option<FileSeq>,
+    /// The oldest append file that is still referenced. Older files can be
+    /// purged.
+    pub append_min_referenced: Option<FileSeq>,
+    /// The oldest rewrite file that is still referenced. Older files can be
+    /// purged.
+    pub rewrite_min_referenced: Option<FileSeq>,
+}
+
+/// Lists log files under a directory, ordered by file ID. Rewrite queue goes
+/// first.
+pub(crate) fn list_log_files<F: FileSystem>(
+    file_system: &F,
+    dir: &Path,
+) -> Result<Vec<(FileId, PathBuf)>> {
+    if !file_system.is_dir(dir) {
+        return Err(Error::InvalidArgument(format!(
+            "Not a directory: {}",
+            dir.display()
+        )));
+    }
+    let mut file_ids: Vec<_> = file_system
+        .list_files(dir)?
+        .into_iter()
+        .filter_map(|p| {
+            let file_id = FileId::parse_file_name(p.file_name()?.to_str()?)?;
+            Some((file_id, p))
+        })
+        .collect();
+    file_ids.sort_by_key(|(file_id, _)| *file_id);
+    Ok(file_ids)
+}
+
+/// A read-only view of the log files under a directory.
+///
+/// Log files are indexed once when the inspector is opened. Unlike
+/// [`Engine`], it doesn't lock the directory or modify any file, corrupted
+/// log batches are reported instead of being truncated.
+///
+/// [`Engine`]: crate::Engine
+pub struct Inspector<F: FileSystem> {
+    file_system: Arc<F>,
+    files: Vec<LogFileInfo>,
+    memtables: MemTableAccessor<VacantAllocator>,
+}
+
+impl<F: FileSystem> Inspector<F> {
+    pub fn open(file_system: Arc<F>, dir: &Path) -> Result<Self> {
+        let file_ids = list_log_files(file_system.as_ref(), dir)?;
+        let mut append = MemTableRecoverContext::default();
+        let mut rewrite = MemTableRecoverContext::default();
+        let mut files = Vec::with_capacity(file_ids.len());
+        for (file_id, path) in file_ids {
+            let size = file_system.open(&path)?.file_size()?;
+            let machine = match file_id.queue {
+                LogQueue::Append => &mut append,
+                LogQueue::Rewrite => &mut rewrite,
+            };
+            let info = match replay_file(file_system.as_ref(), &path, file_id, machine) {
+                Ok(replayed) => LogFileInfo {
+                    file_id,
+                    path,
+                    size,
+                    version: Some(replayed.version),
+                    alignment: replayed.alignment,
+                    valid_size: replayed.valid_offset,
+                    corruption: replayed.corruption.map(|e| e.to_string()),
+                },
+                Err(e) => {
+                    warn!("Failed to read log file {:?}: {}", file_id, e);
+                    LogFileInfo {
+                        file_id,
+                        path,
+                        size,
+                        version: None,
+                        alignment: 0,
+                        valid_size: 0,
+                        corruption: Some(e.to_string()),
+                    }
+                }
+            };
+            files.push(info);
+        }
+        rewrite.merge_append_context(append);
+        let (memtables, _) = rewrite.finish();
+        Ok(Self {
+            file_system,
+            files,
+            memtables,
+        })
+    }
+
+    /// Returns all log files, ordered by file ID.
+    pub fn files(&self) -> &[LogFileInfo] {
+        &self.files
+    }
+
+    /// Returns the IDs of all Raft Groups in ascending order.
+    pub fn raft_groups(&self) -> Vec<u64> {
+        let mut ids = self.memtables.fold(Vec::new(), |mut ids, t| {
+            ids.push(t.region_id());
+            ids
+        });
+        ids.sort_unstable();
+        ids
+    }
+
+    pub fn raft_group(&self, raft_group_id: u64) -> Result<Option<RaftGroupInfo>> {
+        let memtable = match self.memtables.get(raft_group_id) {
+            Some(memtable) => memtable,
+            None => return Ok(None),
+        };
+        let memtable = memtable.read();
+        let mut files = BTreeSet::new();
+        let mut keys = Vec::new();
+        for (key, _, file_id) in memtable.kvs() {
+            keys.push(key.to_vec());
+            files.insert(file_id);
+        }
+        let (first_index, last_index) = (memtable.first_index(), memtable.last_index());
+        if let (Some(first), Some(last)) = (first_index, last_index) {
+            let mut ents_idx = Vec::new();
+            memtable.fetch_entries_to(first, last + 1, None, &mut ents_idx)?;
+            files.extend(ents_idx.iter().map(|idx| idx.entries.unwrap().id));
+        }
+        Ok(Some(RaftGroupInfo {
+            raft_group_id,
+            first_index,
+            last_index,
+            rewrite_count: memtable.rewrite_count(),
+            keys,
+            files: files.into_iter().collect(),
+        }))
+    }
+
+    /// Returns the location of the specified log entry.
+    pub fn entry_index(&self, raft_group_id: u64, index: u64) -> Option<EntryIndex> {
+        self.memtables
+            .get(raft_group_id)
+            .and_then(|t| t.read().get_entry(index))
+    }
+
+    /// Reads the encoded log entry from log file.
+    pub fn read_entry(&self, idx: &EntryIndex) -> Result<Vec<u8>> {
+        self.read_entry_with_cache(idx, &mut None)
+    }
+
+    // Reads the raw bytes of a block from its log file.
+    fn read_bytes(&self, handle: FileBlockHandle) -> Result<Vec<u8>> {
+        let file = self
+            .files
+            .iter()
+            .find(|f| f.file_id == handle.id)
+            .ok_or_else(|| Error::InvalidArgument(format!("Missing log file {:?}", handle.id)))?;
+        let mut reader = build_file_reader(self.file_system.as_ref(), &file.path)?;
+        reader.read(handle)
+    }
+
+    fn read_value(&self, value: &KvValue) -> Result<Vec<u8>> {
+        match value {
+            KvValue::Inline(value) => Ok(value.clone()),
+            KvValue::Spilled(handle, checksum) => {
+                let value = self.read_bytes(*handle)?;
+                KvValue::verify_spilled(handle, *checksum, &value)?;
+                Ok(value)
+            }
+        }
+    }
+
+    // Reads an entry, the last decoded block is kept in `cache`.
+    fn read_entry_with_cache(
+        &self,
+        idx: &EntryIndex,
+        cache: &mut Option<(FileBlockHandle, Vec<u8>)>,
+    ) -> Result<Vec<u8>> {
+        let handle = idx.entries.unwrap();
+        if cache.as_ref().map(|(h, _)| *h) != Some(handle) {
+            let block = LogBatch::decode_entries_block(
+                &self.read_bytes(handle)?,
+                handle,
+                idx.compression_type,
+            )?;
+            *cache = Some((handle, block));
+        }
+        let block = &cache.as_ref().unwrap().1;
+        let (offset, len) = (idx.entry_offset as usize, idx.entry_len as usize);
+        if offset + len > block.len() {
+            return Err(Error::Corruption(format!(
+                "entry {} out of block {:?}",
+                idx.index, handle
+            )));
+        }
+        Ok(block[offset..offset + len].to_vec())
+    }
+
+    /// Reads all live log entries and key values of a Raft Group.
+    pub fn export_region(&self, raft_group_id: u64) -> Result<ExportedRegion> {
+        let memtable = self.memtables.get(raft_group_id).ok_or_else(|| {
+            Error::InvalidArgument(format!("Raft Group {} not found", raft_group_id))
+        })?;
+        let memtable = memtable.read();
+        let mut exported = ExportedRegion {
+            region_id: raft_group_id,
+            first_index: memtable.first_index().unwrap_or(0),
+            ..Default::default()
+        };
+        for (key, value, _) in memtable.kvs() {
+            exported.kvs.push((key.to_vec(), self.read_value(value)?));
+        }
+        if let Some(last) = memtable.last_index() {
+            let mut ents_idx = Vec::new();
+            memtable.fetch_entries_to(exported.first_index, last + 1, None, &mut ents_idx)?;
+            let mut cache = None;
+            for idx in &ents_idx {
+                exported
+                    .entries
+                    .push(self.read_entry_with_cache(idx, &mut cache)?);
+            }
+        }
+        Ok(exported)
+    }
+
+    pub fn get(&self, raft_group_id: u64, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(memtable) = self.memtables.get(raft_group_id) {
+            if let Some(value) = memtable.read().get(key) {
+                return Ok(Some(self.read_value(value)?));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Computes the purge watermarks of current log files with the given
+    /// policy.
+    pub fn purge_watermarks(&self, policy: &dyn PurgePolicy) -> PurgeWatermarks {
+        let span = |queue| {
+            let mut seqs = self
+                .files
+                .iter()
+                .filter(|f| f.file_id.queue == queue)
+                .map(|f| f.file_id.seq);
+            let first = seqs.next()?;
+            Some((first, seqs.next_back().unwrap_or(first)))
+        };
+        let mut watermarks = PurgeWatermarks {
+            append_span: span(LogQueue::Append),
+            rewrite_span: span(LogQueue::Rewrite),
+            ..Default::default()
+        };
+        if let Some((first, active)) = watermarks.append_span {
+            // Same as `PurgeManager`, the active file is excluded.
+            if first < active {
+                let (rewrite, compact) = policy.append_queue_watermarks(first, active);
+                watermarks.rewrite_watermark = Some(std::cmp::min(rewrite, active - 1));
+                watermarks.compact_watermark = Some(std::cmp::min(compact, active - 1));
+            }
+        }
+        let min_referenced = |queue| {
+            self.memtables.fold(None, |min: Option<FileSeq>, t| {
+                match (min, t.min_file_seq(queue)) {
+                    (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+                    (a, b) => a.or(b),
+                }
+            })
+        };
+        watermarks.append_min_referenced = min_referenced(LogQueue::Append);
+        watermarks.rewrite_min_referenced = min_referenced(LogQueue::Rewrite);
+        watermarks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::DefaultFileSystem;
+    use crate::test_util::generate_entries;
+    use crate::{Config, DefaultPurgePolicy, Engine, ReadableSize};
+    use raft::eraftpb::Entry;
+    use std::io::{Seek, SeekFrom, Write};
+
+    #[test]
+    fn test_inspector() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_inspector")
+            .tempdir()
+            .unwrap();
+        let cfg = Config {
+            dir: dir.path().to_str().unwrap().to_owned(),
+            target_file_size: ReadableSize(1),
+            enable_log_recycle: false,
+            ..Default::default()
+        };
+        let engine = Engine::open(cfg).unwrap();
+        let data = vec![b'x'; 16];
+        for index in 1..=10 {
+            let mut batch = LogBatch::default();
+            batch
+                .add_entries::<Entry>(1, &generate_entries(index, index + 1, Some(&data)))
+                .unwrap();
+            batch
+                .put(1, b"key".to_vec(), index.to_le_bytes().to_vec())
+                .unwrap();
+            engine.write(&mut batch, true).unwrap();
+        }
+        engine.compact_to(1, 3);
+        engine.purge_manager().must_rewrite_append_queue(None, None);
+        let mut batch = LogBatch::default();
+        batch
+            .add_entries::<Entry>(2, &generate_entries(1, 2, Some(&data)))
+            .unwrap();
+        engine.write(&mut batch, true).unwrap();
+        let entry = engine.get_entry::<Entry>(1, 5).unwrap().unwrap();
+        let files = engine.file_span(LogQueue::Append);
+        drop(engine);
+
+        let inspector = Inspector::open(Arc::new(DefaultFileSystem), dir.path()).unwrap();
+        assert_eq!(inspector.raft_groups(), vec![1, 2]);
+        let info = inspector.raft_group(1).unwrap().unwrap();
+        assert_eq!(info.first_index, Some(3));
+        assert_eq!(info.last_index, Some(10));
+        assert_eq!(info.rewrite_count, 8);
+        assert_eq!(info.keys, vec![b"key".to_vec()]);
+        assert!(info.files.iter().all(|f| f.queue == LogQueue::Rewrite));
+        assert_eq!(
+            inspector.get(1, b"key").unwrap(),
+            Some(10u64.to_le_bytes().to_vec())
+        );
+        assert!(inspector.entry_index(1, 2).is_none());
+        let idx = inspector.entry_index(1, 5).unwrap();
+        assert_eq!(
+            inspector.read_entry(&idx).unwrap(),
+            protobuf::Message::write_to_bytes(&entry).unwrap()
+        );
+        let watermarks = inspector.purge_watermarks(&DefaultPurgePolicy);
+        assert_eq!(watermarks.append_span.unwrap().1, files.1);
+        assert_eq!(
+            watermarks.append_min_referenced,
+            Some(inspector.raft_group(2).unwrap().unwrap().files[0].seq)
+        );
+        assert!(watermarks.rewrite_min_referenced.is_some());
+
+        // Corrupt the last append file. It's reported but left untouched.
+        let last = inspector.files().last().unwrap().clone();
+        assert!(last.corruption.is_none());
+        assert_eq!(last.valid_size, last.size);
+        let mut f = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&last.path)
+            .unwrap();
+        f.seek(SeekFrom::End(-1)).unwrap();
+        f.write_all(&[0xff]).unwrap();
+        drop(f);
+        let inspector = Inspector::open(Arc::new(DefaultFileSystem), dir.path()).unwrap();
+        let corrupted = inspector.files().last().unwrap();
+        assert!(corrupted.corruption.is_some());
+        assert!(corrupted.valid_size < corrupted.size);
+        assert_eq!(
+            std::fs::metadata(&last.path).unwrap().len() as usize,
+            last.size
+        );
+        assert_eq!(inspector.raft_groups(), vec![1]);
+    }
+}
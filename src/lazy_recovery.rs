@@ -0,0 +1,791 @@
+// Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
+
+//! Helper types to open the engine before log entries are indexed.
+//!
+//! When the engine is closed, a map of Raft Groups, their key value pairs and
+//! the log files containing their records is persisted. If the log files are unchanged on
+//! the next startup, the engine is opened with only key value pairs recovered
+//! from the map. Log entries of each Raft Group are then indexed in background
+//! by replaying its own log files.
+
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use fail::fail_point;
+use hashbrown::HashMap;
+use log::{error, info, warn};
+use parking_lot::{Condvar, Mutex};
+
+use crate::codec::{self, NumberEncoder};
+use crate::env::{FileSystem, Handle};
+use crate::file_pipe_log::{FilePipeLog, ReplayMachine};
+use crate::log_batch::{AtomicGroupStatus, KeyValue, LogItem, LogItemBatch, LogItemContent};
+use crate::memtable::{KvValue, MemTableRecoverContextFactory, MemTables};
+use crate::pipe_log::{FileBlockHandle, FileId, FileSeq, LogQueue};
+use crate::util::{crc32, Factory};
+use crate::{Error, GlobalStats, Result};
+
+pub(crate) const REGION_MAP_FILE_NAME: &str = "REGION_MAP";
+const REGION_MAP_TMP_FILE_NAME: &str = "REGION_MAP.tmp";
+const REGION_MAP_MAGIC: u64 = 0x5245_4749_4f4e_4d50;
+// Version 2 adds key value pairs spilled to log files.
+const REGION_MAP_VERSION: u64 = 2;
+
+/// Maximum time for a read or write to wait until the entries of a Raft Group
+/// are indexed.
+pub(crate) const INDEX_WAIT_TIMEOUT: Duration = Duration::from_millis(10);
+
+#[inline]
+fn has_internal_key(item: &LogItem) -> bool {
+    matches!(&item.content, LogItemContent::Kv(KeyValue { key, .. }) if crate::is_internal_key(key, None))
+}
+
+/// Tracks the log files that contain records of each Raft Group. Replaying
+/// these files is enough to recover a Raft Group.
+#[derive(Default)]
+pub struct RegionFileTracker {
+    files: Mutex<HashMap<u64, BTreeSet<FileId>>>,
+    // Log files that contain renames or clones of Raft Groups. Entries of a
+    // Raft Group can't be recovered from its own files while they exist.
+    migrations: Mutex<BTreeSet<FileId>>,
+}
+
+impl RegionFileTracker {
+    /// Tracks the Raft Groups in `item_batch`, which is written to `file_id`.
+    pub fn track(&self, item_batch: &LogItemBatch, file_id: FileId) {
+        let mut files = self.files.lock();
+        for item in item_batch.iter() {
+            // Atomic group markers carry group IDs rather than Raft Group IDs.
+            if !has_internal_key(item) {
+                files.entry(item.raft_group_id).or_default().insert(file_id);
+            }
+            if matches!(&item.content, LogItemContent::Command(cmd) if cmd.source().is_some()) {
+                self.migrations.lock().insert(file_id);
+            }
+        }
+    }
+
+    pub fn insert(&self, region_id: u64, region_files: impl IntoIterator<Item = FileId>) {
+        self.files
+            .lock()
+            .entry(region_id)
+            .or_default()
+            .extend(region_files);
+    }
+
+    pub fn merge(&self, rhs: RegionFileTracker) {
+        let mut files = self.files.lock();
+        for (region_id, region_files) in rhs.files.into_inner() {
+            files.entry(region_id).or_default().extend(region_files);
+        }
+        self.migrations.lock().extend(rhs.migrations.into_inner());
+    }
+
+    /// Returns the files of a Raft Group, ordered by freshness.
+    pub fn files(&self, region_id: u64) -> Vec<FileId> {
+        self.files
+            .lock()
+            .get(&region_id)
+            .map_or_else(Vec::new, |f| f.iter().copied().collect())
+    }
+
+    /// Stops tracking the files older than `file_id` in the same queue.
+    pub fn untrack_before(&self, file_id: FileId) {
+        self.files.lock().retain(|_, region_files| {
+            region_files.retain(|f| f.queue != file_id.queue || f.seq >= file_id.seq);
+            !region_files.is_empty()
+        });
+        self.migrations
+            .lock()
+            .retain(|f| f.queue != file_id.queue || f.seq >= file_id.seq);
+    }
+
+    /// Returns whether any tracked file contains a rename or clone of Raft
+    /// Groups.
+    pub fn has_migrations(&self) -> bool {
+        !self.migrations.lock().is_empty()
+    }
+}
+
+/// Persisted states of a Raft Group.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RegionRecord {
+    pub region_id: u64,
+    /// Log files that contain records of this Raft Group.
+    pub files: Vec<FileId>,
+    pub kvs: Vec<(Vec<u8>, KvValue, FileId)>,
+}
+
+/// A snapshot of all Raft Groups, taken when the engine is closed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RegionMap {
+    /// File spans of append and rewrite queue when the map is taken.
+    pub spans: [(FileSeq, FileSeq); 2],
+    pub regions: Vec<RegionRecord>,
+}
+
+fn encode_file_id(buf: &mut Vec<u8>, file_id: FileId) -> Result<()> {
+    buf.push(file_id.queue as u8);
+    buf.encode_var_u64(file_id.seq)?;
+    Ok(())
+}
+
+fn decode_file_id(buf: &mut &[u8]) -> Result<FileId> {
+    let queue = match codec::read_u8(buf)? {
+        0 => LogQueue::Append,
+        1 => LogQueue::Rewrite,
+        q => return Err(Error::Corruption(format!("unknown log queue {q}"))),
+    };
+    let seq = codec::decode_var_u64(buf)?;
+    Ok(FileId { queue, seq })
+}
+
+fn encode_bytes(buf: &mut Vec<u8>, bytes: &[u8]) -> Result<()> {
+    buf.encode_var_u64(bytes.len() as u64)?;
+    buf.extend_from_slice(bytes);
+    Ok(())
+}
+
+const KV_VALUE_INLINE: u8 = 0;
+const KV_VALUE_SPILLED: u8 = 1;
+
+fn encode_kv_value(buf: &mut Vec<u8>, value: &KvValue) -> Result<()> {
+    match value {
+        KvValue::Inline(value) => {
+            buf.push(KV_VALUE_INLINE);
+            encode_bytes(buf, value)?;
+        }
+        KvValue::Spilled(handle, checksum) => {
+            buf.push(KV_VALUE_SPILLED);
+            buf.encode_var_u64(handle.offset)?;
+            buf.encode_var_u64(handle.len as u64)?;
+            buf.encode_u32_le(*checksum)?;
+        }
+    }
+    Ok(())
+}
+
+fn decode_kv_value(buf: &mut &[u8], file_id: FileId) -> Result<KvValue> {
+    match codec::read_u8(buf)? {
+        KV_VALUE_INLINE => Ok(KvValue::Inline(decode_bytes(buf)?)),
+        KV_VALUE_SPILLED => Ok(KvValue::Spilled(
+            FileBlockHandle {
+                id: file_id,
+                offset: codec::decode_var_u64(buf)?,
+                len: codec::decode_var_u64(buf)? as usize,
+            },
+            codec::decode_u32_le(buf)?,
+        )),
+        t => Err(Error::Corruption(format!("unknown value type {t}"))),
+    }
+}
+
+fn decode_bytes(buf: &mut &[u8]) -> Result<Vec<u8>> {
+    let len = codec::decode_var_u64(buf)? as usize;
+    if buf.len() < len {
+        return Err(Error::Corruption("region map is truncated".to_owned()));
+    }
+    let (bytes, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(bytes.to_vec())
+}
+
+impl RegionMap {
+    // { magic | version | spans | region count | [regions] | crc32 }
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        buf.encode_u64(REGION_MAP_MAGIC)?;
+        buf.encode_var_u64(REGION_MAP_VERSION)?;
+        for (first, last) in self.spans {
+            buf.encode_var_u64(first)?;
+            buf.encode_var_u64(last)?;
+        }
+        buf.encode_var_u64(self.regions.len() as u64)?;
+        for region in &self.regions {
+            buf.encode_var_u64(region.region_id)?;
+            buf.encode_var_u64(region.files.len() as u64)?;
+            for file_id in &region.files {
+                encode_file_id(&mut buf, *file_id)?;
+            }
+            buf.encode_var_u64(region.kvs.len() as u64)?;
+            for (key, value, file_id) in &region.kvs {
+                encode_bytes(&mut buf, key)?;
+                encode_file_id(&mut buf, *file_id)?;
+                encode_kv_value(&mut buf, value)?;
+            }
+        }
+        let checksum = crc32(&buf);
+        buf.encode_u32_le(checksum)?;
+        Ok(buf)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 4 {
+            return Err(Error::Corruption("region map is truncated".to_owned()));
+        }
+        let (mut buf, mut footer) = buf.split_at(buf.len() - 4);
+        if codec::decode_u32_le(&mut footer)? != crc32(buf) {
+            return Err(Error::Corruption("region map checksum mismatch".to_owned()));
+        }
+        if codec::decode_u64(&mut buf)? != REGION_MAP_MAGIC {
+            return Err(Error::Corruption("region map magic mismatch".to_owned()));
+        }
+        let version = codec::decode_var_u64(&mut buf)?;
+        if version == 0 || version > REGION_MAP_VERSION {
+            return Err(Error::Corruption(format!(
+                "unsupported region map version {version}"
+            )));
+        }
+        let mut map = RegionMap::default();
+        for span in map.spans.iter_mut() {
+            span.0 = codec::decode_var_u64(&mut buf)?;
+            span.1 = codec::decode_var_u64(&mut buf)?;
+        }
+        let count = codec::decode_var_u64(&mut buf)?;
+        for _ in 0..count {
+            let mut region = RegionRecord {
+                region_id: codec::decode_var_u64(&mut buf)?,
+                ..Default::default()
+            };
+            let files = codec::decode_var_u64(&mut buf)?;
+            for _ in 0..files {
+                region.files.push(decode_file_id(&mut buf)?);
+            }
+            let kvs = codec::decode_var_u64(&mut buf)?;
+            for _ in 0..kvs {
+                let key = decode_bytes(&mut buf)?;
+                if version == 1 {
+                    let value = decode_bytes(&mut buf)?;
+                    region
+                        .kvs
+                        .push((key, value.into(), decode_file_id(&mut buf)?));
+                } else {
+                    let file_id = decode_file_id(&mut buf)?;
+                    let value = decode_kv_value(&mut buf, file_id)?;
+                    region.kvs.push((key, value, file_id));
+                }
+            }
+            map.regions.push(region);
+        }
+        Ok(map)
+    }
+
+    /// Reads the map under directory `dir`. Returns `None` if it doesn't
+    /// exist or is broken.
+    pub fn load<F: FileSystem>(file_system: &F, dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(REGION_MAP_FILE_NAME);
+        if !file_system.exists(&path) {
+            return Ok(None);
+        }
+        let handle = Arc::new(file_system.open(&path)?);
+        let mut buf = Vec::new();
+        file_system.new_reader(handle)?.read_to_end(&mut buf)?;
+        match Self::decode(&buf) {
+            Ok(map) => Ok(Some(map)),
+            Err(e) => {
+                warn!("Ignore broken region map: {}", e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Atomically writes the map under directory `dir`.
+    pub fn persist<F: FileSystem>(&self, file_system: &F, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(REGION_MAP_TMP_FILE_NAME);
+        let handle = Arc::new(file_system.create(&tmp_path)?);
+        let mut writer = file_system.new_writer(handle.clone())?;
+        writer.write_all(&self.encode()?)?;
+        handle.sync()?;
+        file_system.rename(tmp_path, dir.join(REGION_MAP_FILE_NAME))?;
+        file_system.sync_dir(dir)?;
+        Ok(())
+    }
+
+    /// Deletes the map under directory `dir` if it exists. It must be done
+    /// before any log file is modified.
+    pub fn remove<F: FileSystem>(file_system: &F, dir: &Path) -> Result<()> {
+        let path = dir.join(REGION_MAP_FILE_NAME);
+        if file_system.exists(&path) {
+            file_system.delete(&path)?;
+            file_system.sync_dir(dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// Replays only the log items of the selected Raft Groups.
+struct RegionFilter<'a, M: ReplayMachine> {
+    regions: &'a HashSet<u64>,
+    machine: &'a mut M,
+}
+
+impl<'a, M: ReplayMachine> RegionFilter<'a, M> {
+    fn replay(&mut self, mut item_batch: LogItemBatch, file_id: FileId) -> Result<()> {
+        let mut filtered = LogItemBatch::with_capacity(item_batch.iter().len());
+        for item in item_batch.drain() {
+            // Atomic group markers are kept to discard incomplete groups.
+            if AtomicGroupStatus::parse(&item).is_some()
+                || self.regions.contains(&item.raft_group_id)
+            {
+                filtered.push(item);
+            }
+        }
+        if filtered.iter().len() > 0 {
+            self.machine.replay(filtered, file_id)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct PendingRegions {
+    /// Raft Groups whose entries are not yet indexed.
+    regions: HashSet<u64>,
+    /// Raft Groups to be indexed first.
+    prioritized: VecDeque<u64>,
+    stopped: bool,
+    error: Option<String>,
+}
+
+/// States of a lazily recovered engine.
+///
+/// Entries of the pending Raft Groups are indexed by a background thread. No
+/// log file can be purged until all of them are indexed.
+pub struct LazyRecovery<F: FileSystem> {
+    file_system: Arc<F>,
+    dir: PathBuf,
+    tracker: Arc<RegionFileTracker>,
+    finished: AtomicBool,
+    pending: Mutex<PendingRegions>,
+    cond: Condvar,
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl<F: FileSystem> LazyRecovery<F> {
+    pub fn new(
+        file_system: Arc<F>,
+        dir: PathBuf,
+        tracker: Arc<RegionFileTracker>,
+        regions: HashSet<u64>,
+    ) -> Self {
+        Self {
+            file_system,
+            dir,
+            tracker,
+            finished: AtomicBool::new(regions.is_empty()),
+            pending: Mutex::new(PendingRegions {
+                regions,
+                ..Default::default()
+            }),
+            cond: Condvar::new(),
+            worker: Mutex::new(None),
+        }
+    }
+
+    pub fn tracker(&self) -> &Arc<RegionFileTracker> {
+        &self.tracker
+    }
+
+    /// Persists `map` to be used by the next startup.
+    pub fn persist(&self, map: &RegionMap) -> Result<()> {
+        map.persist(self.file_system.as_ref(), &self.dir)
+    }
+
+    /// Returns whether the entries of all Raft Groups are indexed.
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+
+    pub fn is_ready(&self, region_id: u64) -> bool {
+        self.is_finished() || !self.pending.lock().regions.contains(&region_id)
+    }
+
+    /// Indexes the entries of the specified Raft Groups before others.
+    pub fn prioritize(&self, region_ids: &[u64]) {
+        if self.is_finished() {
+            return;
+        }
+        let mut pending = self.pending.lock();
+        let mut notify = false;
+        for id in region_ids {
+            if pending.regions.contains(id) {
+                pending.prioritized.push_back(*id);
+                notify = true;
+            }
+        }
+        if notify {
+            self.cond.notify_all();
+        }
+    }
+
+    /// Blocks until the entries of the specified Raft Groups are indexed.
+    /// Returns [`Error::NotReady`] if `timeout` is reached.
+    pub fn wait(&self, region_ids: &[u64], timeout: Option<Duration>) -> Result<()> {
+        if self.is_finished() {
+            return Ok(());
+        }
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut pending = self.pending.lock();
+        let mut prioritized = false;
+        loop {
+            if let Some(e) = &pending.error {
+                return Err(box_err!("Failed to index log entries: {}", e));
+            }
+            let not_ready: Vec<u64> = region_ids
+                .iter()
+                .copied()
+                .filter(|id| pending.regions.contains(id))
+                .collect();
+            if not_ready.is_empty() {
+                return Ok(());
+            }
+            if pending.stopped {
+                return Err(Error::NotReady);
+            }
+            if !prioritized {
+                pending.prioritized.extend(not_ready);
+                self.cond.notify_all();
+                prioritized = true;
+            }
+            match deadline {
+                Some(deadline) => {
+                    if self.cond.wait_until(&mut pending, deadline).timed_out() {
+                        return Err(Error::NotReady);
+                    }
+                }
+                None => self.cond.wait(&mut pending),
+            }
+        }
+    }
+
+    /// Starts indexing entries in a background thread.
+    pub fn start(
+        self: &Arc<Self>,
+        pipe_log: Arc<FilePipeLog<F>>,
+        memtables: MemTables,
+        stats: Arc<GlobalStats>,
+        factory: MemTableRecoverContextFactory,
+        read_block_size: usize,
+    ) -> Result<()>
+    where
+        F: 'static,
+    {
+        if self.is_finished() {
+            return Ok(());
+        }
+        let worker = Worker {
+            lazy: self.clone(),
+            pipe_log,
+            memtables,
+            stats,
+            factory,
+            read_block_size,
+        };
+        let handle = std::thread::Builder::new()
+            .name("re-recovery".into())
+            .spawn(move || worker.run())?;
+        *self.worker.lock() = Some(handle);
+        Ok(())
+    }
+
+    /// Indexes entries of all pending Raft Groups in the current thread.
+    pub fn index_all(
+        self: &Arc<Self>,
+        pipe_log: Arc<FilePipeLog<F>>,
+        memtables: MemTables,
+        stats: Arc<GlobalStats>,
+        factory: MemTableRecoverContextFactory,
+        read_block_size: usize,
+    ) -> Result<()> {
+        if self.is_finished() {
+            return Ok(());
+        }
+        Worker {
+            lazy: self.clone(),
+            pipe_log,
+            memtables,
+            stats,
+            factory,
+            read_block_size,
+        }
+        .index_all()
+    }
+
+    /// Stops the background thread. Raft Groups that are not yet indexed stay
+    /// pending.
+    pub fn stop(&self) {
+        self.pending.lock().stopped = true;
+        self.cond.notify_all();
+        if let Some(handle) = self.worker.lock().take() {
+            handle.join().unwrap();
+        }
+    }
+
+    // Returns the prioritized Raft Groups that are still pending.
+    fn take_prioritized(&self, pending: &mut PendingRegions) -> HashSet<u64> {
+        let mut regions = HashSet::new();
+        for id in pending.prioritized.drain(..) {
+            if pending.regions.contains(&id) {
+                regions.insert(id);
+            }
+        }
+        regions
+    }
+}
+
+struct Worker<F: FileSystem> {
+    lazy: Arc<LazyRecovery<F>>,
+    pipe_log: Arc<FilePipeLog<F>>,
+    memtables: MemTables,
+    stats: Arc<GlobalStats>,
+    factory: MemTableRecoverContextFactory,
+    read_block_size: usize,
+}
+
+impl<F: FileSystem> Worker<F> {
+    fn run(self) {
+        if let Err(e) = self.index_all() {
+            error!("Failed to index log entries: {}", e);
+            self.lazy.pending.lock().error = Some(e.to_string());
+            self.lazy.cond.notify_all();
+        }
+    }
+
+    // Indexes pending Raft Groups until all of them are indexed or it's
+    // stopped.
+    fn index_all(&self) -> Result<()> {
+        fail_point!("lazy_recovery::index_all");
+        let start = Instant::now();
+        loop {
+            let (regions, interruptible) = {
+                let mut pending = self.lazy.pending.lock();
+                if pending.stopped {
+                    return Ok(());
+                }
+                if pending.regions.is_empty() {
+                    self.lazy.finished.store(true, Ordering::Release);
+                    self.lazy.cond.notify_all();
+                    info!("Indexing log entries takes {:?}", start.elapsed());
+                    return Ok(());
+                }
+                let prioritized = self.lazy.take_prioritized(&mut pending);
+                if prioritized.is_empty() {
+                    (pending.regions.clone(), true)
+                } else {
+                    (prioritized, false)
+                }
+            };
+            self.index_regions(regions, interruptible, true)?;
+        }
+    }
+
+    // Indexes the entries of `regions` by replaying their log files. When
+    // `interruptible` is true, prioritized Raft Groups are indexed in between.
+    // Garbage of rewrite queue is only counted when `count_garbage` is true.
+    fn index_regions(
+        &self,
+        regions: HashSet<u64>,
+        interruptible: bool,
+        count_garbage: bool,
+    ) -> Result<()> {
+        // Raft Groups indexed in between.
+        let mut indexed = HashSet::new();
+        let mut files = BTreeSet::new();
+        for id in &regions {
+            files.extend(self.lazy.tracker.files(*id));
+        }
+        let mut rewrite = self.factory.new_target();
+        let mut append = self.factory.new_target();
+        for file_id in files {
+            if interruptible {
+                let prioritized = {
+                    let mut pending = self.lazy.pending.lock();
+                    if pending.stopped {
+                        return Ok(());
+                    }
+                    self.lazy.take_prioritized(&mut pending)
+                };
+                if !prioritized.is_empty() {
+                    // They are still replayed in this pass, where their
+                    // garbage is counted, as part of their files might have
+                    // been replayed already.
+                    indexed.extend(prioritized.iter().copied());
+                    self.index_regions(prioritized, false, false)?;
+                }
+            } else if self.lazy.pending.lock().stopped {
+                return Ok(());
+            }
+            let machine = match file_id.queue {
+                LogQueue::Append => &mut append,
+                LogQueue::Rewrite => &mut rewrite,
+            };
+            let mut filter = RegionFilter {
+                regions: &regions,
+                machine,
+            };
+            self.pipe_log.replay_files(
+                &[file_id],
+                self.read_block_size,
+                &mut |item_batch, file_id| filter.replay(item_batch, file_id),
+            )?;
+        }
+        rewrite.merge_append_context(append);
+        let (memtables, stats) = rewrite.finish();
+        for id in regions.iter().filter(|id| !indexed.contains(id)) {
+            if let (Some(rebuilt), Some(memtable)) = (memtables.get(*id), self.memtables.get(*id)) {
+                memtable.write().restore_entries(&mut rebuilt.write());
+            }
+        }
+        if count_garbage {
+            // Keep the garbage ratio of rewrite queue.
+            let deleted = stats.deleted_rewrite_entries();
+            self.stats.add(LogQueue::Rewrite, deleted);
+            self.stats.delete(LogQueue::Rewrite, deleted);
+        }
+
+        let mut pending = self.lazy.pending.lock();
+        for id in &regions {
+            pending.regions.remove(id);
+        }
+        self.lazy.cond.notify_all();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_region_map_codec() {
+        let map = RegionMap {
+            spans: [(3, 10), (1, 2)],
+            regions: vec![
+                RegionRecord {
+                    region_id: 1,
+                    files: vec![
+                        FileId::new(LogQueue::Rewrite, 2),
+                        FileId::new(LogQueue::Append, 5),
+                    ],
+                    kvs: vec![
+                        (
+                            b"k1".to_vec(),
+                            b"v1".to_vec().into(),
+                            FileId::new(LogQueue::Append, 5),
+                        ),
+                        (
+                            b"k2".to_vec(),
+                            KvValue::Spilled(
+                                FileBlockHandle {
+                                    id: FileId::new(LogQueue::Rewrite, 2),
+                                    offset: 4096,
+                                    len: 1024,
+                                },
+                                0xdead_beef,
+                            ),
+                            FileId::new(LogQueue::Rewrite, 2),
+                        ),
+                    ],
+                },
+                RegionRecord {
+                    region_id: u64::MAX,
+                    ..Default::default()
+                },
+            ],
+        };
+        let mut buf = map.encode().unwrap();
+        assert_eq!(RegionMap::decode(&buf).unwrap(), map);
+        buf[10] ^= 1;
+        assert!(RegionMap::decode(&buf).is_err());
+        assert!(RegionMap::decode(&buf[..3]).is_err());
+
+        // Maps of version 1 only have inline values.
+        let mut buf = Vec::new();
+        buf.encode_u64(REGION_MAP_MAGIC).unwrap();
+        buf.encode_var_u64(1).unwrap();
+        for _ in 0..4 {
+            buf.encode_var_u64(1).unwrap();
+        }
+        buf.encode_var_u64(1).unwrap();
+        buf.encode_var_u64(7).unwrap();
+        buf.encode_var_u64(0).unwrap();
+        buf.encode_var_u64(1).unwrap();
+        encode_bytes(&mut buf, b"k1").unwrap();
+        encode_bytes(&mut buf, b"v1").unwrap();
+        encode_file_id(&mut buf, FileId::new(LogQueue::Append, 1)).unwrap();
+        let checksum = crc32(&buf);
+        buf.encode_u32_le(checksum).unwrap();
+        let map = RegionMap::decode(&buf).unwrap();
+        assert_eq!(
+            map.regions[0].kvs,
+            vec![(
+                b"k1".to_vec(),
+                b"v1".to_vec().into(),
+                FileId::new(LogQueue::Append, 1)
+            )]
+        );
+    }
+
+    #[test]
+    fn test_region_file_tracker() {
+        let tracker = RegionFileTracker::default();
+        let mut batch = crate::LogBatch::default();
+        batch.put(1, b"k".to_vec(), b"v".to_vec()).unwrap();
+        batch.add_command(2, crate::Command::Compact { index: 5 });
+        tracker.track(batch.item_batch(), FileId::new(LogQueue::Append, 3));
+        tracker.insert(1, [FileId::new(LogQueue::Rewrite, 7)]);
+        let other = RegionFileTracker::default();
+        other.insert(2, [FileId::new(LogQueue::Append, 1)]);
+        tracker.merge(other);
+        assert_eq!(
+            tracker.files(1),
+            vec![
+                FileId::new(LogQueue::Rewrite, 7),
+                FileId::new(LogQueue::Append, 3)
+            ]
+        );
+        assert_eq!(
+            tracker.files(2),
+            vec![
+                FileId::new(LogQueue::Append, 1),
+                FileId::new(LogQueue::Append, 3)
+            ]
+        );
+        tracker.untrack_before(FileId::new(LogQueue::Append, 3));
+        assert_eq!(tracker.files(2), vec![FileId::new(LogQueue::Append, 3)]);
+        tracker.untrack_before(FileId::new(LogQueue::Append, 4));
+        assert!(tracker.files(2).is_empty());
+        assert_eq!(tracker.files(1), vec![FileId::new(LogQueue::Rewrite, 7)]);
+    }
+
+    #[test]
+    fn test_wait_pending_regions() {
+        let lazy_recovery = LazyRecovery::new(
+            Arc::new(crate::env::DefaultFileSystem),
+            PathBuf::new(),
+            Arc::new(RegionFileTracker::default()),
+            [1, 2].iter().copied().collect(),
+        );
+        assert!(!lazy_recovery.is_finished());
+        assert!(lazy_recovery.is_ready(3));
+        assert!(!lazy_recovery.is_ready(1));
+        lazy_recovery.wait(&[3], None).unwrap();
+        assert!(matches!(
+            lazy_recovery.wait(&[1, 3], Some(Duration::from_millis(1))),
+            Err(Error::NotReady)
+        ));
+        let prioritized = lazy_recovery.take_prioritized(&mut lazy_recovery.pending.lock());
+        assert_eq!(prioritized, [1].iter().copied().collect());
+        lazy_recovery.stop();
+        assert!(matches!(
+            lazy_recovery.wait(&[2], None),
+            Err(Error::NotReady)
+        ));
+    }
+}
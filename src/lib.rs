@@ -43,16 +43,25 @@ mod codec;
 mod config;
 mod consistency;
 mod engine;
+mod entry_iter;
 mod errors;
 mod event_listener;
+mod export;
 mod file_pipe_log;
 #[cfg(feature = "scripting")]
 mod filter;
+mod inspector;
+mod lazy_recovery;
 mod log_batch;
 mod memtable;
 mod metrics;
 mod pipe_log;
 mod purge;
+mod purge_policy;
+mod rate_limiter;
+mod recovery;
+mod scrubber;
+mod space;
 #[cfg(feature = "swap")]
 mod swappy_allocator;
 #[cfg(test)]
@@ -62,23 +71,40 @@ mod write_barrier;
 
 pub mod env;
 
-pub use config::{Config, RecoveryMode};
-pub use engine::Engine;
+pub use config::{Config, ConfigChange, RecoveryMode};
+pub use consistency::{DeepCheckReport, FileCheckResult, RegionCheckResult};
+pub use engine::{AtomicGroup, Engine, EngineBuilder, Snapshot};
+pub use entry_iter::EntryIter;
 pub use errors::{Error, Result};
+pub use export::{DirectoryRewriteReport, ExportedRegion, ExportedRegions, ImportedRegion};
 pub use log_batch::{Command, LogBatch, MessageExt};
 pub use metrics::{get_perf_context, set_perf_context, take_perf_context, PerfContext};
 pub use pipe_log::Version;
+pub use purge_policy::{
+    DefaultPurgePolicy, PurgeAction, PurgePolicy, RaftGroupView, SizeTieredPurgePolicy,
+};
+pub use recovery::{
+    CancellationToken, CorruptedRange, RecoveryProgress, RecoveryProgressSink, RecoveryReport,
+    Truncation,
+};
+pub use space::{FileSpaceStats, QueueSpaceStats, SpaceStats};
 pub use util::ReadableSize;
 
 #[cfg(feature = "internals")]
 pub mod internals {
     //! A selective view of key components in Raft Engine. Exported under the
     //! `internals` feature only.
+    pub use crate::config::SharedConfig;
     pub use crate::event_listener::*;
     pub use crate::file_pipe_log::*;
+    pub use crate::inspector::*;
+    pub use crate::log_batch::{
+        CompressionType, EntryIndexes, KeyValue, LogItem, LogItemContent, OpType,
+    };
     pub use crate::memtable::*;
     pub use crate::pipe_log::*;
     pub use crate::purge::*;
+    pub use crate::rate_limiter::*;
     #[cfg(feature = "swap")]
     pub use crate::swappy_allocator::*;
     pub use crate::write_barrier::*;
@@ -15,7 +15,7 @@ use protobuf::Message;
 use crate::codec::{self, NumberEncoder};
 use crate::memtable::EntryIndex;
 use crate::metrics::StopWatch;
-use crate::pipe_log::{FileBlockHandle, FileId, LogFileContext, ReactiveBytes};
+use crate::pipe_log::{FileBlockHandle, FileId, LogFileContext, LogQueue, ReactiveBytes};
 use crate::util::{crc32, lz4};
 use crate::{perf_context, Error, Result};
 
@@ -28,6 +28,8 @@ const TYPE_KV: u8 = 0x3;
 
 const CMD_CLEAN: u8 = 0x01;
 const CMD_COMPACT: u8 = 0x02;
+const CMD_RENAME: u8 = 0x03;
+const CMD_CLONE: u8 = 0x04;
 
 const DEFAULT_LOG_ITEM_BATCH_CAP: usize = 64;
 const MAX_LOG_BATCH_BUFFER_CAP: usize = 8 * 1024 * 1024;
@@ -117,11 +119,25 @@ impl EntryIndexes {
 }
 
 // Format:
-// { type | (index) }
+// { type | (index) | (from) | (from | begin | end) }
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Command {
     Clean,
-    Compact { index: u64 },
+    Compact {
+        index: u64,
+    },
+    /// Takes over all entries and key value pairs of Raft Group `from`, which
+    /// is removed afterwards.
+    Rename {
+        from: u64,
+    },
+    /// Shares the entries of Raft Group `from` within `[begin, end)`. Only
+    /// applied to a Raft Group without entries.
+    Clone {
+        from: u64,
+        begin: u64,
+        end: u64,
+    },
 }
 
 impl Command {
@@ -134,6 +150,16 @@ impl Command {
                 vec.push(CMD_COMPACT);
                 vec.encode_var_u64(index).unwrap();
             }
+            Command::Rename { from } => {
+                vec.push(CMD_RENAME);
+                vec.encode_var_u64(from).unwrap();
+            }
+            Command::Clone { from, begin, end } => {
+                vec.push(CMD_CLONE);
+                vec.encode_var_u64(from).unwrap();
+                vec.encode_var_u64(begin).unwrap();
+                vec.encode_var_u64(end).unwrap();
+            }
         }
     }
 
@@ -145,6 +171,16 @@ impl Command {
                 let index = codec::decode_var_u64(buf)?;
                 Ok(Command::Compact { index })
             }
+            CMD_RENAME => {
+                let from = codec::decode_var_u64(buf)?;
+                Ok(Command::Rename { from })
+            }
+            CMD_CLONE => {
+                let from = codec::decode_var_u64(buf)?;
+                let begin = codec::decode_var_u64(buf)?;
+                let end = codec::decode_var_u64(buf)?;
+                Ok(Command::Clone { from, begin, end })
+            }
             _ => Err(Error::Corruption(format!(
                 "Unrecognized command type: {}",
                 command_type
@@ -154,8 +190,18 @@ impl Command {
 
     fn approximate_size(&self) -> usize {
         match &self {
-            Command::Clean => 1,              /* type */
-            Command::Compact { .. } => 1 + 8, /* type + index */
+            Command::Clean => 1,                /* type */
+            Command::Compact { .. } => 1 + 8,   /* type + index */
+            Command::Rename { .. } => 1 + 8,    /* type + from */
+            Command::Clone { .. } => 1 + 8 * 3, /* type + from + begin + end */
+        }
+    }
+
+    /// Returns the Raft Group whose entries are taken by this command.
+    pub(crate) fn source(&self) -> Option<u64> {
+        match *self {
+            Command::Rename { from } | Command::Clone { from, .. } => Some(from),
+            _ => None,
         }
     }
 }
@@ -189,6 +235,10 @@ pub struct KeyValue {
     pub key: Vec<u8>,
     pub value: Option<Vec<u8>>,
     pub file_id: Option<FileId>,
+    /// Location of the value in log file, set when the key value pair is
+    /// written or read from a log file. Before [`LogItemBatch::finish_write`],
+    /// its offset is relative to the encoded [`LogItemBatch`].
+    pub value_handle: Option<FileBlockHandle>,
 }
 
 impl KeyValue {
@@ -198,6 +248,7 @@ impl KeyValue {
             key,
             value,
             file_id: None,
+            value_handle: None,
         }
     }
 
@@ -449,6 +500,11 @@ impl LogItemBatch {
                 LogItemContent::Kv(kv) => {
                     debug_assert!(kv.file_id.is_none());
                     kv.file_id = Some(handle.id);
+                    // Log items are encoded right after the entries.
+                    if let Some(value_handle) = &mut kv.value_handle {
+                        value_handle.id = handle.id;
+                        value_handle.offset += handle.offset + handle.len as u64;
+                    }
                 }
                 _ => {}
             }
@@ -488,12 +544,17 @@ impl LogItemBatch {
         self.items.push(item);
     }
 
-    pub fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+    /// Encodes the log items, and records the relative locations of values in
+    /// the encoded bytes.
+    pub fn encode(&mut self, buf: &mut Vec<u8>) -> Result<()> {
         let offset = buf.len();
         let count = self.items.len() as u64;
         buf.encode_var_u64(count)?;
-        for item in self.items.iter() {
+        for item in self.items.iter_mut() {
             item.encode(buf)?;
+            if let LogItemContent::Kv(kv) = &mut item.content {
+                kv.value_handle = value_handle(kv, buf.len() - offset);
+            }
         }
         let checksum = crc32(&buf[offset..]);
         buf.encode_u32_le(checksum)?;
@@ -511,11 +572,15 @@ impl LogItemBatch {
         // Validate the checksum of each LogItemBatch by the signature.
         verify_checksum_with_signature(buf, file_context.get_signature())?;
         *buf = &buf[..buf.len() - LOG_BATCH_CHECKSUM_LEN];
+        let footer_len = buf.len();
         let count = codec::decode_var_u64(buf)?;
         let mut items = LogItemBatch::with_capacity(count as usize);
         let mut entries_size = 0;
         for _ in 0..count {
-            let item = LogItem::decode(buf, &mut entries_size)?;
+            let mut item = LogItem::decode(buf, &mut entries_size)?;
+            if let LogItemContent::Kv(kv) = &mut item.content {
+                kv.value_handle = value_handle(kv, footer_len - buf.len());
+            }
             items.item_size += item.approximate_size();
             items.items.push(item);
         }
@@ -529,6 +594,10 @@ impl LogItemBatch {
                 }
             } else if let LogItemContent::Kv(kv) = &mut item.content {
                 kv.file_id = Some(entries.id);
+                if let Some(value_handle) = &mut kv.value_handle {
+                    value_handle.id = entries.id;
+                    value_handle.offset += entries.offset + entries.len as u64;
+                }
             }
         }
         Ok(items)
@@ -539,6 +608,21 @@ impl LogItemBatch {
     }
 }
 
+/// Returns the location of the value of `kv` relative to the encoded
+/// [`LogItemBatch`], given the end offset of `kv`. A value is encoded at the
+/// end of its log item.
+fn value_handle(kv: &KeyValue, end: usize) -> Option<FileBlockHandle> {
+    match (kv.op_type, &kv.value) {
+        (OpType::Put, Some(value)) => Some(FileBlockHandle {
+            // The file is set when the log items are written or decoded.
+            id: FileId::new(LogQueue::Append, 0),
+            offset: (end - value.len()) as u64,
+            len: value.len(),
+        }),
+        _ => None,
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 enum BufState {
     /// Buffer contains header and optionally entries.
@@ -846,6 +930,11 @@ impl LogBatch {
         self.item_batch.finish_write(handle);
     }
 
+    /// Returns the log items of this batch.
+    pub(crate) fn item_batch(&self) -> &LogItemBatch {
+        &self.item_batch
+    }
+
     /// Consumes log items into an iterator.
     pub(crate) fn drain(&mut self) -> LogItemDrain {
         debug_assert!(!matches!(self.buf_state, BufState::Incomplete));
@@ -1012,11 +1101,14 @@ impl AtomicGroupStatus {
 /// memtable. However, when read by an older version, they will behave as user
 /// keys. They may also belong to Raft Group that doesn't exist before.
 ///
-/// In practice, we only use atomic group for rewrite operation. (In fact,
-/// atomic group markers in append queue are simply ignored.) Rewrite doesn't
-/// change the value of entries, just locations. So first issue doesn't affect
-/// correctness. There could only be one worker doing the rewrite. So second
-/// issue doesn't change observed write order because there's no mixed write.
+/// Atomic group is used by rewrite operation. Rewrite doesn't change the value
+/// of entries, just locations. So first issue doesn't affect correctness.
+/// There could only be one worker doing the rewrite. So second issue doesn't
+/// change observed write order because there's no mixed write.
+///
+/// It also backs [`AtomicGroup`](crate::AtomicGroup) of append queue, which
+/// applies writes to memtable only after the group ends, and requires that the
+/// Raft Groups in it aren't written elsewhere meanwhile.
 pub(crate) struct AtomicGroupBuilder {
     id: u64,
     status: Option<AtomicGroupStatus>,
@@ -1061,6 +1153,11 @@ impl AtomicGroupBuilder {
         self.flush(lb);
     }
 
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
     #[inline]
     fn flush(&self, lb: &mut LogBatch) {
         let mut s = Vec::with_capacity(ATOMIC_GROUP_VALUE_LEN);
@@ -1143,7 +1240,16 @@ mod tests {
 
     #[test]
     fn test_command_enc_dec() {
-        let cmds = vec![Command::Clean, Command::Compact { index: 7 }];
+        let cmds = vec![
+            Command::Clean,
+            Command::Compact { index: 7 },
+            Command::Rename { from: 3 },
+            Command::Clone {
+                from: 3,
+                begin: 5,
+                end: 10,
+            },
+        ];
         let invalid_command_type = 7;
         for cmd in cmds.into_iter() {
             let mut encoded = vec![];
@@ -1374,6 +1480,14 @@ mod tests {
 
             let entries = &encoded[LOG_BATCH_HEADER_LEN..offset as usize];
             for item in decoded_item_batch.items.iter() {
+                if let LogItemContent::Kv(kv) = &item.content {
+                    assert_eq!(kv.value_handle.is_some(), kv.op_type == OpType::Put);
+                    if let Some(handle) = kv.value_handle {
+                        assert_eq!(handle.id, mocked_file_block_handle.id);
+                        let value = &encoded[handle.offset as usize..][..handle.len];
+                        assert_eq!(kv.value.as_deref(), Some(value));
+                    }
+                }
                 if let LogItemContent::EntryIndexes(entry_indexes) = &item.content {
                     if !entry_indexes.0.is_empty() {
                         let (begin, end) = (
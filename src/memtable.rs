@@ -1,9 +1,9 @@
 // Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
 
-use std::borrow::BorrowMut;
+use std::borrow::{BorrowMut, Cow};
 use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::marker::PhantomData;
-use std::ops::Bound;
+use std::ops::{Bound, Range};
 use std::sync::Arc;
 
 use fail::fail_point;
@@ -11,15 +11,18 @@ use hashbrown::HashMap;
 use log::{error, warn};
 use parking_lot::{Mutex, RwLock};
 
-use crate::config::Config;
+use crate::config::{Config, RecoveryMode};
 use crate::file_pipe_log::ReplayMachine;
+use crate::lazy_recovery::RegionFileTracker;
 use crate::log_batch::{
     AtomicGroupStatus, Command, CompressionType, KeyValue, LogBatch, LogItem, LogItemBatch,
     LogItemContent, OpType,
 };
 use crate::metrics::MEMORY_USAGE;
-use crate::pipe_log::{FileBlockHandle, FileId, FileSeq, LogQueue};
-use crate::util::{hash_u64, Factory};
+use crate::pipe_log::{FileBlockHandle, FileId, FileSeq, LogQueue, PipeLog};
+use crate::purge::TrackedBlock;
+use crate::purge_policy::RaftGroupView;
+use crate::util::{crc32, hash_u64, Factory};
 use crate::{Error, GlobalStats, Result};
 
 #[cfg(feature = "swap")]
@@ -65,6 +68,7 @@ mod swap_conditional_imports {
 }
 
 use swap_conditional_imports::*;
+pub(crate) use swap_conditional_imports::{SelectedAllocator, VacantAllocator};
 
 /// Attempt to shrink entry container if its capacity reaches the threshold.
 const CAPACITY_SHRINK_THRESHOLD: usize = 1024 - 1;
@@ -132,6 +136,59 @@ impl From<&EntryIndex> for ThinEntryIndex {
     }
 }
 
+/// Value of a key value pair.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvValue {
+    /// The value is kept in memory.
+    Inline(Vec<u8>),
+    /// The value is kept in log file only, and read on demand. The crc32
+    /// checksum of the value is kept to verify the read.
+    Spilled(FileBlockHandle, u32),
+}
+
+impl KvValue {
+    /// Returns the value, reading it from log file if it's spilled.
+    pub fn read<P: PipeLog>(&self, pipe_log: &P) -> Result<Cow<'_, [u8]>> {
+        match self {
+            KvValue::Inline(value) => Ok(Cow::Borrowed(value)),
+            KvValue::Spilled(handle, checksum) => {
+                let value = pipe_log.read_bytes(*handle)?;
+                Self::verify_spilled(handle, *checksum, &value)?;
+                Ok(Cow::Owned(value))
+            }
+        }
+    }
+
+    /// Checks the `value` read from `handle` against the checksum recorded
+    /// when it's spilled.
+    pub(crate) fn verify_spilled(
+        handle: &FileBlockHandle,
+        checksum: u32,
+        value: &[u8],
+    ) -> Result<()> {
+        if crc32(value) != checksum {
+            return Err(Error::Corruption(format!(
+                "checksum mismatch of value at {handle:?}"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns the size of the value.
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            KvValue::Inline(value) => value.len(),
+            KvValue::Spilled(handle, _) => handle.len,
+        }
+    }
+}
+
+impl From<Vec<u8>> for KvValue {
+    fn from(value: Vec<u8>) -> Self {
+        KvValue::Inline(value)
+    }
+}
+
 /// In-memory storage for Raft Groups.
 ///
 /// Each Raft Group has its own `MemTable` to store all key value pairs and the
@@ -153,7 +210,7 @@ pub struct MemTable<A: AllocatorTrait> {
     rewrite_count: usize,
 
     /// A map of active key value pairs.
-    kvs: BTreeMap<Vec<u8>, (Vec<u8>, FileId)>,
+    kvs: BTreeMap<Vec<u8>, (KvValue, FileId)>,
 
     /// Shared statistics.
     global_stats: Arc<GlobalStats>,
@@ -188,6 +245,20 @@ impl<A: AllocatorTrait> MemTable<A> {
         }
     }
 
+    /// Returns a copy of this memtable. Its statistics are detached from the
+    /// engine.
+    pub fn snapshot(&self) -> MemTable<A> {
+        MemTable {
+            region_id: self.region_id,
+            entry_indexes: self.entry_indexes.clone(),
+            first_index: self.first_index,
+            rewrite_count: self.rewrite_count,
+            kvs: self.kvs.clone(),
+            global_stats: Arc::new(GlobalStats::default()),
+            _phantom: PhantomData,
+        }
+    }
+
     /// Merges with a newer neighbor [`MemTable`].
     ///
     /// This method is only used for recovery.
@@ -257,12 +328,12 @@ impl<A: AllocatorTrait> MemTable<A> {
     }
 
     /// Returns value for a given key.
-    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.kvs.get(key).map(|v| v.0.clone())
+    pub fn get(&self, key: &[u8]) -> Option<&KvValue> {
+        self.kvs.get(key).map(|v| &v.0)
     }
 
-    /// Iterates over [start_key, end_key) range and yields all key value pairs
-    /// as bytes.
+    /// Iterates over [start_key, end_key) range and yields all key value
+    /// pairs.
     pub fn scan<F>(
         &self,
         start_key: Option<&[u8]>,
@@ -271,7 +342,7 @@ impl<A: AllocatorTrait> MemTable<A> {
         mut f: F,
     ) -> Result<()>
     where
-        F: FnMut(&[u8], &[u8]) -> bool,
+        F: FnMut(&[u8], &KvValue) -> bool,
     {
         let lower = start_key.map(Bound::Included).unwrap_or(Bound::Unbounded);
         let upper = end_key.map(Bound::Excluded).unwrap_or(Bound::Unbounded);
@@ -301,7 +372,7 @@ impl<A: AllocatorTrait> MemTable<A> {
 
     /// Puts a key value pair that has been written to the specified file. The
     /// old value for this key will be deleted if exists.
-    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>, file_id: FileId) {
+    pub fn put(&mut self, key: Vec<u8>, value: KvValue, file_id: FileId) {
         if let Some(origin) = self.kvs.insert(key, (value, file_id)) {
             self.global_stats.delete(origin.1.queue, 1);
         }
@@ -309,13 +380,26 @@ impl<A: AllocatorTrait> MemTable<A> {
     }
 
     /// Rewrites a key by marking its location to the `seq`-th log file in
-    /// rewrite queue. No-op if the key does not exist.
+    /// rewrite queue. No-op if the key does not exist. A spilled value is
+    /// relocated to `value_handle`.
     ///
     /// When `gate` is present, only append data no newer than it will be
     /// rewritten.
-    pub fn rewrite_key(&mut self, key: Vec<u8>, gate: Option<FileSeq>, seq: FileSeq) {
+    pub fn rewrite_key(
+        &mut self,
+        key: Vec<u8>,
+        value_handle: Option<FileBlockHandle>,
+        gate: Option<FileSeq>,
+        seq: FileSeq,
+    ) {
         self.global_stats.add(LogQueue::Rewrite, 1);
         if let Some(origin) = self.kvs.get_mut(&key) {
+            let relocate = |value: &mut KvValue| {
+                if let (KvValue::Spilled(handle, _), Some(new_handle)) = (value, value_handle) {
+                    debug_assert_eq!(handle.len, new_handle.len);
+                    *handle = new_handle;
+                }
+            };
             if origin.1.queue == LogQueue::Append {
                 if let Some(gate) = gate {
                     if origin.1.seq <= gate {
@@ -323,6 +407,7 @@ impl<A: AllocatorTrait> MemTable<A> {
                             queue: LogQueue::Rewrite,
                             seq,
                         };
+                        relocate(&mut origin.0);
                         self.global_stats.delete(LogQueue::Append, 1);
                         return;
                     }
@@ -330,11 +415,59 @@ impl<A: AllocatorTrait> MemTable<A> {
             } else {
                 assert!(origin.1.seq <= seq);
                 origin.1.seq = seq;
+                relocate(&mut origin.0);
             }
         }
         self.global_stats.delete(LogQueue::Rewrite, 1);
     }
 
+    /// Returns all key value pairs along with the files they are stored in.
+    pub fn kvs(&self) -> impl Iterator<Item = (&[u8], &KvValue, FileId)> {
+        self.kvs
+            .iter()
+            .map(|(key, (value, file_id))| (key.as_slice(), value, *file_id))
+    }
+
+    /// Takes over the entries of a [`MemTable`] rebuilt from log files, after
+    /// the engine is lazily recovered. Key value pairs of `rhs` are ignored.
+    pub fn restore_entries(&mut self, rhs: &mut Self) {
+        debug_assert_eq!(self.region_id, rhs.region_id);
+        debug_assert!(self.entry_indexes.is_empty());
+        self.global_stats.add(LogQueue::Rewrite, rhs.rewrite_count);
+        self.global_stats.add(
+            LogQueue::Append,
+            rhs.entry_indexes.len() - rhs.rewrite_count,
+        );
+        self.first_index = rhs.first_index;
+        self.rewrite_count = rhs.rewrite_count;
+        self.entry_indexes.append(&mut rhs.entry_indexes);
+        rhs.rewrite_count = 0;
+    }
+
+    /// Shares the entries of `rhs` within `[begin, end)`. No-op if this table
+    /// already has entries, which means the clone has been applied.
+    pub fn clone_entries(&mut self, rhs: &Self, begin: u64, end: u64) {
+        if !self.entry_indexes.is_empty() {
+            return;
+        }
+        if let Some((first, last)) = rhs.span() {
+            let begin = std::cmp::max(begin, first);
+            let end = std::cmp::min(end, last + 1);
+            if begin < end {
+                let pos = (begin - first) as usize;
+                let len = (end - begin) as usize;
+                self.entry_indexes
+                    .extend(rhs.entry_indexes.range(pos..pos + len).copied());
+                self.first_index = begin;
+                // Rewritten entries are still at the front.
+                self.rewrite_count = std::cmp::min(rhs.rewrite_count.saturating_sub(pos), len);
+                self.global_stats.add(LogQueue::Rewrite, self.rewrite_count);
+                self.global_stats
+                    .add(LogQueue::Append, len - self.rewrite_count);
+            }
+        }
+    }
+
     /// Returns the log entry location for a given logical log index.
     pub fn get_entry(&self, index: u64) -> Option<EntryIndex> {
         if let Some((first, last)) = self.span() {
@@ -682,7 +815,7 @@ impl<A: AllocatorTrait> MemTable<A> {
 
     /// Pulls all key value pairs older than or equal to `gate`, to the provided
     /// buffer.
-    pub fn fetch_kvs_before(&self, gate: FileSeq, vec: &mut Vec<(Vec<u8>, Vec<u8>)>) {
+    pub fn fetch_kvs_before(&self, gate: FileSeq, vec: &mut Vec<(Vec<u8>, KvValue)>) {
         for (key, (value, file_id)) in &self.kvs {
             if file_id.queue == LogQueue::Append && file_id.seq <= gate {
                 vec.push((key.clone(), value.clone()));
@@ -691,7 +824,7 @@ impl<A: AllocatorTrait> MemTable<A> {
     }
 
     /// Pulls all rewrite key value pairs to the provided buffer.
-    pub fn fetch_rewritten_kvs(&self, vec: &mut Vec<(Vec<u8>, Vec<u8>)>) {
+    pub fn fetch_rewritten_kvs(&self, vec: &mut Vec<(Vec<u8>, KvValue)>) {
         for (key, (value, file_id)) in &self.kvs {
             if file_id.queue == LogQueue::Rewrite {
                 vec.push((key.clone(), value.clone()));
@@ -727,6 +860,39 @@ impl<A: AllocatorTrait> MemTable<A> {
         }
     }
 
+    /// Returns whether any entry or key value pair in this table is stored in
+    /// the specified log file.
+    pub fn has_data_in(&self, file_id: FileId) -> bool {
+        self.entry_indexes
+            .iter()
+            .any(|e| e.entries.unwrap().id == file_id)
+            || self.kvs.values().any(|v| v.1 == file_id)
+    }
+
+    /// Adds the bytes of log files referenced by this table to `live`. An
+    /// entry block is counted as a whole if any of its entries is live. Blocks
+    /// already in `blocks` are skipped, since they can be shared by several
+    /// tables. Key value pairs are counted by their raw size.
+    pub fn collect_live_bytes(
+        &self,
+        blocks: &mut HashSet<FileBlockHandle>,
+        live: &mut HashMap<FileId, usize>,
+    ) {
+        let mut last = None;
+        for ei in &self.entry_indexes {
+            let handle = ei.entries.unwrap();
+            if last != Some(handle) {
+                last = Some(handle);
+                if blocks.insert(handle) {
+                    *live.entry(handle.id).or_default() += handle.len;
+                }
+            }
+        }
+        for (key, (value, file_id)) in &self.kvs {
+            *live.entry(*file_id).or_default() += key.len() + value.len();
+        }
+    }
+
     #[inline]
     pub fn has_at_least_some_entries_before(&self, gate: FileId, count: usize) -> bool {
         debug_assert!(count > 0);
@@ -744,6 +910,11 @@ impl<A: AllocatorTrait> MemTable<A> {
         self.rewrite_count
     }
 
+    /// Returns the number of log entries, including rewritten ones.
+    pub fn entries_count(&self) -> usize {
+        self.entry_indexes.len()
+    }
+
     /// Returns the log index of the first log entry.
     pub fn first_index(&self) -> Option<u64> {
         self.span().map(|s| s.0)
@@ -792,6 +963,28 @@ impl<A: AllocatorTrait> MemTable<A> {
     }
 }
 
+impl<A: AllocatorTrait> RaftGroupView for MemTable<A> {
+    fn raft_group_id(&self) -> u64 {
+        self.region_id()
+    }
+
+    fn min_file_seq(&self, queue: LogQueue) -> Option<FileSeq> {
+        MemTable::min_file_seq(self, queue)
+    }
+
+    fn rewrite_count(&self) -> usize {
+        MemTable::rewrite_count(self)
+    }
+
+    fn entries_count(&self) -> usize {
+        MemTable::entries_count(self)
+    }
+
+    fn has_at_least_some_entries_before(&self, gate: FileId, count: usize) -> bool {
+        MemTable::has_at_least_some_entries_before(self, gate, count)
+    }
+}
+
 impl<A: AllocatorTrait> Drop for MemTable<A> {
     fn drop(&mut self) {
         let mut append_kvs = 0;
@@ -813,6 +1006,29 @@ impl<A: AllocatorTrait> Drop for MemTable<A> {
 }
 
 type MemTableMap<A> = HashMap<u64, Arc<RwLock<MemTable<A>>>>;
+
+/// Copies of [`MemTable`]s at a point in time that are still being taken,
+/// see [`MemTableAccessor::start_snapshot`].
+pub struct PendingSnapshot<A: AllocatorTrait> {
+    /// Memtables that existed at that point, `None` if not copied yet.
+    memtables: Mutex<HashMap<u64, Option<MemTable<A>>>>,
+}
+
+impl<A: AllocatorTrait> PendingSnapshot<A> {
+    // Copies the memtable of `raft_group_id` if it's not copied yet. Must be
+    // called before the memtable is changed.
+    fn copy(&self, raft_group_id: u64, accessor: &MemTableAccessor<A>) {
+        let mut memtables = self.memtables.lock();
+        if let Some(copy @ None) = memtables.get_mut(&raft_group_id) {
+            match accessor.get(raft_group_id) {
+                Some(t) => *copy = Some(t.read().snapshot()),
+                None => {
+                    memtables.remove(&raft_group_id);
+                }
+            }
+        }
+    }
+}
 pub type MemTableHandle = Arc<RwLock<MemTable<SelectedAllocator>>>;
 pub type MemTables = MemTableAccessor<SelectedAllocator>;
 
@@ -827,8 +1043,13 @@ pub struct MemTableAccessor<A: AllocatorTrait> {
 
     /// A fixed-size array of maps of [`MemTable`]s.
     slots: Vec<Arc<RwLock<MemTableMap<A>>>>,
-    /// Deleted [`MemTable`]s that are not yet rewritten.
-    removed_memtables: Arc<Mutex<VecDeque<u64>>>,
+    /// Commands that delete or move [`MemTable`]s, which are not yet
+    /// rewritten.
+    tombstones: Arc<Mutex<VecDeque<(u64, Command)>>>,
+    /// Snapshots being taken, whose memtables must be copied before changed.
+    pending_snapshots: Arc<RwLock<Vec<Arc<PendingSnapshot<A>>>>>,
+    /// Values no smaller than this are spilled to log files. Zero means never.
+    kv_spill_threshold: usize,
 }
 
 impl MemTableAccessor<VacantAllocator> {
@@ -841,7 +1062,9 @@ impl MemTableAccessor<VacantAllocator> {
             global_stats,
             allocator: new_vacant_allocator(),
             slots,
-            removed_memtables: Default::default(),
+            tombstones: Default::default(),
+            pending_snapshots: Default::default(),
+            kv_spill_threshold: 0,
         }
     }
 }
@@ -879,7 +1102,9 @@ impl<A: AllocatorTrait> MemTableAccessor<A> {
             global_stats,
             allocator,
             slots,
-            removed_memtables: Default::default(),
+            tombstones: Default::default(),
+            pending_snapshots: Default::default(),
+            kv_spill_threshold: 0,
         }
     }
 
@@ -912,8 +1137,34 @@ impl<A: AllocatorTrait> MemTableAccessor<A> {
             .write()
             .remove(&raft_group_id);
         if record_tombstone {
-            let mut removed_memtables = self.removed_memtables.lock();
-            removed_memtables.push_back(raft_group_id);
+            self.tombstones
+                .lock()
+                .push_back((raft_group_id, Command::Clean));
+        }
+    }
+
+    /// Applies a [`Command::Rename`] or [`Command::Clone`] to `raft_group_id`.
+    /// No-op if the source [`MemTable`] doesn't exist.
+    pub fn migrate(&self, raft_group_id: u64, cmd: Command, record_tombstone: bool) {
+        let from = cmd.source().unwrap();
+        if from != raft_group_id {
+            if let Some(src) = self.get(from) {
+                match cmd {
+                    Command::Rename { .. } => {
+                        self.slots[Self::slot_index(from)].write().remove(&from);
+                        src.write().region_id = raft_group_id;
+                        self.insert(raft_group_id, src);
+                    }
+                    Command::Clone { begin, end, .. } => {
+                        let dst = self.get_or_insert(raft_group_id);
+                        dst.write().clone_entries(&src.read(), begin, end);
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        }
+        if record_tombstone {
+            self.tombstones.lock().push_back((raft_group_id, cmd));
         }
     }
 
@@ -942,14 +1193,63 @@ impl<A: AllocatorTrait> MemTableAccessor<A> {
         memtables
     }
 
+    /// Starts to take copies of the specified [`MemTable`]s, or all of them
+    /// if `raft_group_ids` is `None`, as of now. Must be called with
+    /// application of writes blocked. Afterwards, memtables are copied before
+    /// they are changed by [`apply_append_writes`], and the rest are copied
+    /// by [`finish_snapshot`].
+    ///
+    /// [`apply_append_writes`]: Self::apply_append_writes
+    /// [`finish_snapshot`]: Self::finish_snapshot
+    pub fn start_snapshot(&self, raft_group_ids: Option<&[u64]>) -> Arc<PendingSnapshot<A>> {
+        let mut memtables = HashMap::default();
+        match raft_group_ids {
+            Some(ids) => {
+                for &id in ids {
+                    if self.get(id).is_some() {
+                        memtables.insert(id, None);
+                    }
+                }
+            }
+            None => {
+                for tables in &self.slots {
+                    memtables.extend(tables.read().keys().map(|id| (*id, None)));
+                }
+            }
+        }
+        let snapshot = Arc::new(PendingSnapshot {
+            memtables: Mutex::new(memtables),
+        });
+        self.pending_snapshots.write().push(snapshot.clone());
+        snapshot
+    }
+
+    /// Copies the rest of [`MemTable`]s of `snapshot`, and returns all the
+    /// copies, see [`MemTable::snapshot`].
+    pub fn finish_snapshot(&self, snapshot: Arc<PendingSnapshot<A>>) -> HashMap<u64, MemTable<A>> {
+        let ids: Vec<u64> = snapshot.memtables.lock().keys().copied().collect();
+        for id in ids {
+            snapshot.copy(id, self);
+        }
+        self.pending_snapshots
+            .write()
+            .retain(|s| !Arc::ptr_eq(s, &snapshot));
+        let mut memtables = snapshot.memtables.lock();
+        memtables
+            .drain()
+            .filter_map(|(id, t)| t.map(|t| (id, t)))
+            .collect()
+    }
+
     /// Returns a [`LogBatch`] containing `Command::Clean`s of all deleted
-    /// [`MemTable`]s. The records for these tables will be cleaned up
-    /// afterwards.
+    /// [`MemTable`]s, along with the commands that moved entries between
+    /// them, in the order they are applied. The records for these tables will
+    /// be cleaned up afterwards.
     pub fn take_cleaned_region_logs(&self) -> LogBatch {
         let mut log_batch = LogBatch::default();
-        let mut removed_memtables = self.removed_memtables.lock();
-        for id in removed_memtables.drain(..) {
-            log_batch.add_command(id, Command::Clean);
+        let mut tombstones = self.tombstones.lock();
+        for (id, cmd) in tombstones.drain(..) {
+            log_batch.add_command(id, cmd);
         }
         log_batch
     }
@@ -961,9 +1261,11 @@ impl<A: AllocatorTrait> MemTableAccessor<A> {
     #[allow(dead_code)]
     pub fn cleaned_region_ids(&self) -> HashSet<u64> {
         let mut ids = HashSet::default();
-        let removed_memtables = self.removed_memtables.lock();
-        for raft_id in removed_memtables.iter() {
-            ids.insert(*raft_id);
+        let tombstones = self.tombstones.lock();
+        for (raft_id, cmd) in tombstones.iter() {
+            if *cmd == Command::Clean {
+                ids.insert(*raft_id);
+            }
         }
         ids
     }
@@ -1010,19 +1312,25 @@ impl<A: AllocatorTrait> MemTableAccessor<A> {
             }
         }
         // Tombstones from both table are identical.
-        debug_assert_eq!(
-            self.removed_memtables.lock().len(),
-            rhs.removed_memtables.lock().len()
-        );
+        debug_assert_eq!(self.tombstones.lock().len(), rhs.tombstones.lock().len());
     }
 
     /// Applies changes from log items that have been written to append queue.
     pub fn apply_append_writes(&self, log_items: impl Iterator<Item = LogItem>) {
+        let pending_snapshots = self.pending_snapshots.read().clone();
         for item in log_items {
             if has_internal_key(&item) {
                 continue;
             }
             let raft = item.raft_group_id;
+            for snapshot in &pending_snapshots {
+                snapshot.copy(raft, self);
+                if let LogItemContent::Command(cmd) = &item.content {
+                    if let Some(source) = cmd.source() {
+                        snapshot.copy(source, self);
+                    }
+                }
+            }
             let memtable = self.get_or_insert(raft);
             fail_point!(
                 "memtable_accessor::apply_append_writes::region_3",
@@ -1039,9 +1347,12 @@ impl<A: AllocatorTrait> MemTableAccessor<A> {
                 LogItemContent::Command(Command::Compact { index }) => {
                     memtable.write().compact_to(index);
                 }
+                LogItemContent::Command(cmd) => {
+                    self.migrate(raft, cmd, true /* record_tombstone */);
+                }
                 LogItemContent::Kv(kv) => match kv.op_type {
                     OpType::Put => {
-                        let value = kv.value.unwrap();
+                        let value = self.kv_value(kv.value.unwrap(), kv.value_handle);
                         memtable.write().put(kv.key, value, kv.file_id.unwrap());
                     }
                     OpType::Del => {
@@ -1074,9 +1385,12 @@ impl<A: AllocatorTrait> MemTableAccessor<A> {
                 LogItemContent::Command(Command::Compact { index }) => {
                     memtable.write().compact_to(index);
                 }
+                LogItemContent::Command(cmd) => {
+                    self.migrate(raft, cmd, true /* record_tombstone */);
+                }
                 LogItemContent::Kv(kv) => match kv.op_type {
                     OpType::Put => {
-                        let value = kv.value.unwrap();
+                        let value = self.kv_value(kv.value.unwrap(), kv.value_handle);
                         memtable.write().put(kv.key, value, kv.file_id.unwrap());
                     }
                     OpType::Del => {
@@ -1108,11 +1422,17 @@ impl<A: AllocatorTrait> MemTableAccessor<A> {
                 LogItemContent::Kv(kv) => match kv.op_type {
                     OpType::Put => {
                         let key = kv.key;
-                        memtable.write().rewrite_key(key, watermark, new_file);
+                        memtable
+                            .write()
+                            .rewrite_key(key, kv.value_handle, watermark, new_file);
                     }
                     _ => unreachable!(),
                 },
-                LogItemContent::Command(Command::Clean) => {}
+                // Tombstones are rewritten before any entry, and have been
+                // applied to memtables.
+                LogItemContent::Command(Command::Clean)
+                | LogItemContent::Command(Command::Rename { .. })
+                | LogItemContent::Command(Command::Clone { .. }) => {}
                 _ => unreachable!(),
             }
         }
@@ -1140,9 +1460,12 @@ impl<A: AllocatorTrait> MemTableAccessor<A> {
                 LogItemContent::Command(Command::Compact { index }) => {
                     memtable.write().compact_to(index);
                 }
+                LogItemContent::Command(cmd) => {
+                    self.migrate(raft, cmd, false /* record_tombstone */);
+                }
                 LogItemContent::Kv(kv) => match kv.op_type {
                     OpType::Put => {
-                        let value = kv.value.unwrap();
+                        let value = self.kv_value(kv.value.unwrap(), kv.value_handle);
                         memtable.write().put(kv.key, value, kv.file_id.unwrap());
                     }
                     OpType::Del => {
@@ -1154,6 +1477,19 @@ impl<A: AllocatorTrait> MemTableAccessor<A> {
         }
     }
 
+    // Returns the value to be kept in memtable for a key value pair written at
+    // `value_handle`.
+    fn kv_value(&self, value: Vec<u8>, value_handle: Option<FileBlockHandle>) -> KvValue {
+        match value_handle {
+            Some(handle)
+                if self.kv_spill_threshold > 0 && value.len() >= self.kv_spill_threshold =>
+            {
+                KvValue::Spilled(handle, crc32(&value))
+            }
+            _ => KvValue::Inline(value),
+        }
+    }
+
     #[inline]
     fn slot_index(id: u64) -> usize {
         debug_assert!(MEMTABLE_SLOT_COUNT.is_power_of_two());
@@ -1171,6 +1507,10 @@ struct PendingAtomicGroup {
     status: AtomicGroupStatus,
     items: Vec<LogItem>,
     tombstone_items: Vec<LogItem>,
+    // Only used in append queue. The group has ended and its items are
+    // applied, but its beginning is yet to be seen in older log files, if it
+    // isn't purged.
+    applied: bool,
 }
 
 pub struct MemTableRecoverContext<A: AllocatorTrait> {
@@ -1182,6 +1522,22 @@ pub struct MemTableRecoverContext<A: AllocatorTrait> {
     // All atomic groups that are not yet completed.
     // Each id maps to a list of groups. Each list contains at least one, at most two groups.
     pending_atomic_groups: HashMap<u64, Vec<PendingAtomicGroup>>,
+    // IDs of atomic groups that are discarded because of being incomplete.
+    discarded_atomic_groups: Vec<u64>,
+
+    // Minimum size of append queue entry blocks to be tracked for hole punching.
+    // `None` if hole punching is disabled.
+    hole_punch_min_block_size: Option<usize>,
+    // Tracked entry blocks, ordered by their file locations.
+    tracked_blocks: Vec<TrackedBlock>,
+
+    // Whether holes caused by skipped corruption are tolerated.
+    salvage: bool,
+    // Entries dropped because of holes, grouped by Raft Group.
+    lost_entries: BTreeMap<u64, Vec<Range<u64>>>,
+
+    // Log files of each Raft Group. `None` if lazy recovery is disabled.
+    region_files: Option<RegionFileTracker>,
 }
 
 impl MemTableRecoverContext<VacantAllocator> {
@@ -1192,18 +1548,38 @@ impl MemTableRecoverContext<VacantAllocator> {
             tombstone_items: Vec::new(),
             memtables: MemTableAccessor::new(stats),
             pending_atomic_groups: HashMap::new(),
+            discarded_atomic_groups: Vec::new(),
+            hole_punch_min_block_size: None,
+            tracked_blocks: Vec::new(),
+            salvage: false,
+            lost_entries: BTreeMap::new(),
+            region_files: None,
         }
     }
 }
 
 impl<A: AllocatorTrait> MemTableRecoverContext<A> {
-    fn new_with_allocator(allocator: A) -> Self {
+    fn new_with_allocator(
+        allocator: A,
+        hole_punch_min_block_size: Option<usize>,
+        kv_spill_threshold: usize,
+        salvage: bool,
+        track_region_files: bool,
+    ) -> Self {
         let stats = Arc::new(GlobalStats::default());
+        let mut memtables = MemTableAccessor::new_with_allocator(stats.clone(), allocator);
+        memtables.kv_spill_threshold = kv_spill_threshold;
         Self {
-            stats: stats.clone(),
+            stats,
             tombstone_items: Vec::new(),
-            memtables: MemTableAccessor::new_with_allocator(stats, allocator),
+            memtables,
             pending_atomic_groups: HashMap::new(),
+            discarded_atomic_groups: Vec::new(),
+            hole_punch_min_block_size,
+            tracked_blocks: Vec::new(),
+            salvage,
+            lost_entries: BTreeMap::new(),
+            region_files: track_region_files.then(RegionFileTracker::default),
         }
     }
 
@@ -1211,6 +1587,61 @@ impl<A: AllocatorTrait> MemTableRecoverContext<A> {
         (self.memtables, self.stats)
     }
 
+    /// Takes out the append queue entry blocks that are large enough to be
+    /// reclaimed by hole punching.
+    pub fn take_tracked_blocks(&mut self) -> Vec<TrackedBlock> {
+        std::mem::take(&mut self.tracked_blocks)
+    }
+
+    /// Takes out the IDs of discarded atomic groups, including the ones that
+    /// are still incomplete after recovery.
+    pub fn take_discarded_atomic_groups(&mut self) -> Vec<u64> {
+        for (id, groups) in self.pending_atomic_groups.drain() {
+            for group in groups {
+                if group.applied {
+                    continue;
+                }
+                warn!("discard incomplete atomic group: {group:?}");
+                self.discarded_atomic_groups.push(id);
+            }
+        }
+        std::mem::take(&mut self.discarded_atomic_groups)
+    }
+
+    /// Takes out the append queue entries that are dropped because of holes.
+    /// Only filled in [`RecoveryMode::Salvage`].
+    pub fn take_lost_entries(&mut self) -> BTreeMap<u64, Vec<Range<u64>>> {
+        std::mem::take(&mut self.lost_entries)
+    }
+
+    /// Takes out the log files of each Raft Group. Only filled when lazy
+    /// recovery is enabled.
+    pub fn take_region_files(&mut self) -> Option<RegionFileTracker> {
+        self.region_files.take()
+    }
+
+    /// Drops existing entries of a Raft Group if they can't be connected with
+    /// incoming entries starting at `first_index`. Such a hole is left by
+    /// skipped log batches.
+    fn drop_entries_before_hole(&mut self, raft_group_id: u64, first_index: u64) {
+        if let Some(memtable) = self.memtables.get(raft_group_id) {
+            let mut memtable = memtable.write();
+            if let Some((first, last)) = memtable.span() {
+                if last + 1 < first_index {
+                    warn!(
+                        "Drop entries [{}, {}) of raft group {} because of a hole",
+                        first, first_index, raft_group_id
+                    );
+                    memtable.compact_to(first_index);
+                    self.lost_entries
+                        .entry(raft_group_id)
+                        .or_default()
+                        .push(first..first_index);
+                }
+            }
+        }
+    }
+
     pub fn merge_append_context(&self, append: MemTableRecoverContext<A>) {
         self.memtables
             .apply_append_writes(append.tombstone_items.into_iter());
@@ -1220,15 +1651,24 @@ impl<A: AllocatorTrait> MemTableRecoverContext<A> {
     #[inline]
     fn is_tombstone(item: &LogItem) -> bool {
         match &item.content {
-            LogItemContent::Command(Command::Clean)
-            | LogItemContent::Command(Command::Compact { .. }) => true,
+            LogItemContent::Command(_) => true,
             LogItemContent::Kv(KeyValue { op_type, .. }) if *op_type == OpType::Del => true,
             _ => false,
         }
     }
 
+    fn replay_writes(memtables: &MemTableAccessor<A>, queue: LogQueue, items: Vec<LogItem>) {
+        match queue {
+            LogQueue::Append => memtables.replay_append_writes(items.into_iter()),
+            LogQueue::Rewrite => memtables.replay_rewrite_writes(items.into_iter()),
+        }
+    }
+
+    // Atomic groups of both queues are replayed when they end. Groups of
+    // rewrite queue must be complete. But in append queue, the beginning of a
+    // group can be purged after it's committed, so an ended group is always
+    // applied. A marker is kept in case its beginning is found in older files.
     fn accept_new_group(&mut self, queue: LogQueue, id: u64, mut new_group: PendingAtomicGroup) {
-        assert_eq!(queue, LogQueue::Rewrite);
         if let Some(groups) = self.pending_atomic_groups.get_mut(&id) {
             let group = groups.last_mut().unwrap();
             match (group.status, new_group.status) {
@@ -1238,11 +1678,13 @@ impl<A: AllocatorTrait> MemTableRecoverContext<A> {
                 // (begin, begin), (middle, begin)
                 (_, AtomicGroupStatus::Begin) => {
                     warn!("discard atomic group: {group:?}");
+                    self.discarded_atomic_groups.push(id);
                     *group = new_group;
                 }
                 // (end, middle), (end, end)
                 (AtomicGroupStatus::End, _) => {
                     warn!("discard atomic group: {new_group:?}");
+                    self.discarded_atomic_groups.push(id);
                 }
                 (AtomicGroupStatus::Begin, AtomicGroupStatus::Middle)
                 | (AtomicGroupStatus::Middle, AtomicGroupStatus::Middle) => {
@@ -1253,24 +1695,68 @@ impl<A: AllocatorTrait> MemTableRecoverContext<A> {
                     group.items.append(&mut new_group.items);
                     group.tombstone_items.append(&mut new_group.tombstone_items);
                     group.status = new_group.status;
+                    if queue == LogQueue::Append {
+                        self.tombstone_items.append(&mut group.tombstone_items);
+                        Self::replay_writes(
+                            &self.memtables,
+                            queue,
+                            std::mem::take(&mut group.items),
+                        );
+                        group.applied = true;
+                    }
                 }
                 (AtomicGroupStatus::Begin, AtomicGroupStatus::End) => {
                     let mut group = groups.pop().unwrap();
                     self.tombstone_items.append(&mut group.tombstone_items);
                     self.tombstone_items.append(&mut new_group.tombstone_items);
-                    self.memtables
-                        .replay_rewrite_writes(group.items.into_iter());
-                    self.memtables
-                        .replay_rewrite_writes(new_group.items.into_iter());
+                    Self::replay_writes(&self.memtables, queue, group.items);
+                    Self::replay_writes(&self.memtables, queue, new_group.items);
                 }
             }
             if groups.is_empty() {
                 self.pending_atomic_groups.remove(&id);
             }
         } else {
+            if queue == LogQueue::Append
+                && new_group.status == AtomicGroupStatus::End
+                && !new_group.applied
+            {
+                self.tombstone_items.append(&mut new_group.tombstone_items);
+                Self::replay_writes(&self.memtables, queue, std::mem::take(&mut new_group.items));
+                new_group.applied = true;
+            }
             self.pending_atomic_groups.insert(id, vec![new_group]);
         }
     }
+
+    // Splits out the atomic group marker of a log batch, if any. Returns the
+    // marker, other items, and tombstones among them.
+    fn split_atomic_group(
+        mut item_batch: LogItemBatch,
+    ) -> (Option<(u64, AtomicGroupStatus)>, Vec<LogItem>, Vec<LogItem>) {
+        let mut new_tombstones = Vec::new();
+        let mut is_group = None;
+        let items = item_batch
+            .drain()
+            .filter(|item| {
+                if let Some(g) = AtomicGroupStatus::parse(item) {
+                    if is_group.is_none() {
+                        is_group = Some(g);
+                    } else {
+                        let msg = format!("skipped an atomic group: {:?}", g);
+                        error!("{}", msg);
+                        debug_assert!(false, "{}", msg);
+                    }
+                    return false;
+                }
+                if Self::is_tombstone(item) {
+                    new_tombstones.push(item.clone());
+                }
+                true
+            })
+            .collect();
+        (is_group, items, new_tombstones)
+    }
 }
 
 impl Default for MemTableRecoverContext<VacantAllocator> {
@@ -1280,60 +1766,62 @@ impl Default for MemTableRecoverContext<VacantAllocator> {
 }
 
 impl<A: AllocatorTrait> ReplayMachine for MemTableRecoverContext<A> {
-    fn replay(&mut self, mut item_batch: LogItemBatch, file_id: FileId) -> Result<()> {
+    fn replay(&mut self, item_batch: LogItemBatch, file_id: FileId) -> Result<()> {
+        if let Some(region_files) = &self.region_files {
+            region_files.track(&item_batch, file_id);
+        }
         if file_id.queue == LogQueue::Append {
-            let mut new_tombstones = Vec::new();
-            self.memtables
-                .replay_append_writes(item_batch.drain().filter(|item| {
-                    if Self::is_tombstone(item) {
-                        new_tombstones.push(item.clone());
+            if let Some(min_size) = self.hole_punch_min_block_size {
+                if let Some(block) = TrackedBlock::from_item_batch(&item_batch, min_size) {
+                    self.tracked_blocks.push(block);
+                }
+            }
+            for item in item_batch.iter() {
+                if let LogItemContent::Command(cmd) = &item.content {
+                    if let Some(from) = cmd.source() {
+                        self.tracked_blocks.retain(|b| !b.contains_region(from));
                     }
-                    true
-                }));
-            self.tombstone_items.append(&mut new_tombstones);
-        } else {
-            let mut new_tombstones = Vec::new();
-            let mut is_group = None;
-            let items = item_batch
-                .drain()
-                .filter(|item| {
-                    if let Some(g) = AtomicGroupStatus::parse(item) {
-                        if is_group.is_none() {
-                            is_group = Some(g);
-                        } else {
-                            let msg = format!("skipped an atomic group: {:?}", g);
-                            error!("{}", msg);
-                            debug_assert!(false, "{}", msg);
+                }
+            }
+            if self.salvage {
+                for item in item_batch.iter() {
+                    if let LogItemContent::EntryIndexes(entry_indexes) = &item.content {
+                        if let Some(ei) = entry_indexes.0.first() {
+                            self.drop_entries_before_hole(item.raft_group_id, ei.index);
                         }
-                        return false;
-                    }
-                    if Self::is_tombstone(item) {
-                        new_tombstones.push(item.clone());
                     }
-                    true
-                })
-                .collect();
-            if let Some((id, status)) = is_group {
-                self.accept_new_group(
-                    file_id.queue,
-                    id,
-                    PendingAtomicGroup {
-                        status,
-                        items,
-                        tombstone_items: new_tombstones,
-                    },
-                );
-            } else {
-                self.tombstone_items.append(&mut new_tombstones);
-                self.memtables.replay_rewrite_writes(items.into_iter());
+                }
             }
         }
+        let (is_group, items, mut new_tombstones) = Self::split_atomic_group(item_batch);
+        if let Some((id, status)) = is_group {
+            self.accept_new_group(
+                file_id.queue,
+                id,
+                PendingAtomicGroup {
+                    status,
+                    items,
+                    tombstone_items: new_tombstones,
+                    applied: false,
+                },
+            );
+        } else {
+            self.tombstone_items.append(&mut new_tombstones);
+            Self::replay_writes(&self.memtables, file_id.queue, items);
+        }
         Ok(())
     }
 
     fn merge(&mut self, mut rhs: Self, queue: LogQueue) -> Result<()> {
         self.tombstone_items
             .append(&mut rhs.tombstone_items.clone());
+        for item in &rhs.tombstone_items {
+            if let LogItemContent::Command(cmd) = &item.content {
+                if let Some(from) = cmd.source() {
+                    self.tracked_blocks.retain(|b| !b.contains_region(from));
+                }
+            }
+        }
         for (id, groups) in rhs.pending_atomic_groups.drain() {
             for group in groups {
                 self.accept_new_group(queue, id, group);
@@ -1347,26 +1835,65 @@ impl<A: AllocatorTrait> ReplayMachine for MemTableRecoverContext<A> {
                 .memtables
                 .replay_rewrite_writes(rhs.tombstone_items.into_iter()),
         }
+        if self.salvage && queue == LogQueue::Append {
+            let heads = rhs.memtables.fold(Vec::new(), |mut heads, t| {
+                if let Some((first, _)) = t.span() {
+                    heads.push((t.region_id(), first));
+                }
+                heads
+            });
+            for (raft_group_id, first) in heads {
+                self.drop_entries_before_hole(raft_group_id, first);
+            }
+        }
         self.memtables.merge_newer_neighbor(rhs.memtables);
+        self.tracked_blocks.append(&mut rhs.tracked_blocks);
+        self.discarded_atomic_groups
+            .append(&mut rhs.discarded_atomic_groups);
+        if let (Some(lhs), Some(rhs)) = (&self.region_files, rhs.region_files) {
+            lhs.merge(rhs);
+        }
+        for (raft_group_id, mut ranges) in rhs.lost_entries {
+            self.lost_entries
+                .entry(raft_group_id)
+                .or_default()
+                .append(&mut ranges);
+        }
         Ok(())
     }
 }
 
 pub struct MemTableRecoverContextFactory {
     allocator: SelectedAllocator,
+    hole_punch_min_block_size: Option<usize>,
+    kv_spill_threshold: usize,
+    salvage: bool,
+    track_region_files: bool,
 }
 
 impl MemTableRecoverContextFactory {
     pub fn new(cfg: &Config) -> Self {
         Self {
             allocator: new_allocator(cfg),
+            hole_punch_min_block_size: cfg
+                .enable_hole_punching
+                .then(|| cfg.hole_punch_min_block_size.0 as usize),
+            kv_spill_threshold: cfg.kv_spill_threshold.map_or(0, |t| t.0 as usize),
+            salvage: cfg.recovery_mode == RecoveryMode::Salvage,
+            track_region_files: cfg.enable_lazy_recovery,
         }
     }
 }
 
 impl Factory<MemTableRecoverContext<SelectedAllocator>> for MemTableRecoverContextFactory {
     fn new_target(&self) -> MemTableRecoverContext<SelectedAllocator> {
-        MemTableRecoverContext::new_with_allocator(self.allocator.clone())
+        MemTableRecoverContext::new_with_allocator(
+            self.allocator.clone(),
+            self.hole_punch_min_block_size,
+            self.kv_spill_threshold,
+            self.salvage,
+            self.track_region_files,
+        )
     }
 }
 
@@ -1732,52 +2259,64 @@ mod tests {
             10,
             FileId::new(LogQueue::Append, 1),
         ));
-        memtable.put(k1.to_vec(), v1.to_vec(), FileId::new(LogQueue::Append, 1));
+        memtable.put(
+            k1.to_vec(),
+            v1.to_vec().into(),
+            FileId::new(LogQueue::Append, 1),
+        );
         memtable.append(generate_entry_indexes(
             10,
             20,
             FileId::new(LogQueue::Append, 2),
         ));
-        memtable.put(k2.to_vec(), v2.to_vec(), FileId::new(LogQueue::Append, 2));
+        memtable.put(
+            k2.to_vec(),
+            v2.to_vec().into(),
+            FileId::new(LogQueue::Append, 2),
+        );
         memtable.append(generate_entry_indexes(
             20,
             25,
             FileId::new(LogQueue::Append, 3),
         ));
-        memtable.put(k3.to_vec(), v3.to_vec(), FileId::new(LogQueue::Append, 3));
+        memtable.put(
+            k3.to_vec(),
+            v3.to_vec().into(),
+            FileId::new(LogQueue::Append, 3),
+        );
         memtable.consistency_check();
 
         // Rewrite k1.
-        memtable.rewrite_key(k1.to_vec(), Some(1), 50);
+        memtable.rewrite_key(k1.to_vec(), None, Some(1), 50);
         let mut kvs = Vec::new();
         memtable.fetch_kvs_before(1, &mut kvs);
         assert!(kvs.is_empty());
         memtable.fetch_rewritten_kvs(&mut kvs);
         assert_eq!(kvs.len(), 1);
-        assert_eq!(kvs.pop().unwrap(), (k1.to_vec(), v1.to_vec()));
+        assert_eq!(kvs.pop().unwrap(), (k1.to_vec(), v1.to_vec().into()));
         // Rewrite deleted k1.
         memtable.delete(k1.as_ref());
         assert_eq!(memtable.global_stats.deleted_rewrite_entries(), 1);
-        memtable.rewrite_key(k1.to_vec(), Some(1), 50);
+        memtable.rewrite_key(k1.to_vec(), None, Some(1), 50);
         assert_eq!(memtable.get(k1.as_ref()), None);
         memtable.fetch_rewritten_kvs(&mut kvs);
         assert!(kvs.is_empty());
         assert_eq!(memtable.global_stats.deleted_rewrite_entries(), 2);
         // Rewrite newer append k2/k3.
-        memtable.rewrite_key(k2.to_vec(), Some(1), 50);
+        memtable.rewrite_key(k2.to_vec(), None, Some(1), 50);
         memtable.fetch_rewritten_kvs(&mut kvs);
         assert!(kvs.is_empty());
-        memtable.rewrite_key(k3.to_vec(), None, 50); // Rewrite encounters newer append.
+        memtable.rewrite_key(k3.to_vec(), None, None, 50); // Rewrite encounters newer append.
         memtable.fetch_rewritten_kvs(&mut kvs);
         assert!(kvs.is_empty());
         assert_eq!(memtable.global_stats.deleted_rewrite_entries(), 4);
         // Rewrite k3 multiple times.
-        memtable.rewrite_key(k3.to_vec(), Some(10), 50);
-        memtable.rewrite_key(k3.to_vec(), None, 51);
-        memtable.rewrite_key(k3.to_vec(), Some(11), 52);
+        memtable.rewrite_key(k3.to_vec(), None, Some(10), 50);
+        memtable.rewrite_key(k3.to_vec(), None, None, 51);
+        memtable.rewrite_key(k3.to_vec(), None, Some(11), 52);
         memtable.fetch_rewritten_kvs(&mut kvs);
         assert_eq!(kvs.len(), 1);
-        assert_eq!(kvs.pop().unwrap(), (k3.to_vec(), v3.to_vec()));
+        assert_eq!(kvs.pop().unwrap(), (k3.to_vec(), v3.to_vec().into()));
 
         // Rewrite indexes:
         // [0, 10) queue = rewrite, file_num = 1,
@@ -1821,53 +2360,53 @@ mod tests {
         let region_id = 8;
         let mut memtable = MemTable::new(region_id, Arc::new(GlobalStats::default()));
 
-        memtable.put(key(1), value(1), FileId::new(LogQueue::Append, 1));
-        memtable.put(key(5), value(5), FileId::new(LogQueue::Append, 5));
+        memtable.put(key(1), value(1).into(), FileId::new(LogQueue::Append, 1));
+        memtable.put(key(5), value(5).into(), FileId::new(LogQueue::Append, 5));
         assert_eq!(memtable.min_file_seq(LogQueue::Append).unwrap(), 1);
         assert_eq!(memtable.max_file_seq(LogQueue::Append).unwrap(), 5);
-        assert_eq!(memtable.get(&key(1)), Some(value(1)));
-        assert_eq!(memtable.get(&key(5)), Some(value(5)));
+        assert_eq!(memtable.get(&key(1)), Some(&value(1).into()));
+        assert_eq!(memtable.get(&key(5)), Some(&value(5).into()));
 
         let mut res = Vec::new();
         memtable
             .scan(None, None, false, |k, v| {
-                res.push((k.to_vec(), v.to_vec()));
+                res.push((k.to_vec(), v.clone()));
                 false
             })
             .unwrap();
-        assert_eq!(res, vec![(key(1), value(1))]);
+        assert_eq!(res, vec![(key(1), value(1).into())]);
         res.clear();
         memtable
             .scan(None, None, true, |k, v| {
-                res.push((k.to_vec(), v.to_vec()));
+                res.push((k.to_vec(), v.clone()));
                 false
             })
             .unwrap();
-        assert_eq!(res, vec![(key(5), value(5))]);
+        assert_eq!(res, vec![(key(5), value(5).into())]);
         res.clear();
         memtable
             .scan(Some(&key(5)), None, false, |key, value| {
-                res.push((key.to_vec(), value.to_vec()));
+                res.push((key.to_vec(), value.clone()));
                 true
             })
             .unwrap();
-        assert_eq!(res, vec![(key(5), value(5))]);
+        assert_eq!(res, vec![(key(5), value(5).into())]);
         res.clear();
         memtable
             .scan(Some(&key(1)), Some(&key(5)), false, |key, value| {
-                res.push((key.to_vec(), value.to_vec()));
+                res.push((key.to_vec(), value.clone()));
                 true
             })
             .unwrap();
-        assert_eq!(res, vec![(key(1), value(1))]);
+        assert_eq!(res, vec![(key(1), value(1).into())]);
 
         memtable.delete(&key(5));
         assert_eq!(memtable.get(&key(5)), None);
         assert_eq!(memtable.min_file_seq(LogQueue::Append).unwrap(), 1);
         assert_eq!(memtable.max_file_seq(LogQueue::Append).unwrap(), 1);
 
-        memtable.put(key(1), value(1), FileId::new(LogQueue::Rewrite, 2));
-        memtable.put(key(5), value(5), FileId::new(LogQueue::Rewrite, 3));
+        memtable.put(key(1), value(1).into(), FileId::new(LogQueue::Rewrite, 2));
+        memtable.put(key(5), value(5).into(), FileId::new(LogQueue::Rewrite, 3));
         assert_eq!(memtable.min_file_seq(LogQueue::Append), None);
         assert_eq!(memtable.max_file_seq(LogQueue::Append), None);
         assert_eq!(memtable.min_file_seq(LogQueue::Rewrite).unwrap(), 2);
@@ -1879,7 +2418,7 @@ mod tests {
         assert_eq!(memtable.max_file_seq(LogQueue::Rewrite).unwrap(), 3);
         assert_eq!(memtable.global_stats.deleted_rewrite_entries(), 1);
 
-        memtable.put(key(5), value(5), FileId::new(LogQueue::Append, 7));
+        memtable.put(key(5), value(5).into(), FileId::new(LogQueue::Append, 7));
         assert_eq!(memtable.min_file_seq(LogQueue::Rewrite), None);
         assert_eq!(memtable.max_file_seq(LogQueue::Rewrite), None);
         assert_eq!(memtable.min_file_seq(LogQueue::Append).unwrap(), 7);
@@ -1956,7 +2495,7 @@ mod tests {
         ));
         memtable.put(
             b"kk1".to_vec(),
-            b"vv1".to_vec(),
+            b"vv1".to_vec().into(),
             FileId::new(LogQueue::Append, 2),
         );
         memtable.append(generate_entry_indexes(
@@ -1966,7 +2505,7 @@ mod tests {
         ));
         memtable.put(
             b"kk2".to_vec(),
-            b"vv2".to_vec(),
+            b"vv2".to_vec().into(),
             FileId::new(LogQueue::Append, 3),
         );
         memtable.append(generate_entry_indexes(
@@ -1976,7 +2515,7 @@ mod tests {
         ));
         memtable.put(
             b"kk3".to_vec(),
-            b"vv3".to_vec(),
+            b"vv3".to_vec().into(),
             FileId::new(LogQueue::Append, 4),
         );
         expected_append += 4 * 10 + 3;
@@ -1998,7 +2537,7 @@ mod tests {
         // kk1 -> 2, kk2 -> 3, kk3 -> 4
         let ents_idx = generate_entry_indexes(0, 10, FileId::new(LogQueue::Rewrite, 50));
         memtable.rewrite(ents_idx, Some(1));
-        memtable.rewrite_key(b"kk0".to_vec(), Some(1), 50);
+        memtable.rewrite_key(b"kk0".to_vec(), None, Some(1), 50);
         expected_rewrite += 10 + 1;
         expected_deleted_rewrite += 10 + 1;
         assert_eq!(memtable.min_file_seq(LogQueue::Append).unwrap(), 2);
@@ -2025,14 +2564,14 @@ mod tests {
         // kk1 -> 100(r), kk2 -> 101(r), kk3 -> 4
         let ents_idx = generate_entry_indexes(0, 20, FileId::new(LogQueue::Rewrite, 100));
         memtable.rewrite(ents_idx, Some(2));
-        memtable.rewrite_key(b"kk0".to_vec(), Some(1), 50);
-        memtable.rewrite_key(b"kk1".to_vec(), Some(2), 100);
+        memtable.rewrite_key(b"kk0".to_vec(), None, Some(1), 50);
+        memtable.rewrite_key(b"kk1".to_vec(), None, Some(2), 100);
         expected_append -= 10 + 1;
         expected_rewrite += 20 + 2;
         expected_deleted_rewrite += 10 + 1;
         let ents_idx = generate_entry_indexes(20, 30, FileId::new(LogQueue::Rewrite, 101));
         memtable.rewrite(ents_idx, Some(3));
-        memtable.rewrite_key(b"kk2".to_vec(), Some(3), 101);
+        memtable.rewrite_key(b"kk2".to_vec(), None, Some(3), 101);
         expected_append -= 10 + 1;
         expected_rewrite += 10 + 1;
         assert_eq!(memtable.min_file_seq(LogQueue::Append).unwrap(), 4);
@@ -2040,7 +2579,7 @@ mod tests {
         assert_eq!(memtable.min_file_seq(LogQueue::Rewrite).unwrap(), 100);
         assert_eq!(memtable.max_file_seq(LogQueue::Rewrite).unwrap(), 101);
         assert_eq!(memtable.rewrite_count, 20);
-        assert_eq!(memtable.get(b"kk1"), Some(b"vv1".to_vec()));
+        assert_eq!(memtable.get(b"kk1"), Some(&b"vv1".to_vec().into()));
         assert_eq!(
             memtable.global_stats.live_entries(LogQueue::Append),
             expected_append
@@ -2066,7 +2605,7 @@ mod tests {
         expected_append -= 4;
         memtable.put(
             b"kk3".to_vec(),
-            b"vv33".to_vec(),
+            b"vv33".to_vec().into(),
             FileId::new(LogQueue::Append, 5),
         );
         assert_eq!(memtable.last_index().unwrap(), 35);
@@ -2081,7 +2620,7 @@ mod tests {
         assert_eq!(memtable.min_file_seq(LogQueue::Rewrite).unwrap(), 100);
         assert_eq!(memtable.max_file_seq(LogQueue::Rewrite).unwrap(), 102);
         assert_eq!(memtable.rewrite_count, 25);
-        assert_eq!(memtable.get(b"kk3"), Some(b"vv33".to_vec()));
+        assert_eq!(memtable.get(b"kk3"), Some(&b"vv33".to_vec().into()));
         assert_eq!(
             memtable.global_stats.live_entries(LogQueue::Append),
             expected_append
@@ -2329,6 +2868,55 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_memtables_pending_snapshot() {
+        let file_id = FileId::new(LogQueue::Append, 1);
+        let memtables = MemTableAccessor::new(Arc::new(GlobalStats::default()));
+        let mut batch = LogItemBatch::with_capacity(0);
+        for rid in 1..=4 {
+            batch.add_entry_indexes(rid, generate_entry_indexes(1, 11, file_id));
+            batch.put(rid, b"key".to_vec(), b"val1".to_vec());
+        }
+        batch.finish_write(FileBlockHandle::dummy(LogQueue::Append));
+        memtables.apply_append_writes(batch.drain());
+
+        let all = memtables.start_snapshot(None);
+        let some = memtables.start_snapshot(Some(&[1, 5]));
+        // Changes made before the snapshots are finished.
+        let mut batch = LogItemBatch::with_capacity(0);
+        batch.add_entry_indexes(1, generate_entry_indexes(11, 21, file_id));
+        batch.put(1, b"key".to_vec(), b"val2".to_vec());
+        batch.add_command(2, Command::Compact { index: 5 });
+        batch.add_command(3, Command::Clean);
+        batch.add_command(6, Command::Rename { from: 4 });
+        batch.put(5, b"key".to_vec(), b"val2".to_vec());
+        batch.finish_write(FileBlockHandle::dummy(LogQueue::Append));
+        memtables.apply_append_writes(batch.drain());
+        let all = memtables.finish_snapshot(all);
+        let some = memtables.finish_snapshot(some);
+        assert!(memtables.pending_snapshots.read().is_empty());
+
+        assert_eq!(all.len(), 4);
+        for rid in 1..=4 {
+            let memtable = &all[&rid];
+            assert_eq!(memtable.first_index(), Some(1));
+            assert_eq!(memtable.last_index(), Some(10));
+            assert_eq!(
+                memtable.get(b"key"),
+                Some(&KvValue::Inline(b"val1".to_vec()))
+            );
+        }
+        assert_eq!(some.len(), 1);
+        assert_eq!(some[&1].last_index(), Some(10));
+
+        let memtable = memtables.get(1).unwrap();
+        assert_eq!(memtable.read().last_index(), Some(20));
+        assert_eq!(memtables.get(2).unwrap().read().first_index(), Some(5));
+        assert!(memtables.get(3).is_none());
+        assert!(memtables.get(4).is_none());
+        assert!(memtables.get(6).is_some());
+    }
+
     #[test]
     fn test_memtables_merge_append_neighbor() {
         let first_rid = 17;
@@ -2443,6 +3031,95 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_memtables_replay_append_atomic_group() {
+        use crate::log_batch::AtomicGroupBuilder;
+
+        let files: Vec<_> = (0..3)
+            .map(|i| FileId::new(LogQueue::Append, 10 + i as u64))
+            .collect();
+        let mut groups: Vec<_> = (101..=104).map(AtomicGroupBuilder::with_id).collect();
+        // Beginnings of group 103 and 104 are purged.
+        groups[2].begin(&mut LogBatch::default());
+        groups[3].begin(&mut LogBatch::default());
+        let mut marker = |id: u64, status: AtomicGroupStatus| {
+            let builder = &mut groups[id as usize - 101];
+            let mut lb = LogBatch::default();
+            match status {
+                AtomicGroupStatus::Begin => builder.begin(&mut lb),
+                AtomicGroupStatus::Middle => builder.add(&mut lb),
+                AtomicGroupStatus::End => builder.end(&mut lb),
+            }
+            lb.item_batch().clone()
+        };
+
+        let mut batches = [Vec::new(), Vec::new(), Vec::new()];
+        let mut b = marker(101, AtomicGroupStatus::Begin);
+        b.add_entry_indexes(1, generate_entry_indexes(1, 11, files[0]));
+        batches[0].push(b);
+        let mut b = marker(103, AtomicGroupStatus::Middle);
+        b.add_entry_indexes(5, generate_entry_indexes(1, 11, files[0]));
+        batches[0].push(b);
+        let mut b = marker(101, AtomicGroupStatus::Middle);
+        b.add_entry_indexes(2, generate_entry_indexes(1, 11, files[1]));
+        batches[1].push(b);
+        let mut b = marker(102, AtomicGroupStatus::Begin);
+        b.add_entry_indexes(4, generate_entry_indexes(1, 11, files[1]));
+        batches[1].push(b);
+        let mut b = marker(103, AtomicGroupStatus::End);
+        b.add_entry_indexes(6, generate_entry_indexes(1, 11, files[1]));
+        batches[1].push(b);
+        let mut b = marker(101, AtomicGroupStatus::End);
+        b.put(3, b"key".to_vec(), b"value".to_vec());
+        batches[2].push(b);
+        let mut b = marker(104, AtomicGroupStatus::Middle);
+        b.add_entry_indexes(7, generate_entry_indexes(1, 11, files[2]));
+        batches[2].push(b);
+        for b in batches.iter_mut().flatten() {
+            b.finish_write(FileBlockHandle::dummy(LogQueue::Append));
+        }
+
+        let check = |mut ctx: MemTableRecoverContext<VacantAllocator>| {
+            let mut discarded = ctx.take_discarded_atomic_groups();
+            discarded.sort_unstable();
+            assert_eq!(discarded, vec![102, 104]);
+            let (memtables, _) = ctx.finish();
+            for rid in [1, 2, 5, 6] {
+                assert_eq!(memtables.get(rid).unwrap().read().span(), Some((1, 10)));
+            }
+            let m = memtables.get(3).unwrap();
+            assert_eq!(m.read().get(b"key"), Some(&b"value".to_vec().into()));
+            for rid in [4, 7] {
+                assert!(memtables.get(rid).is_none());
+            }
+        };
+
+        // sequential replay
+        let mut ctx = MemTableRecoverContext::default();
+        for (file_batches, file_id) in batches.iter().zip(&files) {
+            for b in file_batches {
+                ctx.replay(b.clone(), *file_id).unwrap();
+            }
+        }
+        check(ctx);
+
+        // reverse merge
+        let mut ctxs = VecDeque::default();
+        for (file_batches, file_id) in batches.iter().zip(&files) {
+            let mut ctx = MemTableRecoverContext::default();
+            for b in file_batches {
+                ctx.replay(b.clone(), *file_id).unwrap();
+            }
+            ctxs.push_back(ctx);
+        }
+        while ctxs.len() > 1 {
+            let (y, mut x) = (ctxs.pop_back().unwrap(), ctxs.pop_back().unwrap());
+            x.merge(y, LogQueue::Append).unwrap();
+            ctxs.push_back(x);
+        }
+        check(ctxs.pop_front().unwrap());
+    }
+
     #[cfg(feature = "nightly")]
     #[bench]
     fn bench_memtable_single_put(b: &mut test::Bencher) {
@@ -2450,7 +3127,11 @@ mod tests {
         let key = b"some_key".to_vec();
         let value = vec![7; 12];
         b.iter(move || {
-            memtable.put(key.clone(), value.clone(), FileId::dummy(LogQueue::Append));
+            memtable.put(
+                key.clone(),
+                value.clone().into(),
+                FileId::dummy(LogQueue::Append),
+            );
         });
     }
 
@@ -2463,9 +3144,21 @@ mod tests {
         let key2 = b"some_key2".to_vec();
         let value = vec![7; 12];
         b.iter(move || {
-            memtable.put(key0.clone(), value.clone(), FileId::dummy(LogQueue::Append));
-            memtable.put(key1.clone(), value.clone(), FileId::dummy(LogQueue::Append));
-            memtable.put(key2.clone(), value.clone(), FileId::dummy(LogQueue::Append));
+            memtable.put(
+                key0.clone(),
+                value.clone().into(),
+                FileId::dummy(LogQueue::Append),
+            );
+            memtable.put(
+                key1.clone(),
+                value.clone().into(),
+                FileId::dummy(LogQueue::Append),
+            );
+            memtable.put(
+                key2.clone(),
+                value.clone().into(),
+                FileId::dummy(LogQueue::Append),
+            );
         });
     }
 }
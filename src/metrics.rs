@@ -265,6 +265,36 @@ lazy_static! {
         exponential_buckets(256.0, 1.8, 22).unwrap()
     )
     .unwrap();
+    pub static ref BACKGROUND_RATE_LIMIT_DURATION_HISTOGRAM: LogQueueHistogramVec =
+        register_static_histogram_vec!(
+            LogQueueHistogramVec,
+            "raft_engine_background_rate_limit_duration_seconds",
+            "Bucketed histogram of time spent throttled during background rewrite",
+            &["type"],
+            exponential_buckets(0.00005, 1.8, 26).unwrap()
+        )
+        .unwrap();
+    pub static ref BACKGROUND_PUNCH_HOLE_BYTES: Histogram = register_histogram!(
+        "raft_engine_background_punch_hole_bytes",
+        "Bucketed histogram of bytes reclaimed by hole punching",
+        exponential_buckets(256.0, 1.8, 22).unwrap()
+    )
+    .unwrap();
+    pub static ref BACKGROUND_SCRUB_BYTES: LogQueueCounterVec = register_static_int_counter_vec!(
+        LogQueueCounterVec,
+        "raft_engine_background_scrub_bytes_total",
+        "Total bytes of log files verified by background scrubbing",
+        &["type"]
+    )
+    .unwrap();
+    pub static ref BACKGROUND_SCRUB_CORRUPTION: LogQueueCounterVec =
+        register_static_int_counter_vec!(
+            LogQueueCounterVec,
+            "raft_engine_background_scrub_corruption_total",
+            "Total number of corruptions found by background scrubbing",
+            &["type"]
+        )
+        .unwrap();
     pub static ref LOG_FILE_COUNT: LogQueueGaugeVec = register_static_int_gauge_vec!(
         LogQueueGaugeVec,
         "raft_engine_log_file_count",
@@ -272,6 +302,34 @@ lazy_static! {
         &["type"]
     )
     .unwrap();
+    pub static ref LOG_FILE_BYTES: LogQueueGaugeVec = register_static_int_gauge_vec!(
+        LogQueueGaugeVec,
+        "raft_engine_log_file_bytes",
+        "Total size of log files in Raft engine",
+        &["type"]
+    )
+    .unwrap();
+    pub static ref LOG_LIVE_BYTES: LogQueueGaugeVec = register_static_int_gauge_vec!(
+        LogQueueGaugeVec,
+        "raft_engine_log_live_bytes",
+        "Bytes of log files referenced by memtables, updated when space stats are collected",
+        &["type"]
+    )
+    .unwrap();
+    pub static ref LOG_APPENDED_BYTES: LogQueueCounterVec = register_static_int_counter_vec!(
+        LogQueueCounterVec,
+        "raft_engine_log_appended_bytes_total",
+        "Total bytes appended to log files",
+        &["type"]
+    )
+    .unwrap();
+    pub static ref LOG_PURGED_BYTES: LogQueueCounterVec = register_static_int_counter_vec!(
+        LogQueueCounterVec,
+        "raft_engine_log_purged_bytes_total",
+        "Total bytes of purged log files",
+        &["type"]
+    )
+    .unwrap();
     pub static ref SWAP_FILE_COUNT: IntGauge = register_int_gauge!(
         "raft_engine_swap_file_count",
         "Amount of swap files in Raft engine"
@@ -26,7 +26,7 @@ pub enum LogQueue {
 pub type FileSeq = u64;
 
 /// A unique identifier for a log file.
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct FileId {
     pub queue: LogQueue,
     pub seq: FileSeq,
@@ -62,8 +62,17 @@ impl std::cmp::PartialOrd for FileId {
     }
 }
 
+/// Bytes written to and removed from a log queue since it was opened.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct QueueIoStats {
+    /// Bytes appended to log files, file headers excluded.
+    pub appended_bytes: u64,
+    /// Bytes of log files that have been purged.
+    pub purged_bytes: u64,
+}
+
 /// A logical pointer to a chunk of log file data.
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct FileBlockHandle {
     pub id: FileId,
     pub offset: u64,
@@ -170,11 +179,27 @@ where
 /// other ones, and user can still use it afterwards without breaking
 /// consistency.
 pub trait PipeLog: Sized {
+    /// Reads submitted by [`PipeLog::submit_read`]. Reads in flight are waited
+    /// for when it's dropped.
+    type PendingRead;
+
     /// Reads some bytes from the specified position.
     fn read_bytes(&self, handle: FileBlockHandle) -> Result<Vec<u8>>;
 
-    /// Reads bytes from multi blocks using 'Async IO'.
-    fn async_read_bytes(&self, blocks: Vec<FileBlockHandle>) -> Result<Vec<Vec<u8>>>;
+    /// Submits reads of multi blocks using 'Async IO', without waiting for them
+    /// to complete. Blocks can be from either queue.
+    fn submit_read(&self, blocks: Vec<FileBlockHandle>) -> Result<Self::PendingRead>;
+
+    /// Waits for the submitted reads. Bytes of the blocks are returned in the
+    /// same order as they are submitted.
+    fn finish_read(&self, pending: Self::PendingRead) -> Result<Vec<Vec<u8>>>;
+
+    /// Reads bytes from multi blocks using 'Async IO'. Blocks can be from
+    /// either queue, and their bytes are returned in the same order.
+    fn async_read_bytes(&self, blocks: Vec<FileBlockHandle>) -> Result<Vec<Vec<u8>>> {
+        let pending = self.submit_read(blocks)?;
+        self.finish_read(pending)
+    }
 
     /// Appends some bytes to the specified log queue. Returns file position of
     /// the written bytes.
@@ -209,6 +234,12 @@ pub trait PipeLog: Sized {
     /// Returns total size of the specified log queue.
     fn total_size(&self, queue: LogQueue) -> usize;
 
+    /// Returns the size of each active file of the specified log queue.
+    fn file_sizes(&self, queue: LogQueue) -> Result<Vec<(FileSeq, usize)>>;
+
+    /// Returns the I/O statistics of the specified log queue.
+    fn io_stats(&self, queue: LogQueue) -> QueueIoStats;
+
     /// Rotates a new log file for the specified log queue.
     ///
     /// Implementation should be atomic under error conditions but not
@@ -220,4 +251,9 @@ pub trait PipeLog: Sized {
     ///
     /// Returns the number of deleted files.
     fn purge_to(&self, file_id: FileId) -> Result<usize>;
+
+    /// Deallocates the disk space occupied by the specified block. The block
+    /// will be read as zeros afterwards. The active file of a log queue can't
+    /// be punched.
+    fn punch_hole(&self, handle: FileBlockHandle) -> Result<()>;
 }
@@ -1,55 +1,60 @@
 // Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
 
 use std::collections::VecDeque;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::iter::Peekable;
 use std::mem;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use fail::fail_point;
 use log::{info, warn};
-use parking_lot::{Mutex, RwLock};
+use parking_lot::{Mutex, MutexGuard, RwLock};
 
-use crate::config::Config;
-use crate::engine::read_entry_bytes_from_file;
+use crate::config::SharedConfig;
+use crate::engine::read_blocks;
 use crate::event_listener::EventListener;
-use crate::log_batch::{AtomicGroupBuilder, LogBatch};
-use crate::memtable::{MemTableHandle, MemTables};
+use crate::lazy_recovery::RegionFileTracker;
+use crate::log_batch::{
+    AtomicGroupBuilder, CompressionType, LogBatch, LogItemBatch, LogItemContent,
+};
+use crate::memtable::{EntryIndex, KvValue, MemTableHandle, MemTables};
 use crate::metrics::*;
 use crate::pipe_log::{FileBlockHandle, FileId, FileSeq, LogQueue, PipeLog};
+use crate::purge_policy::{PurgeAction, PurgePolicy};
+use crate::rate_limiter::RateLimiter;
+use crate::util::ReadableSize;
 use crate::{GlobalStats, Result};
 
-// Force compact region with oldest 20% logs.
-const FORCE_COMPACT_RATIO: f64 = 0.2;
-// Only rewrite region with oldest 70% logs.
-const REWRITE_RATIO: f64 = 0.7;
-// Only rewrite region with stale logs less than this threshold.
-const MAX_REWRITE_ENTRIES_PER_REGION: usize = 32;
-const MAX_COUNT_BEFORE_FORCE_REWRITE: u32 = 9;
-
-fn max_batch_bytes() -> usize {
-    fail_point!("max_rewrite_batch_bytes", |s| s
-        .unwrap()
-        .parse::<usize>()
-        .unwrap());
-    128 * 1024
-}
-
 pub struct PurgeManager<P>
 where
     P: PipeLog,
 {
-    cfg: Arc<Config>,
+    cfg: Arc<SharedConfig>,
     memtables: MemTables,
     pipe_log: Arc<P>,
     global_stats: Arc<GlobalStats>,
     listeners: Vec<Arc<dyn EventListener>>,
+    policy: Arc<dyn PurgePolicy>,
+    // Limits background rewrite I/O of each queue.
+    rate_limiters: [RateLimiter; 2],
 
     // Only one thread can run `purge_expired_files` at a time.
     //
     // This table records Raft Groups that should be force compacted before. Those that are not
-    // compacted in time (after `MAX_EPOCH_BEFORE_FORCE_REWRITE` epochs) will be force rewritten.
+    // compacted in time will be force rewritten, as decided by `PurgePolicy`.
     force_rewrite_candidates: Arc<Mutex<HashMap<u64, u32>>>,
+
+    // Large entry blocks of append queue that will be reclaimed by hole punching.
+    // `None` if hole punching is disabled.
+    hole_punch_tracker: Option<HolePunchTracker>,
+
+    // Log files of each Raft Group, used by lazy recovery. `None` if lazy
+    // recovery is disabled.
+    region_file_tracker: Option<Arc<RegionFileTracker>>,
+
+    open_atomic_groups: OpenAtomicGroups,
+    snapshot_files: SnapshotFiles,
 }
 
 impl<P> PurgeManager<P>
@@ -57,22 +62,101 @@ where
     P: PipeLog,
 {
     pub fn new(
-        cfg: Arc<Config>,
+        cfg: Arc<SharedConfig>,
         memtables: MemTables,
         pipe_log: Arc<P>,
         global_stats: Arc<GlobalStats>,
         listeners: Vec<Arc<dyn EventListener>>,
+        policy: Arc<dyn PurgePolicy>,
     ) -> PurgeManager<P> {
+        let (hole_punch_tracker, region_file_tracker, rate_limit) = {
+            let cfg = cfg.get();
+            (
+                cfg.enable_hole_punching
+                    .then(|| HolePunchTracker::new(cfg.hole_punch_min_block_size.0 as usize)),
+                cfg.enable_lazy_recovery
+                    .then(|| Arc::new(RegionFileTracker::default())),
+                cfg.rewrite_rate_limit.map(|r| r.0),
+            )
+        };
         PurgeManager {
             cfg,
             memtables,
             pipe_log,
             global_stats,
             listeners,
+            policy,
+            rate_limiters: [RateLimiter::new(rate_limit), RateLimiter::new(rate_limit)],
             force_rewrite_candidates: Arc::new(Mutex::new(HashMap::default())),
+            hole_punch_tracker,
+            region_file_tracker,
+            open_atomic_groups: OpenAtomicGroups::default(),
+            snapshot_files: SnapshotFiles::default(),
+        }
+    }
+
+    pub fn purge_policy(&self) -> &Arc<dyn PurgePolicy> {
+        &self.policy
+    }
+
+    /// Changes the maximum rate of background rewrite I/O for each queue.
+    /// `None` means unlimited.
+    pub fn set_rewrite_rate_limit(&self, rate_limit: Option<ReadableSize>) {
+        for limiter in &self.rate_limiters {
+            limiter.set_bytes_per_sec(rate_limit.map(|r| r.0));
         }
     }
 
+    #[cfg(test)]
+    pub(crate) fn set_rate_limiter_clock(&mut self, clock: Arc<dyn crate::rate_limiter::Clock>) {
+        let rate = self.rate_limiters[0].bytes_per_sec();
+        self.rate_limiters = [
+            RateLimiter::with_clock(rate, clock.clone()),
+            RateLimiter::with_clock(rate, clock),
+        ];
+    }
+
+    // Blocks until `bytes` of I/O on `queue` is allowed by the rate limiter.
+    fn throttle(&self, queue: LogQueue, bytes: usize) {
+        let throttled = self.rate_limiters[queue as usize].request(bytes);
+        if !throttled.is_zero() {
+            let histogram = match queue {
+                LogQueue::Append => &BACKGROUND_RATE_LIMIT_DURATION_HISTOGRAM.append,
+                LogQueue::Rewrite => &BACKGROUND_RATE_LIMIT_DURATION_HISTOGRAM.rewrite,
+            };
+            histogram.observe(throttled.as_secs_f64());
+        }
+    }
+
+    fn max_batch_bytes(&self) -> usize {
+        fail_point!("max_rewrite_batch_bytes", |s| s
+            .unwrap()
+            .parse::<usize>()
+            .unwrap());
+        self.policy.max_rewrite_batch_bytes()
+    }
+
+    pub(crate) fn hole_punch_tracker(&self) -> Option<&HolePunchTracker> {
+        self.hole_punch_tracker.as_ref()
+    }
+
+    pub(crate) fn region_file_tracker(&self) -> Option<&Arc<RegionFileTracker>> {
+        self.region_file_tracker.as_ref()
+    }
+
+    pub(crate) fn open_atomic_groups(&self) -> &OpenAtomicGroups {
+        &self.open_atomic_groups
+    }
+
+    pub(crate) fn snapshot_files(&self) -> &SnapshotFiles {
+        &self.snapshot_files
+    }
+
+    /// Blocks `purge_expired_files` until the returned guard is dropped.
+    pub(crate) fn pause_purge(&self) -> MutexGuard<'_, HashMap<u64, u32>> {
+        self.force_rewrite_candidates.lock()
+    }
+
     pub fn purge_expired_files(&self) -> Result<Vec<u64>> {
         let _t = StopWatch::new(&*ENGINE_PURGE_DURATION_HISTOGRAM);
         let guard = self.force_rewrite_candidates.try_lock();
@@ -101,6 +185,18 @@ where
                         l.first_file_not_ready_for_purge(LogQueue::Append)
                             .map_or(barrier, |f| std::cmp::min(f, barrier))
                     });
+                let append_queue_barrier = self
+                    .open_atomic_groups
+                    .first_file()
+                    .map_or(append_queue_barrier, |f| {
+                        std::cmp::min(f, append_queue_barrier)
+                    });
+                let append_queue_barrier = self
+                    .snapshot_files
+                    .first_file(LogQueue::Append)
+                    .map_or(append_queue_barrier, |f| {
+                        std::cmp::min(f, append_queue_barrier)
+                    });
 
                 // Ordering
                 // 1. Must rewrite tombstones AFTER acquiring
@@ -110,6 +206,19 @@ where
                 //    entries from recreated region might be lost after
                 //    restart.
                 self.rewrite_append_queue_tombstones()?;
+                // Punch holes before rewrite, so that live blocks that remain
+                // tracked are left in place.
+                if let Some(tracker) = &self.hole_punch_tracker {
+                    if append_queue_barrier > first_append {
+                        self.punch_obsolete_blocks(
+                            tracker,
+                            std::cmp::min(rewrite_watermark, append_queue_barrier - 1),
+                        )?;
+                    }
+                    // Live blocks in the oldest files are rewritten as usual, or
+                    // they would hold back the purge indefinitely.
+                    tracker.untrack_before(compact_watermark);
+                }
                 should_compact.extend(self.rewrite_or_compact_append_queue(
                     rewrite_watermark,
                     compact_watermark,
@@ -174,13 +283,14 @@ where
         }
 
         let total_size = self.pipe_log.total_size(queue);
+        let cfg = self.cfg.get();
         match queue {
-            LogQueue::Append => total_size > self.cfg.purge_threshold.0 as usize,
+            LogQueue::Append => total_size > cfg.purge_threshold.0 as usize,
             LogQueue::Rewrite => {
                 let compacted_rewrites_ratio = self.global_stats.deleted_rewrite_entries() as f64
                     / self.global_stats.rewrite_entries() as f64;
-                total_size > self.cfg.purge_rewrite_threshold.unwrap().0 as usize
-                    && compacted_rewrites_ratio > self.cfg.purge_rewrite_garbage_ratio
+                total_size > cfg.purge_rewrite_threshold.unwrap().0 as usize
+                    && compacted_rewrites_ratio > cfg.purge_rewrite_garbage_ratio
             }
         }
     }
@@ -197,8 +307,9 @@ where
             return (None, None);
         }
 
-        let rewrite_watermark = self.pipe_log.file_at(queue, REWRITE_RATIO);
-        let compact_watermark = self.pipe_log.file_at(queue, FORCE_COMPACT_RATIO);
+        let (rewrite_watermark, compact_watermark) =
+            self.policy.append_queue_watermarks(first_file, active_file);
+        debug_assert!(compact_watermark <= rewrite_watermark);
         debug_assert!(active_file - 1 > 0);
         (
             Some(std::cmp::min(rewrite_watermark, active_file - 1)),
@@ -218,39 +329,34 @@ where
 
         let mut new_candidates = HashMap::with_capacity(rewrite_candidates.len());
         let memtables = self.memtables.collect(|t| {
-            let min_append_seq = t.min_file_seq(LogQueue::Append).unwrap_or(u64::MAX);
-            let old = min_append_seq < compact_watermark || t.rewrite_count() > 0;
-            let has_something_to_rewrite = min_append_seq <= rewrite_watermark;
-            let append_heavy = t.has_at_least_some_entries_before(
-                FileId::new(LogQueue::Append, rewrite_watermark),
-                MAX_REWRITE_ENTRIES_PER_REGION + t.rewrite_count(),
-            );
-            let full_heavy = t.has_at_least_some_entries_before(
-                FileId::new(LogQueue::Append, rewrite_watermark),
-                MAX_REWRITE_ENTRIES_PER_REGION,
-            );
             // counter is the times that target region triggers force compact.
             let compact_counter = rewrite_candidates.get(&t.region_id()).unwrap_or(&0);
-            if old && full_heavy {
-                if *compact_counter < MAX_COUNT_BEFORE_FORCE_REWRITE {
+            let has_something_to_rewrite = t
+                .min_file_seq(LogQueue::Append)
+                .map_or(false, |seq| seq <= rewrite_watermark);
+            match self.policy.select_append_action(
+                t,
+                rewrite_watermark,
+                compact_watermark,
+                *compact_counter,
+            ) {
+                PurgeAction::Skip => false,
+                PurgeAction::Rewrite => has_something_to_rewrite,
+                PurgeAction::Compact => {
                     // repeatedly ask user to compact these heavy regions.
                     should_compact.push(t.region_id());
                     new_candidates.insert(t.region_id(), *compact_counter + 1);
-                    return false;
-                } else {
+                    false
+                }
+                PurgeAction::CompactAndRewrite => {
                     // user is not responsive, do the rewrite ourselves.
                     should_compact.push(t.region_id());
-                    return has_something_to_rewrite;
+                    has_something_to_rewrite
                 }
             }
-            !append_heavy && has_something_to_rewrite
         });
 
-        self.rewrite_memtables(
-            memtables,
-            MAX_REWRITE_ENTRIES_PER_REGION,
-            Some(rewrite_watermark),
-        )?;
+        self.rewrite_memtables(memtables, 0, Some(rewrite_watermark))?;
         *rewrite_candidates = new_candidates;
 
         Ok(should_compact)
@@ -263,8 +369,7 @@ where
 
         let mut force_compact_regions = vec![];
         let memtables = self.memtables.collect(|t| {
-            // if the region is force rewritten, we should also trigger compact.
-            if t.rewrite_count() > MAX_REWRITE_ENTRIES_PER_REGION {
+            if self.policy.should_compact_rewritten(t) {
                 force_compact_regions.push(t.region_id());
             }
             t.min_file_seq(LogQueue::Rewrite).is_some()
@@ -286,6 +391,10 @@ where
 
     // Exclusive.
     fn rescan_memtables_and_purge_stale_files(&self, queue: LogQueue, seq: FileSeq) -> Result<()> {
+        let seq = self
+            .snapshot_files
+            .first_file(queue)
+            .map_or(seq, |f| std::cmp::min(f, seq));
         let min_seq = self.memtables.fold(seq, |min, t| {
             t.min_file_seq(queue).map_or(min, |m| std::cmp::min(min, m))
         });
@@ -296,6 +405,17 @@ where
         })?;
         if purged > 0 {
             info!("purged {} expired log files for queue {:?}", purged, queue);
+            if queue == LogQueue::Append {
+                if let Some(tracker) = &self.hole_punch_tracker {
+                    tracker.untrack_before(min_seq);
+                }
+            }
+            if let Some(tracker) = &self.region_file_tracker {
+                tracker.untrack_before(FileId {
+                    queue,
+                    seq: min_seq,
+                });
+            }
             for listener in &self.listeners {
                 listener.post_purge(FileId {
                     queue,
@@ -325,6 +445,17 @@ where
                 let m = memtable.read();
                 if let Some(rewrite) = rewrite {
                     m.fetch_entry_indexes_before(rewrite, &mut entry_indexes)?;
+                    if let Some(tracker) = &self.hole_punch_tracker {
+                        // Entries inside a tracked block are left in place. Their
+                        // space is reclaimed by hole punching after they become
+                        // obsolete.
+                        if let Some(pos) = entry_indexes
+                            .iter()
+                            .position(|ei| tracker.contains(ei.entries.as_ref().unwrap()))
+                        {
+                            entry_indexes.truncate(pos);
+                        }
+                    }
                     m.fetch_kvs_before(rewrite, &mut kvs);
                 } else {
                     m.fetch_rewritten_entry_indexes(&mut entry_indexes)?;
@@ -338,17 +469,30 @@ where
             let mut current_entry_indexes = Vec::new();
             let mut current_entries = Vec::new();
             let mut current_size = 0;
+            let mut blocks = distinct_blocks(&entry_indexes).into_iter().peekable();
+            let mut decoded_blocks = VecDeque::new();
+            let mut current_block: Option<(FileBlockHandle, Vec<u8>)> = None;
             // Split the entries into smaller chunks, so that we don't OOM, and the
             // compression overhead is not too high.
             let mut entry_indexes = entry_indexes.into_iter().peekable();
             while let Some(ei) = entry_indexes.next() {
-                let entry = read_entry_bytes_from_file(self.pipe_log.as_ref(), &ei)?;
+                let block = ei.entries.unwrap();
+                if current_block.as_ref().map(|(b, _)| *b) != Some(block) {
+                    if decoded_blocks.is_empty() {
+                        self.read_blocks_ahead(&mut blocks, &mut decoded_blocks)?;
+                    }
+                    current_block = decoded_blocks.pop_front();
+                    debug_assert_eq!(current_block.as_ref().map(|(b, _)| *b), Some(block));
+                }
+                let entry = current_block.as_ref().unwrap().1
+                    [ei.entry_offset as usize..(ei.entry_offset + ei.entry_len) as usize]
+                    .to_vec();
                 current_size += entry.len();
                 current_entries.push(entry);
                 current_entry_indexes.push(ei);
                 // If this is the last entry, we handle them outside the loop.
                 if entry_indexes.peek().is_some()
-                    && current_size + previous_size > max_batch_bytes()
+                    && current_size + previous_size > self.max_batch_bytes()
                 {
                     if needs_atomicity {
                         if previous_size > 0 {
@@ -357,7 +501,7 @@ where
                             // To avoid breaking atomicity, we need to flush.
                             self.rewrite_impl(&mut log_batch, rewrite, false)?;
                             previous_size = 0;
-                            if current_size <= max_batch_bytes() {
+                            if current_size <= self.max_batch_bytes() {
                                 continue;
                             }
                         }
@@ -385,18 +529,113 @@ where
             }
             log_batch.add_raw_entries(region_id, current_entry_indexes, current_entries)?;
             for (k, v) in kvs {
+                let v = match v {
+                    KvValue::Inline(v) => v,
+                    KvValue::Spilled(handle, _) => {
+                        self.throttle(handle.id.queue, handle.len);
+                        v.read(self.pipe_log.as_ref())?.into_owned()
+                    }
+                };
                 log_batch.put(region_id, k, v)?;
+                if !needs_atomicity && log_batch.approximate_size() > self.max_batch_bytes() {
+                    self.rewrite_impl(&mut log_batch, rewrite, false)?;
+                }
             }
             if let Some(g) = atomic_group.as_mut() {
                 g.end(&mut log_batch);
                 self.rewrite_impl(&mut log_batch, rewrite, false)?;
-            } else if log_batch.approximate_size() > max_batch_bytes() {
+            } else if log_batch.approximate_size() > self.max_batch_bytes() {
                 self.rewrite_impl(&mut log_batch, rewrite, false)?;
             }
         }
         self.rewrite_impl(&mut log_batch, rewrite, true)
     }
 
+    // Reads and decodes the next blocks of entries to be rewritten, at most
+    // `max_batch_bytes` of them unless a single block is larger. Blocks read
+    // together can be served by async I/O.
+    fn read_blocks_ahead<I>(
+        &self,
+        blocks: &mut Peekable<I>,
+        decoded: &mut VecDeque<(FileBlockHandle, Vec<u8>)>,
+    ) -> Result<()>
+    where
+        I: Iterator<Item = (FileBlockHandle, CompressionType)>,
+    {
+        let max_batch_bytes = self.max_batch_bytes();
+        let mut batch = Vec::new();
+        let mut batch_size = 0;
+        while let Some((block, _)) = blocks.peek() {
+            if !batch.is_empty() && batch_size + block.len > max_batch_bytes {
+                break;
+            }
+            batch_size += block.len;
+            self.throttle(block.id.queue, block.len);
+            batch.push(blocks.next().unwrap());
+        }
+        let handles: Vec<_> = batch.iter().map(|(b, _)| *b).collect();
+        let bytes = read_blocks(self.pipe_log.as_ref(), &self.cfg.get(), &handles)?;
+        for ((block, compression_type), bytes) in batch.into_iter().zip(bytes) {
+            let entries = LogBatch::decode_entries_block(&bytes, block, compression_type)?;
+            decoded.push_back((block, entries));
+        }
+        Ok(())
+    }
+
+    // Deallocates tracked entry blocks with file seqno no larger than
+    // `watermark` whose entries are all obsolete.
+    fn punch_obsolete_blocks(&self, tracker: &HolePunchTracker, watermark: FileSeq) -> Result<()> {
+        let obsolete: Vec<_> = tracker
+            .blocks_before(watermark)
+            .into_iter()
+            .filter(|b| self.is_block_obsolete(b))
+            .collect();
+        if obsolete.is_empty() {
+            return Ok(());
+        }
+        // Entries are made obsolete by newer writes in append queue, which must
+        // be persisted before the data is gone for good.
+        self.pipe_log.sync(LogQueue::Append)?;
+        let mut punched = 0;
+        for block in obsolete {
+            match self.pipe_log.punch_hole(block.handle) {
+                Ok(()) => {
+                    punched += block.handle.len;
+                    BACKGROUND_PUNCH_HOLE_BYTES.observe(block.handle.len as f64);
+                }
+                Err(e) => warn!("failed to punch hole at {:?}: {}", block.handle, e),
+            }
+            tracker.remove(&block.handle);
+        }
+        if punched > 0 {
+            info!("reclaimed {} bytes of obsolete log entries", punched);
+        }
+        Ok(())
+    }
+
+    // A block is obsolete if none of its entries is still referenced by
+    // memtables, or will be referenced again after restart. Entries that are
+    // rewritten are not considered obsolete, because recovery replays append
+    // queue on top of rewrite queue.
+    fn is_block_obsolete(&self, block: &TrackedBlock) -> bool {
+        let handle = &block.handle;
+        block.ranges.iter().all(|&(region_id, first, last)| {
+            let memtable = match self.memtables.get(region_id) {
+                Some(memtable) => memtable,
+                None => return true,
+            };
+            let memtable = memtable.read();
+            (first..=last).all(|index| match memtable.get_entry(index) {
+                None => true,
+                Some(ei) => {
+                    let h = ei.entries.unwrap();
+                    h.id.queue == LogQueue::Append
+                        && (h.id.seq, h.offset) > (handle.id.seq, handle.offset)
+                }
+            })
+        })
+    }
+
     fn rewrite_impl(
         &self,
         log_batch: &mut LogBatch,
@@ -407,12 +646,17 @@ where
             debug_assert!(sync);
             return self.pipe_log.sync(LogQueue::Rewrite);
         }
-        log_batch.finish_populate(self.cfg.batch_compression_threshold.0 as usize)?;
+        let compression_threshold = self.cfg.get().batch_compression_threshold.0 as usize;
+        let len = log_batch.finish_populate(compression_threshold)?;
+        self.throttle(LogQueue::Rewrite, len);
         let file_handle = self.pipe_log.append(LogQueue::Rewrite, log_batch)?;
         if sync {
             self.pipe_log.sync(LogQueue::Rewrite)?
         }
         log_batch.finish_write(file_handle);
+        if let Some(tracker) = &self.region_file_tracker {
+            tracker.track(log_batch.item_batch(), file_handle.id);
+        }
         self.memtables.apply_rewrite_writes(
             log_batch.drain(),
             rewrite_watermark,
@@ -434,6 +678,213 @@ where
     }
 }
 
+// Returns the blocks of `entry_indexes` in order, each of them only once.
+fn distinct_blocks(entry_indexes: &[EntryIndex]) -> Vec<(FileBlockHandle, CompressionType)> {
+    let mut blocks: Vec<(FileBlockHandle, CompressionType)> = Vec::new();
+    for ei in entry_indexes {
+        let block = ei.entries.unwrap();
+        if blocks.last().map(|(b, _)| *b) != Some(block) {
+            blocks.push((block, ei.compression_type));
+        }
+    }
+    blocks
+}
+
+/// Atomic groups of append queue that are not yet committed, along with the
+/// first log files they are written to. These files can't be purged, or the
+/// groups can't be recovered as a whole.
+#[derive(Default)]
+pub(crate) struct OpenAtomicGroups(Mutex<HashMap<u64, FileSeq>>);
+
+impl OpenAtomicGroups {
+    pub fn insert(&self, id: u64, first_file: FileSeq) {
+        self.0.lock().insert(id, first_file);
+    }
+
+    pub fn remove(&self, id: u64) {
+        self.0.lock().remove(&id);
+    }
+
+    pub fn first_file(&self) -> Option<FileSeq> {
+        self.0.lock().values().min().copied()
+    }
+}
+
+/// First log files of each queue referenced by live snapshots. These files and
+/// newer ones can't be purged, or the snapshots can't be read.
+#[derive(Default)]
+pub(crate) struct SnapshotFiles {
+    next_id: AtomicU64,
+    files: Mutex<HashMap<u64, [Option<FileSeq>; 2]>>,
+}
+
+impl SnapshotFiles {
+    /// Registers the first files of append and rewrite queue referenced by a
+    /// snapshot. Returns the ID of the snapshot.
+    pub fn insert(&self, first_files: [Option<FileSeq>; 2]) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.files.lock().insert(id, first_files);
+        id
+    }
+
+    /// Replaces the first files registered by a snapshot.
+    pub fn update(&self, id: u64, first_files: [Option<FileSeq>; 2]) {
+        if let Some(f) = self.files.lock().get_mut(&id) {
+            *f = first_files;
+        }
+    }
+
+    pub fn remove(&self, id: u64) {
+        self.files.lock().remove(&id);
+    }
+
+    pub fn first_file(&self, queue: LogQueue) -> Option<FileSeq> {
+        self.files
+            .lock()
+            .values()
+            .filter_map(|f| f[queue as usize])
+            .min()
+    }
+}
+
+/// A block of log entries written to append queue, along with the index
+/// ranges of Raft Groups stored in it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrackedBlock {
+    pub handle: FileBlockHandle,
+    /// (raft group id, first index, last index) of each group of entries.
+    pub ranges: Vec<(u64, u64, u64)>,
+}
+
+impl TrackedBlock {
+    /// Collects the entry block of a written `LogItemBatch`. Returns `None` if
+    /// the block is smaller than `min_size`.
+    pub(crate) fn from_item_batch(item_batch: &LogItemBatch, min_size: usize) -> Option<Self> {
+        let mut handle = None;
+        let mut ranges = Vec::new();
+        for item in item_batch.iter() {
+            if let LogItemContent::EntryIndexes(entry_indexes) = &item.content {
+                if let (Some(first), Some(last)) = (entry_indexes.0.first(), entry_indexes.0.last())
+                {
+                    handle = first.entries;
+                    ranges.push((item.raft_group_id, first.index, last.index));
+                }
+            }
+        }
+        match handle {
+            Some(handle) if handle.len >= min_size => Some(Self { handle, ranges }),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn contains_region(&self, raft_group_id: u64) -> bool {
+        self.ranges.iter().any(|r| r.0 == raft_group_id)
+    }
+}
+
+/// Keeps track of large entry blocks in append queue. Once all entries of a
+/// block become obsolete, its disk space is reclaimed by hole punching, so
+/// that live blocks around it don't need to be rewritten.
+pub struct HolePunchTracker {
+    min_block_size: usize,
+    // Blocks of each file, ordered by offset.
+    blocks: Mutex<BTreeMap<FileSeq, Vec<TrackedBlock>>>,
+}
+
+impl HolePunchTracker {
+    pub fn new(min_block_size: usize) -> Self {
+        Self {
+            min_block_size,
+            blocks: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Tracks the entry block of an append queue write.
+    pub(crate) fn track(&self, item_batch: &LogItemBatch) {
+        for item in item_batch.iter() {
+            if let LogItemContent::Command(cmd) = &item.content {
+                if let Some(from) = cmd.source() {
+                    self.untrack_region(from);
+                }
+            }
+        }
+        if let Some(block) = TrackedBlock::from_item_batch(item_batch, self.min_block_size) {
+            self.extend(vec![block]);
+        }
+    }
+
+    pub fn extend(&self, blocks: Vec<TrackedBlock>) {
+        let mut tracked = self.blocks.lock();
+        for block in blocks {
+            debug_assert_eq!(block.handle.id.queue, LogQueue::Append);
+            let file_blocks = tracked.entry(block.handle.id.seq).or_default();
+            // Writers of the same write group are applied concurrently.
+            match file_blocks.binary_search_by_key(&block.handle.offset, |b| b.handle.offset) {
+                Ok(i) => file_blocks[i] = block,
+                Err(i) => file_blocks.insert(i, block),
+            }
+        }
+    }
+
+    pub fn contains(&self, handle: &FileBlockHandle) -> bool {
+        self.blocks
+            .lock()
+            .get(&handle.id.seq)
+            .map_or(false, |blocks| {
+                blocks
+                    .binary_search_by_key(&handle.offset, |b| b.handle.offset)
+                    .is_ok()
+            })
+    }
+
+    pub fn remove(&self, handle: &FileBlockHandle) {
+        let mut tracked = self.blocks.lock();
+        if let Some(blocks) = tracked.get_mut(&handle.id.seq) {
+            if let Ok(i) = blocks.binary_search_by_key(&handle.offset, |b| b.handle.offset) {
+                blocks.remove(i);
+            }
+            if blocks.is_empty() {
+                tracked.remove(&handle.id.seq);
+            }
+        }
+    }
+
+    /// Returns all blocks with file seqno no larger than `seq`.
+    pub fn blocks_before(&self, seq: FileSeq) -> Vec<TrackedBlock> {
+        self.blocks
+            .lock()
+            .range(..=seq)
+            .flat_map(|(_, blocks)| blocks.iter().cloned())
+            .collect()
+    }
+
+    /// Stops tracking blocks of files with seqno smaller than `seq`. Blocks of
+    /// purged files must not be tracked, because the files might be recycled
+    /// afterwards.
+    pub fn untrack_before(&self, seq: FileSeq) {
+        let mut tracked = self.blocks.lock();
+        *tracked = tracked.split_off(&seq);
+    }
+
+    /// Stops tracking blocks that contain entries of `raft_group_id`. After a
+    /// Raft Group is renamed or cloned, its entries are referenced by another
+    /// Raft Group, so are its blocks.
+    pub fn untrack_region(&self, raft_group_id: u64) {
+        self.blocks.lock().retain(|_, blocks| {
+            blocks.retain(|b| !b.contains_region(raft_group_id));
+            !blocks.is_empty()
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.lock().values().map(|b| b.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.lock().is_empty()
+    }
+}
+
 #[derive(Default)]
 pub struct PurgeHook {
     // Append queue log files that are not yet fully applied to MemTable must not be
@@ -0,0 +1,430 @@
+// Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
+
+use crate::pipe_log::{FileId, FileSeq, LogQueue};
+use crate::{Error, Result};
+
+/// A read-only view of the in-memory index of a Raft Group, exposed to
+/// [`PurgePolicy`].
+pub trait RaftGroupView {
+    /// Returns the Raft Group ID.
+    fn raft_group_id(&self) -> u64;
+
+    /// Returns the smallest file sequence number of entries or key value pairs
+    /// of this Raft Group in the specified queue.
+    fn min_file_seq(&self, queue: LogQueue) -> Option<FileSeq>;
+
+    /// Returns the number of entries stored in rewrite queue.
+    fn rewrite_count(&self) -> usize;
+
+    /// Returns the number of live entries, in both queues.
+    fn entries_count(&self) -> usize;
+
+    /// Returns true if the Raft Group has at least `count` entries stored in
+    /// files no newer than `gate`, counting from the oldest entry.
+    fn has_at_least_some_entries_before(&self, gate: FileId, count: usize) -> bool;
+}
+
+/// What to do with a Raft Group when purging append queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PurgeAction {
+    /// Leave the Raft Group as it is.
+    Skip,
+    /// Rewrite the stale logs of the Raft Group into rewrite queue.
+    Rewrite,
+    /// Ask the user to compact the Raft Group. The request is returned by
+    /// `Engine::purge_expired_files`.
+    Compact,
+    /// Ask the user to compact the Raft Group, and rewrite its stale logs
+    /// without waiting for the compaction.
+    CompactAndRewrite,
+}
+
+/// Decides how to reclaim the space of obsolete log files.
+///
+/// Methods of the policy are called by the purge thread, and must not block.
+pub trait PurgePolicy: Send + Sync {
+    /// Returns `(rewrite_watermark, compact_watermark)` of append queue,
+    /// whose files span from `first` to `active` (inclusive, `first <
+    /// active`). Logs in files no newer than `rewrite_watermark` are
+    /// candidates for rewrite. Raft Groups with logs older than
+    /// `compact_watermark` are considered lagging behind.
+    ///
+    /// The watermarks are capped to exclude the active file, and
+    /// `compact_watermark` must not be larger than `rewrite_watermark`.
+    fn append_queue_watermarks(&self, first: FileSeq, active: FileSeq) -> (FileSeq, FileSeq);
+
+    /// Decides what to do with a Raft Group with logs in append queue.
+    /// `compact_requests` is the number of consecutive purges in which the
+    /// Raft Group was asked to compact. Raft Groups without logs in files no
+    /// newer than `rewrite_watermark` are never rewritten.
+    fn select_append_action(
+        &self,
+        raft_group: &dyn RaftGroupView,
+        rewrite_watermark: FileSeq,
+        compact_watermark: FileSeq,
+        compact_requests: u32,
+    ) -> PurgeAction;
+
+    /// Returns true if a Raft Group should be asked to compact when its
+    /// entries in rewrite queue are rewritten.
+    fn should_compact_rewritten(&self, raft_group: &dyn RaftGroupView) -> bool;
+
+    /// Returns the maximum size of a log batch written by rewrite.
+    fn max_rewrite_batch_bytes(&self) -> usize;
+
+    /// Checks whether the policy is valid. Called when the engine is opened.
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Force compact region with oldest 20% logs.
+const FORCE_COMPACT_RATIO: f64 = 0.2;
+// Only rewrite region with oldest 70% logs.
+const REWRITE_RATIO: f64 = 0.7;
+// Only rewrite region with stale logs less than this threshold.
+const MAX_REWRITE_ENTRIES_PER_REGION: usize = 32;
+const MAX_COUNT_BEFORE_FORCE_REWRITE: u32 = 9;
+const MAX_REWRITE_BATCH_BYTES: usize = 128 * 1024;
+
+// Returns the file at `position` of the span.
+fn file_at(first: FileSeq, active: FileSeq, position: f64) -> FileSeq {
+    let count = active - first + 1;
+    first + (count as f64 * position.clamp(0.0, 1.0)) as u64
+}
+
+/// The purge policy used by default.
+///
+/// Raft Groups with few stale logs are rewritten. Those with a lot of stale
+/// logs are asked to compact, and are only rewritten after ignoring the
+/// request several times.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultPurgePolicy;
+
+impl PurgePolicy for DefaultPurgePolicy {
+    fn append_queue_watermarks(&self, first: FileSeq, active: FileSeq) -> (FileSeq, FileSeq) {
+        (
+            file_at(first, active, REWRITE_RATIO),
+            file_at(first, active, FORCE_COMPACT_RATIO),
+        )
+    }
+
+    fn select_append_action(
+        &self,
+        t: &dyn RaftGroupView,
+        rewrite_watermark: FileSeq,
+        compact_watermark: FileSeq,
+        compact_requests: u32,
+    ) -> PurgeAction {
+        let min_append_seq = t.min_file_seq(LogQueue::Append).unwrap_or(u64::MAX);
+        let old = min_append_seq < compact_watermark || t.rewrite_count() > 0;
+        let has_something_to_rewrite = min_append_seq <= rewrite_watermark;
+        let gate = FileId::new(LogQueue::Append, rewrite_watermark);
+        let append_heavy = t.has_at_least_some_entries_before(
+            gate,
+            MAX_REWRITE_ENTRIES_PER_REGION + t.rewrite_count(),
+        );
+        let full_heavy = t.has_at_least_some_entries_before(gate, MAX_REWRITE_ENTRIES_PER_REGION);
+        if old && full_heavy {
+            if compact_requests < MAX_COUNT_BEFORE_FORCE_REWRITE {
+                // repeatedly ask user to compact these heavy regions.
+                return PurgeAction::Compact;
+            } else {
+                // user is not responsive, do the rewrite ourselves.
+                return PurgeAction::CompactAndRewrite;
+            }
+        }
+        if !append_heavy && has_something_to_rewrite {
+            PurgeAction::Rewrite
+        } else {
+            PurgeAction::Skip
+        }
+    }
+
+    fn should_compact_rewritten(&self, t: &dyn RaftGroupView) -> bool {
+        // if the region is force rewritten, we should also trigger compact.
+        t.rewrite_count() > MAX_REWRITE_ENTRIES_PER_REGION
+    }
+
+    fn max_rewrite_batch_bytes(&self) -> usize {
+        MAX_REWRITE_BATCH_BYTES
+    }
+}
+
+/// A purge policy that groups Raft Groups into tiers by the number of their
+/// stale logs, for workloads where the sizes of Raft Groups vary a lot.
+///
+/// - Small Raft Groups, with no more than `small_tier_entries` stale entries,
+///   are always rewritten.
+/// - Medium Raft Groups are rewritten only when they lag behind the compact
+///   watermark, without bothering the user.
+/// - Large Raft Groups, with at least `large_tier_entries` stale entries, are
+///   asked to compact, and are only rewritten after ignoring
+///   `max_compact_requests` requests.
+#[derive(Clone, Debug)]
+pub struct SizeTieredPurgePolicy {
+    /// Ratio of the oldest append queue files to be rewritten.
+    pub rewrite_ratio: f64,
+    /// Ratio of the oldest append queue files where Raft Groups are
+    /// considered lagging behind.
+    pub compact_ratio: f64,
+    pub small_tier_entries: usize,
+    pub large_tier_entries: usize,
+    pub max_compact_requests: u32,
+    pub max_rewrite_batch_bytes: usize,
+}
+
+impl Default for SizeTieredPurgePolicy {
+    fn default() -> Self {
+        Self {
+            rewrite_ratio: REWRITE_RATIO,
+            compact_ratio: FORCE_COMPACT_RATIO,
+            small_tier_entries: MAX_REWRITE_ENTRIES_PER_REGION,
+            large_tier_entries: 1024,
+            max_compact_requests: MAX_COUNT_BEFORE_FORCE_REWRITE,
+            max_rewrite_batch_bytes: MAX_REWRITE_BATCH_BYTES,
+        }
+    }
+}
+
+impl PurgePolicy for SizeTieredPurgePolicy {
+    fn append_queue_watermarks(&self, first: FileSeq, active: FileSeq) -> (FileSeq, FileSeq) {
+        let rewrite_watermark = file_at(first, active, self.rewrite_ratio);
+        let compact_watermark = file_at(first, active, self.compact_ratio);
+        (
+            rewrite_watermark,
+            std::cmp::min(compact_watermark, rewrite_watermark),
+        )
+    }
+
+    fn select_append_action(
+        &self,
+        t: &dyn RaftGroupView,
+        rewrite_watermark: FileSeq,
+        compact_watermark: FileSeq,
+        compact_requests: u32,
+    ) -> PurgeAction {
+        let min_append_seq = t.min_file_seq(LogQueue::Append).unwrap_or(u64::MAX);
+        if min_append_seq > rewrite_watermark {
+            return PurgeAction::Skip;
+        }
+        let gate = FileId::new(LogQueue::Append, rewrite_watermark);
+        let stale_entries_at_least = |count: usize| {
+            count > 0 && t.has_at_least_some_entries_before(gate, count + t.rewrite_count())
+        };
+        if !stale_entries_at_least(self.small_tier_entries + 1) {
+            PurgeAction::Rewrite
+        } else if !stale_entries_at_least(self.large_tier_entries) {
+            if min_append_seq < compact_watermark {
+                PurgeAction::Rewrite
+            } else {
+                PurgeAction::Skip
+            }
+        } else if compact_requests < self.max_compact_requests {
+            PurgeAction::Compact
+        } else {
+            PurgeAction::CompactAndRewrite
+        }
+    }
+
+    fn should_compact_rewritten(&self, t: &dyn RaftGroupView) -> bool {
+        t.rewrite_count() >= self.large_tier_entries
+    }
+
+    fn max_rewrite_batch_bytes(&self) -> usize {
+        self.max_rewrite_batch_bytes
+    }
+
+    fn validate(&self) -> Result<()> {
+        for (name, ratio) in [
+            ("rewrite_ratio", self.rewrite_ratio),
+            ("compact_ratio", self.compact_ratio),
+        ] {
+            // Also rejects NaN.
+            if !(0.0..=1.0).contains(&ratio) {
+                return Err(Error::InvalidArgument(format!(
+                    "{name} ({ratio}) must be within [0, 1]"
+                )));
+            }
+        }
+        if self.compact_ratio > self.rewrite_ratio {
+            return Err(Error::InvalidArgument(format!(
+                "compact_ratio ({}) must not be larger than rewrite_ratio ({})",
+                self.compact_ratio, self.rewrite_ratio
+            )));
+        }
+        if self.large_tier_entries <= self.small_tier_entries {
+            return Err(Error::InvalidArgument(format!(
+                "large_tier_entries ({}) must be larger than small_tier_entries ({})",
+                self.large_tier_entries, self.small_tier_entries
+            )));
+        }
+        if self.max_rewrite_batch_bytes == 0 {
+            return Err(Error::InvalidArgument(
+                "max_rewrite_batch_bytes must be positive".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockRaftGroup {
+        // File seqno of each entry, oldest first.
+        entries: Vec<FileSeq>,
+        rewrite_count: usize,
+    }
+
+    impl RaftGroupView for MockRaftGroup {
+        fn raft_group_id(&self) -> u64 {
+            1
+        }
+
+        fn min_file_seq(&self, queue: LogQueue) -> Option<FileSeq> {
+            match queue {
+                LogQueue::Append => self.entries.get(self.rewrite_count).copied(),
+                LogQueue::Rewrite => self.entries[..self.rewrite_count].first().copied(),
+            }
+        }
+
+        fn rewrite_count(&self) -> usize {
+            self.rewrite_count
+        }
+
+        fn entries_count(&self) -> usize {
+            self.entries.len()
+        }
+
+        fn has_at_least_some_entries_before(&self, gate: FileId, count: usize) -> bool {
+            self.entries
+                .get(count - 1)
+                .map_or(false, |seq| *seq <= gate.seq)
+        }
+    }
+
+    #[test]
+    fn test_default_policy() {
+        let policy = DefaultPurgePolicy;
+        assert_eq!(policy.append_queue_watermarks(1, 10), (8, 3));
+
+        let small = MockRaftGroup {
+            entries: vec![1; 10],
+            rewrite_count: 0,
+        };
+        assert_eq!(
+            policy.select_append_action(&small, 8, 3, 0),
+            PurgeAction::Rewrite
+        );
+        let fresh = MockRaftGroup {
+            entries: vec![9; 10],
+            rewrite_count: 0,
+        };
+        assert_eq!(
+            policy.select_append_action(&fresh, 8, 3, 0),
+            PurgeAction::Skip
+        );
+        let heavy = MockRaftGroup {
+            entries: vec![1; 100],
+            rewrite_count: 0,
+        };
+        assert_eq!(
+            policy.select_append_action(&heavy, 8, 3, 0),
+            PurgeAction::Compact
+        );
+        assert_eq!(
+            policy.select_append_action(&heavy, 8, 3, MAX_COUNT_BEFORE_FORCE_REWRITE),
+            PurgeAction::CompactAndRewrite
+        );
+        let heavy_but_recent = MockRaftGroup {
+            entries: vec![5; 100],
+            rewrite_count: 0,
+        };
+        assert_eq!(
+            policy.select_append_action(&heavy_but_recent, 8, 3, 0),
+            PurgeAction::Skip
+        );
+    }
+
+    #[test]
+    fn test_size_tiered_policy() {
+        let policy = SizeTieredPurgePolicy {
+            small_tier_entries: 10,
+            large_tier_entries: 100,
+            max_compact_requests: 2,
+            ..Default::default()
+        };
+        assert_eq!(policy.append_queue_watermarks(1, 10), (8, 3));
+
+        let small = MockRaftGroup {
+            entries: vec![5; 10],
+            rewrite_count: 0,
+        };
+        assert_eq!(
+            policy.select_append_action(&small, 8, 3, 0),
+            PurgeAction::Rewrite
+        );
+        let medium = MockRaftGroup {
+            entries: vec![5; 50],
+            rewrite_count: 0,
+        };
+        assert_eq!(
+            policy.select_append_action(&medium, 8, 3, 0),
+            PurgeAction::Skip
+        );
+        let lagging_medium = MockRaftGroup {
+            entries: vec![1; 50],
+            rewrite_count: 0,
+        };
+        assert_eq!(
+            policy.select_append_action(&lagging_medium, 8, 3, 0),
+            PurgeAction::Rewrite
+        );
+        let large = MockRaftGroup {
+            entries: vec![5; 200],
+            rewrite_count: 0,
+        };
+        assert_eq!(
+            policy.select_append_action(&large, 8, 3, 1),
+            PurgeAction::Compact
+        );
+        assert_eq!(
+            policy.select_append_action(&large, 8, 3, 2),
+            PurgeAction::CompactAndRewrite
+        );
+        assert!(!policy.should_compact_rewritten(&small));
+    }
+
+    #[test]
+    fn test_size_tiered_policy_validate() {
+        SizeTieredPurgePolicy::default().validate().unwrap();
+        for (rewrite_ratio, compact_ratio) in [
+            (f64::NAN, 0.2),
+            (0.7, f64::NAN),
+            (-0.1, 0.0),
+            (1.5, 0.2),
+            (0.7, -0.2),
+            (0.2, 0.7),
+        ] {
+            let policy = SizeTieredPurgePolicy {
+                rewrite_ratio,
+                compact_ratio,
+                ..Default::default()
+            };
+            assert!(policy.validate().is_err());
+        }
+        let policy = SizeTieredPurgePolicy {
+            small_tier_entries: 10,
+            large_tier_entries: 10,
+            ..Default::default()
+        };
+        assert!(policy.validate().is_err());
+        let policy = SizeTieredPurgePolicy {
+            max_rewrite_batch_bytes: 0,
+            ..Default::default()
+        };
+        assert!(policy.validate().is_err());
+    }
+}
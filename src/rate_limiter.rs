@@ -0,0 +1,212 @@
+// Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// Maximum amount of bytes that can be consumed in a burst, measured in
+/// seconds of the refill rate.
+const MAX_BURST_SECS: f64 = 0.1;
+
+/// Source of time used by [`RateLimiter`].
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Blocks the current thread for `dur`.
+    fn sleep(&self, dur: Duration);
+}
+
+/// A [`Clock`] backed by the system time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, dur: Duration) {
+        std::thread::sleep(dur);
+    }
+}
+
+struct Bucket {
+    // Can be negative, in which case later requests must wait for the debt to
+    // be paid off.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// A token bucket that limits the rate of I/O in bytes per second.
+///
+/// Requests larger than the bucket capacity are allowed, and are paid off by
+/// waiting.
+pub struct RateLimiter {
+    // Zero means unlimited.
+    bytes_per_sec: AtomicU64,
+    bucket: Mutex<Bucket>,
+    clock: Arc<dyn Clock>,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter. `None` means unlimited.
+    pub fn new(bytes_per_sec: Option<u64>) -> Self {
+        Self::with_clock(bytes_per_sec, Arc::new(SystemClock))
+    }
+
+    /// Creates a rate limiter that measures and waits for time with `clock`.
+    pub fn with_clock(bytes_per_sec: Option<u64>, clock: Arc<dyn Clock>) -> Self {
+        let rate = bytes_per_sec.unwrap_or(0);
+        Self {
+            bytes_per_sec: AtomicU64::new(rate),
+            bucket: Mutex::new(Bucket {
+                tokens: rate as f64 * MAX_BURST_SECS,
+                last_refill: clock.now(),
+            }),
+            clock,
+        }
+    }
+
+    /// Returns the current rate limit. `None` means unlimited.
+    pub fn bytes_per_sec(&self) -> Option<u64> {
+        match self.bytes_per_sec.load(Ordering::Relaxed) {
+            0 => None,
+            rate => Some(rate),
+        }
+    }
+
+    /// Changes the rate limit. `None` means unlimited. Takes effect on
+    /// subsequent requests.
+    pub fn set_bytes_per_sec(&self, bytes_per_sec: Option<u64>) {
+        self.bytes_per_sec
+            .store(bytes_per_sec.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Consumes `bytes` from the bucket, blocks the current thread until the
+    /// request is allowed. Returns the duration being throttled.
+    pub fn request(&self, bytes: usize) -> Duration {
+        let wait = self.consume(bytes);
+        if !wait.is_zero() {
+            self.clock.sleep(wait);
+        }
+        wait
+    }
+
+    /// Consumes `bytes` from the bucket without blocking. Returns how long the
+    /// caller should wait before the request is allowed.
+    pub fn consume(&self, bytes: usize) -> Duration {
+        let rate = self.bytes_per_sec.load(Ordering::Relaxed);
+        if rate == 0 {
+            return Duration::ZERO;
+        }
+        let rate = rate as f64;
+        let mut bucket = self.bucket.lock();
+        let now = self.clock.now();
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        bucket.last_refill = now;
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(rate * MAX_BURST_SECS);
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens < 0.0 {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// A [`Clock`] that only advances when slept on.
+#[cfg(test)]
+pub(crate) struct ManualClock {
+    now: Mutex<Instant>,
+    slept: Mutex<Duration>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub(crate) fn new() -> Self {
+        Self {
+            now: Mutex::new(Instant::now()),
+            slept: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub(crate) fn advance(&self, dur: Duration) {
+        *self.now.lock() += dur;
+    }
+
+    /// Returns the total duration slept on this clock.
+    pub(crate) fn slept(&self) -> Duration {
+        *self.slept.lock()
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock()
+    }
+
+    fn sleep(&self, dur: Duration) {
+        self.advance(dur);
+        *self.slept.lock() += dur;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Durations are derived from floating point numbers, so they are compared
+    // with a tolerance.
+    fn assert_duration(actual: Duration, expected: Duration) {
+        let diff = if actual > expected {
+            actual - expected
+        } else {
+            expected - actual
+        };
+        assert!(
+            diff <= Duration::from_millis(1),
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let clock = Arc::new(ManualClock::new());
+        let limiter = RateLimiter::with_clock(None, clock.clone());
+        assert_eq!(limiter.bytes_per_sec(), None);
+        assert_duration(limiter.request(usize::MAX), Duration::ZERO);
+
+        // 100KB per second, with a burst of 10KB.
+        limiter.set_bytes_per_sec(Some(100 * 1024));
+        assert_eq!(limiter.bytes_per_sec(), Some(100 * 1024));
+        // The bucket is filled after being idle.
+        clock.advance(Duration::from_secs(1));
+        assert_duration(limiter.request(10 * 1024), Duration::ZERO);
+        // Requests are paid off by sleeping.
+        assert_duration(limiter.request(10 * 1024), Duration::from_millis(100));
+        assert_duration(limiter.request(50 * 1024), Duration::from_millis(500));
+        assert_duration(clock.slept(), Duration::from_millis(600));
+        // Tokens are refilled as time goes by.
+        clock.advance(Duration::from_millis(50));
+        assert_duration(limiter.request(10 * 1024), Duration::from_millis(50));
+        assert_duration(clock.slept(), Duration::from_millis(650));
+        // Requests larger than the burst are allowed.
+        assert_duration(limiter.request(100 * 1024), Duration::from_secs(1));
+        // The caller waits by itself.
+        assert_duration(limiter.consume(10 * 1024), Duration::from_millis(100));
+        assert_duration(clock.slept(), Duration::from_millis(1650));
+        clock.advance(Duration::from_millis(100));
+        assert_duration(limiter.consume(10 * 1024), Duration::from_millis(100));
+        clock.advance(Duration::from_millis(100));
+
+        // Requests are not throttled after the limit is lifted.
+        limiter.set_bytes_per_sec(None);
+        assert_duration(limiter.request(100 * 1024 * 1024), Duration::ZERO);
+        assert_duration(clock.slept(), Duration::from_millis(1650));
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::pipe_log::{FileId, LogQueue};
+
+/// A log file truncated during recovery.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Truncation {
+    pub file_id: FileId,
+    /// The file size after truncation.
+    pub offset: u64,
+    pub reason: String,
+}
+
+/// A range of bytes in a log file that can't be decoded. It's skipped during
+/// recovery in [`RecoveryMode::Salvage`].
+///
+/// [`RecoveryMode::Salvage`]: crate::RecoveryMode::Salvage
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorruptedRange {
+    pub file_id: FileId,
+    pub offset: u64,
+    pub len: u64,
+    /// Whether the range is truncated from the file. Only the tail of the
+    /// last log file is truncated, other ranges are left in place.
+    pub truncated: bool,
+}
+
+/// Summary of decisions made during recovery.
+#[derive(Clone, Debug, Default)]
+pub struct RecoveryReport {
+    /// Number of log files scanned, excluding recycled ones.
+    pub files_scanned: usize,
+    /// Number of bytes of log batches replayed.
+    pub bytes_replayed: u64,
+    /// Log files truncated because of corruption.
+    pub truncations: Vec<Truncation>,
+    /// Log files discarded because of a hole in file sequence.
+    pub discarded_files: Vec<FileId>,
+    /// Number of stale metadata deleted, which is left by an older version.
+    pub stale_metadata_deleted: usize,
+    /// Ranges of log files that are skipped because of corruption. Only
+    /// filled in [`RecoveryMode::Salvage`].
+    ///
+    /// [`RecoveryMode::Salvage`]: crate::RecoveryMode::Salvage
+    pub corrupted_ranges: Vec<CorruptedRange>,
+    /// Log entries that are lost because of corruption, grouped by Raft
+    /// Group. Index ranges are half-open. Only filled in
+    /// [`RecoveryMode::Salvage`].
+    ///
+    /// [`RecoveryMode::Salvage`]: crate::RecoveryMode::Salvage
+    pub lost_entries: BTreeMap<u64, Vec<Range<u64>>>,
+    /// IDs of incomplete atomic groups that are discarded.
+    pub discarded_atomic_groups: Vec<u64>,
+    /// Number of Raft Groups recovered.
+    pub regions_recovered: usize,
+    /// Number of Raft Groups whose log entries are indexed in background
+    /// after the engine is opened. Only non-zero when the engine is lazily
+    /// recovered, see `Config::enable_lazy_recovery`.
+    pub regions_deferred: usize,
+
+    /// Time spent on scanning the directory.
+    pub scan_duration: Duration,
+    /// Time spent on reading and replaying log files.
+    pub replay_duration: Duration,
+    /// Time spent on merging replayed states and building the engine.
+    pub build_duration: Duration,
+}
+
+impl RecoveryReport {
+    /// Returns whether no data is dropped during recovery.
+    pub fn is_clean(&self) -> bool {
+        self.truncations.is_empty()
+            && self.discarded_files.is_empty()
+            && self.corrupted_ranges.is_empty()
+            && self.lost_entries.is_empty()
+            && self.discarded_atomic_groups.is_empty()
+    }
+
+    /// Returns the total time spent on recovery.
+    pub fn total_duration(&self) -> Duration {
+        self.scan_duration + self.replay_duration + self.build_duration
+    }
+}
+
+/// Progress of recovering the log files of one queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecoveryProgress {
+    pub queue: LogQueue,
+    pub files_processed: usize,
+    pub files_total: usize,
+    pub bytes_processed: u64,
+    pub bytes_total: u64,
+}
+
+/// A receiver of recovery progress.
+///
+/// # Threading
+///
+/// Log files are recovered concurrently. [`on_progress`] can be called from
+/// multiple threads at the same time, and the progress received might not be
+/// monotonic.
+///
+/// [`on_progress`]: RecoveryProgressSink::on_progress
+pub trait RecoveryProgressSink: Send + Sync {
+    /// Called *after* a log file is recovered.
+    fn on_progress(&self, progress: RecoveryProgress);
+}
+
+/// A token to cancel an ongoing recovery. Cancelled recovery returns
+/// [`Error::Cancelled`] without modifying any log file.
+///
+/// [`Error::Cancelled`]: crate::Error::Cancelled
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
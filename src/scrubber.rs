@@ -0,0 +1,214 @@
+// Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
+
+//! Background verification of sealed log files.
+
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use log::warn;
+use parking_lot::{Condvar, Mutex};
+
+use crate::env::FileSystem;
+use crate::event_listener::EventListener;
+use crate::file_pipe_log::FilePipeLog;
+use crate::memtable::MemTables;
+use crate::metrics::*;
+use crate::pipe_log::{FileId, LogQueue, PipeLog};
+use crate::rate_limiter::RateLimiter;
+use crate::{Error, Result};
+
+/// Time to wait before scrubbing log files again after a full pass.
+const SCRUB_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Reads through sealed log files in a background thread at a limited rate,
+/// and reports corrupted data to [`EventListener`]s.
+pub struct Scrubber {
+    stopped: Mutex<bool>,
+    cond: Condvar,
+    // Unlimited means scrubbing is paused.
+    rate_limiter: RateLimiter,
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Default for Scrubber {
+    fn default() -> Self {
+        Self {
+            stopped: Mutex::new(false),
+            cond: Condvar::new(),
+            rate_limiter: RateLimiter::new(None),
+            worker: Mutex::new(None),
+        }
+    }
+}
+
+impl Scrubber {
+    /// Starts scrubbing in a background thread if it isn't started yet.
+    /// Nothing is scrubbed until a rate limit is set.
+    pub fn start<F: FileSystem + 'static>(
+        self: &Arc<Self>,
+        pipe_log: Arc<FilePipeLog<F>>,
+        memtables: MemTables,
+        listeners: Vec<Arc<dyn EventListener>>,
+    ) -> Result<()> {
+        let mut handle = self.worker.lock();
+        if handle.is_some() {
+            return Ok(());
+        }
+        let worker = Worker {
+            scrubber: self.clone(),
+            pipe_log,
+            memtables,
+            listeners,
+        };
+        *handle = Some(
+            std::thread::Builder::new()
+                .name("re-scrub".into())
+                .spawn(move || worker.run())?,
+        );
+        Ok(())
+    }
+
+    /// Changes the rate limit of scrubbing. `None` pauses scrubbing.
+    pub fn set_rate_limit(&self, bytes_per_sec: Option<u64>) {
+        // Changed under the lock, so that the worker doesn't miss resuming.
+        let _stopped = self.stopped.lock();
+        let paused = self.is_paused();
+        self.rate_limiter.set_bytes_per_sec(bytes_per_sec);
+        if paused && bytes_per_sec.is_some() {
+            self.cond.notify_all();
+        }
+    }
+
+    /// Stops the background thread.
+    pub fn stop(&self) {
+        *self.stopped.lock() = true;
+        self.cond.notify_all();
+        if let Some(handle) = self.worker.lock().take() {
+            handle.join().unwrap();
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        *self.stopped.lock()
+    }
+
+    fn is_paused(&self) -> bool {
+        self.rate_limiter.bytes_per_sec().is_none()
+    }
+
+    // Returns false if the scrubber is stopped. Might return early if
+    // scrubbing is resumed.
+    fn wait(&self, timeout: Duration) -> bool {
+        let mut stopped = self.stopped.lock();
+        if !*stopped {
+            self.cond.wait_for(&mut stopped, timeout);
+        }
+        !*stopped
+    }
+
+    // Waits before starting another pass. A pass interrupted by pausing is
+    // started again as soon as scrubbing is resumed. Returns false if the
+    // scrubber is stopped.
+    fn wait_for_next_pass(&self, interrupted: bool) -> bool {
+        let mut stopped = self.stopped.lock();
+        if !*stopped && (!interrupted || self.is_paused()) {
+            self.cond.wait_for(&mut stopped, SCRUB_INTERVAL);
+        }
+        !*stopped
+    }
+}
+
+struct Worker<F: FileSystem> {
+    scrubber: Arc<Scrubber>,
+    pipe_log: Arc<FilePipeLog<F>>,
+    memtables: MemTables,
+    listeners: Vec<Arc<dyn EventListener>>,
+}
+
+impl<F: FileSystem> Worker<F> {
+    fn run(&self) {
+        loop {
+            let mut interrupted = false;
+            'pass: for queue in [LogQueue::Append, LogQueue::Rewrite] {
+                let (first, active) = self.pipe_log.file_span(queue);
+                // The active file is still being written.
+                for seq in first..active {
+                    if self.scrubber.is_stopped() {
+                        return;
+                    }
+                    if self.scrubber.is_paused() {
+                        interrupted = true;
+                        break 'pass;
+                    }
+                    self.scrub_file(FileId { queue, seq });
+                }
+            }
+            if !self.scrubber.wait_for_next_pass(interrupted) {
+                return;
+            }
+        }
+    }
+
+    fn scrub_file(&self, file_id: FileId) {
+        let (bytes_counter, corruption_counter) = match file_id.queue {
+            LogQueue::Append => (
+                &BACKGROUND_SCRUB_BYTES.append,
+                &BACKGROUND_SCRUB_CORRUPTION.append,
+            ),
+            LogQueue::Rewrite => (
+                &BACKGROUND_SCRUB_BYTES.rewrite,
+                &BACKGROUND_SCRUB_CORRUPTION.rewrite,
+            ),
+        };
+        let mut corruptions = Vec::new();
+        let res = self.pipe_log.scrub_file(
+            file_id,
+            &mut |bytes| {
+                if self.scrubber.is_stopped() || self.scrubber.is_paused() {
+                    return Err(Error::Cancelled);
+                }
+                // Waits on the condition variable instead of sleeping, so that
+                // stopping isn't blocked by a low rate limit.
+                let wait = self.scrubber.rate_limiter.consume(bytes);
+                if !wait.is_zero() && !self.scrubber.wait(wait) {
+                    return Err(Error::Cancelled);
+                }
+                bytes_counter.inc_by(bytes as u64);
+                Ok(())
+            },
+            &mut |offset, regions, e| corruptions.push((offset, regions, e)),
+        );
+        // Purged files might have been recycled and overwritten.
+        if self.pipe_log.file_span(file_id.queue).0 > file_id.seq {
+            return;
+        }
+        match res {
+            Ok(()) => {}
+            Err(Error::Cancelled) => return,
+            Err(e) => {
+                warn!("Failed to scrub log file {:?}: {}", file_id, e);
+                return;
+            }
+        }
+        for (offset, mut regions, e) in corruptions {
+            if regions.is_empty() {
+                regions = self
+                    .memtables
+                    .collect(|t| t.has_data_in(file_id))
+                    .iter()
+                    .map(|t| t.read().region_id())
+                    .collect();
+                regions.sort_unstable();
+            }
+            warn!(
+                "Corruption detected in log file {:?} at offset {}: {}, affected regions: {:?}",
+                file_id, offset, e, regions
+            );
+            corruption_counter.inc();
+            for listener in &self.listeners {
+                listener.on_corruption_detected(file_id, offset, &regions);
+            }
+        }
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
+
+//! Disk space accounting of log files.
+
+use std::collections::HashSet;
+
+use hashbrown::HashMap;
+
+use crate::memtable::MemTables;
+use crate::metrics::*;
+use crate::pipe_log::{FileId, FileSeq, LogQueue, PipeLog};
+use crate::Result;
+
+/// Space usage of a single log file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileSpaceStats {
+    pub seq: FileSeq,
+    /// Size of the file, excluding the space preallocated for writes.
+    pub size: usize,
+    /// Bytes still referenced by memtables.
+    pub live_bytes: usize,
+}
+
+impl FileSpaceStats {
+    /// Bytes that can be reclaimed by rewriting this file, including those of
+    /// file header and metadata.
+    pub fn dead_bytes(&self) -> usize {
+        self.size.saturating_sub(self.live_bytes)
+    }
+}
+
+/// Space usage of a log queue.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueueSpaceStats {
+    /// Active files, from the oldest to the newest.
+    pub files: Vec<FileSpaceStats>,
+    /// Bytes appended to this queue since the engine was opened.
+    pub appended_bytes: u64,
+    /// Bytes of files purged from this queue since the engine was opened.
+    pub purged_bytes: u64,
+}
+
+impl QueueSpaceStats {
+    /// Returns the total size of active files.
+    pub fn size(&self) -> usize {
+        self.files.iter().map(|f| f.size).sum()
+    }
+
+    pub fn live_bytes(&self) -> usize {
+        self.files.iter().map(|f| f.live_bytes).sum()
+    }
+
+    pub fn dead_bytes(&self) -> usize {
+        self.files.iter().map(|f| f.dead_bytes()).sum()
+    }
+}
+
+/// Space usage of the engine, returned by
+/// [`Engine::space_stats`](crate::Engine::space_stats).
+///
+/// Cumulative counters start from zero every time the engine is opened.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SpaceStats {
+    pub append: QueueSpaceStats,
+    pub rewrite: QueueSpaceStats,
+}
+
+impl SpaceStats {
+    /// Returns the total size of active log files.
+    pub fn size(&self) -> usize {
+        self.append.size() + self.rewrite.size()
+    }
+
+    /// Bytes written by users.
+    pub fn user_bytes(&self) -> u64 {
+        self.append.appended_bytes
+    }
+
+    /// Bytes written by purge, including rewritten entries, key values and
+    /// tombstones.
+    pub fn rewrite_bytes(&self) -> u64 {
+        self.rewrite.appended_bytes
+    }
+
+    pub fn purged_bytes(&self) -> u64 {
+        self.append.purged_bytes + self.rewrite.purged_bytes
+    }
+
+    /// Returns the ratio of all bytes written to log files to bytes written
+    /// by users, or 0 if users haven't written anything.
+    pub fn write_amplification(&self) -> f64 {
+        let user_bytes = self.user_bytes();
+        if user_bytes == 0 {
+            return 0.0;
+        }
+        (user_bytes + self.rewrite_bytes()) as f64 / user_bytes as f64
+    }
+}
+
+/// Collects space usage from log files and the memtables referencing them.
+pub(crate) fn collect_space_stats<P: PipeLog>(
+    pipe_log: &P,
+    memtables: &MemTables,
+) -> Result<SpaceStats> {
+    let mut blocks = HashSet::new();
+    let mut live = HashMap::default();
+    for memtable in memtables.collect(|_| true) {
+        memtable.read().collect_live_bytes(&mut blocks, &mut live);
+    }
+    let mut stats = SpaceStats::default();
+    for queue in [LogQueue::Append, LogQueue::Rewrite] {
+        let io_stats = pipe_log.io_stats(queue);
+        let files = pipe_log
+            .file_sizes(queue)?
+            .into_iter()
+            .map(|(seq, size)| FileSpaceStats {
+                seq,
+                size,
+                live_bytes: live.get(&FileId { queue, seq }).copied().unwrap_or(0),
+            })
+            .collect();
+        let queue_stats = QueueSpaceStats {
+            files,
+            appended_bytes: io_stats.appended_bytes,
+            purged_bytes: io_stats.purged_bytes,
+        };
+        let gauge = match queue {
+            LogQueue::Append => &LOG_LIVE_BYTES.append,
+            LogQueue::Rewrite => &LOG_LIVE_BYTES.rewrite,
+        };
+        gauge.set(queue_stats.live_bytes() as i64);
+        match queue {
+            LogQueue::Append => stats.append = queue_stats,
+            LogQueue::Rewrite => stats.rewrite = queue_stats,
+        }
+    }
+    Ok(stats)
+}
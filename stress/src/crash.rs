@@ -0,0 +1,439 @@
+// Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
+
+//! Crash-recovery verification.
+//!
+//! Writers run against an engine backed by [`MemFileSystem`], with payloads
+//! generated from a seed. The file system is periodically crashed, tearing
+//! the last unsynced write, and the engine is reopened. Each Raft Group is
+//! then checked against what writers have observed: everything acknowledged
+//! by a synced write must be recovered, and nothing that was never written
+//! may appear.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread::{sleep, Builder as ThreadBuilder, JoinHandle};
+use std::time::{Duration, Instant};
+
+use raft::eraftpb::Entry;
+use raft_engine::env::{FileSystem, MemFileSystem};
+use raft_engine::{Command, Config, Engine, LogBatch};
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+
+use crate::{MessageExtTyped, TestArgs};
+
+type CrashEngine = Engine<MemFileSystem>;
+
+/// Number of keys written to each Raft Group.
+const KEYS_PER_REGION: u64 = 4;
+
+/// Observable state of a Raft Group.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct RegionState {
+    /// First and last index of entries.
+    entries: Option<(u64, u64)>,
+    kvs: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl RegionState {
+    fn last_index(&self) -> u64 {
+        self.entries.map_or(0, |(_, last)| last)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct RegionModel {
+    region_id: u64,
+    /// State that must survive a crash.
+    durable: RegionState,
+    /// States after writes that are not known to be durable, oldest first.
+    /// Any one of them can be recovered.
+    pending: Vec<RegionState>,
+}
+
+impl RegionModel {
+    fn latest(&self) -> &RegionState {
+        self.pending.last().unwrap_or(&self.durable)
+    }
+
+    // Called when all previous writes are synced.
+    fn persist(&mut self) {
+        if let Some(state) = self.pending.pop() {
+            self.durable = state;
+            self.pending.clear();
+        }
+    }
+}
+
+fn payload(seed: u64, region_id: u64, index: u64, entry_size: usize) -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(
+        seed ^ region_id.rotate_left(32) ^ index.wrapping_mul(0x9e37_79b9_7f4a_7c15),
+    );
+    let len = rng.gen_range(entry_size / 2..=entry_size + entry_size / 2);
+    let mut data = vec![0; len];
+    rng.fill_bytes(&mut data);
+    data
+}
+
+fn key(i: u64) -> Vec<u8> {
+    format!("key-{}", i).into_bytes()
+}
+
+struct Writer {
+    engine: Arc<CrashEngine>,
+    args: TestArgs,
+    seed: u64,
+    rng: StdRng,
+    // Held in read mode while writing, a crash can only happen in between.
+    crash_lock: Arc<RwLock<()>>,
+    crashed: Arc<AtomicBool>,
+    models: Vec<RegionModel>,
+    acknowledged: usize,
+}
+
+impl Writer {
+    fn run(mut self) -> Self {
+        let mut log_batch = LogBatch::default();
+        let crash_lock = self.crash_lock.clone();
+        loop {
+            let _guard = crash_lock.read().unwrap();
+            if self.crashed.load(Ordering::SeqCst) {
+                break;
+            }
+            let mut written = Vec::new();
+            for _ in 0..self.args.write_region_count {
+                let i = self.rng.gen_range(0..self.models.len());
+                if written.contains(&i) {
+                    continue;
+                }
+                let state = self.next_state(i, &mut log_batch);
+                written.push(i);
+                self.models[i].pending.push(state);
+            }
+            let sync = !self.args.write_without_sync && self.rng.gen_bool(0.5);
+            if let Err(e) = self.engine.write(&mut log_batch, sync) {
+                println!("write error {:?}", e);
+                break;
+            }
+            self.acknowledged += 1;
+            if sync {
+                // Everything written before is synced along with it.
+                for model in &mut self.models {
+                    model.persist();
+                }
+            }
+            if !written.is_empty() && self.rng.gen_ratio(1, 10) {
+                let i = written[self.rng.gen_range(0..written.len())];
+                let mut state = self.models[i].latest().clone();
+                if let Some((first, last)) = state.entries {
+                    let index = self.rng.gen_range(first..=last);
+                    self.engine.compact_to(self.models[i].region_id, index);
+                    state.entries = Some((index, last));
+                    self.models[i].pending.push(state);
+                }
+            }
+        }
+        self
+    }
+
+    // Adds a random write of the `i`-th Raft Group to `log_batch`, and returns
+    // the state after it.
+    fn next_state(&mut self, i: usize, log_batch: &mut LogBatch) -> RegionState {
+        let region_id = self.models[i].region_id;
+        let mut state = self.models[i].latest().clone();
+        let last = state.last_index();
+        let count = self.args.write_entry_count;
+        let entries: Vec<Entry> = (last + 1..=last + count)
+            .map(|index| Entry {
+                index,
+                data: payload(self.seed, region_id, index, self.args.entry_size).into(),
+                ..Default::default()
+            })
+            .collect();
+        log_batch
+            .add_entries::<MessageExtTyped>(region_id, &entries)
+            .unwrap();
+        let first = state.entries.map_or(last + 1, |(first, _)| first);
+        state.entries = Some((first, last + count));
+        if self.args.compact_count > 0 && last + count - first + 1 > self.args.compact_count {
+            let index = last + count - self.args.compact_count + 1;
+            log_batch.add_command(region_id, Command::Compact { index });
+            state.entries = Some((index, last + count));
+        }
+        if self.rng.gen_ratio(1, 4) {
+            let k = key(self.rng.gen_range(0..KEYS_PER_REGION));
+            let mut value = vec![0; 16];
+            self.rng.fill_bytes(&mut value);
+            log_batch.put(region_id, k.clone(), value.clone()).unwrap();
+            state.kvs.insert(k, value);
+        } else if self.rng.gen_ratio(1, 16) {
+            let k = key(self.rng.gen_range(0..KEYS_PER_REGION));
+            log_batch.delete(region_id, k.clone());
+            state.kvs.remove(&k);
+        }
+        state
+    }
+}
+
+// Returns the recovered state of a Raft Group if it's one of the states the
+// Raft Group could be in, otherwise the first divergence.
+fn verify_region(
+    engine: &CrashEngine,
+    seed: u64,
+    entry_size: usize,
+    model: &RegionModel,
+) -> Result<RegionState, String> {
+    let region_id = model.region_id;
+    let mut recovered = RegionState {
+        entries: engine
+            .try_first_index(region_id)
+            .and_then(|first| Ok(first.zip(engine.try_last_index(region_id)?)))
+            .map_err(|e| format!("failed to read region {}: {}", region_id, e))?,
+        ..Default::default()
+    };
+    if let Some((first, last)) = recovered.entries {
+        let mut entries = Vec::new();
+        if let Err(e) = engine.fetch_entries_to::<MessageExtTyped>(
+            region_id,
+            first,
+            last + 1,
+            None,
+            &mut entries,
+        ) {
+            return Err(format!(
+                "failed to fetch entries {}..={}: {}",
+                first, last, e
+            ));
+        }
+        for entry in entries {
+            if entry.data[..] != payload(seed, region_id, entry.index, entry_size)[..] {
+                return Err(format!("entry {} has unexpected payload", entry.index));
+            }
+        }
+    }
+    for i in 0..KEYS_PER_REGION {
+        match engine.get(region_id, &key(i)) {
+            Ok(Some(v)) => {
+                recovered.kvs.insert(key(i), v);
+            }
+            Ok(None) => {}
+            Err(e) => return Err(format!("failed to read key {}: {}", i, e)),
+        }
+    }
+
+    if recovered == model.durable || model.pending.contains(&recovered) {
+        return Ok(recovered);
+    }
+    let (durable, latest) = (&model.durable, model.latest());
+    let recovered_last = recovered.last_index();
+    if recovered_last < durable.last_index() {
+        return Err(format!(
+            "entry {} is lost, entries are acknowledged until {}",
+            recovered_last + 1,
+            durable.last_index()
+        ));
+    }
+    if recovered_last > latest.last_index() {
+        return Err(format!(
+            "entry {} is recovered, but entries are only written until {}",
+            recovered_last,
+            latest.last_index()
+        ));
+    }
+    let first = |s: &RegionState| s.entries.map(|(first, _)| first);
+    if !std::iter::once(durable)
+        .chain(model.pending.iter())
+        .any(|s| first(s) == first(&recovered))
+    {
+        return Err(format!(
+            "first index is {:?}, expected between {:?} and {:?}",
+            first(&recovered),
+            first(durable),
+            first(latest)
+        ));
+    }
+    for (k, v) in &recovered.kvs {
+        if durable.kvs.get(k) != Some(v) && !model.pending.iter().any(|s| s.kvs.get(k) == Some(v)) {
+            return Err(format!(
+                "key {:?} has unexpected value {:?}",
+                String::from_utf8_lossy(k),
+                v
+            ));
+        }
+    }
+    Err(format!(
+        "recovered {:?} matches no acknowledged state, acknowledged {:?}, written {:?}",
+        recovered, durable, latest
+    ))
+}
+
+// Checks all Raft Groups against their models, and restarts the models from
+// the recovered states. Exits the process if any Raft Group diverges.
+fn verify(
+    engine: &CrashEngine,
+    args: &TestArgs,
+    seed: u64,
+    round: u64,
+    models: &mut [RegionModel],
+) {
+    let mut divergences = 0;
+    for model in models.iter_mut() {
+        match verify_region(engine, seed, args.entry_size, model) {
+            Ok(recovered) => {
+                model.durable = recovered;
+                model.pending.clear();
+            }
+            Err(e) => {
+                println!("[round {}] region {}: {}", round, model.region_id, e);
+                divergences += 1;
+            }
+        }
+    }
+    if divergences > 0 {
+        println!(
+            "Crash test failed: {} regions diverged after {} crashes (seed = {})",
+            divergences, round, seed
+        );
+        std::process::exit(1);
+    }
+}
+
+// Returns the log files in `dir` along with their sizes.
+fn list_files(fs: &MemFileSystem, dir: &str) -> Vec<(PathBuf, Option<usize>)> {
+    let mut files: Vec<_> = fs
+        .list_files(dir)
+        .unwrap()
+        .into_iter()
+        .map(|path| {
+            let size = fs.file_size(&path);
+            (path, size)
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+fn spawn_purge(
+    engine: Arc<CrashEngine>,
+    interval: Duration,
+    crash_lock: Arc<RwLock<()>>,
+    crashed: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    ThreadBuilder::new()
+        .name("stress-crash-purge-thread".to_owned())
+        .spawn(move || {
+            let start = Instant::now();
+            let mut next = start + interval;
+            while !crashed.load(Ordering::SeqCst) {
+                if Instant::now() < next {
+                    sleep(Duration::from_millis(10));
+                    continue;
+                }
+                next += interval;
+                let _guard = crash_lock.read().unwrap();
+                if crashed.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Err(e) = engine.purge_expired_files() {
+                    println!("purge error {:?}", e);
+                }
+            }
+        })
+        .unwrap()
+}
+
+/// Runs writers and crashes the engine every `crash_interval` until
+/// `args.time` is reached. Exits the process with an error if any Raft Group
+/// diverges after recovery.
+pub fn run(config: Config, args: TestArgs, seed: u64, crash_interval: Duration) {
+    std::fs::create_dir_all(&config.dir).unwrap();
+    let fs = Arc::new(MemFileSystem::new());
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut models: Vec<RegionModel> = (0..args.regions)
+        .map(|region_id| RegionModel {
+            region_id,
+            ..Default::default()
+        })
+        .collect();
+    let start = Instant::now();
+    let mut round = 0;
+    let mut acknowledged = 0;
+    while start.elapsed() < args.time {
+        let engine =
+            Arc::new(CrashEngine::open_with_file_system(config.clone(), fs.clone()).unwrap());
+        verify(&engine, &args, seed, round, &mut models);
+
+        let crash_lock = Arc::new(RwLock::new(()));
+        let crashed = Arc::new(AtomicBool::new(false));
+        let mut writers = Vec::new();
+        for index in 0..args.write_threads {
+            let writer = Writer {
+                engine: engine.clone(),
+                args: args.clone(),
+                seed,
+                rng: StdRng::seed_from_u64(seed ^ (round << 16) ^ index),
+                crash_lock: crash_lock.clone(),
+                crashed: crashed.clone(),
+                models: models
+                    .iter()
+                    .filter(|m| m.region_id % args.write_threads == index)
+                    .cloned()
+                    .collect(),
+                acknowledged: 0,
+            };
+            writers.push(
+                ThreadBuilder::new()
+                    .name(format!("stress-crash-write-thread-{}", index))
+                    .spawn(move || writer.run())
+                    .unwrap(),
+            );
+        }
+        let purge = if args.purge_interval.as_millis() > 0 {
+            Some(spawn_purge(
+                engine.clone(),
+                args.purge_interval,
+                crash_lock.clone(),
+                crashed.clone(),
+            ))
+        } else {
+            None
+        };
+
+        sleep(std::cmp::min(
+            crash_interval,
+            args.time.saturating_sub(start.elapsed()),
+        ));
+        {
+            let _guard = crash_lock.write().unwrap();
+            crashed.store(true, Ordering::SeqCst);
+            fs.crash_with_torn_write(rng.gen_range(0..=args.entry_size));
+        }
+        for writer in writers {
+            let writer = writer.join().unwrap();
+            acknowledged += writer.acknowledged;
+            for model in writer.models {
+                let i = model.region_id as usize;
+                models[i] = model;
+            }
+        }
+        if let Some(purge) = purge {
+            purge.join().unwrap();
+        }
+        // Files opened before the crash are detached from `fs`, so closing
+        // them when the engine is dropped doesn't change what is recovered.
+        // The engine is dropped instead of leaked to release the directory
+        // lock.
+        let files = list_files(&fs, &config.dir);
+        drop(engine);
+        assert_eq!(files, list_files(&fs, &config.dir));
+        round += 1;
+    }
+    let engine = CrashEngine::open_with_file_system(config, fs).unwrap();
+    verify(&engine, &args, seed, round, &mut models);
+    println!(
+        "Crash test passed: {} crashes, {} writes acknowledged (seed = {})",
+        round, acknowledged, seed
+    );
+}
@@ -2,6 +2,11 @@
 
 extern crate hdrhistogram;
 
+mod crash;
+mod profile;
+mod report;
+
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
@@ -15,8 +20,12 @@ use num_traits::FromPrimitive;
 use parking_lot_core::SpinWait;
 use raft::eraftpb::Entry;
 use raft_engine::internals::{EventListener, FileBlockHandle};
-use raft_engine::{Command, Config, Engine, LogBatch, MessageExt, ReadableSize, Version};
-use rand::{thread_rng, Rng, RngCore};
+use raft_engine::{Command, Config, Engine, Error, LogBatch, MessageExt, ReadableSize, Version};
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+
+use crate::profile::{CompactPolicy, Profile, ReadPattern, RegionSampler, SizeDistribution};
+use crate::report::{Latency, OpReport, PurgeReport, Report};
 
 type WriteBatch = LogBatch;
 
@@ -45,6 +54,7 @@ const DEFAULT_ENTRY_SIZE: usize = 1024;
 const DEFAULT_WRITE_ENTRY_COUNT: u64 = 10;
 const DEFAULT_WRITE_REGION_COUNT: u64 = 5;
 const DEFAULT_WRITE_SYNC: bool = false;
+const DEFAULT_CRASH_INTERVAL: Duration = Duration::from_millis(1000);
 
 #[derive(Debug, Clone, Parser)]
 #[clap(
@@ -243,6 +253,45 @@ struct ControlOpt {
         help = "Recycle purged and stale logs for incoming writing"
     )]
     enable_log_recycle: bool,
+
+    #[clap(
+        long = "crash-test",
+        help = "Crash the engine periodically and verify recovered data, instead of measuring performance"
+    )]
+    crash_test: bool,
+
+    #[clap(
+        long = "crash-interval",
+        value_name = "interval[ms]",
+        takes_value = true,
+        default_value = formatcp!("{}", DEFAULT_CRASH_INTERVAL.as_millis()),
+        help = "Set the interval between crashes in crash test"
+    )]
+    crash_interval: u64,
+
+    #[clap(
+        long = "seed",
+        takes_value = true,
+        default_value = "0",
+        help = "Set the seed of random workload and written data"
+    )]
+    seed: u64,
+
+    #[clap(
+        long = "profile",
+        value_name = "path",
+        takes_value = true,
+        help = "Load workload profile from a TOML file, which overrides --entry-size and --compact-count"
+    )]
+    profile: Option<PathBuf>,
+
+    #[clap(
+        long = "report",
+        value_name = "path",
+        takes_value = true,
+        help = "Write results as JSON to this file"
+    )]
+    report: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone)]
@@ -260,6 +309,8 @@ struct TestArgs {
     write_entry_count: u64,
     write_region_count: u64,
     write_without_sync: bool,
+    seed: u64,
+    profile: Profile,
 }
 
 impl Default for TestArgs {
@@ -278,6 +329,8 @@ impl Default for TestArgs {
             write_entry_count: DEFAULT_WRITE_ENTRY_COUNT,
             write_region_count: DEFAULT_WRITE_REGION_COUNT,
             write_without_sync: DEFAULT_WRITE_SYNC,
+            seed: 0,
+            profile: Profile::default(),
         }
     }
 }
@@ -295,7 +348,7 @@ impl TestArgs {
                 "Write region count must be smaller than region-count / write-threads.".to_owned(),
             );
         }
-        Ok(())
+        self.profile.validate()
     }
 }
 
@@ -303,6 +356,9 @@ struct ThreadSummary {
     hist: Histogram<u64>,
     first: Option<Instant>,
     last: Option<Instant>,
+    // Bytes of entries and key values written.
+    bytes: u64,
+    misses: u64,
 }
 
 impl ThreadSummary {
@@ -311,6 +367,8 @@ impl ThreadSummary {
             hist: Histogram::new(3 /* significant figures */).unwrap(),
             first: None,
             last: None,
+            bytes: 0,
+            misses: 0,
         }
     }
 
@@ -337,6 +395,8 @@ impl ThreadSummary {
 struct Summary {
     hist: Option<Histogram<u64>>,
     thread_qps: Vec<f64>,
+    bytes: u64,
+    misses: u64,
 }
 
 impl Summary {
@@ -344,11 +404,15 @@ impl Summary {
         Summary {
             hist: None,
             thread_qps: Vec::new(),
+            bytes: 0,
+            misses: 0,
         }
     }
 
     fn add(&mut self, s: ThreadSummary) {
         self.thread_qps.push(s.qps());
+        self.bytes += s.bytes;
+        self.misses += s.misses;
         if let Some(hist) = &mut self.hist {
             *hist += s.hist;
         } else {
@@ -385,8 +449,21 @@ impl Summary {
                 f64::abs(first - last) / (first + last)
             };
             println!("Fairness = {:.01}%", 100.0 - fairness * 100.0);
+            if self.misses > 0 {
+                println!("Misses = {}", self.misses);
+            }
         }
     }
+
+    fn report(&self) -> Option<OpReport> {
+        let hist = self.hist.as_ref()?;
+        Some(OpReport {
+            ops: hist.len(),
+            qps: self.thread_qps.iter().sum(),
+            latency_us: Latency::new(hist),
+            misses: self.misses,
+        })
+    }
 }
 
 fn spawn_write(
@@ -400,7 +477,9 @@ fn spawn_write(
         .spawn(move || {
             let mut summary = ThreadSummary::new();
             let mut log_batch = WriteBatch::with_capacity(4 * 1024);
-            let mut entry_batch = Vec::with_capacity(args.write_entry_count as usize);
+            let mut rng = StdRng::seed_from_u64(args.seed ^ index);
+            let sampler =
+                RegionSampler::new(&args.profile.regions, args.regions / args.write_threads);
             let min_interval = if args.write_ops_per_thread > 0 {
                 Some(Duration::from_secs_f64(
                     1.0 / args.write_ops_per_thread as f64,
@@ -408,43 +487,66 @@ fn spawn_write(
             } else {
                 None
             };
-            for _ in 0..args.write_entry_count {
-                let mut data = vec![0; args.entry_size];
-                thread_rng().fill_bytes(&mut data);
-                entry_batch.push(Entry {
-                    data: data.into(),
-                    ..Default::default()
-                });
-            }
+            let mut rids = Vec::with_capacity(args.write_region_count as usize);
             while !shutdown.load(Ordering::Relaxed) {
-                // TODO(tabokie): scattering regions in one batch
-                let mut rid = thread_rng().gen_range(0..(args.regions / args.write_threads))
-                    * args.write_threads
-                    + index;
+                let mut bytes = 0;
+                rids.clear();
                 for _ in 0..args.write_region_count {
+                    let rid = sampler.sample(&mut rng) * args.write_threads + index;
+                    if rids.contains(&rid) {
+                        continue;
+                    }
+                    rids.push(rid);
+                    if let Some(churn) = &args.profile.churn {
+                        if rng.gen_bool(churn.clean_ratio) {
+                            log_batch.add_command(rid, Command::Clean);
+                            continue;
+                        }
+                    }
                     let first = engine.first_index(rid).unwrap_or(0);
                     let last = engine.last_index(rid).unwrap_or(0);
-                    let entries = prepare_entries(&entry_batch, last + 1);
+                    let entries: Vec<Entry> = (last + 1..=last + args.write_entry_count)
+                        .map(|index| {
+                            let size = args.profile.entry_size.sample(&mut rng);
+                            bytes += size;
+                            Entry {
+                                index,
+                                data: random_bytes(&mut rng, size).into(),
+                                ..Default::default()
+                            }
+                        })
+                        .collect();
                     log_batch
                         .add_entries::<MessageExtTyped>(rid, &entries)
                         .unwrap();
-                    if args.compact_count > 0 && last - first + 1 > args.compact_count {
-                        log_batch.add_command(
-                            rid,
-                            Command::Compact {
-                                index: last - args.compact_count + 1,
-                            },
-                        );
+                    if let CompactPolicy::KeepLatest { count } = args.profile.compact {
+                        if last - first + 1 > count {
+                            log_batch.add_command(
+                                rid,
+                                Command::Compact {
+                                    index: last - count + 1,
+                                },
+                            );
+                        }
+                    }
+                    if let Some(kv) = &args.profile.kv {
+                        if rng.gen_bool(kv.put_ratio) {
+                            let key =
+                                format!("key-{}", rng.gen_range(0..kv.key_count)).into_bytes();
+                            let value = random_bytes(&mut rng, kv.value_size.0 as usize);
+                            bytes += key.len() + value.len();
+                            log_batch.put(rid, key, value).unwrap();
+                        }
                     }
-                    rid += args.write_threads;
                 }
                 let mut start = Instant::now();
                 if let (Some(i), Some(last)) = (min_interval, summary.last) {
                     // TODO(tabokie): compensate for slow requests
                     wait_til(&mut start, last + i);
                 }
-                if let Err(e) = engine.write(&mut log_batch, !args.write_without_sync) {
-                    println!("write error {:?} in thread {}", e, index);
+                match engine.write(&mut log_batch, !args.write_without_sync) {
+                    Ok(_) => summary.bytes += bytes as u64,
+                    Err(e) => println!("write error {:?} in thread {}", e, index),
                 }
                 let end = Instant::now();
                 summary.record(start, end);
@@ -464,6 +566,9 @@ fn spawn_read(
         .name(format!("stress-read-thread-{}", index))
         .spawn(move || {
             let mut summary = ThreadSummary::new();
+            let mut rng = StdRng::seed_from_u64(args.seed ^ !index);
+            let sampler =
+                RegionSampler::new(&args.profile.regions, args.regions / args.read_threads);
             let min_interval = if args.read_ops_per_thread > 0 {
                 Some(Duration::from_secs_f64(
                     1.0 / args.read_ops_per_thread as f64,
@@ -471,18 +576,46 @@ fn spawn_read(
             } else {
                 None
             };
+            let mut entries = Vec::new();
             while !shutdown.load(Ordering::Relaxed) {
-                let rid = thread_rng().gen_range(0..(args.regions / args.read_threads))
-                    * args.read_threads
-                    + index;
+                let rid = sampler.sample(&mut rng) * args.read_threads + index;
                 let mut start = Instant::now();
                 if let (Some(i), Some(last)) = (min_interval, summary.last) {
                     wait_til(&mut start, last + i);
                 }
-                // Read newest entry to avoid conflicting with compact
                 if let Some(last) = engine.last_index(rid) {
-                    if let Err(e) = engine.get_entry::<MessageExtTyped>(rid, last) {
-                        println!("read error {:?} in thread {}", e, index);
+                    let res = match args.profile.read {
+                        // Read newest entry to avoid conflicting with compact
+                        ReadPattern::LastEntry => {
+                            engine.get_entry::<MessageExtTyped>(rid, last).map(|_| ())
+                        }
+                        ReadPattern::Range {
+                            min_lag,
+                            max_lag,
+                            max_entries,
+                        } => {
+                            let lag = rng.gen_range(min_lag..=max_lag);
+                            let begin = std::cmp::max(last.saturating_sub(lag), 1);
+                            let end = std::cmp::min(begin + max_entries, last + 1);
+                            entries.clear();
+                            engine
+                                .fetch_entries_to::<MessageExtTyped>(
+                                    rid,
+                                    begin,
+                                    end,
+                                    None,
+                                    &mut entries,
+                                )
+                                .map(|_| ())
+                        }
+                    };
+                    match res {
+                        Ok(()) => {}
+                        // The region is compacted or cleaned concurrently.
+                        Err(Error::EntryCompacted) | Err(Error::EntryNotFound) => {
+                            summary.misses += 1
+                        }
+                        Err(e) => println!("read error {:?} in thread {}", e, index),
                     }
                     let end = Instant::now();
                     summary.record(start, end);
@@ -498,13 +631,20 @@ fn spawn_purge(
     args: TestArgs,
     index: u64,
     shutdown: Arc<AtomicBool>,
-) -> JoinHandle<()> {
+) -> JoinHandle<PurgeReport> {
     ThreadBuilder::new()
         .name(format!("stress-purge-thread-{}", index))
         .spawn(move || {
+            let mut report = PurgeReport::default();
             while !shutdown.load(Ordering::Relaxed) {
                 sleep(args.purge_interval);
-                match engine.purge_expired_files() {
+                let start = Instant::now();
+                let res = engine.purge_expired_files();
+                let elapsed = start.elapsed().as_secs_f64();
+                report.count += 1;
+                report.total_secs += elapsed;
+                report.max_secs = f64::max(report.max_secs, elapsed);
+                match res {
                     Ok(regions) => {
                         for region in regions.into_iter() {
                             let first = engine.first_index(region).unwrap_or(0);
@@ -518,20 +658,41 @@ fn spawn_purge(
                     Err(e) => println!("purge error {:?} in thread {}", e, index),
                 }
             }
+            report
         })
         .unwrap()
 }
 
-fn prepare_entries(entries: &[Entry], mut start_index: u64) -> Vec<Entry> {
-    entries
-        .iter()
-        .cloned()
-        .map(|mut e| {
-            e.set_index(start_index);
-            start_index += 1;
-            e
+fn spawn_compact(
+    engine: Arc<Engine>,
+    args: TestArgs,
+    interval: Duration,
+    keep: u64,
+    shutdown: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    ThreadBuilder::new()
+        .name("stress-compact-thread".to_owned())
+        .spawn(move || {
+            while !shutdown.load(Ordering::Relaxed) {
+                sleep(interval);
+                for rid in 0..args.regions {
+                    if let (Some(first), Some(last)) =
+                        (engine.first_index(rid), engine.last_index(rid))
+                    {
+                        if last - first + 1 > keep {
+                            engine.compact_to(rid, last - keep + 1);
+                        }
+                    }
+                }
+            }
         })
-        .collect()
+        .unwrap()
+}
+
+fn random_bytes<R: RngCore>(rng: &mut R, size: usize) -> Vec<u8> {
+    let mut data = vec![0; size];
+    rng.fill_bytes(&mut data);
+    data
 }
 
 fn wait_til(now: &mut Instant, t: Instant) {
@@ -561,11 +722,12 @@ impl WrittenBytesHook {
         Self(AtomicUsize::new(0))
     }
 
+    pub fn bytes(&self) -> u64 {
+        self.0.load(Ordering::Relaxed) as u64
+    }
+
     pub fn print(&self, time: u64) {
-        println!(
-            "Write Bandwidth = {}/s",
-            ReadableSize(self.0.load(Ordering::Relaxed) as u64 / time)
-        );
+        println!("Write Bandwidth = {}/s", ReadableSize(self.bytes() / time));
     }
 }
 
@@ -609,6 +771,23 @@ fn main() {
     args.write_entry_count = opts.write_entry_count;
     args.write_region_count = opts.write_region_count;
     args.write_without_sync = opts.write_without_sync;
+    args.seed = opts.seed;
+    args.profile = match &opts.profile {
+        Some(path) => Profile::load(path).unwrap(),
+        None => Profile {
+            entry_size: SizeDistribution::Fixed {
+                size: ReadableSize(args.entry_size as u64),
+            },
+            compact: if args.compact_count > 0 {
+                CompactPolicy::KeepLatest {
+                    count: args.compact_count,
+                }
+            } else {
+                CompactPolicy::None
+            },
+            ..Default::default()
+        },
+    };
     if !opts.reuse_data {
         // clean up existing log files
         let _ = std::fs::remove_dir_all(&config.dir);
@@ -617,6 +796,16 @@ fn main() {
     args.validate().unwrap();
     config.sanitize().unwrap();
 
+    if opts.crash_test {
+        crash::run(
+            config,
+            args,
+            opts.seed,
+            Duration::from_millis(opts.crash_interval),
+        );
+        return;
+    }
+
     let wb = Arc::new(WrittenBytesHook::new());
 
     let engine = Arc::new(Engine::open_with_listeners(config, vec![wb.clone()]).unwrap());
@@ -624,12 +813,24 @@ fn main() {
     let mut read_threads = Vec::new();
     let mut misc_threads = Vec::new();
     let shutdown = Arc::new(AtomicBool::new(false));
-    if args.purge_interval.as_millis() > 0 {
-        misc_threads.push(spawn_purge(
+    let start = Instant::now();
+    let purge_thread = if args.purge_interval.as_millis() > 0 {
+        Some(spawn_purge(
             engine.clone(),
             args.clone(),
             0,
             shutdown.clone(),
+        ))
+    } else {
+        None
+    };
+    if let CompactPolicy::Periodic { interval_ms, keep } = args.profile.compact {
+        misc_threads.push(spawn_compact(
+            engine.clone(),
+            args.clone(),
+            Duration::from_millis(interval_ms),
+            keep,
+            shutdown.clone(),
         ));
     }
     if args.read_threads > 0 {
@@ -654,20 +855,39 @@ fn main() {
     }
     sleep(args.time);
     shutdown.store(true, Ordering::Relaxed);
-    write_threads
-        .into_iter()
-        .fold(Summary::new(), |mut s, t| {
-            s.add(t.join().unwrap());
-            s
-        })
-        .print("write");
-    read_threads
-        .into_iter()
-        .fold(Summary::new(), |mut s, t| {
-            s.add(t.join().unwrap());
-            s
-        })
-        .print("read");
+    let write_summary = write_threads.into_iter().fold(Summary::new(), |mut s, t| {
+        s.add(t.join().unwrap());
+        s
+    });
+    write_summary.print("write");
+    let read_summary = read_threads.into_iter().fold(Summary::new(), |mut s, t| {
+        s.add(t.join().unwrap());
+        s
+    });
+    read_summary.print("read");
+    let purge = purge_thread.map_or_else(PurgeReport::default, |t| t.join().unwrap());
     misc_threads.into_iter().for_each(|t| t.join().unwrap());
+    let duration = start.elapsed();
     wb.print(args.time.as_secs());
+    let write_amplification = if write_summary.bytes > 0 {
+        wb.bytes() as f64 / write_summary.bytes as f64
+    } else {
+        0.0
+    };
+    println!("Write Amplification = {:.02}", write_amplification);
+
+    if let Some(path) = &opts.report {
+        let report = Report {
+            seed: args.seed,
+            duration_secs: duration.as_secs_f64(),
+            profile: args.profile.clone(),
+            write: write_summary.report(),
+            read: read_summary.report(),
+            user_bytes_written: write_summary.bytes,
+            disk_bytes_written: wb.bytes(),
+            write_amplification,
+            purge,
+        };
+        std::fs::write(path, serde_json::to_string_pretty(&report).unwrap()).unwrap();
+    }
 }
@@ -0,0 +1,315 @@
+// Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
+
+//! Workload profiles loaded from TOML files.
+//!
+//! ```toml
+//! [regions]
+//! distribution = "zipf"
+//! exponent = 1.1
+//!
+//! [entry-size]
+//! distribution = "log-normal"
+//! median = "1KB"
+//! sigma = 0.5
+//!
+//! [kv]
+//! put-ratio = 0.1
+//! key-count = 16
+//! value-size = "256B"
+//!
+//! [read]
+//! pattern = "range"
+//! max-lag = 200
+//! max-entries = 64
+//!
+//! [churn]
+//! clean-ratio = 0.001
+//!
+//! [compact]
+//! policy = "periodic"
+//! interval-ms = 5000
+//! keep = 1000
+//! ```
+
+use std::path::Path;
+
+use raft_engine::ReadableSize;
+use rand::Rng;
+use rand_distr::{Distribution, LogNormal, Zipf};
+use serde::{Deserialize, Serialize};
+
+/// How regions are chosen by writers and readers.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "distribution", rename_all = "kebab-case")]
+pub enum RegionDistribution {
+    Uniform,
+    /// Region with the smaller ID is more frequently accessed.
+    Zipf {
+        exponent: f64,
+    },
+}
+
+/// Picks regions from `0..n` following a [`RegionDistribution`].
+pub enum RegionSampler {
+    Uniform(u64),
+    Zipf(Zipf<f64>),
+}
+
+impl RegionSampler {
+    pub fn new(distribution: &RegionDistribution, n: u64) -> Self {
+        match distribution {
+            RegionDistribution::Uniform => RegionSampler::Uniform(n),
+            RegionDistribution::Zipf { exponent } => {
+                RegionSampler::Zipf(Zipf::new(n, *exponent).unwrap())
+            }
+        }
+    }
+
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> u64 {
+        match self {
+            RegionSampler::Uniform(n) => rng.gen_range(0..*n),
+            RegionSampler::Zipf(zipf) => zipf.sample(rng) as u64 - 1,
+        }
+    }
+}
+
+/// Size of a written log entry.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "distribution", rename_all = "kebab-case")]
+pub enum SizeDistribution {
+    Fixed {
+        size: ReadableSize,
+    },
+    Uniform {
+        min: ReadableSize,
+        max: ReadableSize,
+    },
+    LogNormal {
+        median: ReadableSize,
+        sigma: f64,
+    },
+}
+
+impl SizeDistribution {
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> usize {
+        match self {
+            SizeDistribution::Fixed { size } => size.0 as usize,
+            SizeDistribution::Uniform { min, max } => rng.gen_range(min.0..=max.0) as usize,
+            SizeDistribution::LogNormal { median, sigma } => {
+                LogNormal::new((median.0 as f64).ln(), *sigma)
+                    .unwrap()
+                    .sample(rng) as usize
+            }
+        }
+    }
+}
+
+/// Key values written along with log entries.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct KvWorkload {
+    /// Probability that a key is put when a region is written.
+    pub put_ratio: f64,
+    /// Number of distinct keys of each region.
+    pub key_count: u64,
+    pub value_size: ReadableSize,
+}
+
+impl Default for KvWorkload {
+    fn default() -> Self {
+        KvWorkload {
+            put_ratio: 0.1,
+            key_count: 16,
+            value_size: ReadableSize(256),
+        }
+    }
+}
+
+/// What readers fetch from a region.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "pattern", rename_all = "kebab-case")]
+pub enum ReadPattern {
+    /// The newest entry.
+    LastEntry,
+    /// A range of entries lagging behind the newest one, like a follower
+    /// catching up.
+    #[serde(rename_all = "kebab-case")]
+    Range {
+        #[serde(default)]
+        min_lag: u64,
+        max_lag: u64,
+        max_entries: u64,
+    },
+}
+
+/// Regions being removed and created again.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Churn {
+    /// Probability that a region is cleaned instead of written.
+    pub clean_ratio: f64,
+}
+
+/// How log entries are compacted.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "policy", rename_all = "kebab-case")]
+pub enum CompactPolicy {
+    /// Only compacted when purge asks for it.
+    None,
+    /// Writers compact entries beyond the newest `count` ones.
+    KeepLatest { count: u64 },
+    /// All regions are compacted to the newest `keep` entries every
+    /// `interval-ms`.
+    #[serde(rename_all = "kebab-case")]
+    Periodic { interval_ms: u64, keep: u64 },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Profile {
+    pub regions: RegionDistribution,
+    pub entry_size: SizeDistribution,
+    pub kv: Option<KvWorkload>,
+    pub read: ReadPattern,
+    pub churn: Option<Churn>,
+    pub compact: CompactPolicy,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            regions: RegionDistribution::Uniform,
+            entry_size: SizeDistribution::Fixed {
+                size: ReadableSize(crate::DEFAULT_ENTRY_SIZE as u64),
+            },
+            kv: None,
+            read: ReadPattern::LastEntry,
+            churn: None,
+            compact: CompactPolicy::None,
+        }
+    }
+}
+
+impl Profile {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        toml::from_str(&content).map_err(|e| format!("invalid profile {}: {}", path.display(), e))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let RegionDistribution::Zipf { exponent } = self.regions {
+            if exponent <= 0.0 {
+                return Err("Zipf exponent must be positive.".to_owned());
+            }
+        }
+        match &self.entry_size {
+            SizeDistribution::Uniform { min, max } if min > max => {
+                return Err("Minimum entry size must not exceed maximum.".to_owned());
+            }
+            SizeDistribution::LogNormal { median, sigma } if median.0 == 0 || *sigma < 0.0 => {
+                return Err("Log-normal median must be positive, sigma non-negative.".to_owned());
+            }
+            _ => {}
+        }
+        if let Some(kv) = &self.kv {
+            if !(0.0..=1.0).contains(&kv.put_ratio) || kv.key_count == 0 {
+                return Err("KV put ratio must be in [0, 1], key count positive.".to_owned());
+            }
+        }
+        if let ReadPattern::Range {
+            min_lag,
+            max_lag,
+            max_entries,
+        } = self.read
+        {
+            if min_lag > max_lag || max_entries == 0 {
+                return Err("Read lag range is empty or max entries is zero.".to_owned());
+            }
+        }
+        if let Some(churn) = &self.churn {
+            if !(0.0..=1.0).contains(&churn.clean_ratio) {
+                return Err("Clean ratio must be in [0, 1].".to_owned());
+            }
+        }
+        match self.compact {
+            CompactPolicy::KeepLatest { count: 0 } | CompactPolicy::Periodic { keep: 0, .. } => {
+                Err("Compaction must keep at least one entry.".to_owned())
+            }
+            CompactPolicy::Periodic { interval_ms: 0, .. } => {
+                Err("Compaction interval must be positive.".to_owned())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_profile() {
+        let profile: Profile = toml::from_str(
+            r#"
+            [regions]
+            distribution = "zipf"
+            exponent = 1.1
+
+            [entry-size]
+            distribution = "uniform"
+            min = "128B"
+            max = "4KB"
+
+            [kv]
+            put-ratio = 0.5
+
+            [read]
+            pattern = "range"
+            max-lag = 200
+            max-entries = 64
+
+            [churn]
+            clean-ratio = 0.01
+
+            [compact]
+            policy = "periodic"
+            interval-ms = 5000
+            keep = 1000
+            "#,
+        )
+        .unwrap();
+        profile.validate().unwrap();
+        assert_eq!(profile.regions, RegionDistribution::Zipf { exponent: 1.1 });
+        assert_eq!(
+            profile.entry_size,
+            SizeDistribution::Uniform {
+                min: ReadableSize(128),
+                max: ReadableSize::kb(4)
+            }
+        );
+        assert_eq!(profile.kv.as_ref().unwrap().put_ratio, 0.5);
+        assert_eq!(profile.kv.as_ref().unwrap().key_count, 16);
+        assert_eq!(
+            profile.read,
+            ReadPattern::Range {
+                min_lag: 0,
+                max_lag: 200,
+                max_entries: 64
+            }
+        );
+        assert_eq!(
+            profile.compact,
+            CompactPolicy::Periodic {
+                interval_ms: 5000,
+                keep: 1000
+            }
+        );
+
+        let default: Profile = toml::from_str("").unwrap();
+        assert_eq!(default, Profile::default());
+        let invalid: Profile =
+            toml::from_str("[compact]\npolicy = \"keep-latest\"\ncount = 0").unwrap();
+        assert!(invalid.validate().is_err());
+    }
+}
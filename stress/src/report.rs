@@ -0,0 +1,67 @@
+// Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
+
+//! Machine-readable results of a stress test.
+
+use hdrhistogram::Histogram;
+use serde::Serialize;
+
+use crate::profile::Profile;
+
+/// Latency quantiles in microseconds.
+#[derive(Debug, Serialize)]
+pub struct Latency {
+    pub min: u64,
+    pub mean: f64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p95: u64,
+    pub p99: u64,
+    pub p999: u64,
+    pub max: u64,
+}
+
+impl Latency {
+    pub fn new(hist: &Histogram<u64>) -> Self {
+        Latency {
+            min: hist.min(),
+            mean: hist.mean(),
+            p50: hist.value_at_quantile(0.5),
+            p90: hist.value_at_quantile(0.9),
+            p95: hist.value_at_quantile(0.95),
+            p99: hist.value_at_quantile(0.99),
+            p999: hist.value_at_quantile(0.999),
+            max: hist.max(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct OpReport {
+    pub ops: u64,
+    pub qps: f64,
+    pub latency_us: Latency,
+    /// Reads of compacted or missing entries.
+    pub misses: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct PurgeReport {
+    pub count: u64,
+    pub total_secs: f64,
+    pub max_secs: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub seed: u64,
+    pub duration_secs: f64,
+    pub profile: Profile,
+    pub write: Option<OpReport>,
+    pub read: Option<OpReport>,
+    /// Bytes of log entries and key values submitted by writers.
+    pub user_bytes_written: u64,
+    /// Bytes appended to log files, including rewrites.
+    pub disk_bytes_written: u64,
+    pub write_amplification: f64,
+    pub purge: PurgeReport,
+}
@@ -399,6 +399,48 @@ fn test_incomplete_purge() {
     assert_eq!(engine.last_index(rid).unwrap(), 59);
 }
 
+#[test]
+fn test_lazy_recovery_not_ready() {
+    let dir = tempfile::Builder::new()
+        .prefix("test_lazy_recovery_not_ready")
+        .tempdir()
+        .unwrap();
+    let cfg = Config {
+        dir: dir.path().to_str().unwrap().to_owned(),
+        enable_lazy_recovery: true,
+        ..Default::default()
+    };
+    let rid = 1;
+    let data = vec![b'7'; 1024];
+
+    let engine = Engine::open(cfg.clone()).unwrap();
+    append(&engine, rid, 1, 11, Some(&data));
+    engine.close().unwrap();
+
+    let f = FailGuard::new("lazy_recovery::index_all", "pause");
+    let engine = Engine::open(cfg).unwrap();
+    let mut batch = LogBatch::default();
+    batch
+        .add_entries::<MessageExtTyped>(rid, &generate_entries(11, 12, Some(&data)))
+        .unwrap();
+    // Writes and compactions fail instead of waiting for the entries forever.
+    assert!(matches!(
+        engine.write(&mut batch, false),
+        Err(Error::NotReady)
+    ));
+    assert_eq!(engine.compact_to(rid, 5), 0);
+    assert!(matches!(engine.try_first_index(rid), Err(Error::NotReady)));
+    assert_eq!(engine.first_index(rid), None);
+
+    drop(f);
+    while !engine.is_region_ready(rid) {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    engine.write(&mut batch, false).unwrap();
+    assert_eq!(engine.compact_to(rid, 5), 4);
+    assert_eq!(engine.last_index(rid), Some(11));
+}
+
 #[test]
 fn test_tail_corruption() {
     let data = vec![b'x'; 16];
@@ -726,6 +768,81 @@ fn test_build_engine_with_datalayout_abnormal() {
     }
 }
 
+#[test]
+fn test_salvage_recovery_with_datalayout_alignment() {
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+
+    let dir = tempfile::Builder::new()
+        .prefix("test_salvage_recovery_with_datalayout_alignment")
+        .tempdir()
+        .unwrap();
+    let data = vec![b'x'; 16];
+    let cfg = Config {
+        dir: dir.path().to_str().unwrap().to_owned(),
+        // One big file.
+        target_file_size: ReadableSize::gb(10),
+        recovery_mode: RecoveryMode::Salvage,
+        format_version: Version::V2,
+        ..Default::default()
+    };
+    let _f = FailGuard::new("file_pipe_log::open::force_set_alignment", "return");
+    let engine = Engine::open(cfg.clone()).unwrap();
+    for i in 0..10 {
+        for rid in 1..=3 {
+            append(&engine, rid, i * 5 + 1, i * 5 + 6, Some(&data));
+        }
+    }
+    drop(engine);
+
+    let inspector = Engine::inspect(dir.path()).unwrap();
+    let handle = inspector.entry_index(2, 26).unwrap().entries.unwrap();
+    let handle1 = inspector.entry_index(1, 36).unwrap().entries.unwrap();
+    let file = inspector
+        .files()
+        .iter()
+        .find(|f| f.file_id == handle.id)
+        .unwrap();
+    assert!(file.alignment > 0);
+    // Length of the log batch header.
+    let header_len = 16;
+    let mut f = OpenOptions::new().write(true).open(&file.path).unwrap();
+    // Corrupt the header of a log batch in the middle.
+    let batch_offset = handle.offset - header_len;
+    f.seek(SeekFrom::Start(batch_offset)).unwrap();
+    f.write_all(&[0xff; 16]).unwrap();
+    // Corrupt the footer of another log batch, whose header is intact.
+    let batch_offset1 = handle1.offset - header_len;
+    f.seek(SeekFrom::Start(handle1.offset + handle1.len as u64))
+        .unwrap();
+    f.write_all(&[0xff]).unwrap();
+    drop(f);
+
+    let (engine, report) = EngineBuilder::new(cfg).open_with_report().unwrap();
+    assert_eq!(report.corrupted_ranges.len(), 2);
+    // Corrupted ranges include the paddings before the log batches.
+    let range = &report.corrupted_ranges[0];
+    assert!(range.offset <= batch_offset && range.offset + file.alignment > batch_offset);
+    assert!(!range.truncated);
+    // Only the corrupted log batch is skipped, recovery continues from the next
+    // aligned log batch.
+    let range = &report.corrupted_ranges[1];
+    assert!(range.offset <= batch_offset1 && range.offset + file.alignment > batch_offset1);
+    assert_eq!((range.offset + range.len) % file.alignment, 0);
+    assert!(range.len < 2 * (handle1.len as u64 + header_len));
+    assert_eq!(report.lost_entries.len(), 2);
+    assert_eq!(report.lost_entries[&1], vec![1..41]);
+    assert_eq!(report.lost_entries[&2], vec![1..31]);
+    for (rid, first) in [(1, 41), (2, 31), (3, 1)] {
+        assert_eq!(engine.first_index(rid), Some(first));
+        assert_eq!(engine.last_index(rid), Some(50));
+        for idx in first..=50 {
+            let entry = engine.get_entry::<MessageExtTyped>(rid, idx).unwrap();
+            assert_eq!(entry.unwrap().data, data);
+        }
+    }
+}
+
 // issue-228
 #[test]
 fn test_partial_rewrite_rewrite() {
@@ -968,6 +1085,6 @@ fn test_split_rewrite_batch_with_only_kvs() {
     drop(engine);
     let engine = Engine::open(cfg).unwrap();
     for i in 1..=rid {
-        assert_eq!(engine.get(i, &key).unwrap(), value);
+        assert_eq!(engine.get(i, &key).unwrap().unwrap(), value);
     }
 }
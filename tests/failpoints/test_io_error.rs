@@ -76,6 +76,40 @@ fn test_file_read_error() {
         .unwrap_err();
 }
 
+#[test]
+fn test_multi_fetch_async_read() {
+    let dir = tempfile::Builder::new()
+        .prefix("test_multi_fetch_async_read")
+        .tempdir()
+        .unwrap();
+    let cfg = Config {
+        dir: dir.path().to_str().unwrap().to_owned(),
+        ..Default::default()
+    };
+    let entry = vec![b'x'; 16];
+    let engine = Engine::open(cfg).unwrap();
+    engine
+        .write(&mut generate_batch(1, 1, 3, Some(&entry)), true)
+        .unwrap();
+    engine
+        .write(&mut generate_batch(2, 1, 3, Some(&entry)), true)
+        .unwrap();
+
+    // Small reads are still issued with async I/O, which bypasses the failpoint.
+    let _f = FailGuard::new("log_fd::read::err", "return");
+    let results = engine
+        .multi_fetch::<MessageExtTyped>(&[(1, 1, 3, None), (2, 1, 3, None)])
+        .unwrap();
+    assert_eq!(results.len(), 2);
+    for res in results {
+        assert_eq!(res.unwrap().len(), 2);
+    }
+    let mut entries = Vec::new();
+    engine
+        .fetch_entries_to::<MessageExtTyped>(1, 1, 3, None, &mut entries)
+        .unwrap_err();
+}
+
 #[test]
 fn test_file_write_error() {
     let dir = tempfile::Builder::new()
@@ -497,3 +531,50 @@ fn test_start_with_recycled_file_allocate_error() {
     engine.purge_expired_files().unwrap();
     assert!(engine.file_span(LogQueue::Append).0 > start);
 }
+
+#[test]
+fn test_purge_file_size_error() {
+    let dir = tempfile::Builder::new()
+        .prefix("test_purge_file_size_error")
+        .tempdir()
+        .unwrap();
+    let cfg = Config {
+        dir: dir.path().to_str().unwrap().to_owned(),
+        target_file_size: ReadableSize::kb(1),
+        purge_threshold: ReadableSize::kb(2),
+        ..Default::default()
+    };
+    let entry = vec![b'x'; 1024];
+    let engine = Engine::open(cfg).unwrap();
+    for i in 1..=10 {
+        engine
+            .write(&mut generate_batch(1, i, i + 1, Some(&entry)), true)
+            .unwrap();
+    }
+    engine.compact_to(1, 10);
+    let span = engine.file_span(LogQueue::Append);
+    let used_size = engine.get_used_size();
+    {
+        let _f = FailGuard::new("log_fd::file_size::err", "return");
+        assert!(engine.purge_expired_files().is_err());
+        assert_eq!(engine.file_span(LogQueue::Append), span);
+        assert_eq!(engine.get_used_size(), used_size);
+    }
+    // Files are purged once the error is gone.
+    engine.purge_expired_files().unwrap();
+    assert!(engine.file_span(LogQueue::Append).0 > span.0);
+    assert!(engine.get_used_size() < used_size);
+    let (first, last) = engine.file_span(LogQueue::Append);
+    let log_files = std::fs::read_dir(dir.path())
+        .unwrap()
+        .filter(|e| {
+            e.as_ref()
+                .unwrap()
+                .file_name()
+                .to_str()
+                .unwrap()
+                .ends_with(".raftlog")
+        })
+        .count();
+    assert_eq!(log_files as u64, last - first + 1);
+}